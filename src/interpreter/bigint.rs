@@ -0,0 +1,331 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Arbitrary precision integer: sign and magnitude, the magnitude as base
+/// 2^32 digits, least significant first, without leading zeros.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+fn trim(mut digits: Vec<u32>) -> Vec<u32> {
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    digits
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len());
+    }
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x.cmp(y);
+        }
+    }
+    Ordering::Equal
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if carry > 0 {
+        result.push(carry as u32);
+    }
+    result
+}
+
+/// a - b, where a >= b.
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for i in 0..a.len() {
+        let mut diff = a[i] as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        else {
+            borrow = 0;
+        }
+        result.push(diff as u32);
+    }
+    trim(result)
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec!(0u32; a.len() + b.len());
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let t = x as u64 * y as u64 + result[i + j] as u64 + carry;
+            result[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        result[i + b.len()] = carry as u32;
+    }
+    trim(result)
+}
+
+/// Magnitude division by a single digit: quotient and remainder.
+fn divrem_small(a: &[u32], b: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec!(0u32; a.len());
+    let mut rem = 0u64;
+    for i in (0..a.len()).rev() {
+        let cur = (rem << 32) | a[i] as u64;
+        quotient[i] = (cur / b as u64) as u32;
+        rem = cur % b as u64;
+    }
+    (trim(quotient), rem as u32)
+}
+
+/// Schoolbook binary long division of magnitudes, b non-zero.
+fn divrem_mag(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if cmp_mag(a, b) == Ordering::Less {
+        return (vec!(), a.to_vec());
+    }
+    if b.len() == 1 {
+        let (q, r) = divrem_small(a, b[0]);
+        return (q, trim(vec!(r)));
+    }
+
+    let mut quotient = vec!(0u32; a.len());
+    let mut rem: Vec<u32> = vec!();
+    for i in (0..a.len() * 32).rev() {
+        // rem = rem << 1 | bit i of a
+        let mut carry = (a[i / 32] >> (i % 32)) & 1;
+        for d in rem.iter_mut() {
+            let next = *d >> 31;
+            *d = (*d << 1) | carry;
+            carry = next;
+        }
+        if carry > 0 {
+            rem.push(carry);
+        }
+
+        if cmp_mag(&rem, b) != Ordering::Less {
+            rem = sub_mag(&rem, b);
+            quotient[i / 32] |= 1 << (i % 32);
+        }
+    }
+    (trim(quotient), rem)
+}
+
+impl BigInt {
+    fn from_parts(negative: bool, digits: Vec<u32>) -> BigInt {
+        let digits = trim(digits);
+        BigInt { negative: negative && !digits.is_empty(), digits: digits }
+    }
+
+    pub fn zero() -> BigInt {
+        BigInt { negative: false, digits: vec!() }
+    }
+
+    pub fn from_i64(i: i64) -> BigInt {
+        let magnitude = if i < 0 { (i as u64).wrapping_neg() } else { i as u64 };
+        BigInt::from_parts(i < 0, vec!(magnitude as u32, (magnitude >> 32) as u32))
+    }
+
+    /// Parses an optionally signed run of decimal digits.
+    pub fn parse(s: &str) -> Option<BigInt> {
+        let (negative, digits) = if s.starts_with('-') {
+            (true, &s[1..])
+        }
+        else if s.starts_with('+') {
+            (false, &s[1..])
+        }
+        else {
+            (false, s)
+        };
+
+        if digits.is_empty() {
+            return None;
+        }
+
+        let mut magnitude: Vec<u32> = vec!();
+        for c in digits.chars() {
+            let d = match c.to_digit(10) {
+                Some(d) => d,
+                None => return None,
+            };
+            magnitude = add_mag(&mul_mag(&magnitude, &[10]), &[d]);
+        }
+        Some(BigInt::from_parts(negative, magnitude))
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 2 {
+            return None;
+        }
+
+        let magnitude = self.digits.iter().rev().fold(0u64, |acc, &d| (acc << 32) | d as u64);
+        if self.negative {
+            if magnitude <= 1 << 63 {
+                Some((magnitude as i64).wrapping_neg())
+            }
+            else {
+                None
+            }
+        }
+        else if magnitude <= i64::max_value() as u64 {
+            Some(magnitude as i64)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self.digits.iter().rev().fold(0f64, |acc, &d| acc * 4294967296.0 + d as f64);
+        if self.negative { -magnitude } else { magnitude }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn is_odd(&self) -> bool {
+        self.digits.first().map(|d| d & 1 == 1).unwrap_or(false)
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.digits.clone())
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::from_parts(false, self.digits.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_mag(&self.digits, &other.digits));
+        }
+
+        match cmp_mag(&self.digits, &other.digits) {
+            Ordering::Less => BigInt::from_parts(other.negative, sub_mag(&other.digits, &self.digits)),
+            _ => BigInt::from_parts(self.negative, sub_mag(&self.digits, &other.digits)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        BigInt::from_parts(self.negative != other.negative, mul_mag(&self.digits, &other.digits))
+    }
+
+    /// Truncating division: the quotient rounds toward zero and the
+    /// remainder takes the sign of the dividend. None when dividing by zero.
+    pub fn divrem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+
+        let (q, r) = divrem_mag(&self.digits, &other.digits);
+        Some((BigInt::from_parts(self.negative != other.negative, q),
+              BigInt::from_parts(self.negative, r)))
+    }
+
+    pub fn gcd(&self, other: &BigInt) -> BigInt {
+        let mut a = self.abs();
+        let mut b = other.abs();
+        while !b.is_zero() {
+            let (_, r) = a.divrem(&b).unwrap();
+            a = b;
+            b = r;
+        }
+        a
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.digits, &other.digits),
+            (true, true) => cmp_mag(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+
+        // peel off nine decimal digits at a time
+        let mut chunks = vec!();
+        let mut magnitude = self.digits.clone();
+        while !magnitude.is_empty() {
+            let (q, r) = divrem_small(&magnitude, 1000000000);
+            chunks.push(r);
+            magnitude = q;
+        }
+
+        if self.negative {
+            try!(write!(f, "-"));
+        }
+        try!(write!(f, "{}", chunks.pop().unwrap()));
+        for chunk in chunks.iter().rev() {
+            try!(write!(f, "{:09}", chunk));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BigInt;
+
+    fn big(s: &str) -> BigInt {
+        BigInt::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parse_and_print() {
+        for s in ["0", "1", "-1", "4294967296", "-9223372036854775808",
+                  "123456789012345678901234567890"].iter() {
+            assert_eq!(*s, big(s).to_string());
+        }
+        assert_eq!(None, BigInt::parse("12a"));
+        assert_eq!(Some(i64::min_value()), BigInt::from_i64(i64::min_value()).to_i64());
+        assert_eq!(None, big("9223372036854775808").to_i64());
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+
+        assert_eq!("-864197532086419753208641975320", a.add(&b).to_string());
+        assert_eq!("1111111110111111111011111111100", a.sub(&b).to_string());
+        assert_eq!("-121932631137021795226185032733622923332237463801111263526900",
+                   a.mul(&b).to_string());
+
+        let (q, r) = b.divrem(&a).unwrap();
+        assert_eq!("-8", q.to_string());
+        assert_eq!("-9000000000900000000090", r.to_string());
+        assert!(a.divrem(&BigInt::zero()).is_none());
+        assert_eq!("90", big("1234567890").gcd(&big("-90")).to_string());
+    }
+}
@@ -0,0 +1,517 @@
+use std::rc::Rc;
+
+use parser;
+
+use super::{apply, eval_forms};
+use super::env::Env;
+use super::error::{Error, error};
+use super::hashmap::{Map, Set};
+use super::io;
+use super::list::List;
+use super::macros;
+use super::namespaces;
+use super::numbers;
+use super::refs;
+use super::seq;
+use super::sequences;
+use super::strings;
+use super::value::{self, Builtin, NativeFn, Value};
+use super::vector::Vector;
+
+/// The part of fancy.core written in fancy itself.
+const PRELUDE: &'static str = include_str!("core.fc");
+
+/// A fresh interpreter: fancy.core holding the builtins, and the scope of
+/// the `user` namespace that programs start in.
+pub fn root_env() -> Env {
+    let core = namespaces::core();
+
+    let all = builtins().into_iter()
+        .chain(numbers::builtins())
+        .chain(sequences::builtins())
+        .chain(macros::builtins())
+        .chain(refs::builtins())
+        .chain(io::builtins())
+        .chain(strings::builtins());
+    for (name, f) in all {
+        core.define(name, Value::Builtin(Builtin { name: name, f: f }));
+    }
+    for (name, value) in io::vars() {
+        core.define(name, value);
+    }
+
+    let string = namespaces::find_or_create(&core, strings::STRING);
+    for (name, f) in strings::string_builtins() {
+        string.define(name, Value::Builtin(Builtin { name: name, f: f }));
+    }
+
+    let prelude = parser::parse_string(String::from(PRELUDE));
+    if let Err(e) = eval_forms(&prelude, &core) {
+        panic!("error in prelude: {}", e.stack_trace());
+    }
+
+    namespaces::find_or_create(&core, "user")
+}
+
+fn builtins() -> Vec<(&'static str, NativeFn)> {
+    vec!(
+        ("=", fn_equals),
+        ("list", fn_list),
+        ("vector", fn_vector),
+        ("hash-map", fn_hash_map),
+        ("set", fn_hash_set),
+        ("hash-set", fn_hash_set),
+        ("conj", fn_conj),
+        ("assoc", fn_assoc),
+        ("dissoc", fn_dissoc),
+        ("get", fn_get),
+        ("get-in", fn_get_in),
+        ("update", fn_update),
+        ("count", fn_count),
+        ("contains?", fn_contains),
+        ("nth", fn_nth),
+        ("first", fn_first),
+        ("rest", fn_rest),
+        ("seq", fn_seq),
+        ("cons", fn_cons),
+        ("realized?", fn_realized),
+        ("apply", fn_apply),
+        ("string?", fn_is_string),
+        ("symbol?", fn_is_symbol),
+        ("keyword?", fn_is_keyword),
+        ("number?", fn_is_number),
+        ("fn?", fn_is_fn),
+        ("vector?", fn_is_vector),
+        ("map?", fn_is_map),
+        ("set?", fn_is_set),
+        ("seq?", fn_is_seq),
+        ("coll?", fn_is_coll),
+        ("ex-info", fn_ex_info),
+        ("ex-data", fn_ex_data),
+        ("ex-message", fn_ex_message),
+        ("ex-cause", fn_ex_cause),
+    )
+}
+
+pub fn arity(name: &str, args: &Vec<Value>, min: usize, max: usize) -> Result<(), Error> {
+    if args.len() < min || args.len() > max {
+        if min == max {
+            return error(format!("{} requires {} args, got {}", name, min, args.len()));
+        }
+        else if max == MANY {
+            return error(format!("{} requires at least {} args, got {}", name, min, args.len()));
+        }
+        else {
+            return error(format!("{} requires {} to {} args, got {}", name, min, max, args.len()));
+        }
+    }
+    Ok(())
+}
+
+pub const MANY: usize = usize::max_value();
+
+pub fn int_arg(name: &str, value: &Value) -> Result<i64, Error> {
+    match *value {
+        Value::Int(i) => Ok(i),
+        Value::BigInt(ref b) => match b.to_i64() {
+            Some(i) => Ok(i),
+            None => error(format!("{} got an integer out of range: {}", name, b)),
+        },
+        ref other => error(format!("{} expects an integer, got {}", name, other.type_name())),
+    }
+}
+
+fn fn_equals(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("=", &args, 1, MANY));
+    for pair in args.windows(2) {
+        if !try!(value::equals(&pair[0], &pair[1])) {
+            return Ok(Value::Bool(false));
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+fn fn_list(args: Vec<Value>) -> Result<Value, Error> {
+    Ok(Value::List(List::from_vec(args)))
+}
+
+fn fn_vector(args: Vec<Value>) -> Result<Value, Error> {
+    Ok(Value::Vector(Vector::from_vec(args)))
+}
+
+fn fn_hash_map(args: Vec<Value>) -> Result<Value, Error> {
+    if args.len() % 2 != 0 {
+        return error(String::from("hash-map requires an even number of args"));
+    }
+    assoc_pairs(Map::new(), args)
+}
+
+fn fn_hash_set(args: Vec<Value>) -> Result<Value, Error> {
+    let mut set = Set::new();
+    for item in args {
+        try!(item.check_key());
+        set = set.conj(item);
+    }
+    Ok(Value::Set(set))
+}
+
+fn assoc_pairs(mut map: Map, args: Vec<Value>) -> Result<Value, Error> {
+    let mut args = args.into_iter();
+    while let Some(k) = args.next() {
+        match args.next() {
+            Some(v) => {
+                try!(k.check_key());
+                map = map.assoc(k, v);
+            },
+            None => return error(format!("No value supplied for key: {}", k)),
+        }
+    }
+    Ok(Value::Map(map))
+}
+
+/// Adds one item the way the collection prefers: lists at the front,
+/// vectors at the end, maps take `[k v]` entries or other maps.
+pub fn conj(coll: Value, item: Value) -> Result<Value, Error> {
+    match coll {
+        Value::Nil => Ok(Value::List(List::new().conj(item))),
+        Value::List(list) => Ok(Value::List(list.conj(item))),
+        Value::Vector(vector) => Ok(Value::Vector(vector.conj(item))),
+        Value::Set(set) => {
+            try!(item.check_key());
+            Ok(Value::Set(set.conj(item)))
+        },
+        Value::Map(map) => match item {
+            Value::Map(other) =>
+                Ok(Value::Map(other.iter().fold(map, |m, (k, v)| m.assoc(k.clone(), v.clone())))),
+            Value::Vector(ref entry) if entry.len() == 2 => {
+                let key = entry.get(0).unwrap();
+                try!(key.check_key());
+                Ok(Value::Map(map.assoc(key, entry.get(1).unwrap())))
+            },
+            other => error(format!("Can't conj {} onto a map", other)),
+        },
+        other => error(format!("Can't conj onto {}", other.type_name())),
+    }
+}
+
+fn fn_conj(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("conj", &args, 1, MANY));
+    let mut args = args.into_iter();
+    let mut coll = args.next().unwrap();
+    for item in args {
+        coll = try!(conj(coll, item));
+    }
+    Ok(coll)
+}
+
+pub fn assoc(coll: Value, key: Value, value: Value) -> Result<Value, Error> {
+    match coll {
+        Value::Nil => {
+            try!(key.check_key());
+            Ok(Value::Map(Map::new().assoc(key, value)))
+        },
+        Value::Map(map) => {
+            try!(key.check_key());
+            Ok(Value::Map(map.assoc(key, value)))
+        },
+        Value::Vector(vector) => {
+            let i = try!(int_arg("assoc", &key));
+            if i < 0 {
+                return error(format!("Index out of bounds: {}", i));
+            }
+            match vector.assoc(i as usize, value) {
+                Some(vector) => Ok(Value::Vector(vector)),
+                None => error(format!("Index out of bounds: {}", i)),
+            }
+        },
+        other => error(format!("Can't assoc on {}", other.type_name())),
+    }
+}
+
+fn fn_assoc(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("assoc", &args, 3, MANY));
+    if args.len() % 2 != 1 {
+        return error(String::from("assoc expects even number of arguments after map/vector"));
+    }
+
+    let mut args = args.into_iter();
+    let mut coll = args.next().unwrap();
+    while let (Some(k), Some(v)) = (args.next(), args.next()) {
+        coll = try!(assoc(coll, k, v));
+    }
+    Ok(coll)
+}
+
+fn fn_dissoc(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("dissoc", &args, 1, MANY));
+    let mut args = args.into_iter();
+    match args.next().unwrap() {
+        Value::Nil => Ok(Value::Nil),
+        Value::Map(map) => Ok(Value::Map(args.fold(map, |m, k| m.dissoc(&k)))),
+        other => error(format!("Can't dissoc on {}", other.type_name())),
+    }
+}
+
+/// Lookup by key or index; None when absent or the collection doesn't support it.
+pub fn get(coll: &Value, key: &Value) -> Option<Value> {
+    match *coll {
+        Value::Map(ref map) => map.get(key).cloned(),
+        Value::Set(ref set) => set.get(key).cloned(),
+        Value::Vector(ref vector) => match *key {
+            Value::Int(i) if i >= 0 => vector.get(i as usize),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn fn_get(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("get", &args, 2, 3));
+    try!(args[1].check_key());
+    let default = args.get(2).cloned().unwrap_or(Value::Nil);
+    Ok(get(&args[0], &args[1]).unwrap_or(default))
+}
+
+fn fn_get_in(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("get-in", &args, 2, 3));
+    let default = args.get(2).cloned().unwrap_or(Value::Nil);
+    let keys = try!(seq::to_vec(&args[1]));
+
+    let mut current = args[0].clone();
+    for k in keys {
+        match get(&current, &k) {
+            Some(v) => current = v,
+            None => return Ok(default),
+        }
+    }
+    Ok(current)
+}
+
+fn fn_update(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("update", &args, 3, MANY));
+    let mut args = args.into_iter();
+    let coll = args.next().unwrap();
+    let key = args.next().unwrap();
+    let f = args.next().unwrap();
+
+    let mut f_args = vec!(get(&coll, &key).unwrap_or(Value::Nil));
+    f_args.extend(args);
+    let value = try!(apply(&f, f_args));
+    assoc(coll, key, value)
+}
+
+fn fn_count(mut args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("count", &args, 1, 1));
+    if let Value::LazySeq(_) = args[0] {
+        let mut count = 0;
+        for item in try!(seq::into_iter(args.pop().unwrap())) {
+            try!(item);
+            count += 1;
+        }
+        return Ok(Value::Int(count));
+    }
+
+    let count = match args[0] {
+        Value::Nil => 0,
+        Value::Str(ref s) => s.chars().count(),
+        Value::List(ref list) => list.len(),
+        Value::Vector(ref vector) => vector.len(),
+        Value::Map(ref map) => map.len(),
+        Value::Set(ref set) => set.len(),
+        Value::Seq(_) => try!(seq::to_vec(&args[0])).len(),
+        ref other => return error(format!("count not supported on {}", other.type_name())),
+    };
+    Ok(Value::Int(count as i64))
+}
+
+fn fn_contains(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("contains?", &args, 2, 2));
+    try!(args[1].check_key());
+    let contains = match args[0] {
+        Value::Nil => false,
+        Value::Map(ref map) => map.contains_key(&args[1]),
+        Value::Set(ref set) => set.contains(&args[1]),
+        Value::Vector(ref vector) => match args[1] {
+            Value::Int(i) => i >= 0 && (i as usize) < vector.len(),
+            _ => false,
+        },
+        ref other => return error(format!("contains? not supported on {}", other.type_name())),
+    };
+    Ok(Value::Bool(contains))
+}
+
+fn fn_nth(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("nth", &args, 2, 3));
+    let i = try!(int_arg("nth", &args[1]));
+    let found = if i < 0 {
+        None
+    }
+    else {
+        match args[0] {
+            Value::Vector(ref vector) => vector.get(i as usize),
+            Value::Str(ref s) => s.chars().nth(i as usize).map(Value::Char),
+            // walked by hand, as Iterator::nth drops the errors of the
+            // elements it skips
+            ref coll => {
+                let mut found = None;
+                for (j, item) in try!(seq::iter(coll)).enumerate() {
+                    let item = try!(item);
+                    if j == i as usize {
+                        found = Some(item);
+                        break;
+                    }
+                }
+                found
+            },
+        }
+    };
+
+    match (found, args.get(2)) {
+        (Some(v), _) => Ok(v),
+        (None, Some(default)) => Ok(default.clone()),
+        (None, None) => error(format!("Index out of bounds: {}", i)),
+    }
+}
+
+fn fn_seq(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("seq", &args, 1, 1));
+    match try!(seq::seq(&args[0])) {
+        Some(s) => Ok(Value::Seq(s)),
+        None => Ok(Value::Nil),
+    }
+}
+
+fn fn_first(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("first", &args, 1, 1));
+    match try!(seq::seq(&args[0])) {
+        Some(s) => Ok(s.first()),
+        None => Ok(Value::Nil),
+    }
+}
+
+fn fn_rest(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("rest", &args, 1, 1));
+    match try!(seq::seq(&args[0])) {
+        Some(s) => s.rest(),
+        None => Ok(Value::List(List::new())),
+    }
+}
+
+fn fn_cons(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("cons", &args, 2, 2));
+    let mut args = args.into_iter();
+    Ok(seq::cons(args.next().unwrap(), args.next().unwrap()))
+}
+
+fn fn_realized(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("realized?", &args, 1, 1));
+    match args[0] {
+        Value::LazySeq(ref lazy) => Ok(Value::Bool(lazy.is_realized())),
+        ref other => error(format!("realized? not supported on {}", other.type_name())),
+    }
+}
+
+fn fn_ex_info(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("ex-info", &args, 2, 3));
+    let mut args = args.into_iter();
+    let message = match args.next().unwrap() {
+        Value::Str(s) => s,
+        other => return error(format!("ex-info expects a string message, got {}", other.type_name())),
+    };
+    let data = match args.next().unwrap() {
+        Value::Map(map) => Value::Map(map),
+        other => return error(format!("ex-info expects a map of data, got {}", other.type_name())),
+    };
+    let cause = match args.next() {
+        None | Some(Value::Nil) => None,
+        Some(Value::Error(e)) => Some(e),
+        Some(other) => return error(format!("ex-info expects an exception as cause, got {}", other.type_name())),
+    };
+    Ok(Value::Error(Rc::new(Error::with_data(message, data, cause))))
+}
+
+fn fn_ex_data(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("ex-data", &args, 1, 1));
+    match args[0] {
+        Value::Error(ref e) => Ok(e.data.clone()),
+        _ => Ok(Value::Nil),
+    }
+}
+
+fn fn_ex_message(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("ex-message", &args, 1, 1));
+    match args[0] {
+        Value::Error(ref e) => Ok(Value::Str(e.message.clone())),
+        _ => Ok(Value::Nil),
+    }
+}
+
+fn fn_ex_cause(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("ex-cause", &args, 1, 1));
+    match args[0] {
+        Value::Error(ref e) => Ok(e.cause.clone().map(Value::Error).unwrap_or(Value::Nil)),
+        _ => Ok(Value::Nil),
+    }
+}
+
+/// `(apply f args... coll)`: calls f with the args followed by the items of
+/// coll.
+fn fn_apply(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("apply", &args, 2, MANY));
+    let mut args = args.into_iter();
+    let f = args.next().unwrap();
+    let mut spread: Vec<Value> = args.collect();
+    let last = spread.pop().unwrap();
+    for item in try!(seq::into_iter(last)) {
+        spread.push(try!(item));
+    }
+    apply(&f, spread)
+}
+
+fn is(name: &str, args: &Vec<Value>, test: fn(&Value) -> bool) -> Result<Value, Error> {
+    try!(arity(name, args, 1, 1));
+    Ok(Value::Bool(test(&args[0])))
+}
+
+fn fn_is_string(args: Vec<Value>) -> Result<Value, Error> {
+    is("string?", &args, |v| match *v { Value::Str(_) => true, _ => false })
+}
+
+fn fn_is_symbol(args: Vec<Value>) -> Result<Value, Error> {
+    is("symbol?", &args, |v| match *v { Value::Symbol(_) => true, _ => false })
+}
+
+fn fn_is_keyword(args: Vec<Value>) -> Result<Value, Error> {
+    is("keyword?", &args, |v| match *v { Value::Keyword(_) => true, _ => false })
+}
+
+fn fn_is_number(args: Vec<Value>) -> Result<Value, Error> {
+    is("number?", &args, numbers::is_number)
+}
+
+fn fn_is_fn(args: Vec<Value>) -> Result<Value, Error> {
+    is("fn?", &args, |v| match *v {
+        Value::Builtin(_) | Value::Native(_) | Value::Lambda(_) => true,
+        _ => false,
+    })
+}
+
+fn fn_is_vector(args: Vec<Value>) -> Result<Value, Error> {
+    is("vector?", &args, |v| match *v { Value::Vector(_) => true, _ => false })
+}
+
+fn fn_is_map(args: Vec<Value>) -> Result<Value, Error> {
+    is("map?", &args, |v| match *v { Value::Map(_) => true, _ => false })
+}
+
+fn fn_is_set(args: Vec<Value>) -> Result<Value, Error> {
+    is("set?", &args, |v| match *v { Value::Set(_) => true, _ => false })
+}
+
+fn fn_is_seq(args: Vec<Value>) -> Result<Value, Error> {
+    is("seq?", &args, |v| match *v { Value::List(_) | Value::Seq(_) | Value::LazySeq(_) => true, _ => false })
+}
+
+fn fn_is_coll(args: Vec<Value>) -> Result<Value, Error> {
+    is("coll?", &args, |v| v.is_sequential() || match *v { Value::Map(_) | Value::Set(_) => true, _ => false })
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use parser;
+use parser::Expression;
+use parser::Expression::{Symbol, SExpression, Params};
+
+use super::binding_vector;
+use super::error::{Error, error};
+use super::macros::gensym;
+
+// Destructuring is desugared while expanding macros, into `let`, `loop` and
+// `fn` forms whose bindings are plain symbols, so neither backend ever sees
+// a binding pattern.
+
+/// The functions the desugared forms call, which the LLVM backend, having
+/// no collections, can't compile.
+pub const DESTRUCTURING_HELPERS: [&'static str; 3] = ["fancy.core/nth", "fancy.core/nthnext", "fancy.core/get"];
+
+fn symbol(name: &str) -> Expression {
+    Symbol(String::from(name))
+}
+
+fn call(function: &str, mut args: Vec<Expression>) -> Expression {
+    args.insert(0, symbol(function));
+    SExpression(args)
+}
+
+fn is_symbol(pattern: &Expression) -> bool {
+    match *pattern {
+        Symbol(_) => true,
+        _ => false,
+    }
+}
+
+/// The items of a `{...}` pattern, which reads as `(hash-map ...)`.
+fn map_pattern(pattern: &Expression) -> Option<&[Expression]> {
+    match *pattern {
+        SExpression(ref items) if items.first() == Some(&parser::symbol("hash-map")) => Some(&items[1..]),
+        _ => None,
+    }
+}
+
+/// Appends bindings of plain symbols that bind the pattern to the value.
+fn bind(pattern: &Expression, value: Expression, out: &mut Vec<Expression>) -> Result<(), Error> {
+    if is_symbol(pattern) {
+        out.push(pattern.clone());
+        out.push(value);
+        Ok(())
+    }
+    else if let Some(items) = binding_vector(pattern) {
+        bind_sequential(items, value, out)
+    }
+    else if let Some(items) = map_pattern(pattern) {
+        bind_associative(items, value, out)
+    }
+    else {
+        error(format!("Unsupported binding form: {:?}", pattern))
+    }
+}
+
+/// `[a b & rest :as all]`
+fn bind_sequential(items: &[Expression], value: Expression, out: &mut Vec<Expression>) -> Result<(), Error> {
+    let g = symbol(&gensym("vec__"));
+    out.push(g.clone());
+    out.push(value);
+
+    let mut n = 0;
+    let mut iter = items.iter();
+    while let Some(item) = iter.next() {
+        match *item {
+            Symbol(ref s) if s == "&" => match iter.next() {
+                Some(rest) => {
+                    let tail = call("fancy.core/nthnext", vec!(g.clone(), Expression::Number(n.to_string())));
+                    try!(bind(rest, tail, out));
+                },
+                None => return error(String::from("Binding vector requires a pattern after &")),
+            },
+            Symbol(ref s) if s == ":as" => match iter.next() {
+                Some(all @ &Symbol(_)) => try!(bind(all, g.clone(), out)),
+                _ => return error(String::from("Binding vector requires a symbol after :as")),
+            },
+            _ => {
+                let nth = call("fancy.core/nth", vec!(g.clone(), Expression::Number(n.to_string()), symbol("nil")));
+                try!(bind(item, nth, out));
+                n += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `{a :a, :keys [x y], :strs [s], :syms [q], :or {y 0}, :as m}`
+fn bind_associative(items: &[Expression], value: Expression, out: &mut Vec<Expression>) -> Result<(), Error> {
+    if items.len() % 2 != 0 {
+        return error(String::from("Map binding form requires an even number of forms"));
+    }
+
+    let g = symbol(&gensym("map__"));
+    out.push(g.clone());
+    out.push(value);
+
+    let mut defaults = HashMap::new();
+    for pair in items.chunks(2) {
+        if pair[0] == symbol(":or") {
+            match map_pattern(&pair[1]) {
+                Some(or) if or.len() % 2 == 0 => for default in or.chunks(2) {
+                    if let Symbol(ref name) = default[0] {
+                        defaults.insert(name.clone(), default[1].clone());
+                    }
+                },
+                _ => return error(String::from(":or requires a map of defaults")),
+            }
+        }
+    }
+
+    let lookup = |name: &Expression, key: Expression| {
+        let mut args = vec!(g.clone(), key);
+        if let Symbol(ref name) = *name {
+            if let Some(default) = defaults.get(name) {
+                args.push(default.clone());
+            }
+        }
+        call("fancy.core/get", args)
+    };
+
+    for pair in items.chunks(2) {
+        match pair[0] {
+            Symbol(ref s) if s == ":or" => (),
+            Symbol(ref s) if s == ":as" => try!(bind(&pair[1], g.clone(), out)),
+            Symbol(ref s) if s == ":keys" || s == ":strs" || s == ":syms" => {
+                let names = match binding_vector(&pair[1]) {
+                    Some(names) => names,
+                    None => return error(format!("{} requires a vector of symbols", s)),
+                };
+                for name in names {
+                    let key = match (s.as_ref(), name) {
+                        (":keys", &Symbol(ref n)) => symbol(&format!(":{}", n)),
+                        (":strs", &Symbol(ref n)) => Expression::String(n.clone()),
+                        (":syms", &Symbol(_)) => call("quote", vec!(name.clone())),
+                        _ => return error(format!("{} requires a vector of symbols", s)),
+                    };
+                    try!(bind(name, lookup(name, key), out));
+                }
+            },
+            ref inner => try!(bind(inner, lookup(inner, pair[1].clone()), out)),
+        }
+    }
+    Ok(())
+}
+
+/// Flattens pattern/value pairs into symbol/value pairs.
+fn destructure(bindings: &[Expression]) -> Result<Vec<Expression>, Error> {
+    if bindings.len() % 2 != 0 {
+        return error(String::from("Binding vector requires an even number of forms"));
+    }
+
+    let mut out = Vec::with_capacity(bindings.len());
+    for pair in bindings.chunks(2) {
+        try!(bind(&pair[0], pair[1].clone(), &mut out));
+    }
+    Ok(out)
+}
+
+/// Whether the patterns of a binding vector are all plain symbols.
+fn plain(bindings: &[Expression]) -> bool {
+    bindings.chunks(2).all(|pair| is_symbol(&pair[0]))
+}
+
+fn vector(items: Vec<Expression>) -> Expression {
+    call("vector", items)
+}
+
+/// `(let [patterns...] body)` with plain symbols only.
+pub fn desugar_let(items: &[Expression]) -> Result<Expression, Error> {
+    match items.get(1).and_then(binding_vector) {
+        Some(bindings) if !plain(bindings) => {
+            let mut result = vec!(items[0].clone(), vector(try!(destructure(bindings))));
+            result.extend(items[2..].iter().cloned());
+            Ok(SExpression(result))
+        },
+        _ => Ok(SExpression(items.to_vec())),
+    }
+}
+
+/// `(loop [patterns...] body)` becomes a `let` destructuring the initial
+/// values, around a loop over the values themselves that destructures them
+/// again on each pass.
+pub fn desugar_loop(items: &[Expression]) -> Result<Expression, Error> {
+    let bindings = match items.get(1).and_then(binding_vector) {
+        Some(bindings) if !plain(bindings) => bindings,
+        _ => return Ok(SExpression(items.to_vec())),
+    };
+    if bindings.len() % 2 != 0 {
+        return error(String::from("loop requires an even number of forms in binding vector"));
+    }
+
+    let mut outer = vec!();
+    let mut loop_bindings = vec!();
+    let mut inner = vec!();
+    for pair in bindings.chunks(2) {
+        let name = if is_symbol(&pair[0]) {
+            outer.push(pair[0].clone());
+            outer.push(pair[1].clone());
+            pair[0].clone()
+        }
+        else {
+            let g = symbol(&gensym("loop__"));
+            outer.push(g.clone());
+            outer.push(pair[1].clone());
+            outer.push(pair[0].clone());
+            outer.push(g.clone());
+            inner.push(pair[0].clone());
+            inner.push(g.clone());
+            g
+        };
+        loop_bindings.push(name.clone());
+        loop_bindings.push(name);
+    }
+
+    let mut body = vec!(symbol("let"), vector(inner));
+    body.extend(items[2..].iter().cloned());
+    let inner_loop = SExpression(vec!(items[0].clone(), vector(loop_bindings), SExpression(body)));
+    desugar_let(&[symbol("let"), vector(outer), inner_loop])
+}
+
+/// `(fn name? [patterns... & pattern] body)` takes plain symbols and
+/// destructures them in a `let` around the body.
+pub fn desugar_fn(items: &[Expression]) -> Result<Expression, Error> {
+    let mut i = 1;
+    if let Some(&Symbol(_)) = items.get(i) {
+        i += 1;
+    }
+    let params = match items.get(i).and_then(binding_vector) {
+        Some(params) if !params.iter().all(is_symbol) => params,
+        _ => return Ok(SExpression(items.to_vec())),
+    };
+
+    let mut symbols = vec!();
+    let mut bindings = vec!();
+    for param in params {
+        if is_symbol(param) {
+            symbols.push(param.clone());
+        }
+        else {
+            let g = symbol(&gensym("p__"));
+            symbols.push(g.clone());
+            bindings.push(param.clone());
+            bindings.push(g);
+        }
+    }
+
+    let mut body = vec!(symbol("let"), vector(bindings));
+    body.extend(items[i + 1..].iter().cloned());
+
+    let mut result = items[..i].to_vec();
+    result.push(match items[i] {
+        Params(_) => Params(symbols),
+        _ => vector(symbols),
+    });
+    result.push(try!(desugar_let(&body)));
+    Ok(SExpression(result))
+}
@@ -0,0 +1,109 @@
+use parser::Expression;
+use parser::Expression::{Symbol, SExpression};
+
+use super::env::Env;
+use super::error::{Error, error};
+use super::io::write_out;
+use super::namespaces::{self, Var};
+use super::value::{Lambda, Value};
+
+// `doc`, `source` and `dir` print what the REPL user asks about. They take
+// their arguments unevaluated, like the special forms they are.
+
+fn symbol_arg<'a>(form: &str, args: &'a [Expression]) -> Result<&'a str, Error> {
+    match args.first() {
+        Some(&Symbol(ref name)) if args.len() == 1 => Ok(name),
+        _ => error(format!("{} requires a symbol", form)),
+    }
+}
+
+fn var_arg(form: &str, args: &[Expression], env: &Env) -> Result<Var, Error> {
+    let symbol = try!(symbol_arg(form, args));
+    match namespaces::find_var(env, symbol) {
+        Some(var) => Ok(var),
+        None => error(format!("Unable to resolve var: {}", symbol)),
+    }
+}
+
+/// The docstring of a `(def name "doc" value)`, `(defn name "doc" ...)` or
+/// `(defmacro name "doc" ...)` form.
+fn docstring(source: &Expression) -> Option<&str> {
+    let items = match *source {
+        SExpression(ref items) if items.len() > 3 => items,
+        _ => return None,
+    };
+    match (&items[0], &items[2]) {
+        (&Symbol(ref def), _) if def == "def" && items.len() != 4 => None,
+        (&Symbol(_), &Expression::String(ref doc)) => Some(doc),
+        _ => None,
+    }
+}
+
+impl Var {
+    pub fn doc(&self) -> Option<&str> {
+        self.source.as_ref().and_then(docstring)
+    }
+
+    /// How the function is called, like `([x & more])`.
+    pub fn arglists(&self) -> Option<String> {
+        match self.value {
+            Value::Lambda(ref lambda) => Some(arglist(lambda, 0)),
+            Value::Macro(ref lambda) => Some(arglist(lambda, 2)),
+            _ => None,
+        }
+    }
+}
+
+fn arglist(lambda: &Lambda, skip: usize) -> String {
+    let mut params: Vec<String> = lambda.params[skip..].to_vec();
+    if let Some(ref rest) = lambda.rest {
+        params.push(String::from("&"));
+        params.push(rest.clone());
+    }
+    format!("([{}])", params.join(" "))
+}
+
+pub fn special_doc(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    let var = try!(var_arg("doc", args, env));
+
+    let mut text = format!("-------------------------\n{}/{}\n", var.namespace, var.name);
+    if let Some(arglists) = var.arglists() {
+        text.push_str(&format!("{}\n", arglists));
+    }
+    match var.value {
+        Value::Macro(_) => text.push_str("Macro\n"),
+        Value::Builtin(_) | Value::Native(_) => text.push_str("Builtin function\n"),
+        _ => (),
+    }
+    if let Some(doc) = var.doc() {
+        text.push_str(&format!("  {}\n", doc));
+    }
+    try!(write_out(&text));
+    Ok(Value::Nil)
+}
+
+pub fn special_source(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    let var = try!(var_arg("source", args, env));
+    match var.source {
+        Some(source) => try!(write_out(&format!("{}\n", source))),
+        None => try!(write_out("Source not found\n")),
+    };
+    Ok(Value::Nil)
+}
+
+/// `(dir ns)` prints the names defined in a namespace.
+pub fn special_dir(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    let ns = try!(symbol_arg("dir", args));
+    match namespaces::names_in(env, ns) {
+        Some(names) => {
+            let mut text = String::new();
+            for name in names {
+                text.push_str(&name);
+                text.push('\n');
+            }
+            try!(write_out(&text));
+            Ok(Value::Nil)
+        },
+        None => error(format!("No namespace: {}", ns)),
+    }
+}
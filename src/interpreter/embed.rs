@@ -0,0 +1,475 @@
+use std::rc::Rc;
+
+use parser;
+use parser::Expression;
+
+use super::{apply, eval_forms, with_interrupt, Interrupt};
+use super::core::{self, MANY};
+use super::env::Env;
+use super::error::{Error, error};
+use super::io;
+use super::namespaces::{self, Var};
+use super::seq;
+use super::value::{Native, Value};
+use super::vector::Vector;
+
+/// An interpreter for a program embedding fancy: it evaluates source, calls
+/// fancy functions, and gives fancy code functions written in Rust.
+///
+/// ```ignore
+/// let mut fancy = Interpreter::new();
+/// fancy.register_fn("add", 2, |(a, b): (i64, i64)| a + b).unwrap();
+/// assert_eq!(Value::Int(3), fancy.eval_str("(add 1 2)").unwrap());
+/// ```
+pub struct Interpreter {
+    env: Env,
+    interrupt: Interrupt,
+}
+
+/// Rust types that fancy values convert to when passed to a registered
+/// function.
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Option<Self>;
+
+    /// What the conversion accepts, for error messages.
+    fn expected() -> String;
+}
+
+/// Rust types that convert to fancy values.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+/// What registered functions may return: a value, or a value or an error.
+pub trait HostResult {
+    fn into_result(self) -> Result<Value, Error>;
+}
+
+/// The parameters of a registered function: a tuple of one type per
+/// argument, or a Vec taking any number of arguments of one type.
+pub trait FromArgs: Sized {
+    fn accepts(arity: usize) -> bool;
+
+    fn from_args(name: &str, args: Vec<Value>) -> Result<Self, Error>;
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter { env: core::root_env(), interrupt: Interrupt::new() }
+    }
+
+    /// Evaluates all forms of the source and returns the value of the last.
+    /// An `ns` form switches the namespace later calls evaluate in. Source
+    /// that doesn't read is an error, and nothing of it is evaluated.
+    pub fn eval_str(&mut self, source: &str) -> Result<Value, Error> {
+        let forms = match parser::try_parse_string(String::from(source)) {
+            Ok(forms) => forms,
+            Err(e) => return error(format!("Read error: {}", e)),
+        };
+        let (value, env) = try!(with_interrupt(&self.interrupt, || eval_forms(&forms, &self.env)));
+        self.env = env;
+        Ok(value)
+    }
+
+    /// Evaluates one top-level form, which may be an `ns` form.
+    pub fn eval_form(&mut self, form: &Expression) -> Result<Value, Error> {
+        let (value, env) = try!(with_interrupt(&self.interrupt, || eval_forms(&[form.clone()], &self.env)));
+        self.env = env;
+        Ok(value)
+    }
+
+    /// Calls the function a symbol resolves to in the current namespace.
+    pub fn call(&self, name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        match namespaces::resolve(&self.env, name) {
+            Some(f) => with_interrupt(&self.interrupt, || apply(&f, args)),
+            None => error(format!("Unable to resolve symbol: {}", name)),
+        }
+    }
+
+    /// Defines a function in fancy.core, so that code in every namespace
+    /// can call it. Arguments are checked against the arity, the minimum
+    /// when the parameters are a Vec, and converted to the parameter types.
+    /// An arity the parameters don't take is an error, and nothing is
+    /// defined.
+    pub fn register_fn<A, R, F>(&mut self, name: &str, arity: usize, f: F) -> Result<(), Error>
+        where A: FromArgs, R: HostResult, F: Fn(A) -> R + 'static
+    {
+        if !A::accepts(arity) {
+            return error(format!("{} is registered with {} args, which its parameters don't take", name, arity));
+        }
+        let max = if A::accepts(arity + 1) { MANY } else { arity };
+
+        let fn_name = String::from(name);
+        let native = Native {
+            name: String::from(name),
+            f: Box::new(move |args: Vec<Value>| {
+                try!(core::arity(&fn_name, &args, arity, max));
+                f(try!(A::from_args(&fn_name, args))).into_result()
+            }),
+        };
+        self.define(name, Value::Native(Rc::new(native)));
+        Ok(())
+    }
+
+    /// The interrupt of this interpreter, which stops the evaluation in
+    /// progress from wherever it's called. An interrupt that comes between
+    /// evaluations stops the next one, unless cleared first.
+    pub fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    /// Defines a value in fancy.core.
+    pub fn define(&mut self, name: &str, value: Value) {
+        namespaces::find_or_create(&self.env, namespaces::CORE).define(name, value);
+    }
+
+    /// The name of the namespace code is evaluated in.
+    pub fn namespace(&self) -> String {
+        self.env.namespace_of().name.clone()
+    }
+
+    /// Switches to the namespace, creating it if it doesn't exist.
+    pub fn set_namespace(&mut self, name: &str) {
+        self.env = namespaces::find_or_create(&self.env, name);
+    }
+
+    /// The global definition a symbol refers to in the current namespace.
+    pub fn find_var(&self, symbol: &str) -> Option<Var> {
+        namespaces::find_var(&self.env, symbol)
+    }
+
+    /// The names defined in a namespace, given by name or alias.
+    pub fn names_in(&self, namespace: &str) -> Option<Vec<String>> {
+        namespaces::names_in(&self.env, namespace)
+    }
+
+    pub fn set_source_paths(&mut self, paths: Vec<::std::path::PathBuf>) {
+        namespaces::set_source_paths(&self.env, paths);
+    }
+
+    pub fn set_command_line_args(&mut self, args: Vec<String>) {
+        io::set_command_line_args(&self.env, args);
+    }
+}
+
+fn arg<T: FromValue>(name: &str, args: &[Value], i: usize) -> Result<T, Error> {
+    match T::from_value(&args[i]) {
+        Some(value) => Ok(value),
+        None => error(format!("{} expects {} as argument {}, got {}",
+                              name, T::expected(), i + 1, args[i].type_name())),
+    }
+}
+
+macro_rules! tuple_args {
+    ($n:expr; $($t:ident $i:tt),*) => {
+        impl<$($t: FromValue),*> FromArgs for ($($t,)*) {
+            fn accepts(arity: usize) -> bool {
+                arity == $n
+            }
+
+            // the arguments go unused for no parameters
+            #[allow(unused_variables)]
+            fn from_args(name: &str, args: Vec<Value>) -> Result<Self, Error> {
+                Ok(($(try!(arg::<$t>(name, &args, $i)),)*))
+            }
+        }
+    }
+}
+
+tuple_args!(0;);
+tuple_args!(1; A 0);
+tuple_args!(2; A 0, B 1);
+tuple_args!(3; A 0, B 1, C 2);
+tuple_args!(4; A 0, B 1, C 2, D 3);
+
+impl<T: FromValue> FromArgs for Vec<T> {
+    fn accepts(_: usize) -> bool {
+        true
+    }
+
+    fn from_args(name: &str, args: Vec<Value>) -> Result<Self, Error> {
+        let mut values = Vec::with_capacity(args.len());
+        for i in 0..args.len() {
+            values.push(try!(arg(name, &args, i)));
+        }
+        Ok(values)
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+
+    fn expected() -> String {
+        String::from("any value")
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match *value {
+            Value::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        String::from("an integer")
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match *value {
+            Value::Int(i) => Some(i as f64),
+            Value::Float(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        String::from("a number")
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Option<Self> {
+        match *value {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        String::from("a boolean")
+    }
+}
+
+impl FromValue for char {
+    fn from_value(value: &Value) -> Option<Self> {
+        match *value {
+            Value::Char(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        String::from("a char")
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Option<Self> {
+        match *value {
+            Value::Str(ref s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    fn expected() -> String {
+        String::from("a string")
+    }
+}
+
+/// nil is None.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match *value {
+            Value::Nil => Some(None),
+            ref other => T::from_value(other).map(Some),
+        }
+    }
+
+    fn expected() -> String {
+        format!("{} or nil", T::expected())
+    }
+}
+
+/// The items of any seqable collection; nil is empty.
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        let mut values = vec!();
+        for item in match seq::iter(value) { Ok(iter) => iter, Err(_) => return None } {
+            match item.ok().as_ref().and_then(T::from_value) {
+                Some(value) => values.push(value),
+                None => return None,
+            }
+        }
+        Some(values)
+    }
+
+    fn expected() -> String {
+        format!("a collection of {}", T::expected())
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Value {
+        Value::Int(self)
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::Float(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl IntoValue for char {
+    fn into_value(self) -> Value {
+        Value::Char(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::Str(self)
+    }
+}
+
+impl<'a> IntoValue for &'a str {
+    fn into_value(self) -> Value {
+        Value::Str(String::from(self))
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map(IntoValue::into_value).unwrap_or(Value::Nil)
+    }
+}
+
+/// A vector.
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::Vector(Vector::from_vec(self.into_iter().map(IntoValue::into_value).collect()))
+    }
+}
+
+macro_rules! host_result {
+    ($($t:ty),*) => {
+        $(impl HostResult for $t {
+            fn into_result(self) -> Result<Value, Error> {
+                Ok(self.into_value())
+            }
+        })*
+    }
+}
+
+host_result!(Value, (), i64, f64, bool, char, String, &'static str);
+
+impl<T: IntoValue> HostResult for Option<T> {
+    fn into_result(self) -> Result<Value, Error> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> HostResult for Vec<T> {
+    fn into_result(self) -> Result<Value, Error> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> HostResult for Result<T, Error> {
+    fn into_result(self) -> Result<Value, Error> {
+        self.map(IntoValue::into_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interpreter, IntoValue};
+    use super::super::error::{Error, error};
+    use super::super::value::Value;
+
+    #[test]
+    fn registered_functions_convert_arguments_and_results() {
+        let mut fancy = Interpreter::new();
+        fancy.register_fn("add", 2, |(a, b): (i64, i64)| a + b).unwrap();
+        fancy.register_fn("shout", 1, |(s,): (String,)| s.to_uppercase() + "!").unwrap();
+        fancy.register_fn("sum", 0, |xs: Vec<f64>| xs.iter().fold(0.0, |acc, x| acc + x)).unwrap();
+        fancy.register_fn("lengths", 1, |(words,): (Vec<String>,)| {
+            words.iter().map(|w| w.chars().count() as i64).collect::<Vec<i64>>()
+        }).unwrap();
+
+        assert_eq!(Value::Int(3), fancy.eval_str("(add 1 2)").unwrap());
+        assert_eq!("\"HÉLLO!\"", fancy.eval_str("(shout \"héllo\")").unwrap().to_string());
+        assert_eq!(Value::Float(4.5), fancy.eval_str("(sum 1 2 1.5)").unwrap());
+        assert_eq!("[2 3]", fancy.eval_str("(lengths [\"ab\" \"äöü\"])").unwrap().to_string());
+    }
+
+    #[test]
+    fn registered_functions_report_bad_arguments() {
+        let mut fancy = Interpreter::new();
+        fancy.register_fn("add", 2, |(a, b): (i64, i64)| a + b).unwrap();
+        fancy.register_fn("checked", 1, |(x,): (i64,)| -> Result<i64, Error> {
+            if x < 0 { error(String::from("negative")) } else { Ok(x) }
+        }).unwrap();
+
+        let message = |fancy: &mut Interpreter, source| fancy.eval_str(source).unwrap_err().message;
+        assert_eq!("add requires 2 args, got 1", message(&mut fancy, "(add 1)"));
+        assert_eq!("add expects an integer as argument 2, got string", message(&mut fancy, "(add 1 \"2\")"));
+        assert_eq!("negative", message(&mut fancy, "(checked -1)"));
+        assert_eq!(":caught", fancy.eval_str("(try (checked -1) (catch Exception e :caught))").unwrap().to_string());
+    }
+
+    #[test]
+    fn registering_with_an_arity_the_parameters_dont_take_fails() {
+        let mut fancy = Interpreter::new();
+        assert_eq!("pair is registered with 3 args, which its parameters don't take",
+                   fancy.register_fn("pair", 3, |(a, b): (i64, i64)| a + b).unwrap_err().message);
+        assert!(fancy.find_var("pair").is_none());
+        assert!(fancy.register_fn("sum", 2, |xs: Vec<i64>| xs.iter().fold(0, |acc, x| acc + x)).is_ok());
+    }
+
+    #[test]
+    fn call_fancy_functions_from_rust() {
+        let mut fancy = Interpreter::new();
+        fancy.eval_str("(ns config) (defn port [base] (+ base 80))").unwrap();
+        assert_eq!(Value::Int(8080), fancy.call("port", vec!(8000i64.into_value())).unwrap());
+        assert_eq!(Value::Int(3), fancy.call("+", vec!(1i64.into_value(), 2i64.into_value())).unwrap());
+        assert!(fancy.call("missing", vec!()).is_err());
+    }
+
+    #[test]
+    fn malformed_source_is_an_error() {
+        let mut fancy = Interpreter::new();
+        assert_eq!("Read error: expected \")\", got nothing", fancy.eval_str("(").unwrap_err().message);
+        assert!(fancy.eval_str("(def a 1) [a)").is_err());
+        assert!(fancy.find_var("a").is_none());
+        assert_eq!(Value::Int(2), fancy.eval_str("(inc 1)").unwrap());
+    }
+
+    #[test]
+    fn interrupts_stop_only_their_interpreter() {
+        let mut fancy = Interpreter::new();
+        let mut other = Interpreter::new();
+        fancy.interrupt().interrupt();
+        assert_eq!(Value::Int(2), other.eval_str("(inc 1)").unwrap());
+        assert!(fancy.eval_str("(inc 1)").unwrap_err().interrupt);
+        assert_eq!(Value::Int(2), fancy.eval_str("(inc 1)").unwrap());
+
+        fancy.interrupt().interrupt();
+        fancy.interrupt().clear();
+        assert_eq!(Value::Int(2), fancy.eval_str("(inc 1)").unwrap());
+    }
+}
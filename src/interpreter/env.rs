@@ -0,0 +1,73 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::namespaces::Namespace;
+use super::value::Value;
+
+pub type Env = Rc<Scope>;
+
+/// A lexical scope. The outermost scope of every chain is the global scope
+/// of a namespace, which holds its definitions.
+pub struct Scope {
+    bindings: RefCell<HashMap<String, Value>>,
+    parent: Option<Env>,
+    namespace: Option<Rc<Namespace>>,
+}
+
+impl Scope {
+    /// The global scope of a namespace. Its parent is the namespace whose
+    /// definitions it also sees: fancy.core, for all but fancy.core itself.
+    pub fn namespace(namespace: Rc<Namespace>, parent: Option<&Env>) -> Env {
+        Rc::new(Scope {
+            bindings: RefCell::new(HashMap::new()),
+            parent: parent.cloned(),
+            namespace: Some(namespace),
+        })
+    }
+
+    pub fn child(parent: &Env) -> Env {
+        Rc::new(Scope { bindings: RefCell::new(HashMap::new()), parent: Some(parent.clone()), namespace: None })
+    }
+
+    pub fn define(&self, name: &str, value: Value) {
+        self.bindings.borrow_mut().insert(String::from(name), value);
+    }
+
+    /// Defines in the scope of the enclosing namespace, as `def` does.
+    pub fn define_global(&self, name: &str, value: Value) {
+        match self.parent {
+            Some(ref parent) if self.namespace.is_none() => parent.define_global(name, value),
+            _ => self.define(name, value),
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Value> {
+        if let Some(value) = self.bindings.borrow().get(name) {
+            return Some(value.clone());
+        }
+
+        match self.parent {
+            Some(ref parent) => parent.lookup(name),
+            None => None,
+        }
+    }
+
+    /// Looks in this scope only, not the ones around it.
+    pub fn lookup_here(&self, name: &str) -> Option<Value> {
+        self.bindings.borrow().get(name).cloned()
+    }
+
+    pub fn bindings(&self) -> Vec<(String, Value)> {
+        self.bindings.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// The namespace the scope belongs to.
+    pub fn namespace_of(&self) -> Rc<Namespace> {
+        match (&self.namespace, &self.parent) {
+            (&Some(ref namespace), _) => namespace.clone(),
+            (&None, &Some(ref parent)) => parent.namespace_of(),
+            (&None, &None) => unreachable!("scope outside of any namespace"),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use std::rc::Rc;
+
+use super::value::Value;
+
+/// An exception. Builtins raise them with just a message; `ex-info` adds a
+/// data map and optionally the exception that caused it. The trace lists the
+/// functions the error passed through, innermost first. An interrupt
+/// unwinds the evaluation as an error too, but no `catch` handles it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pub message: String,
+    pub data: Value,
+    pub cause: Option<Rc<Error>>,
+    pub trace: Vec<String>,
+    pub interrupt: bool,
+}
+
+impl Error {
+    pub fn new(message: String) -> Error {
+        Error { message: message, data: Value::Nil, cause: None, trace: vec!(), interrupt: false }
+    }
+
+    pub fn with_data(message: String, data: Value, cause: Option<Rc<Error>>) -> Error {
+        Error { message: message, data: data, cause: cause, trace: vec!(), interrupt: false }
+    }
+
+    pub fn interrupted() -> Error {
+        Error { interrupt: true, ..Error::new(String::from("Interrupted")) }
+    }
+
+    /// Records that the error propagated out of a call to the named function.
+    pub fn called_from(mut self, function: &str) -> Error {
+        self.trace.push(String::from(function));
+        self
+    }
+
+    /// The message followed by the trace, one call site per line, and the
+    /// chain of causes.
+    pub fn stack_trace(&self) -> String {
+        let mut out = self.message.clone();
+        if self.data != Value::Nil {
+            out.push_str(&format!(" {}", self.data));
+        }
+        for frame in &self.trace {
+            out.push_str(&format!("\n    at {}", frame));
+        }
+        if let Some(ref cause) = self.cause {
+            out.push_str(&format!("\nCaused by: {}", cause.stack_trace()));
+        }
+        out
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+pub fn error<T>(message: String) -> Result<T, Error> {
+    Err(Error::new(message))
+}
@@ -0,0 +1,375 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use super::value::Value;
+
+const BITS: u32 = 5;
+const MASK: u32 = (1 << BITS) - 1;
+
+#[derive(Clone)]
+enum Entry {
+    Pair(u32, Value, Value),
+    Child(Rc<Node>),
+}
+
+#[derive(Clone)]
+enum Node {
+    Bitmap(u32, Vec<Entry>),
+    Collision(u32, Vec<(Value, Value)>),
+}
+
+/// Persistent hash map, a hash array mapped trie (HAMT) with 32-way
+/// bitmap-indexed nodes and collision nodes for full hash clashes.
+#[derive(Clone)]
+pub struct Map {
+    count: usize,
+    root: Rc<Node>,
+}
+
+/// Persistent hash set, a `Map` with every key mapped to itself.
+#[derive(Clone)]
+pub struct Set {
+    map: Map,
+}
+
+pub fn hash_of(value: &Value) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+fn bitpos(hash: u32, shift: u32) -> u32 {
+    1 << ((hash >> shift) & MASK)
+}
+
+fn index(bitmap: u32, bit: u32) -> usize {
+    (bitmap & (bit - 1)).count_ones() as usize
+}
+
+impl Node {
+    fn empty() -> Node {
+        Node::Bitmap(0, vec!())
+    }
+
+    fn get(&self, shift: u32, hash: u32, key: &Value) -> Option<&Value> {
+        match *self {
+            Node::Bitmap(bitmap, ref entries) => {
+                let bit = bitpos(hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+
+                match entries[index(bitmap, bit)] {
+                    Entry::Pair(h, ref k, ref v) =>
+                        if h == hash && k == key { Some(v) } else { None },
+                    Entry::Child(ref node) => node.get(shift + BITS, hash, key),
+                }
+            },
+            Node::Collision(h, ref pairs) => {
+                if h != hash {
+                    return None;
+                }
+                pairs.iter().find(|p| p.0 == *key).map(|p| &p.1)
+            }
+        }
+    }
+
+    /// Returns the new node and whether a key was added.
+    fn assoc(&self, shift: u32, hash: u32, key: Value, value: Value) -> (Node, bool) {
+        match *self {
+            Node::Bitmap(bitmap, ref entries) => {
+                let bit = bitpos(hash, shift);
+                let idx = index(bitmap, bit);
+                let mut entries = entries.clone();
+
+                if bitmap & bit == 0 {
+                    entries.insert(idx, Entry::Pair(hash, key, value));
+                    return (Node::Bitmap(bitmap | bit, entries), true);
+                }
+
+                let (entry, added) = match entries[idx] {
+                    Entry::Pair(h, ref k, ref v) => {
+                        if h == hash && *k == key {
+                            (Entry::Pair(hash, key, value), false)
+                        }
+                        else {
+                            let node = create_node(shift + BITS, h, k.clone(), v.clone(),
+                                                   hash, key, value);
+                            (Entry::Child(Rc::new(node)), true)
+                        }
+                    },
+                    Entry::Child(ref node) => {
+                        let (node, added) = node.assoc(shift + BITS, hash, key, value);
+                        (Entry::Child(Rc::new(node)), added)
+                    }
+                };
+
+                entries[idx] = entry;
+                (Node::Bitmap(bitmap, entries), added)
+            },
+            Node::Collision(h, ref pairs) => {
+                if h == hash {
+                    let mut pairs = pairs.clone();
+                    match pairs.iter().position(|p| p.0 == key) {
+                        Some(i) => {
+                            pairs[i].1 = value;
+                            (Node::Collision(h, pairs), false)
+                        },
+                        None => {
+                            pairs.push((key, value));
+                            (Node::Collision(h, pairs), true)
+                        }
+                    }
+                }
+                else {
+                    let nested = Node::Bitmap(bitpos(h, shift), vec!(Entry::Child(Rc::new(self.clone()))));
+                    nested.assoc(shift, hash, key, value)
+                }
+            }
+        }
+    }
+
+    /// None when the key is absent, Some(None) when the node became empty.
+    fn dissoc(&self, shift: u32, hash: u32, key: &Value) -> Option<Option<Node>> {
+        match *self {
+            Node::Bitmap(bitmap, ref entries) => {
+                let bit = bitpos(hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+
+                let idx = index(bitmap, bit);
+                let replacement = match entries[idx] {
+                    Entry::Pair(h, ref k, _) => {
+                        if h == hash && k == key { None } else { return None }
+                    },
+                    Entry::Child(ref node) => match node.dissoc(shift + BITS, hash, key) {
+                        None => return None,
+                        Some(None) => None,
+                        Some(Some(node)) => Some(Entry::Child(Rc::new(node))),
+                    }
+                };
+
+                let mut entries = entries.clone();
+                match replacement {
+                    Some(entry) => {
+                        entries[idx] = entry;
+                        Some(Some(Node::Bitmap(bitmap, entries)))
+                    },
+                    None if bitmap == bit => Some(None),
+                    None => {
+                        entries.remove(idx);
+                        Some(Some(Node::Bitmap(bitmap ^ bit, entries)))
+                    }
+                }
+            },
+            Node::Collision(h, ref pairs) => {
+                if h != hash {
+                    return None;
+                }
+
+                match pairs.iter().position(|p| p.0 == *key) {
+                    None => None,
+                    Some(_) if pairs.len() == 1 => Some(None),
+                    Some(i) => {
+                        let mut pairs = pairs.clone();
+                        pairs.remove(i);
+                        Some(Some(Node::Collision(h, pairs)))
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn create_node(shift: u32, h1: u32, k1: Value, v1: Value, h2: u32, k2: Value, v2: Value) -> Node {
+    if h1 == h2 {
+        Node::Collision(h1, vec!((k1, v1), (k2, v2)))
+    }
+    else {
+        let (node, _) = Node::empty().assoc(shift, h1, k1, v1);
+        node.assoc(shift, h2, k2, v2).0
+    }
+}
+
+impl Map {
+    pub fn new() -> Map {
+        Map { count: 0, root: Rc::new(Node::empty()) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Whether both are the very same map, not merely equal ones.
+    pub fn is_identical(&self, other: &Map) -> bool {
+        &*self.root as *const Node == &*other.root as *const Node
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.root.get(0, hash_of(key), key)
+    }
+
+    pub fn contains_key(&self, key: &Value) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn assoc(&self, key: Value, value: Value) -> Map {
+        let (root, added) = self.root.assoc(0, hash_of(&key), key, value);
+        Map {
+            count: if added { self.count + 1 } else { self.count },
+            root: Rc::new(root),
+        }
+    }
+
+    pub fn dissoc(&self, key: &Value) -> Map {
+        match self.root.dissoc(0, hash_of(key), key) {
+            None => self.clone(),
+            Some(root) => Map {
+                count: self.count - 1,
+                root: Rc::new(root.unwrap_or(Node::empty())),
+            }
+        }
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter { stack: vec!((&*self.root, 0)) }
+    }
+}
+
+pub struct Iter<'a> {
+    stack: Vec<(&'a Node, usize)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Value, &'a Value);
+
+    fn next(&mut self) -> Option<(&'a Value, &'a Value)> {
+        while let Some((node, i)) = self.stack.pop() {
+            match *node {
+                Node::Bitmap(_, ref entries) => {
+                    if i < entries.len() {
+                        self.stack.push((node, i + 1));
+                        match entries[i] {
+                            Entry::Pair(_, ref k, ref v) => return Some((k, v)),
+                            Entry::Child(ref child) => self.stack.push((&**child, 0)),
+                        }
+                    }
+                },
+                Node::Collision(_, ref pairs) => {
+                    if i < pairs.len() {
+                        self.stack.push((node, i + 1));
+                        return Some((&pairs[i].0, &pairs[i].1));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Set {
+    pub fn new() -> Set {
+        Set { map: Map::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn is_identical(&self, other: &Set) -> bool {
+        self.map.is_identical(&other.map)
+    }
+
+    pub fn contains(&self, value: &Value) -> bool {
+        self.map.contains_key(value)
+    }
+
+    pub fn get(&self, value: &Value) -> Option<&Value> {
+        self.map.get(value)
+    }
+
+    pub fn conj(&self, value: Value) -> Set {
+        Set { map: self.map.assoc(value.clone(), value) }
+    }
+
+    pub fn disj(&self, value: &Value) -> Set {
+        Set { map: self.map.dissoc(value) }
+    }
+
+    pub fn iter<'a>(&'a self) -> SetIter<'a> {
+        SetIter { inner: self.map.iter() }
+    }
+}
+
+pub struct SetIter<'a> {
+    inner: Iter<'a>,
+}
+
+impl<'a> Iterator for SetIter<'a> {
+    type Item = &'a Value;
+
+    fn next(&mut self) -> Option<&'a Value> {
+        self.inner.next().map(|(k, _)| k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use super::{Map, Node, Entry};
+    use super::super::value::Value;
+
+    #[test]
+    fn assoc_get_dissoc() {
+        let mut m = Map::new();
+        for i in 0..5000 {
+            m = m.assoc(Value::Int(i), Value::Int(i * 2));
+        }
+        assert_eq!(5000, m.len());
+        assert_eq!(5000, m.iter().count());
+        assert_eq!(Some(&Value::Int(8000)), m.get(&Value::Int(4000)));
+
+        let smaller = m.dissoc(&Value::Int(4000)).dissoc(&Value::Int(-1));
+        assert_eq!(4999, smaller.len());
+        assert_eq!(None, smaller.get(&Value::Int(4000)));
+        assert_eq!(Some(&Value::Int(8000)), m.get(&Value::Int(4000)));
+
+        let mut empty = smaller;
+        for i in 0..5000 {
+            empty = empty.dissoc(&Value::Int(i));
+        }
+        assert!(empty.is_empty());
+        assert_eq!(0, empty.iter().count());
+    }
+
+    #[test]
+    fn full_hash_collisions() {
+        let collision = Node::Collision(7, vec!((Value::Int(1), Value::Nil),
+                                                (Value::Int(2), Value::Nil)));
+        let node = Node::Bitmap(1 << 7, vec!(Entry::Child(Rc::new(collision))));
+
+        let (node, added) = node.assoc(0, 7, Value::Int(3), Value::Bool(true));
+        assert!(added);
+        assert_eq!(Some(&Value::Bool(true)), node.get(0, 7, &Value::Int(3)));
+
+        let (node, added) = node.assoc(0, 7 | (1 << 5), Value::Int(4), Value::Nil);
+        assert!(added);
+        assert_eq!(Some(&Value::Nil), node.get(0, 7 | (1 << 5), &Value::Int(4)));
+        assert_eq!(Some(&Value::Nil), node.get(0, 7, &Value::Int(1)));
+
+        let node = node.dissoc(0, 7, &Value::Int(1)).unwrap().unwrap();
+        assert_eq!(None, node.get(0, 7, &Value::Int(1)));
+        assert_eq!(Some(&Value::Nil), node.get(0, 7, &Value::Int(2)));
+    }
+}
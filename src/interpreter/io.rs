@@ -0,0 +1,249 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process;
+use std::rc::Rc;
+
+use super::core::{arity, MANY};
+use super::env::Env;
+use super::error::{Error, error};
+use super::hashmap::Map;
+use super::list::List;
+use super::namespaces;
+use super::seq;
+use super::value::{NativeFn, Value};
+
+/// A source of text: a file opened by `reader`, or standard input as `*in*`.
+pub struct Reader {
+    pub name: String,
+    input: RefCell<Box<BufRead>>,
+}
+
+pub fn builtins() -> Vec<(&'static str, NativeFn)> {
+    vec!(
+        ("print", fn_print),
+        ("println", fn_println),
+        ("pr", fn_pr),
+        ("prn", fn_prn),
+        ("print-str", fn_print_str),
+        ("pr-str", fn_pr_str),
+        ("reader", fn_reader),
+        ("slurp", fn_slurp),
+        ("spit", fn_spit),
+        ("line-seq", fn_line_seq),
+        ("getenv", fn_getenv),
+        ("exit", fn_exit),
+    )
+}
+
+/// The dynamic vars of fancy.core that refer to the host.
+pub fn vars() -> Vec<(&'static str, Value)> {
+    let stdin = Reader { name: String::from("*in*"), input: RefCell::new(Box::new(BufReader::new(io::stdin()))) };
+    vec!(
+        ("*in*", Value::Reader(Rc::new(stdin))),
+        ("*command-line-args*", Value::Nil),
+    )
+}
+
+/// Binds `*command-line-args*` to the arguments following the program.
+pub fn set_command_line_args(env: &Env, args: Vec<String>) {
+    let args = if args.is_empty() {
+        Value::Nil
+    }
+    else {
+        Value::List(List::from_vec(args.into_iter().map(Value::Str).collect()))
+    };
+    namespaces::find_or_create(env, namespaces::CORE).define("*command-line-args*", args);
+}
+
+/// Text of a value as it reads, which fails if a lazy seq in it fails to
+/// realize rather than printing it cut short.
+pub fn pr_str(value: &Value) -> Result<String, Error> {
+    try!(value.realize());
+    Ok(value.to_string())
+}
+
+/// Text of a value for humans: strings and chars without quotes or escapes.
+pub fn print_str(value: &Value) -> Result<String, Error> {
+    match *value {
+        Value::Str(ref s) => Ok(s.clone()),
+        Value::Char(c) => Ok(c.to_string()),
+        ref other => pr_str(other),
+    }
+}
+
+fn join(args: &Vec<Value>, readably: bool) -> Result<String, Error> {
+    let mut texts = Vec::with_capacity(args.len());
+    for arg in args {
+        texts.push(try!(if readably { pr_str(arg) } else { print_str(arg) }));
+    }
+    Ok(texts.join(" "))
+}
+
+thread_local!(static OUTPUT: RefCell<Option<Box<FnMut(&str)>>> = RefCell::new(None));
+
+/// Sends what `print` and friends write on this thread to the sink instead
+/// of standard output, until reset with None.
+pub fn redirect_output(sink: Option<Box<FnMut(&str)>>) {
+    OUTPUT.with(|output| *output.borrow_mut() = sink);
+}
+
+pub fn write_out(text: &str) -> Result<Value, Error> {
+    let redirected = OUTPUT.with(|output| match *output.borrow_mut() {
+        Some(ref mut sink) => {
+            sink(text);
+            true
+        },
+        None => false,
+    });
+    if redirected {
+        return Ok(Value::Nil);
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match out.write_all(text.as_bytes()).and_then(|_| out.flush()) {
+        Ok(_) => Ok(Value::Nil),
+        Err(e) => error(format!("Could not write to standard output: {}", e)),
+    }
+}
+
+fn fn_print(args: Vec<Value>) -> Result<Value, Error> {
+    write_out(&try!(join(&args, false)))
+}
+
+fn fn_println(args: Vec<Value>) -> Result<Value, Error> {
+    write_out(&(try!(join(&args, false)) + "\n"))
+}
+
+fn fn_pr(args: Vec<Value>) -> Result<Value, Error> {
+    write_out(&try!(join(&args, true)))
+}
+
+fn fn_prn(args: Vec<Value>) -> Result<Value, Error> {
+    write_out(&(try!(join(&args, true)) + "\n"))
+}
+
+fn fn_print_str(args: Vec<Value>) -> Result<Value, Error> {
+    Ok(Value::Str(try!(join(&args, false))))
+}
+
+fn fn_pr_str(args: Vec<Value>) -> Result<Value, Error> {
+    Ok(Value::Str(try!(join(&args, true))))
+}
+
+fn path_arg<'a>(name: &str, value: &'a Value) -> Result<&'a str, Error> {
+    match *value {
+        Value::Str(ref path) => Ok(path),
+        ref other => error(format!("{} expects a path, got {}", name, other.type_name())),
+    }
+}
+
+fn open(path: &str) -> Result<Rc<Reader>, Error> {
+    match File::open(path) {
+        Ok(file) => Ok(Rc::new(Reader { name: String::from(path), input: RefCell::new(Box::new(BufReader::new(file))) })),
+        Err(e) => error(format!("Could not open {}: {}", path, e)),
+    }
+}
+
+/// A reader given either as one, or as the path of a file to open.
+fn reader_arg(name: &str, value: &Value) -> Result<Rc<Reader>, Error> {
+    match *value {
+        Value::Reader(ref reader) => Ok(reader.clone()),
+        Value::Str(ref path) => open(path),
+        ref other => error(format!("{} expects a reader or a path, got {}", name, other.type_name())),
+    }
+}
+
+fn fn_reader(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("reader", &args, 1, 1));
+    let path = try!(path_arg("reader", &args[0]));
+    Ok(Value::Reader(try!(open(path))))
+}
+
+/// `(slurp path)` reads a whole file, or what is left of a reader.
+fn fn_slurp(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("slurp", &args, 1, 1));
+    let reader = try!(reader_arg("slurp", &args[0]));
+    let mut text = String::new();
+    let read = reader.input.borrow_mut().read_to_string(&mut text);
+    match read {
+        Ok(_) => Ok(Value::Str(text)),
+        Err(e) => error(format!("Could not read {}: {}", reader.name, e)),
+    }
+}
+
+/// `(spit path content :append true)` writes the content to the file,
+/// replacing it unless appending.
+fn fn_spit(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("spit", &args, 2, MANY));
+    if args.len() % 2 != 0 {
+        return error(String::from("spit options must come in pairs"));
+    }
+    let path = try!(path_arg("spit", &args[0]));
+
+    let mut append = false;
+    for option in args[2..].chunks(2) {
+        match option[0] {
+            Value::Keyword(ref k) if k == "append" => append = option[1].is_truthy(),
+            ref other => return error(format!("Unsupported spit option: {}", other)),
+        }
+    }
+
+    let content = try!(print_str(&args[1]));
+    let file = OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(path);
+    match file.and_then(|mut file| file.write_all(content.as_bytes())) {
+        Ok(_) => Ok(Value::Nil),
+        Err(e) => error(format!("Could not write {}: {}", path, e)),
+    }
+}
+
+/// The lines still to be read from the reader, read one at a time as the
+/// seq is walked.
+fn lines(reader: Rc<Reader>) -> Value {
+    seq::lazy(move || {
+        let mut line = String::new();
+        let read = reader.input.borrow_mut().read_line(&mut line);
+        match read {
+            Ok(0) => Ok(Value::Nil),
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                Ok(seq::cons(Value::Str(line), lines(reader.clone())))
+            },
+            Err(e) => error(format!("Could not read {}: {}", reader.name, e)),
+        }
+    })
+}
+
+fn fn_line_seq(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("line-seq", &args, 1, 1));
+    Ok(lines(try!(reader_arg("line-seq", &args[0]))))
+}
+
+/// `(getenv name)` is the value of an environment variable or nil;
+/// `(getenv)` is a map of all of them.
+fn fn_getenv(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("getenv", &args, 0, 1));
+    match args.get(0) {
+        None => Ok(Value::Map(env::vars().fold(Map::new(), |map, (k, v)| map.assoc(Value::Str(k), Value::Str(v))))),
+        Some(&Value::Str(ref name)) => Ok(env::var(name).map(Value::Str).unwrap_or(Value::Nil)),
+        Some(other) => error(format!("getenv expects a string, got {}", other.type_name())),
+    }
+}
+
+fn fn_exit(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("exit", &args, 0, 1));
+    let code = match args.get(0) {
+        None => 0,
+        Some(&Value::Int(code)) => code as i32,
+        Some(other) => return error(format!("exit expects an integer status, got {}", other.type_name())),
+    };
+    io::stdout().flush().ok();
+    process::exit(code)
+}
@@ -0,0 +1,105 @@
+use std::rc::Rc;
+
+use super::value::Value;
+
+struct Cons {
+    first: Value,
+    rest: List,
+    count: usize,
+}
+
+/// Persistent singly linked list; conj prepends and shares the tail.
+#[derive(Clone)]
+pub struct List {
+    head: Option<Rc<Cons>>,
+}
+
+impl List {
+    pub fn new() -> List {
+        List { head: None }
+    }
+
+    pub fn from_vec(values: Vec<Value>) -> List {
+        let mut list = List::new();
+        for v in values.into_iter().rev() {
+            list = list.conj(v);
+        }
+        list
+    }
+
+    pub fn len(&self) -> usize {
+        match self.head {
+            Some(ref cons) => cons.count,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// Whether both are the very same list, not merely equal ones.
+    pub fn is_identical(&self, other: &List) -> bool {
+        match (&self.head, &other.head) {
+            (&Some(ref a), &Some(ref b)) => &**a as *const Cons == &**b as *const Cons,
+            (&None, &None) => true,
+            _ => false,
+        }
+    }
+
+    pub fn conj(&self, value: Value) -> List {
+        List {
+            head: Some(Rc::new(Cons {
+                first: value,
+                rest: self.clone(),
+                count: self.len() + 1,
+            }))
+        }
+    }
+
+    pub fn first(&self) -> Option<&Value> {
+        self.head.as_ref().map(|cons| &cons.first)
+    }
+
+    pub fn rest(&self) -> List {
+        match self.head {
+            Some(ref cons) => cons.rest.clone(),
+            None => List::new(),
+        }
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter { current: self }
+    }
+}
+
+impl Drop for List {
+    // unlink iteratively, dropping a long list recursively overflows the stack
+    fn drop(&mut self) {
+        let mut head = self.head.take();
+        while let Some(cons) = head {
+            match Rc::try_unwrap(cons) {
+                Ok(mut cons) => head = cons.rest.head.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    current: &'a List,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Value;
+
+    fn next(&mut self) -> Option<&'a Value> {
+        match self.current.head {
+            Some(ref cons) => {
+                self.current = &cons.rest;
+                Some(&cons.first)
+            },
+            None => None,
+        }
+    }
+}
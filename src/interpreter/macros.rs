@@ -0,0 +1,257 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use parser::Expression;
+use parser::Expression::{Symbol, SExpression, Params};
+
+use super::{apply_lambda, binding_vector, eval, quote, special_fn};
+use super::core::arity;
+use super::destructure;
+use super::env::Env;
+use super::error::{Error, error};
+use super::hashmap::Map;
+use super::namespaces;
+use super::value::{Lambda, NativeFn, Value};
+
+/// Names bound by enclosing `let`s and `fn`s; these shadow macros.
+type Locals = HashSet<String>;
+
+thread_local!(static GENSYM_COUNTER: Cell<usize> = Cell::new(0));
+
+pub fn builtins() -> Vec<(&'static str, NativeFn)> {
+    vec!(
+        ("gensym", fn_gensym),
+    )
+}
+
+/// `(defmacro name doc? [params] body...)`: like a named `fn`, but called
+/// with its arguments unevaluated, plus `&form` and `&env`.
+pub fn special_defmacro(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    let name = match args.first() {
+        Some(&Symbol(ref name)) => name.clone(),
+        _ => return error(String::from("defmacro requires a name")),
+    };
+    let mut fn_args = args.to_vec();
+    if let Some(&Expression::String(_)) = fn_args.get(1) {
+        fn_args.remove(1);
+    }
+
+    let mut lambda = match try!(special_fn(&fn_args, env)) {
+        Value::Lambda(lambda) => Rc::try_unwrap(lambda).ok().unwrap(),
+        _ => unreachable!("fn returns a lambda"),
+    };
+    lambda.params.insert(0, String::from("&form"));
+    lambda.params.insert(1, String::from("&env"));
+
+    env.define_global(&name, Value::Macro(Rc::new(lambda)));
+    namespaces::record_source(env, &name);
+    Ok(Value::Symbol(name))
+}
+
+/// The macro a form calls, if its head names one that isn't shadowed.
+fn macro_for(form: &Expression, env: &Env, locals: &Locals) -> Option<Rc<Lambda>> {
+    match *form {
+        SExpression(ref items) => match items.first() {
+            Some(&Symbol(ref name)) if !locals.contains(name) => match namespaces::resolve(env, name) {
+                Some(Value::Macro(lambda)) => Some(lambda),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn call_macro(lambda: Rc<Lambda>, form: &Expression, locals: &Locals) -> Result<Expression, Error> {
+    let items = match *form {
+        SExpression(ref items) => items,
+        _ => unreachable!("macro call is an s-expression"),
+    };
+
+    let env_map = locals.iter()
+        .fold(Map::new(), |map, name| map.assoc(Value::Symbol(name.clone()), Value::Nil));
+    let mut args = vec!(quote(form), Value::Map(env_map));
+    args.extend(items[1..].iter().map(quote));
+
+    let function = Value::Macro(lambda.clone());
+    let expansion = try!(apply_lambda(&function, &lambda, args)
+        .map_err(|e| e.called_from(lambda.name.as_ref().map(|n| n.as_ref()).unwrap_or("macro"))));
+    Ok(expansion.to_expression())
+}
+
+/// Expands the form once if it is a macro call, None otherwise.
+fn expand_1_in(form: &Expression, env: &Env, locals: &Locals) -> Result<Option<Expression>, Error> {
+    match macro_for(form, env, locals) {
+        Some(lambda) => Ok(Some(try!(call_macro(lambda, form, locals)))),
+        None => Ok(None),
+    }
+}
+
+/// Expands the head of the form until it is no longer a macro call.
+fn expand_in(form: &Expression, env: &Env, locals: &Locals) -> Result<Expression, Error> {
+    let mut form = form.clone();
+    while let Some(expansion) = try!(expand_1_in(&form, env, locals)) {
+        form = expansion;
+    }
+    Ok(form)
+}
+
+pub fn expand_1(form: &Expression, env: &Env) -> Result<Expression, Error> {
+    Ok(try!(expand_1_in(form, env, &Locals::new())).unwrap_or(form.clone()))
+}
+
+pub fn expand(form: &Expression, env: &Env) -> Result<Expression, Error> {
+    expand_in(form, env, &Locals::new())
+}
+
+/// Expands every macro call in the form, leaving quoted data alone.
+pub fn expand_all(form: &Expression, env: &Env) -> Result<Expression, Error> {
+    walk(form, env, &Locals::new())
+}
+
+/// Symbols a binding form introduces; `[...]` and `{...}` read as
+/// `(vector ...)` and `(hash-map ...)`.
+fn binding_symbols(pattern: &Expression, into: &mut Locals) {
+    match *pattern {
+        Symbol(ref s) if s != "&" && !s.starts_with(':') => {
+            into.insert(s.clone());
+        },
+        SExpression(ref items) => for item in items.iter().skip(1) {
+            binding_symbols(item, into);
+        },
+        Params(ref items) => for item in items {
+            binding_symbols(item, into);
+        },
+        _ => (),
+    }
+}
+
+fn walk_each(items: &[Expression], env: &Env, locals: &Locals) -> Result<Vec<Expression>, Error> {
+    let mut expanded = Vec::with_capacity(items.len());
+    for item in items {
+        expanded.push(try!(walk(item, env, locals)));
+    }
+    Ok(expanded)
+}
+
+/// Rebuilds a binding vector in the shape it was written.
+fn rebuild_bindings(original: &Expression, bindings: Vec<Expression>) -> Expression {
+    match *original {
+        Params(_) => Params(bindings),
+        _ => {
+            let mut items = vec!(Symbol(String::from("vector")));
+            items.extend(bindings);
+            SExpression(items)
+        }
+    }
+}
+
+fn walk_let(items: &[Expression], env: &Env, locals: &Locals) -> Result<Expression, Error> {
+    let bindings = match items.get(1).and_then(binding_vector) {
+        Some(bindings) => bindings,
+        None => return Ok(SExpression(try!(walk_each(items, env, locals)))),
+    };
+
+    let mut locals = locals.clone();
+    let mut expanded = Vec::with_capacity(bindings.len());
+    for pair in bindings.chunks(2) {
+        expanded.push(pair[0].clone());
+        if pair.len() == 2 {
+            expanded.push(try!(walk(&pair[1], env, &locals)));
+        }
+        binding_symbols(&pair[0], &mut locals);
+    }
+
+    let mut result = vec!(items[0].clone(), rebuild_bindings(&items[1], expanded));
+    result.extend(try!(walk_each(&items[2..], env, &locals)));
+    Ok(SExpression(result))
+}
+
+fn walk_fn(items: &[Expression], env: &Env, locals: &Locals) -> Result<Expression, Error> {
+    let mut locals = locals.clone();
+    let mut i = 1;
+    if let Some(&Symbol(ref name)) = items.get(i) {
+        locals.insert(name.clone());
+        i += 1;
+    }
+    if let Some(&Expression::String(_)) = items.get(i) {
+        i += 1;
+    }
+    match items.get(i) {
+        Some(params) if binding_vector(params).is_some() => {
+            binding_symbols(params, &mut locals);
+            i += 1;
+        },
+        _ => return Ok(SExpression(try!(walk_each(items, env, &locals)))),
+    }
+
+    let mut result = items[..i].to_vec();
+    result.extend(try!(walk_each(&items[i..], env, &locals)));
+    Ok(SExpression(result))
+}
+
+/// Desugars destructuring in `let`, `loop` and `fn` forms.
+fn desugar(form: Expression, locals: &Locals) -> Result<Expression, Error> {
+    let desugared = match form {
+        SExpression(ref items) => match items.first() {
+            Some(&Symbol(ref s)) if locals.contains(s) => None,
+            Some(&Symbol(ref s)) if s == "let" => Some(try!(destructure::desugar_let(items))),
+            Some(&Symbol(ref s)) if s == "loop" => Some(try!(destructure::desugar_loop(items))),
+            Some(&Symbol(ref s)) if s == "fn" => Some(try!(destructure::desugar_fn(items))),
+            _ => None,
+        },
+        _ => None,
+    };
+    Ok(desugared.unwrap_or(form))
+}
+
+fn walk(form: &Expression, env: &Env, locals: &Locals) -> Result<Expression, Error> {
+    let form = try!(desugar(try!(expand_in(form, env, locals)), locals));
+    match form {
+        SExpression(ref items) if !items.is_empty() => match items[0] {
+            Symbol(ref s) if s == "quote" => Ok(form.clone()),
+            Symbol(ref s) if (s == "let" || s == "loop") && !locals.contains(s) =>
+                walk_let(items, env, locals),
+            Symbol(ref s) if (s == "fn" || s == "defmacro") && !locals.contains(s) =>
+                walk_fn(items, env, locals),
+            _ => Ok(SExpression(try!(walk_each(items, env, locals)))),
+        },
+        Params(ref items) => Ok(Params(try!(walk_each(items, env, locals)))),
+        _ => Ok(form.clone()),
+    }
+}
+
+/// `(macroexpand-1 form)` and `(macroexpand form)`: the form is evaluated,
+/// then expanded as code.
+pub fn special_macroexpand(args: &[Expression], env: &Env, once: bool) -> Result<Value, Error> {
+    let name = if once { "macroexpand-1" } else { "macroexpand" };
+    if args.len() != 1 {
+        return error(format!("{} requires 1 arg, got {}", name, args.len()));
+    }
+
+    let form = try!(eval(&args[0], env)).to_expression();
+    let expansion = if once { try!(expand_1(&form, env)) } else { try!(expand(&form, env)) };
+    Ok(quote(&expansion))
+}
+
+fn fn_gensym(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("gensym", &args, 0, 1));
+    let prefix = match args.first() {
+        None => String::from("G__"),
+        Some(&Value::Str(ref s)) => s.clone(),
+        Some(&Value::Symbol(ref s)) => s.clone(),
+        Some(other) => return error(format!("gensym expects a string prefix, got {}", other.type_name())),
+    };
+
+    Ok(Value::Symbol(gensym(&prefix)))
+}
+
+/// A symbol name that no other call returns.
+pub fn gensym(prefix: &str) -> String {
+    let n = GENSYM_COUNTER.with(|counter| {
+        counter.set(counter.get() + 1);
+        counter.get()
+    });
+    format!("{}{}", prefix, n)
+}
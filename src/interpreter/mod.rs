@@ -1,74 +1,644 @@
-use std::str::FromStr;
+mod bigint;
+mod core;
+mod destructure;
+mod docs;
+mod embed;
+mod env;
+mod error;
+mod hashmap;
+mod io;
+mod list;
+mod macros;
+mod namespaces;
+mod numbers;
+mod refs;
+mod seq;
+mod sequences;
+mod strings;
+mod value;
+mod vector;
+
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use parser;
 use parser::Expression;
-use parser::Expression::{Symbol, Number, SExpression};
+use parser::Expression::{Symbol, Number, SExpression, Params};
 use parser::Expression::String as Str;
 
+pub use self::embed::{FromArgs, FromValue, HostResult, Interpreter, IntoValue};
+pub use self::env::{Env, Scope};
+pub use self::error::Error;
+pub use self::core::root_env;
+pub use self::destructure::DESTRUCTURING_HELPERS;
+pub use self::io::pr_str;
+pub use self::io::set_command_line_args;
+pub use self::io::redirect_output;
+pub use self::macros::expand_all;
+pub use self::namespaces::Var;
+pub use self::namespaces::names_in;
+pub use self::namespaces::set_source_paths;
+pub use self::value::Value;
+
+use self::error::error;
+use self::value::Lambda;
+
 //TODO: interpreter implementation will fail on recursion
 pub fn interpret(input: Vec<Expression>) -> Expression
 {
-    let mut result: Expression = SExpression(vec!());
-    for i in input {
-        match i {
-            SExpression(expression) => result = sexpression(&expression),
-            _ => result = SExpression(vec!()),
+    if input.is_empty() {
+        return SExpression(vec!());
+    }
+
+    match eval_forms(&input, &core::root_env()) {
+        Ok((value, _)) => value.to_expression(),
+        Err(e) => panic!("{}", e.stack_trace()),
+    }
+}
+
+/// Evaluates the forms of a program or file in order. An `ns` form switches
+/// the namespace the forms after it are evaluated in; the namespace in
+/// effect at the end is returned with the last value.
+pub fn eval_forms(forms: &[Expression], env: &Env) -> Result<(Value, Env), Error> {
+    let mut env = env.clone();
+    let mut result = Value::Nil;
+    for form in forms {
+        result = match namespaces::ns_form(form) {
+            Some(args) => {
+                env = try!(namespaces::enter(args, &env));
+                Value::Nil
+            },
+            None => try!(eval_form(form, &env)),
         };
     }
+    Ok((result, env))
+}
+
+/// Evaluates a top-level form, expanding its macros first. The forms of a
+/// top-level `do` are top-level too, so a macro can be used right after the
+/// `defmacro` that defines it.
+pub fn eval_form(expr: &Expression, env: &Env) -> Result<Value, Error> {
+    if let SExpression(ref items) = *expr {
+        if items.first() == Some(&parser::symbol("do")) && env.lookup("do").is_none() {
+            let mut result = Value::Nil;
+            for item in &items[1..] {
+                result = try!(eval_form(item, env));
+            }
+            return Ok(result);
+        }
+    }
+
+    let previous = namespaces::set_current_form(env, Some(expr.clone()));
+    let result = expand_all(expr, env).and_then(|expanded| {
+        try!(check_recur(&expanded, false));
+        eval(&expanded, env)
+    });
+    namespaces::set_current_form(env, previous);
     result
 }
 
-fn lookup_symbol(name: String) -> Box<FnMut(Vec<Expression>) -> Expression> {
-    match name.as_ref() {
-        "+" => Box::new(fn_plus),
-        "-" => Box::new(fn_min),
-        _ => Box::new(|v: Vec<Expression>| parser::number("3"))
-    }
-}
-
-fn fn_plus(expr: Vec<Expression>) -> Expression {
-     let mut acc: i64 = 0;
-     for i in expr {
-         match i {
-             Number(number) => acc += FromStr::from_str(&number).unwrap_or(0),
-             SExpression(exprs) => match sexpression(&(exprs.clone())) {
-                    Number(number) => acc += FromStr::from_str(&number).unwrap_or(0),
-                    _ => (),
-                 },
-             _ => (),
-         }
-     };
-     Number(acc.to_string())
-}
-
-fn fn_min(expr: Vec<Expression>) -> Expression {
-     let mut acc: i64 = match expr[0].clone() {
-         Number(number) => FromStr::from_str(&number).unwrap_or(0),
-         _ => 0,
-     };
-     let rest = expr[1..].to_vec();
-     for i in rest {
-         match i {
-             Number(number) => acc -= FromStr::from_str(&number).unwrap_or(0),
-             SExpression(exprs) => match sexpression(&(exprs.clone())) {
-                    Number(number) => acc -= FromStr::from_str(&number).unwrap_or(0),
-                    _ => (),
-                 },
-             _ => (),
-         }
-     };
-     Number(acc.to_string())
-}
-
-fn sexpression(expr: &Vec<Expression>) -> Expression {
-    let error = parser::symbol("error");
-    let error_str = String::from("error");
-    let symbol = match expr.first().unwrap_or(&error) {
-        &Symbol(ref symbol) => symbol,
-        _ => &error_str,
+/// The interrupt flag of an interpreter. Clones share the flag, so one can
+/// be handed to another thread, or to a signal handler, to stop what the
+/// interpreter is evaluating.
+#[derive(Clone)]
+pub struct Interrupt {
+    flag: Arc<AtomicBool>,
+}
+
+impl Interrupt {
+    pub fn new() -> Interrupt {
+        Interrupt { flag: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Makes the evaluation in progress fail at its next step with an error
+    /// `try` doesn't catch; safe to call from a signal handler.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn clear(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+thread_local!(static INTERRUPT: RefCell<Option<Interrupt>> = RefCell::new(None));
+
+/// Runs f with the interrupt as the one evaluation on this thread checks.
+pub fn with_interrupt<T, F: FnOnce() -> T>(interrupt: &Interrupt, f: F) -> T {
+    let previous = INTERRUPT.with(|current| mem::replace(&mut *current.borrow_mut(), Some(interrupt.clone())));
+    let result = f();
+    INTERRUPT.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+/// Fails with the interrupt error if the evaluation has been interrupted,
+/// clearing the interrupt.
+pub fn check_interrupt() -> Result<(), Error> {
+    INTERRUPT.with(|current| match *current.borrow() {
+        Some(ref interrupt) if interrupt.flag.load(Ordering::Relaxed) => {
+            interrupt.clear();
+            Err(Error::interrupted())
+        },
+        _ => Ok(()),
+    })
+}
+
+pub fn eval(expr: &Expression, env: &Env) -> Result<Value, Error> {
+    try!(check_interrupt());
+
+    match *expr {
+        Symbol(ref symbol) => eval_symbol(symbol, env),
+        Number(ref number) => numbers::parse(number),
+        Str(ref s) => Ok(Value::Str(s.clone())),
+        SExpression(ref exprs) => sexpression(exprs, env),
+        Params(ref params) => {
+            let items = try!(eval_each(params, env));
+            Ok(Value::Vector(vector::Vector::from_vec(items)))
+        }
+    }
+}
+
+/// Symbols that evaluate to themselves: nil, booleans and keywords.
+fn literal_symbol(symbol: &str) -> Option<Value> {
+    match symbol {
+        "nil" => Some(Value::Nil),
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ if symbol.starts_with(':') && symbol.len() > 1 =>
+            Some(Value::Keyword(String::from(&symbol[1..]))),
+        _ => None,
+    }
+}
+
+fn eval_symbol(symbol: &str, env: &Env) -> Result<Value, Error> {
+    if let Some(value) = literal_symbol(symbol) {
+        return Ok(value);
+    }
+
+    match namespaces::resolve(env, symbol) {
+        Some(value) => Ok(value),
+        None => error(format!("Unable to resolve symbol: {}", symbol)),
+    }
+}
+
+fn eval_each(exprs: &[Expression], env: &Env) -> Result<Vec<Value>, Error> {
+    let mut values = Vec::with_capacity(exprs.len());
+    for e in exprs {
+        values.push(try!(eval(e, env)));
+    }
+    Ok(values)
+}
+
+fn eval_body(body: &[Expression], env: &Env) -> Result<Value, Error> {
+    let mut result = Value::Nil;
+    for e in body {
+        result = try!(eval(e, env));
+    }
+    Ok(result)
+}
+
+fn sexpression(expr: &Vec<Expression>, env: &Env) -> Result<Value, Error> {
+    if expr.is_empty() {
+        return Ok(Value::List(list::List::new()));
+    }
+
+    if let Symbol(ref symbol) = expr[0] {
+        let args = &expr[1..];
+        match symbol.as_ref() {
+            "quote" => return special_quote(args),
+            "if" => return special_if(args, env),
+            "do" => return eval_body(args, env),
+            "def" => return special_def(args, env),
+            "let" => return special_let(args, env),
+            "loop" => return special_loop(args, env),
+            "recur" => return special_recur(args, env),
+            "fn" => return special_fn(args, env),
+            "lazy-seq" => return Ok(special_lazy_seq(args, env)),
+            "throw" => return special_throw(args, env),
+            "try" => return special_try(args, env),
+            "defmacro" => return macros::special_defmacro(args, env),
+            "macroexpand-1" => return macros::special_macroexpand(args, env, true),
+            "macroexpand" => return macros::special_macroexpand(args, env, false),
+            "ns" => return namespaces::special_ns(args, env),
+            "require" => return namespaces::special_require(args, env),
+            "doc" => return docs::special_doc(args, env),
+            "source" => return docs::special_source(args, env),
+            "dir" => return docs::special_dir(args, env),
+            _ => (),
+        }
+    }
+
+    let function = try!(eval(&expr[0], env));
+    let args = try!(eval_each(&expr[1..], env));
+    apply(&function, args)
+}
+
+/// The items of a binding vector, written either `[...]` or `#[...]`.
+fn binding_vector(expr: &Expression) -> Option<&[Expression]> {
+    match *expr {
+        Params(ref items) => Some(items),
+        SExpression(ref items) if items.first() == Some(&parser::symbol("vector")) =>
+            Some(&items[1..]),
+        _ => None,
+    }
+}
+
+fn special_quote(args: &[Expression]) -> Result<Value, Error> {
+    if args.len() != 1 {
+        return error(format!("quote requires 1 arg, got {}", args.len()));
+    }
+    Ok(quote(&args[0]))
+}
+
+/// The expression as data, without evaluating it.
+pub fn quote(expr: &Expression) -> Value {
+    match *expr {
+        Symbol(ref symbol) => literal_symbol(symbol).unwrap_or(Value::Symbol(symbol.clone())),
+        Number(ref number) => numbers::parse(number).unwrap_or(Value::Symbol(number.clone())),
+        Str(ref s) => Value::Str(s.clone()),
+        SExpression(ref exprs) => {
+            // the reader turns [...], {...} and #{...} into calls, which
+            // quoted should be the collections as written
+            let items: Vec<Value> = exprs.iter().map(quote).collect();
+            match exprs.first() {
+                Some(&Symbol(ref s)) if s == "vector" => Value::Vector(vector::Vector::from_vec(items[1..].to_vec())),
+                Some(&Symbol(ref s)) if s == "hash-map" && items.len() % 2 == 1 =>
+                    Value::Map(items[1..].chunks(2)
+                        .fold(hashmap::Map::new(), |map, kv| map.assoc(kv[0].clone(), kv[1].clone()))),
+                Some(&Symbol(ref s)) if s == "set" =>
+                    Value::Set(items[1..].iter().fold(hashmap::Set::new(), |set, v| set.conj(v.clone()))),
+                _ => Value::List(list::List::from_vec(items)),
+            }
+        },
+        Params(ref params) => Value::Vector(vector::Vector::from_vec(params.iter().map(quote).collect())),
+    }
+}
+
+fn special_if(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    if args.len() < 2 || args.len() > 3 {
+        return error(format!("if requires 2 or 3 args, got {}", args.len()));
+    }
+
+    if try!(eval(&args[0], env)).is_truthy() {
+        eval(&args[1], env)
+    }
+    else if args.len() == 3 {
+        eval(&args[2], env)
+    }
+    else {
+        Ok(Value::Nil)
+    }
+}
+
+fn special_def(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    if args.len() != 2 {
+        return error(format!("def requires 2 args, got {}", args.len()));
+    }
+
+    match args[0] {
+        Symbol(ref name) => {
+            let value = try!(eval(&args[1], env));
+            env.define_global(name, value);
+            namespaces::record_source(env, name);
+            Ok(Value::Symbol(name.clone()))
+        },
+        ref other => error(format!("def requires a symbol, got {:?}", other)),
+    }
+}
+
+/// Evaluates the binding vector of a `let` or `loop` into a new scope, and
+/// returns it along with the names bound.
+fn bind_sequentially(form: &str, args: &[Expression], env: &Env) -> Result<(Env, Vec<String>), Error> {
+    let bindings = match args.first().and_then(binding_vector) {
+        Some(bindings) => bindings,
+        None => return error(format!("{} requires a binding vector", form)),
+    };
+    if bindings.len() % 2 != 0 {
+        return error(format!("{} requires an even number of forms in binding vector", form));
+    }
+
+    let scope = Scope::child(env);
+    let mut names = Vec::with_capacity(bindings.len() / 2);
+    for pair in bindings.chunks(2) {
+        match pair[0] {
+            Symbol(ref name) => {
+                let value = try!(eval(&pair[1], &scope));
+                scope.define(name, value);
+                names.push(name.clone());
+            },
+            ref other => return error(format!("Unsupported binding form: {:?}", other)),
+        }
+    }
+    Ok((scope, names))
+}
+
+fn special_let(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    let (scope, _) = try!(bind_sequentially("let", args, env));
+    eval_body(&args[1..], &scope)
+}
+
+/// `(loop [bindings] body)`: like `let`, but `recur` in tail position
+/// starts the body again with the names rebound.
+fn special_loop(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    if let Some(bindings) = args.first() {
+        try!(check_recur(bindings, false));
+    }
+    try!(check_recur_body(&args[1..], true));
+
+    let (mut scope, names) = try!(bind_sequentially("loop", args, env));
+    loop {
+        match try!(eval_body(&args[1..], &scope)) {
+            Value::Recur(values) => {
+                if values.len() != names.len() {
+                    return error(format!("Mismatched argument count to recur, expected: {} args, got: {}",
+                                         names.len(), values.len()));
+                }
+                scope = Scope::child(env);
+                for (name, value) in names.iter().zip(values) {
+                    scope.define(name, value);
+                }
+            },
+            result => return Ok(result),
+        }
+    }
+}
+
+/// `(recur args...)` evaluates to a marker that the enclosing `loop` or
+/// `fn` rebinds its names from.
+fn special_recur(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    Ok(Value::Recur(try!(eval_each(args, env))))
+}
+
+/// Makes sure every `recur` in an expanded form is in tail position, where
+/// its marker is what the enclosing `loop` or `fn` gets back. The bodies of
+/// nested `loop`s and `fn`s are checked when they're evaluated. The backend
+/// checks the forms it compiles the same way.
+pub fn check_recur(expr: &Expression, tail: bool) -> Result<(), Error> {
+    let items = match *expr {
+        SExpression(ref items) => items,
+        Params(ref items) => return check_recur_body(items, false),
+        _ => return Ok(()),
+    };
+
+    let args = if items.is_empty() { &items[..] } else { &items[1..] };
+    match items.first() {
+        Some(&Symbol(ref s)) => match s.as_ref() {
+            "quote" | "fn" | "loop" | "defmacro" => Ok(()),
+            "recur" if !tail => error(String::from("Can only recur from tail position")),
+            "if" => {
+                for (i, arg) in args.iter().enumerate() {
+                    try!(check_recur(arg, tail && i > 0));
+                }
+                Ok(())
+            },
+            "do" => check_recur_body(args, tail),
+            "let" => {
+                if let Some(bindings) = args.first() {
+                    try!(check_recur(bindings, false));
+                }
+                check_recur_body(&args[1..], tail)
+            },
+            "try" => {
+                let clause = |e: &Expression, name: &str| match *e {
+                    SExpression(ref items) if items.first() == Some(&Symbol(String::from(name))) => true,
+                    _ => false,
+                };
+                let body_len = args.iter().position(|e| clause(e, "catch") || clause(e, "finally")).unwrap_or(args.len());
+                try!(check_recur_body(&args[..body_len], tail));
+                // a handler's body is the try's value, what finally does isn't
+                for e in &args[body_len..] {
+                    if let SExpression(ref items) = *e {
+                        let (start, handler_tail) = if clause(e, "catch") { (3, tail) } else { (1, false) };
+                        if items.len() > start {
+                            try!(check_recur_body(&items[start..], handler_tail));
+                        }
+                    }
+                }
+                Ok(())
+            },
+            _ => check_recur_body(args, false),
+        },
+        _ => check_recur_body(items, false),
+    }
+}
+
+/// Checks a body whose last form is in tail position if the body is.
+pub fn check_recur_body(body: &[Expression], tail: bool) -> Result<(), Error> {
+    for (i, e) in body.iter().enumerate() {
+        try!(check_recur(e, tail && i + 1 == body.len()));
+    }
+    Ok(())
+}
+
+fn special_fn(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    let (name, args) = match args.first() {
+        Some(&Symbol(ref name)) => (Some(name.clone()), &args[1..]),
+        _ => (None, args),
     };
-    let rest: Vec<Expression> = expr[1..].to_vec();
-    (*lookup_symbol(symbol.clone()))(rest)
+
+    let param_exprs = match args.first().and_then(binding_vector) {
+        Some(params) => params,
+        None => return error(String::from("fn requires a parameter vector")),
+    };
+
+    let mut params = Vec::new();
+    let mut rest = None;
+    let mut iter = param_exprs.iter();
+    while let Some(p) = iter.next() {
+        match *p {
+            Symbol(ref s) if s == "&" => match iter.next() {
+                Some(&Symbol(ref r)) => rest = Some(r.clone()),
+                _ => return error(String::from("fn requires a symbol after &")),
+            },
+            Symbol(ref s) => params.push(s.clone()),
+            ref other => return error(format!("Unsupported parameter: {:?}", other)),
+        }
+    }
+
+    try!(check_recur_body(&args[1..], true));
+
+    Ok(Value::Lambda(Rc::new(Lambda {
+        name: name,
+        params: params,
+        rest: rest,
+        body: args[1..].to_vec(),
+        env: env.clone(),
+    })))
+}
+
+fn special_lazy_seq(args: &[Expression], env: &Env) -> Value {
+    let body = args.to_vec();
+    let env = env.clone();
+    seq::lazy(move || eval_body(&body, &env))
+}
+
+fn special_throw(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    if args.len() != 1 {
+        return error(format!("throw requires 1 arg, got {}", args.len()));
+    }
+
+    match try!(eval(&args[0], env)) {
+        Value::Error(e) => Err((*e).clone()),
+        other => error(format!("throw requires an exception, got {}", other.type_name())),
+    }
+}
+
+/// Whether a `catch` clause naming `class` handles the error.
+fn catches(class: &str, e: &Error) -> Result<bool, Error> {
+    match class {
+        "Exception" | "Throwable" | ":default" => Ok(true),
+        "ExceptionInfo" => Ok(e.data != Value::Nil),
+        _ => error(format!("Unknown exception class: {}", class)),
+    }
+}
+
+fn special_try(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    fn clause<'a>(expr: &'a Expression, name: &str) -> Option<&'a [Expression]> {
+        match *expr {
+            SExpression(ref items) if items.first() == Some(&Symbol(String::from(name))) =>
+                Some(&items[1..]),
+            _ => None,
+        }
+    }
+
+    let body_len = args.iter()
+        .position(|e| clause(e, "catch").is_some() || clause(e, "finally").is_some())
+        .unwrap_or(args.len());
+    let mut handlers = Vec::new();
+    let mut finally = None;
+    for (i, e) in args[body_len..].iter().enumerate() {
+        if let Some(handler) = clause(e, "catch") {
+            if finally.is_some() {
+                return error(String::from("finally must be the last clause of try"));
+            }
+            match (handler.get(0), handler.get(1)) {
+                (Some(&Symbol(ref class)), Some(&Symbol(ref name))) =>
+                    handlers.push((class, name, &handler[2..])),
+                _ => return error(String::from("catch requires an exception class and a symbol")),
+            }
+        }
+        else if let Some(body) = clause(e, "finally") {
+            if body_len + i + 1 != args.len() {
+                return error(String::from("finally must be the last clause of try"));
+            }
+            finally = Some(body);
+        }
+        else {
+            return error(String::from("try body must come before catch and finally"));
+        }
+    }
+
+    let mut result = eval_body(&args[..body_len], env);
+    if let Err(e) = result.clone() {
+        for &(class, name, body) in &handlers {
+            // an interrupt unwinds past every handler
+            if !e.interrupt && try!(catches(class, &e)) {
+                let scope = Scope::child(env);
+                scope.define(name, Value::Error(Rc::new(e)));
+                result = eval_body(body, &scope);
+                break;
+            }
+        }
+    }
+
+    if let Some(body) = finally {
+        try!(eval_body(body, env));
+    }
+    result
+}
+
+pub fn apply(function: &Value, args: Vec<Value>) -> Result<Value, Error> {
+    match *function {
+        Value::Builtin(ref builtin) => (builtin.f)(args).map_err(|e| e.called_from(builtin.name)),
+        Value::Native(ref native) => (native.f)(args).map_err(|e| e.called_from(&native.name)),
+        Value::Lambda(ref lambda) => apply_lambda(function, lambda, args)
+            .map_err(|e| e.called_from(lambda.name.as_ref().map(|n| n.as_ref()).unwrap_or("fn"))),
+        Value::Keyword(_) | Value::Map(_) | Value::Set(_) | Value::Vector(_) => {
+            let (coll, key, default) = match *function {
+                Value::Keyword(_) if args.len() == 1 || args.len() == 2 =>
+                    (args[0].clone(), function.clone(), args.get(1).cloned()),
+                Value::Keyword(_) => return error(format!(
+                    "{} requires 1 or 2 args, got {}", function, args.len())),
+                _ if args.len() == 1 || args.len() == 2 =>
+                    (function.clone(), args[0].clone(), args.get(1).cloned()),
+                _ => return error(format!(
+                    "{} requires 1 or 2 args, got {}", function.type_name(), args.len())),
+            };
+            Ok(core::get(&coll, &key).unwrap_or(default.unwrap_or(Value::Nil)))
+        },
+        _ => error(format!("{} is not a function", function)),
+    }
+}
+
+fn apply_lambda(function: &Value, lambda: &Lambda, args: Vec<Value>) -> Result<Value, Error> {
+    let arity_ok = if lambda.rest.is_some() {
+        args.len() >= lambda.params.len()
+    }
+    else {
+        args.len() == lambda.params.len()
+    };
+    if !arity_ok {
+        return error(format!("{} requires {} args, got {}", function, lambda.params.len(), args.len()));
+    }
+
+    let mut scope = Scope::child(&lambda.env);
+    if let Some(ref name) = lambda.name {
+        scope.define(name, function.clone());
+    }
+
+    let mut args = args.into_iter();
+    for p in &lambda.params {
+        scope.define(p, args.next().unwrap());
+    }
+    if let Some(ref rest) = lambda.rest {
+        let rest_args: Vec<Value> = args.collect();
+        if rest_args.is_empty() {
+            scope.define(rest, Value::Nil);
+        }
+        else {
+            scope.define(rest, Value::List(list::List::from_vec(rest_args)));
+        }
+    }
+
+    loop {
+        let values = match try!(eval_body(&lambda.body, &scope)) {
+            Value::Recur(values) => values,
+            result => return Ok(result),
+        };
+
+        // recur passes the rest args as a single seq
+        let expected = lambda.params.len() + if lambda.rest.is_some() { 1 } else { 0 };
+        if values.len() != expected {
+            return error(format!("Mismatched argument count to recur, expected: {} args, got: {}",
+                                 expected, values.len()));
+        }
+
+        scope = Scope::child(&lambda.env);
+        if let Some(ref name) = lambda.name {
+            scope.define(name, function.clone());
+        }
+        for (p, value) in lambda.params.iter().chain(lambda.rest.iter()).zip(values) {
+            scope.define(p, value);
+        }
+    }
+}
+
+#[cfg(test)]
+fn assert_pairs(path: &'static str) {
+    assert_pairs_in(path, &core::root_env());
+}
+
+#[cfg(test)]
+fn assert_pairs_in(path: &'static str, env: &Env) {
+    let expressions = parser::parse_file(String::from(path));
+    for pair in expressions.chunks(2) {
+        let result = eval_form(&pair[0], env).unwrap();
+        let expected = eval_form(&pair[1], env).unwrap();
+        assert_eq!(expected, result, "{:?}", pair[0]);
+    }
 }
 
 #[test]
@@ -83,6 +653,133 @@ fn interpret_plus_file() {
     assert_eq!(interpret(vec!(expressions[0].clone())), expressions[1]);
 }
 
+#[test]
+fn interpret_collections_file() {
+    assert_pairs("resources/interpreter/collections.fc");
+}
+
+#[test]
+fn interpret_sequences_file() {
+    assert_pairs("resources/interpreter/sequences.fc");
+}
+
+#[test]
+fn interpret_lazy_file() {
+    assert_pairs("resources/interpreter/lazy.fc");
+}
+
+#[test]
+fn interpret_numbers_file() {
+    assert_pairs("resources/interpreter/numbers.fc");
+}
+
+#[test]
+fn interpret_exceptions_file() {
+    assert_pairs("resources/interpreter/exceptions.fc");
+}
+
+#[test]
+fn interpret_macros_file() {
+    assert_pairs("resources/interpreter/macros.fc");
+}
+
+#[test]
+fn interpret_prelude_file() {
+    assert_pairs("resources/interpreter/prelude.fc");
+}
+
+#[test]
+fn interpret_destructuring_file() {
+    assert_pairs("resources/interpreter/destructuring.fc");
+}
+
+#[test]
+fn interpret_refs_file() {
+    assert_pairs("resources/interpreter/refs.fc");
+}
+
+#[test]
+fn interpret_io_file() {
+    assert_pairs("resources/interpreter/io.fc");
+}
+
+#[test]
+fn interpret_strings_file() {
+    assert_pairs("resources/interpreter/strings.fc");
+}
+
+#[test]
+fn definitions_remember_their_source() {
+    let env = root_env();
+    let program = "(defn sq \"Squares x.\" [x] (* x x)) (def n 1)";
+    eval_forms(&parser::parse_string(String::from(program)), &env).unwrap();
+
+    let sq = namespaces::find_var(&env, "sq").unwrap();
+    assert_eq!(("user", "sq"), (&sq.namespace[..], &sq.name[..]));
+    assert_eq!("(defn sq \"Squares x.\" [x] (* x x))", sq.source.unwrap().to_string());
+    assert_eq!("(def n 1)", namespaces::find_var(&env, "n").unwrap().source.unwrap().to_string());
+    assert_eq!("fancy.core", namespaces::find_var(&env, "when").unwrap().namespace);
+    assert!(namespaces::find_var(&env, "+").unwrap().source.is_none());
+    assert!(namespaces::names_in(&env, "fancy.string").unwrap().contains(&String::from("split")));
+}
+
+#[test]
+fn spit_and_slurp_round_trip() {
+    let path = ::std::env::temp_dir().join("fancy-spit-test.txt");
+    let path = path.to_str().unwrap();
+    let program = format!(
+        "(spit \"{0}\" 1) (spit \"{0}\" 2 :append true) (spit \"{0}\" \"3\" :append true) \
+         [(slurp \"{0}\") (count (line-seq \"{0}\"))]", path);
+    let env = root_env();
+    let (value, _) = eval_forms(&parser::parse_string(program), &env).unwrap();
+    assert_eq!("[\"123\" 1]", value.to_string());
+}
+
+#[test]
+fn interpret_namespaces_file() {
+    let env = core::root_env();
+    set_source_paths(&env, vec!(::std::path::PathBuf::from("resources/interpreter/src")));
+    assert_pairs_in("resources/interpreter/namespaces.fc", &env);
+}
+
+#[test]
+fn ns_switches_namespace_for_following_forms() {
+    let env = core::root_env();
+    let program = parser::parse_string(String::from(
+        "(ns my.app) (def answer 42) (ns user) my.app/answer"));
+    let (value, env) = eval_forms(&program, &env).unwrap();
+    assert_eq!(Value::Int(42), value);
+    assert_eq!("user", env.namespace_of().name);
+    assert!(eval_forms(&parser::parse_string(String::from("answer")), &env).is_err());
+}
+
+#[test]
+fn uncaught_errors_carry_a_trace() {
+    let env = core::root_env();
+    let program = parser::parse_string(String::from(
+        "(def half (fn half [x] (/ x 0))) (def twice (fn twice [x] (half x))) (twice 1)"));
+    for e in &program[..2] {
+        eval(e, &env).unwrap();
+    }
+
+    let e = eval(&program[2], &env).unwrap_err();
+    assert_eq!(vec!("/", "half", "twice"), e.trace);
+    assert_eq!("Divide by zero\n    at /\n    at half\n    at twice", e.stack_trace());
+}
+
+#[test]
+fn interpret_arithmetic_errors() {
+    let env = core::root_env();
+    for &(source, message) in [("(+ 9223372036854775807 1)", "Integer overflow"),
+                               ("(* -9223372036854775808 -1)", "Integer overflow"),
+                               ("(/ 1 0)", "Divide by zero"),
+                               ("(quot 1 0)", "Divide by zero"),
+                               ("(mod 1/2 0)", "Divide by zero")].iter() {
+        let expression = parser::parse_string(String::from(source)).remove(0);
+        assert_eq!(message, eval(&expression, &env).unwrap_err().message);
+    }
+}
+
 #[test]
 fn interpret_empty() {
     assert_eq!(interpret(vec!()), SExpression(vec!()));
@@ -113,3 +810,65 @@ fn interpret_plus_10_20() {
 
         )
 }
+
+#[test]
+fn printing_a_lazy_seq_that_fails_raises_its_error() {
+    let value = Interpreter::new().eval_str("(map (fn [x] (/ 1 x)) [1 0])").unwrap();
+    assert_eq!("Divide by zero", pr_str(&value).unwrap_err().message);
+    assert_eq!("Divide by zero", pr_str(&Value::Vector(vector::Vector::from_vec(vec!(value)))).unwrap_err().message);
+    assert_eq!("(1 2)", pr_str(&Interpreter::new().eval_str("(map inc [0 1])").unwrap()).unwrap());
+}
+
+#[test]
+fn interrupts_stop_builtins_walking_endless_seqs() {
+    use std::thread;
+    use std::time::Duration;
+
+    let mut fancy = Interpreter::new();
+    for source in &["(count (range))", "(reduce + (range))", "(into [] (repeat 1))",
+                    "(first (filter neg? (range)))", "(nth (cycle [1 2]) 9223372036854775807)"] {
+        let interrupt = fancy.interrupt();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            interrupt.interrupt();
+        });
+        let e = fancy.eval_str(source).unwrap_err();
+        interrupter.join().unwrap();
+        assert!(e.interrupt, "{}", source);
+    }
+    assert_eq!(Value::Int(10), fancy.eval_str("(count (range 10))").unwrap());
+}
+
+#[test]
+fn interrupts_unwind_past_catch_and_leave_lazy_seqs_to_retry() {
+    use std::cell::Cell;
+
+    // `stop` is interrupted as often as asked, then passes its argument on
+    let stops = Rc::new(Cell::new(0));
+    let mut fancy = Interpreter::new();
+    let remaining = stops.clone();
+    fancy.register_fn("stop", 1, move |(x,): (Value,)| {
+        if remaining.get() == 0 {
+            return Ok(x);
+        }
+        remaining.set(remaining.get() - 1);
+        Err(Error::interrupted())
+    }).unwrap();
+
+    stops.set(1);
+    let e = fancy.eval_str("(try (stop 1) (catch Exception e :caught))").unwrap_err();
+    assert!(e.interrupt);
+
+    fancy.eval_str("(def xs (map stop [1 2 3]))
+                    (def ys (filter stop (range 5)))
+                    (def zs (drop 2 (map stop (list 1 2 3 4))))").unwrap();
+    for (coll, expected) in vec!(("xs", "[1 2 3]"), ("ys", "[0 1 2 3 4]"), ("zs", "[3 4]")) {
+        stops.set(1);
+        assert!(fancy.eval_str(&format!("(into [] {})", coll)).unwrap_err().interrupt);
+        assert_eq!(expected, fancy.eval_str(&format!("(into [] {})", coll)).unwrap().to_string());
+    }
+
+    // other errors are still caught, and kept by the seq that failed
+    assert_eq!(":caught", fancy.eval_str("(try (first (map (fn [x] (/ 1 x)) [0])) (catch Exception e :caught))")
+               .unwrap().to_string());
+}
@@ -0,0 +1,312 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use parser;
+use parser::Expression;
+use parser::Expression::{Symbol, SExpression};
+
+use super::{binding_vector, eval_forms};
+use super::env::{Env, Scope};
+use super::error::{Error, error};
+use super::value::Value;
+
+/// The namespace builtins live in, which all others refer.
+pub const CORE: &'static str = "fancy.core";
+
+/// A named global scope, and the short names it gave other namespaces.
+pub struct Namespace {
+    pub name: String,
+    aliases: RefCell<HashMap<String, String>>,
+    sources: RefCell<HashMap<String, Expression>>,
+    registry: Rc<Registry>,
+}
+
+/// The namespaces of one interpreter and where to load more from.
+pub struct Registry {
+    namespaces: RefCell<HashMap<String, Env>>,
+    loading: RefCell<Vec<String>>,
+    source_paths: RefCell<Vec<PathBuf>>,
+    current_form: RefCell<Option<Expression>>,
+}
+
+/// A global definition, as `doc` and `source` describe it.
+pub struct Var {
+    pub namespace: String,
+    pub name: String,
+    pub value: Value,
+    /// The top-level form that defined it, when it was defined in fancy.
+    pub source: Option<Expression>,
+}
+
+/// A fresh set of namespaces holding only an empty fancy.core, which is
+/// returned.
+pub fn core() -> Env {
+    let registry = Rc::new(Registry {
+        namespaces: RefCell::new(HashMap::new()),
+        loading: RefCell::new(vec!()),
+        source_paths: RefCell::new(vec!(PathBuf::from("."))),
+        current_form: RefCell::new(None),
+    });
+    create(&registry, CORE)
+}
+
+fn create(registry: &Rc<Registry>, name: &str) -> Env {
+    let namespace = Rc::new(Namespace {
+        name: String::from(name),
+        aliases: RefCell::new(HashMap::new()),
+        sources: RefCell::new(HashMap::new()),
+        registry: registry.clone(),
+    });
+    let core = registry.namespaces.borrow().get(CORE).cloned();
+    let env = Scope::namespace(namespace, core.as_ref());
+    registry.namespaces.borrow_mut().insert(String::from(name), env.clone());
+    env
+}
+
+fn find(registry: &Registry, name: &str) -> Option<Env> {
+    registry.namespaces.borrow().get(name).cloned()
+}
+
+/// The global scope of the named namespace, created if it doesn't exist.
+pub fn find_or_create(env: &Env, name: &str) -> Env {
+    let registry = env.namespace_of().registry.clone();
+    let existing = find(&registry, name);
+    existing.unwrap_or_else(|| create(&registry, name))
+}
+
+/// Directories searched, in order, for the files of required namespaces.
+pub fn set_source_paths(env: &Env, paths: Vec<PathBuf>) {
+    *env.namespace_of().registry.source_paths.borrow_mut() = paths;
+}
+
+/// Splits `ns/name`; a lone `/` is the division function, not a namespace.
+fn qualified(symbol: &str) -> Option<(&str, &str)> {
+    match symbol.find('/') {
+        Some(i) if i > 0 && i + 1 < symbol.len() => Some((&symbol[..i], &symbol[i + 1..])),
+        _ => None,
+    }
+}
+
+/// Looks a symbol up lexically, or in another namespace when it is
+/// qualified with the namespace's name or an alias for it.
+pub fn resolve(env: &Env, symbol: &str) -> Option<Value> {
+    if let Some(value) = env.lookup(symbol) {
+        return Some(value);
+    }
+
+    let (ns, name) = match qualified(symbol) {
+        Some(parts) => parts,
+        None => return None,
+    };
+    let namespace = env.namespace_of();
+    let full_name = namespace.aliases.borrow().get(ns).cloned().unwrap_or(String::from(ns));
+    let target = find(&namespace.registry, &full_name);
+    target.and_then(|target| target.lookup_here(name))
+}
+
+/// Sets the top-level form being evaluated, returning the previous one.
+pub fn set_current_form(env: &Env, form: Option<Expression>) -> Option<Expression> {
+    let registry = env.namespace_of().registry.clone();
+    let previous = registry.current_form.borrow_mut().take();
+    *registry.current_form.borrow_mut() = form;
+    previous
+}
+
+/// Remembers the top-level form being evaluated as the source of a global
+/// definition.
+pub fn record_source(env: &Env, name: &str) {
+    let namespace = env.namespace_of();
+    let form = namespace.registry.current_form.borrow().clone();
+    match form {
+        Some(form) => namespace.sources.borrow_mut().insert(String::from(name), form),
+        None => namespace.sources.borrow_mut().remove(name),
+    };
+}
+
+/// The global definition a symbol refers to: in the current namespace or
+/// fancy.core, or in the namespace it is qualified with.
+pub fn find_var(env: &Env, symbol: &str) -> Option<Var> {
+    let namespace = env.namespace_of();
+    let candidates = match qualified(symbol) {
+        Some((ns, name)) => {
+            let full_name = namespace.aliases.borrow().get(ns).cloned().unwrap_or(String::from(ns));
+            vec!((full_name, name))
+        },
+        None => vec!((namespace.name.clone(), symbol), (String::from(CORE), symbol)),
+    };
+
+    for (ns, name) in candidates {
+        let target = match find(&namespace.registry, &ns) {
+            Some(target) => target,
+            None => continue,
+        };
+        if let Some(value) = target.lookup_here(name) {
+            let source = target.namespace_of().sources.borrow().get(name).cloned();
+            return Some(Var { namespace: ns, name: String::from(name), value: value, source: source });
+        }
+    }
+    None
+}
+
+/// The names defined in a namespace, sorted, or None if it doesn't exist.
+pub fn names_in(env: &Env, name: &str) -> Option<Vec<String>> {
+    let namespace = env.namespace_of();
+    let full_name = namespace.aliases.borrow().get(name).cloned().unwrap_or(String::from(name));
+    find(&namespace.registry, &full_name).map(|target| {
+        let mut names: Vec<String> = target.bindings().into_iter().map(|(name, _)| name).collect();
+        names.sort();
+        names
+    })
+}
+
+/// The body of an `(ns name ...)` form, if the expression is one.
+pub fn ns_form(expr: &Expression) -> Option<&[Expression]> {
+    match *expr {
+        SExpression(ref items) if items.first() == Some(&parser::symbol("ns")) => Some(&items[1..]),
+        _ => None,
+    }
+}
+
+/// `(ns name doc? (:require specs...)*)`: creates the namespace, or finds
+/// it, and processes its requires. Returns its global scope; top-level forms
+/// after it are evaluated there.
+pub fn enter(args: &[Expression], env: &Env) -> Result<Env, Error> {
+    let name = match args.first() {
+        Some(&Symbol(ref name)) => name,
+        _ => return error(String::from("ns requires a name")),
+    };
+
+    let ns_env = find_or_create(env, name);
+    for clause in &args[1..] {
+        match *clause {
+            Expression::String(_) => (),
+            SExpression(ref items) if items.first() == Some(&parser::symbol(":require")) =>
+                for spec in &items[1..] {
+                    try!(require(spec, &ns_env));
+                },
+            ref other => return error(format!("Unsupported ns clause: {:?}", other)),
+        }
+    }
+    Ok(ns_env)
+}
+
+pub fn special_ns(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    try!(enter(args, env));
+    Ok(Value::Nil)
+}
+
+/// `(require spec...)`, each spec `lib.name` or `[lib.name :as alias
+/// :refer [names...]]`, quoted or not.
+pub fn special_require(args: &[Expression], env: &Env) -> Result<Value, Error> {
+    for spec in args {
+        try!(require(spec, env));
+    }
+    Ok(Value::Nil)
+}
+
+fn require(spec: &Expression, env: &Env) -> Result<(), Error> {
+    let spec = match *spec {
+        SExpression(ref items) if items.len() == 2 && items[0] == parser::symbol("quote") => &items[1],
+        _ => spec,
+    };
+
+    let (name, options) = match binding_vector(spec) {
+        Some(items) => match items.first() {
+            Some(&Symbol(ref name)) => (name, &items[1..]),
+            _ => return error(format!("require spec must start with a namespace name: {:?}", spec)),
+        },
+        None => match *spec {
+            Symbol(ref name) => (name, &[][..]),
+            _ => return error(format!("Unsupported require spec: {:?}", spec)),
+        },
+    };
+
+    let lib = try!(load(name, env));
+    let namespace = env.namespace_of();
+    if options.len() % 2 != 0 {
+        return error(format!("require options for {} must come in pairs", name));
+    }
+
+    for option in options.chunks(2) {
+        match (&option[0], &option[1]) {
+            (&Symbol(ref key), &Symbol(ref alias)) if key == ":as" => {
+                namespace.aliases.borrow_mut().insert(alias.clone(), name.clone());
+            },
+            (&Symbol(ref key), &Symbol(ref all)) if key == ":refer" && all == ":all" =>
+                for (symbol, value) in lib.bindings() {
+                    env.define_global(&symbol, value);
+                },
+            (&Symbol(ref key), names) if key == ":refer" && binding_vector(names).is_some() =>
+                for referred in binding_vector(names).unwrap() {
+                    let symbol = match *referred {
+                        Symbol(ref symbol) => symbol,
+                        ref other => return error(format!("Can't refer {:?}", other)),
+                    };
+                    match lib.lookup_here(symbol) {
+                        Some(value) => env.define_global(symbol, value),
+                        None => return error(format!("{} does not exist in {}", symbol, name)),
+                    }
+                },
+            (key, _) => return error(format!("Unsupported require option: {:?}", key)),
+        }
+    }
+    Ok(())
+}
+
+/// The path a namespace is loaded from, relative to a source directory:
+/// `my.lib.core` is `my/lib/core.fc`.
+fn relative_path(name: &str) -> PathBuf {
+    let mut path: PathBuf = name.split('.').collect();
+    path.set_extension("fc");
+    path
+}
+
+/// The global scope of the namespace, loading it from the source path the
+/// first time it is asked for.
+fn load(name: &str, env: &Env) -> Result<Env, Error> {
+    let registry = env.namespace_of().registry.clone();
+    if registry.loading.borrow().iter().any(|n| n == name) {
+        let mut chain = registry.loading.borrow().clone();
+        chain.push(String::from(name));
+        return error(format!("Circular require: {}", chain.join(" -> ")));
+    }
+    if let Some(loaded) = find(&registry, name) {
+        return Ok(loaded);
+    }
+
+    let relative = relative_path(name);
+    let path = registry.source_paths.borrow().iter()
+        .map(|dir| dir.join(&relative))
+        .find(|path| path.is_file());
+    let path = match path {
+        Some(path) => path,
+        None => return error(format!("Could not locate {} on source path", relative.display())),
+    };
+
+    let mut source = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut file| file.read_to_string(&mut source)) {
+        return error(format!("Could not read {}: {}", path.display(), e));
+    }
+    let forms = match parser::try_parse_string(source) {
+        Ok(forms) => forms,
+        Err(e) => return error(format!("Could not read {}: {}", path.display(), e)),
+    };
+
+    registry.loading.borrow_mut().push(String::from(name));
+    let lib = create(&registry, name);
+    let result = eval_forms(&forms, &lib);
+    registry.loading.borrow_mut().pop();
+
+    match result {
+        Ok(_) => Ok(lib),
+        Err(e) => {
+            // so that requiring it again tries again
+            registry.namespaces.borrow_mut().remove(name);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,522 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use super::bigint::BigInt;
+use super::core::{arity, MANY};
+use super::error::{Error, error};
+use super::value::{NativeFn, Value};
+
+/// Exact fraction in lowest terms, with a denominator greater than one.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Ratio {
+    pub numer: BigInt,
+    pub denom: BigInt,
+}
+
+/// A number widened for arithmetic. Contagion goes left to right: any float
+/// makes a float, otherwise any ratio makes a ratio, and so on.
+enum Num {
+    Int(i64),
+    Big(BigInt),
+    Ratio(BigInt, BigInt),
+    Float(f64),
+}
+
+pub fn builtins() -> Vec<(&'static str, NativeFn)> {
+    vec!(
+        ("+", fn_plus),
+        ("+'", fn_plus_promoting),
+        ("-", fn_min),
+        ("-'", fn_min_promoting),
+        ("*", fn_times),
+        ("*'", fn_times_promoting),
+        ("/", fn_divide),
+        ("quot", fn_quot),
+        ("rem", fn_rem),
+        ("mod", fn_mod),
+        ("inc", fn_inc),
+        ("inc'", fn_inc_promoting),
+        ("dec", fn_dec),
+        ("dec'", fn_dec_promoting),
+        ("min", fn_min_of),
+        ("max", fn_max_of),
+        ("abs", fn_abs),
+        ("==", fn_num_equals),
+        ("<", fn_less),
+        (">", fn_greater),
+        ("<=", fn_less_equal),
+        (">=", fn_greater_equal),
+        ("zero?", fn_is_zero),
+        ("pos?", fn_is_pos),
+        ("neg?", fn_is_neg),
+        ("even?", fn_is_even),
+        ("odd?", fn_is_odd),
+    )
+}
+
+pub fn is_number(value: &Value) -> bool {
+    match *value {
+        Value::Int(_) | Value::BigInt(_) | Value::Ratio(_) | Value::Float(_) => true,
+        _ => false,
+    }
+}
+
+/// Reads a number literal: integers (with an optional `N` suffix for big
+/// ones), ratios such as `1/3` and floats.
+pub fn parse(number: &str) -> Result<Value, Error> {
+    if let Ok(i) = i64::from_str(number) {
+        return Ok(Value::Int(i));
+    }
+
+    let digits = if number.ends_with('N') { &number[..number.len() - 1] } else { number };
+    if let Some(big) = BigInt::parse(digits) {
+        return Ok(Value::BigInt(Rc::new(big)));
+    }
+
+    if let Some(slash) = number.find('/') {
+        if let (Some(n), Some(d)) = (BigInt::parse(&number[..slash]), BigInt::parse(&number[slash + 1..])) {
+            if d.is_zero() {
+                return divide_by_zero();
+            }
+            return Ok(ratio_value(n, d));
+        }
+    }
+
+    match f64::from_str(number) {
+        Ok(x) => Ok(Value::Float(x)),
+        Err(_) => error(format!("Invalid number: {}", number)),
+    }
+}
+
+fn overflow<T>() -> Result<T, Error> {
+    error(String::from("Integer overflow"))
+}
+
+fn divide_by_zero<T>() -> Result<T, Error> {
+    error(String::from("Divide by zero"))
+}
+
+fn num(name: &str, value: &Value) -> Result<Num, Error> {
+    match *value {
+        Value::Int(i) => Ok(Num::Int(i)),
+        Value::BigInt(ref b) => Ok(Num::Big((**b).clone())),
+        Value::Ratio(ref r) => Ok(Num::Ratio(r.numer.clone(), r.denom.clone())),
+        Value::Float(x) => Ok(Num::Float(x)),
+        ref other => error(format!("{} expects a number, got {}", name, other.type_name())),
+    }
+}
+
+/// Result of promoting i64 arithmetic, or of exact ratio arithmetic; small
+/// enough results go back to i64. Arithmetic on bigints stays in bigints.
+fn big_value(b: BigInt) -> Value {
+    match b.to_i64() {
+        Some(i) => Value::Int(i),
+        None => Value::BigInt(Rc::new(b)),
+    }
+}
+
+/// Reduces to lowest terms; d must not be zero.
+fn ratio_value(n: BigInt, d: BigInt) -> Value {
+    let g = n.gcd(&d);
+    let (n, d) = (n.divrem(&g).unwrap().0, d.divrem(&g).unwrap().0);
+    let (n, d) = if d.is_negative() { (n.neg(), d.neg()) } else { (n, d) };
+
+    if d == BigInt::from_i64(1) {
+        big_value(n)
+    }
+    else {
+        Value::Ratio(Rc::new(Ratio { numer: n, denom: d }))
+    }
+}
+
+fn value(n: Num) -> Value {
+    match n {
+        Num::Int(i) => Value::Int(i),
+        Num::Big(b) => Value::BigInt(Rc::new(b)),
+        Num::Ratio(n, d) => ratio_value(n, d),
+        Num::Float(x) => Value::Float(x),
+    }
+}
+
+fn rank(n: &Num) -> u8 {
+    match *n {
+        Num::Int(_) => 0,
+        Num::Big(_) => 1,
+        Num::Ratio(_, _) => 2,
+        Num::Float(_) => 3,
+    }
+}
+
+fn to_big(n: &Num) -> BigInt {
+    match *n {
+        Num::Int(i) => BigInt::from_i64(i),
+        Num::Big(ref b) => b.clone(),
+        _ => unreachable!("to_big on ratio or float"),
+    }
+}
+
+fn to_ratio(n: &Num) -> (BigInt, BigInt) {
+    match *n {
+        Num::Ratio(ref n, ref d) => (n.clone(), d.clone()),
+        _ => (to_big(n), BigInt::from_i64(1)),
+    }
+}
+
+fn to_float(n: &Num) -> f64 {
+    match *n {
+        Num::Int(i) => i as f64,
+        Num::Big(ref b) => b.to_f64(),
+        Num::Ratio(ref n, ref d) => n.to_f64() / d.to_f64(),
+        Num::Float(x) => x,
+    }
+}
+
+fn is_zero(n: &Num) -> bool {
+    match *n {
+        Num::Int(i) => i == 0,
+        Num::Big(ref b) => b.is_zero(),
+        Num::Ratio(ref n, _) => n.is_zero(),
+        Num::Float(x) => x == 0.0,
+    }
+}
+
+fn add(name: &str, a: &Value, b: &Value, promote: bool) -> Result<Value, Error> {
+    let (a, b) = (try!(num(name, a)), try!(num(name, b)));
+    if let (&Num::Int(x), &Num::Int(y)) = (&a, &b) {
+        return match x.checked_add(y) {
+            Some(z) => Ok(Value::Int(z)),
+            None if promote => Ok(big_value(BigInt::from_i64(x).add(&BigInt::from_i64(y)))),
+            None => overflow(),
+        };
+    }
+
+    Ok(value(match rank(&a).max(rank(&b)) {
+        3 => Num::Float(to_float(&a) + to_float(&b)),
+        2 => {
+            let ((an, ad), (bn, bd)) = (to_ratio(&a), to_ratio(&b));
+            Num::Ratio(an.mul(&bd).add(&bn.mul(&ad)), ad.mul(&bd))
+        },
+        _ => Num::Big(to_big(&a).add(&to_big(&b))),
+    }))
+}
+
+fn negate(name: &str, a: &Value, promote: bool) -> Result<Value, Error> {
+    match try!(num(name, a)) {
+        Num::Int(i) => match i.checked_neg() {
+            Some(n) => Ok(Value::Int(n)),
+            None if promote => Ok(big_value(BigInt::from_i64(i).neg())),
+            None => overflow(),
+        },
+        Num::Big(b) => Ok(Value::BigInt(Rc::new(b.neg()))),
+        Num::Ratio(n, d) => Ok(ratio_value(n.neg(), d)),
+        Num::Float(x) => Ok(Value::Float(-x)),
+    }
+}
+
+fn sub(name: &str, a: &Value, b: &Value, promote: bool) -> Result<Value, Error> {
+    if let (&Value::Int(x), &Value::Int(y)) = (a, b) {
+        return match x.checked_sub(y) {
+            Some(z) => Ok(Value::Int(z)),
+            None if promote => Ok(big_value(BigInt::from_i64(x).sub(&BigInt::from_i64(y)))),
+            None => overflow(),
+        };
+    }
+    add(name, a, &try!(negate(name, b, true)), promote)
+}
+
+fn mul(name: &str, a: &Value, b: &Value, promote: bool) -> Result<Value, Error> {
+    let (a, b) = (try!(num(name, a)), try!(num(name, b)));
+    if let (&Num::Int(x), &Num::Int(y)) = (&a, &b) {
+        return match x.checked_mul(y) {
+            Some(z) => Ok(Value::Int(z)),
+            None if promote => Ok(big_value(BigInt::from_i64(x).mul(&BigInt::from_i64(y)))),
+            None => overflow(),
+        };
+    }
+
+    Ok(value(match rank(&a).max(rank(&b)) {
+        3 => Num::Float(to_float(&a) * to_float(&b)),
+        2 => {
+            let ((an, ad), (bn, bd)) = (to_ratio(&a), to_ratio(&b));
+            Num::Ratio(an.mul(&bn), ad.mul(&bd))
+        },
+        _ => Num::Big(to_big(&a).mul(&to_big(&b))),
+    }))
+}
+
+fn div(a: &Value, b: &Value) -> Result<Value, Error> {
+    let (a, b) = (try!(num("/", a)), try!(num("/", b)));
+    if rank(&a) == 3 || rank(&b) == 3 {
+        return Ok(Value::Float(to_float(&a) / to_float(&b)));
+    }
+    if is_zero(&b) {
+        return divide_by_zero();
+    }
+
+    let ((an, ad), (bn, bd)) = (to_ratio(&a), to_ratio(&b));
+    match ratio_value(an.mul(&bd), ad.mul(&bn)) {
+        Value::Int(i) if rank(&a).max(rank(&b)) == 1 => Ok(Value::BigInt(Rc::new(BigInt::from_i64(i)))),
+        v => Ok(v),
+    }
+}
+
+/// Division rounding toward zero, for `quot`.
+fn quot(a: &Value, b: &Value) -> Result<Value, Error> {
+    let (a, b) = (try!(num("quot", a)), try!(num("quot", b)));
+    if is_zero(&b) {
+        return divide_by_zero();
+    }
+
+    match (rank(&a).max(rank(&b)), &a, &b) {
+        (0, &Num::Int(x), &Num::Int(y)) => match x.checked_div(y) {
+            Some(q) => Ok(Value::Int(q)),
+            None => overflow(),
+        },
+        (1, _, _) => Ok(Value::BigInt(Rc::new(to_big(&a).divrem(&to_big(&b)).unwrap().0))),
+        (2, _, _) => {
+            let ((an, ad), (bn, bd)) = (to_ratio(&a), to_ratio(&b));
+            Ok(big_value(an.mul(&bd).divrem(&ad.mul(&bn)).unwrap().0))
+        },
+        _ => Ok(Value::Float((to_float(&a) / to_float(&b)).trunc())),
+    }
+}
+
+/// Remainder of `quot`, taking the sign of the dividend.
+fn rem(a: &Value, b: &Value) -> Result<Value, Error> {
+    let q = try!(quot(a, b));
+    match (a, b) {
+        (&Value::Int(x), &Value::Int(y)) => Ok(Value::Int(x.checked_rem(y).unwrap_or(0))),
+        (&Value::Float(_), _) | (_, &Value::Float(_)) =>
+            Ok(Value::Float(to_float(&try!(num("rem", a))) % to_float(&try!(num("rem", b))))),
+        _ => sub("rem", a, &try!(mul("rem", b, &q, true)), true),
+    }
+}
+
+/// Modulus, taking the sign of the divisor.
+fn modulo(a: &Value, b: &Value) -> Result<Value, Error> {
+    let m = try!(rem(a, b));
+    let zero = Value::Int(0);
+    if try!(compare(&m, &zero)) != Ordering::Equal &&
+        (try!(compare(&m, &zero)) == Ordering::Less) != (try!(compare(b, &zero)) == Ordering::Less)
+    {
+        add("mod", &m, b, true)
+    }
+    else {
+        Ok(m)
+    }
+}
+
+pub fn compare(a: &Value, b: &Value) -> Result<Ordering, Error> {
+    if let (&Value::Int(x), &Value::Int(y)) = (a, b) {
+        return Ok(x.cmp(&y));
+    }
+
+    let (a, b) = (try!(num("compare", a)), try!(num("compare", b)));
+    match rank(&a).max(rank(&b)) {
+        3 => match to_float(&a).partial_cmp(&to_float(&b)) {
+            Some(ordering) => Ok(ordering),
+            None => error(String::from("Can't compare NaN")),
+        },
+        2 => {
+            let ((an, ad), (bn, bd)) = (to_ratio(&a), to_ratio(&b));
+            Ok(an.mul(&bd).cmp(&bn.mul(&ad)))
+        },
+        _ => Ok(to_big(&a).cmp(&to_big(&b))),
+    }
+}
+
+fn fold(name: &str, args: Vec<Value>, identity: Value,
+        op: fn(&str, &Value, &Value, bool) -> Result<Value, Error>, promote: bool)
+        -> Result<Value, Error>
+{
+    let mut acc = identity;
+    for a in &args {
+        acc = try!(op(name, &acc, a, promote));
+    }
+    if args.len() == 1 {
+        try!(num(name, &acc));
+    }
+    Ok(acc)
+}
+
+fn fn_plus(expr: Vec<Value>) -> Result<Value, Error> {
+    fold("+", expr, Value::Int(0), add, false)
+}
+
+fn fn_plus_promoting(expr: Vec<Value>) -> Result<Value, Error> {
+    fold("+'", expr, Value::Int(0), add, true)
+}
+
+fn difference(name: &str, expr: Vec<Value>, promote: bool) -> Result<Value, Error> {
+    try!(arity(name, &expr, 1, MANY));
+    if expr.len() == 1 {
+        return negate(name, &expr[0], promote);
+    }
+
+    let mut acc = expr[0].clone();
+    for i in &expr[1..] {
+        acc = try!(sub(name, &acc, i, promote));
+    }
+    Ok(acc)
+}
+
+fn fn_min(expr: Vec<Value>) -> Result<Value, Error> {
+    difference("-", expr, false)
+}
+
+fn fn_min_promoting(expr: Vec<Value>) -> Result<Value, Error> {
+    difference("-'", expr, true)
+}
+
+fn fn_times(args: Vec<Value>) -> Result<Value, Error> {
+    fold("*", args, Value::Int(1), mul, false)
+}
+
+fn fn_times_promoting(args: Vec<Value>) -> Result<Value, Error> {
+    fold("*'", args, Value::Int(1), mul, true)
+}
+
+fn fn_divide(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("/", &args, 1, MANY));
+    if args.len() == 1 {
+        return div(&Value::Int(1), &args[0]);
+    }
+
+    let mut acc = args[0].clone();
+    for a in &args[1..] {
+        acc = try!(div(&acc, a));
+    }
+    Ok(acc)
+}
+
+fn fn_quot(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("quot", &args, 2, 2));
+    quot(&args[0], &args[1])
+}
+
+fn fn_rem(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("rem", &args, 2, 2));
+    rem(&args[0], &args[1])
+}
+
+fn fn_mod(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("mod", &args, 2, 2));
+    modulo(&args[0], &args[1])
+}
+
+fn fn_inc(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("inc", &args, 1, 1));
+    add("inc", &args[0], &Value::Int(1), false)
+}
+
+fn fn_inc_promoting(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("inc'", &args, 1, 1));
+    add("inc'", &args[0], &Value::Int(1), true)
+}
+
+fn fn_dec(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("dec", &args, 1, 1));
+    sub("dec", &args[0], &Value::Int(1), false)
+}
+
+fn fn_dec_promoting(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("dec'", &args, 1, 1));
+    sub("dec'", &args[0], &Value::Int(1), true)
+}
+
+fn extreme(name: &str, args: Vec<Value>, keep: Ordering) -> Result<Value, Error> {
+    try!(arity(name, &args, 1, MANY));
+    let mut args = args.into_iter();
+    let mut best = args.next().unwrap();
+    try!(num(name, &best));
+    for a in args {
+        if try!(compare(&a, &best)) == keep {
+            best = a;
+        }
+    }
+    Ok(best)
+}
+
+fn fn_min_of(args: Vec<Value>) -> Result<Value, Error> {
+    extreme("min", args, Ordering::Less)
+}
+
+fn fn_max_of(args: Vec<Value>) -> Result<Value, Error> {
+    extreme("max", args, Ordering::Greater)
+}
+
+fn fn_abs(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("abs", &args, 1, 1));
+    match try!(num("abs", &args[0])) {
+        Num::Float(x) => Ok(Value::Float(x.abs())),
+        _ if try!(compare(&args[0], &Value::Int(0))) == Ordering::Less => negate("abs", &args[0], false),
+        _ => Ok(args[0].clone()),
+    }
+}
+
+fn chain(name: &str, args: Vec<Value>, holds: fn(Ordering) -> bool) -> Result<Value, Error> {
+    try!(arity(name, &args, 1, MANY));
+    try!(num(name, &args[0]));
+    for w in args.windows(2) {
+        if !holds(try!(compare(&w[0], &w[1]))) {
+            return Ok(Value::Bool(false));
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+fn fn_num_equals(args: Vec<Value>) -> Result<Value, Error> {
+    chain("==", args, |o| o == Ordering::Equal)
+}
+
+fn fn_less(args: Vec<Value>) -> Result<Value, Error> {
+    chain("<", args, |o| o == Ordering::Less)
+}
+
+fn fn_greater(args: Vec<Value>) -> Result<Value, Error> {
+    chain(">", args, |o| o == Ordering::Greater)
+}
+
+fn fn_less_equal(args: Vec<Value>) -> Result<Value, Error> {
+    chain("<=", args, |o| o != Ordering::Greater)
+}
+
+fn fn_greater_equal(args: Vec<Value>) -> Result<Value, Error> {
+    chain(">=", args, |o| o != Ordering::Less)
+}
+
+fn sign_is(name: &str, args: Vec<Value>, sign: Ordering) -> Result<Value, Error> {
+    try!(arity(name, &args, 1, 1));
+    Ok(Value::Bool(try!(compare(&args[0], &Value::Int(0))) == sign))
+}
+
+fn fn_is_zero(args: Vec<Value>) -> Result<Value, Error> {
+    sign_is("zero?", args, Ordering::Equal)
+}
+
+fn fn_is_pos(args: Vec<Value>) -> Result<Value, Error> {
+    sign_is("pos?", args, Ordering::Greater)
+}
+
+fn fn_is_neg(args: Vec<Value>) -> Result<Value, Error> {
+    sign_is("neg?", args, Ordering::Less)
+}
+
+fn is_odd(name: &str, value: &Value) -> Result<bool, Error> {
+    match *value {
+        Value::Int(i) => Ok(i % 2 != 0),
+        Value::BigInt(ref b) => Ok(b.is_odd()),
+        ref other => error(format!("{} expects an integer, got {}", name, other.type_name())),
+    }
+}
+
+fn fn_is_even(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("even?", &args, 1, 1));
+    Ok(Value::Bool(!try!(is_odd("even?", &args[0]))))
+}
+
+fn fn_is_odd(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("odd?", &args, 1, 1));
+    Ok(Value::Bool(try!(is_odd("odd?", &args[0]))))
+}
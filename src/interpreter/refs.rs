@@ -0,0 +1,217 @@
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use super::apply;
+use super::core::{arity, MANY};
+use super::error::{Error, error};
+use super::value::{self, NativeFn, Value};
+
+/// A reference to a value that changes over time. Changes go through
+/// `swap!`, `reset!` and `compare-and-set!`, which check the validator and
+/// then notify the watches.
+pub struct Atom {
+    value: RefCell<Value>,
+    validator: RefCell<Value>,
+    watches: RefCell<Vec<(Value, Value)>>,
+}
+
+/// A bare mutable box, without validator or watches.
+pub struct Volatile {
+    value: RefCell<Value>,
+}
+
+impl Atom {
+    pub fn get(&self) -> Value {
+        self.value.borrow().clone()
+    }
+}
+
+impl Volatile {
+    pub fn get(&self) -> Value {
+        self.value.borrow().clone()
+    }
+}
+
+pub fn builtins() -> Vec<(&'static str, NativeFn)> {
+    vec!(
+        ("atom", fn_atom),
+        ("deref", fn_deref),
+        ("swap!", fn_swap),
+        ("reset!", fn_reset),
+        ("compare-and-set!", fn_compare_and_set),
+        ("set-validator!", fn_set_validator),
+        ("get-validator", fn_get_validator),
+        ("add-watch", fn_add_watch),
+        ("remove-watch", fn_remove_watch),
+        ("volatile!", fn_volatile),
+        ("vreset!", fn_vreset),
+        ("vswap!", fn_vswap),
+    )
+}
+
+fn atom_arg<'a>(name: &str, value: &'a Value) -> Result<&'a Rc<Atom>, Error> {
+    match *value {
+        Value::Atom(ref atom) => Ok(atom),
+        ref other => error(format!("{} expects an atom, got {}", name, other.type_name())),
+    }
+}
+
+fn volatile_arg<'a>(name: &str, value: &'a Value) -> Result<&'a Rc<Volatile>, Error> {
+    match *value {
+        Value::Volatile(ref volatile) => Ok(volatile),
+        ref other => error(format!("{} expects a volatile, got {}", name, other.type_name())),
+    }
+}
+
+fn validate(validator: &Value, value: &Value) -> Result<(), Error> {
+    if *validator == Value::Nil || try!(apply(validator, vec!(value.clone()))).is_truthy() {
+        Ok(())
+    }
+    else {
+        error(String::from("Invalid reference state"))
+    }
+}
+
+/// Validates and stores the new value, then calls the watches with the
+/// key, the atom, the old and the new value.
+fn set(reference: &Value, atom: &Atom, new: Value) -> Result<Value, Error> {
+    let validator = atom.validator.borrow().clone();
+    try!(validate(&validator, &new));
+
+    let old = mem::replace(&mut *atom.value.borrow_mut(), new.clone());
+    let watches = atom.watches.borrow().clone();
+    for (key, watch) in watches {
+        try!(apply(&watch, vec!(key, reference.clone(), old.clone(), new.clone())));
+    }
+    Ok(new)
+}
+
+/// `(atom x :validator f)`
+fn fn_atom(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("atom", &args, 1, MANY));
+    if args.len() % 2 != 1 {
+        return error(String::from("atom options must come in pairs"));
+    }
+
+    let mut validator = Value::Nil;
+    for option in args[1..].chunks(2) {
+        match option[0] {
+            Value::Keyword(ref k) if k == "validator" => validator = option[1].clone(),
+            ref other => return error(format!("Unsupported atom option: {}", other)),
+        }
+    }
+
+    try!(validate(&validator, &args[0]));
+    Ok(Value::Atom(Rc::new(Atom {
+        value: RefCell::new(args[0].clone()),
+        validator: RefCell::new(validator),
+        watches: RefCell::new(vec!()),
+    })))
+}
+
+fn fn_deref(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("deref", &args, 1, 1));
+    match args[0] {
+        Value::Atom(ref atom) => Ok(atom.get()),
+        Value::Volatile(ref volatile) => Ok(volatile.get()),
+        ref other => error(format!("deref expects a reference, got {}", other.type_name())),
+    }
+}
+
+/// `(swap! atom f args...)` sets the atom to `(f @atom args...)`. When f
+/// changes the atom itself, what it returned is stale and it's run again on
+/// the atom's new value.
+fn fn_swap(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("swap!", &args, 2, MANY));
+    let atom = try!(atom_arg("swap!", &args[0]));
+
+    loop {
+        let old = atom.get();
+        let mut f_args = vec!(old.clone());
+        f_args.extend(args[2..].iter().cloned());
+        let new = try!(apply(&args[1], f_args));
+        if value::identical(&atom.get(), &old) {
+            return set(&args[0], atom, new);
+        }
+    }
+}
+
+fn fn_reset(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("reset!", &args, 2, 2));
+    let atom = try!(atom_arg("reset!", &args[0]));
+    set(&args[0], atom, args[1].clone())
+}
+
+/// Sets the atom to new if its value is old, the same value and not just an
+/// equal one; returns whether it did.
+fn fn_compare_and_set(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("compare-and-set!", &args, 3, 3));
+    let atom = try!(atom_arg("compare-and-set!", &args[0]));
+    if !value::identical(&atom.get(), &args[1]) {
+        return Ok(Value::Bool(false));
+    }
+
+    try!(set(&args[0], atom, args[2].clone()));
+    Ok(Value::Bool(true))
+}
+
+fn fn_set_validator(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("set-validator!", &args, 2, 2));
+    let atom = try!(atom_arg("set-validator!", &args[0]));
+    try!(validate(&args[1], &atom.get()));
+    *atom.validator.borrow_mut() = args[1].clone();
+    Ok(Value::Nil)
+}
+
+fn fn_get_validator(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("get-validator", &args, 1, 1));
+    let atom = try!(atom_arg("get-validator", &args[0]));
+    let validator = atom.validator.borrow().clone();
+    Ok(validator)
+}
+
+/// `(add-watch atom key f)`; a watch added again under the same key
+/// replaces the earlier one.
+fn fn_add_watch(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("add-watch", &args, 3, 3));
+    {
+        let atom = try!(atom_arg("add-watch", &args[0]));
+        let mut watches = atom.watches.borrow_mut();
+        watches.retain(|&(ref key, _)| *key != args[1]);
+        watches.push((args[1].clone(), args[2].clone()));
+    }
+    Ok(args[0].clone())
+}
+
+fn fn_remove_watch(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("remove-watch", &args, 2, 2));
+    {
+        let atom = try!(atom_arg("remove-watch", &args[0]));
+        atom.watches.borrow_mut().retain(|&(ref key, _)| *key != args[1]);
+    }
+    Ok(args[0].clone())
+}
+
+fn fn_volatile(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("volatile!", &args, 1, 1));
+    Ok(Value::Volatile(Rc::new(Volatile { value: RefCell::new(args[0].clone()) })))
+}
+
+fn fn_vreset(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("vreset!", &args, 2, 2));
+    let volatile = try!(volatile_arg("vreset!", &args[0]));
+    *volatile.value.borrow_mut() = args[1].clone();
+    Ok(args[1].clone())
+}
+
+fn fn_vswap(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("vswap!", &args, 2, MANY));
+    let volatile = try!(volatile_arg("vswap!", &args[0]));
+
+    let mut f_args = vec!(volatile.get());
+    f_args.extend(args[2..].iter().cloned());
+    let new = try!(apply(&args[1], f_args));
+    *volatile.value.borrow_mut() = new.clone();
+    Ok(new)
+}
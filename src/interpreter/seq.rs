@@ -0,0 +1,377 @@
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use super::check_interrupt;
+use super::error::{Error, error};
+use super::list::List;
+use super::value::Value;
+use super::vector::Vector;
+
+/// Number of elements realized at once by chunked lazy seqs.
+pub const CHUNK_SIZE: usize = 32;
+
+pub struct Cons {
+    first: Value,
+    rest: Value,
+}
+
+pub struct Chunk {
+    items: Rc<Vec<Value>>,
+    more: Value,
+}
+
+/// A non-empty seq: a cursor over the elements of some collection.
+#[derive(Clone)]
+pub enum Seq {
+    List(List),
+    Vector(Vector, usize),
+    Chars(Rc<Vec<char>>, usize),
+    Items(Rc<Vec<Value>>, usize),
+    Cons(Rc<Cons>),
+    Chunked(Rc<Chunk>, usize),
+}
+
+pub type Thunk = Box<FnMut() -> Result<Value, Error>>;
+
+enum LazyState {
+    Pending(Thunk),
+    Realizing,
+    Realized(Option<Seq>),
+    Failed(Error),
+}
+
+/// A seq whose contents are computed by a thunk on first use. Once realized
+/// the thunk is dropped, so it no longer holds on to what it was built from.
+/// A thunk that fails is dropped as well, and its error is rethrown on every
+/// later use; thunks may have consumed their input and cannot be rerun. An
+/// interrupted thunk is kept, and those that consume their input put back
+/// what's left of it.
+pub struct LazySeq {
+    state: RefCell<LazyState>,
+}
+
+impl Seq {
+    pub fn first(&self) -> Value {
+        match *self {
+            Seq::List(ref list) => list.first().cloned().unwrap_or(Value::Nil),
+            Seq::Vector(ref vector, i) => vector.get(i).unwrap_or(Value::Nil),
+            Seq::Chars(ref chars, i) => Value::Char(chars[i]),
+            Seq::Items(ref items, i) => items[i].clone(),
+            Seq::Cons(ref cons) => cons.first.clone(),
+            Seq::Chunked(ref chunk, i) => chunk.items[i].clone(),
+        }
+    }
+
+    /// Whether both are the very same seq, at the same place in it.
+    pub fn is_identical(&self, other: &Seq) -> bool {
+        fn same<T>(a: &Rc<T>, b: &Rc<T>) -> bool {
+            &**a as *const T == &**b as *const T
+        }
+
+        match (self, other) {
+            (&Seq::List(ref a), &Seq::List(ref b)) => a.is_identical(b),
+            (&Seq::Vector(ref a, i), &Seq::Vector(ref b, j)) => i == j && a.is_identical(b),
+            (&Seq::Chars(ref a, i), &Seq::Chars(ref b, j)) => i == j && same(a, b),
+            (&Seq::Items(ref a, i), &Seq::Items(ref b, j)) => i == j && same(a, b),
+            (&Seq::Cons(ref a), &Seq::Cons(ref b)) => same(a, b),
+            (&Seq::Chunked(ref a, i), &Seq::Chunked(ref b, j)) => i == j && same(a, b),
+            _ => false,
+        }
+    }
+
+    /// The seq after the first element, None when exhausted.
+    pub fn next(&self) -> Result<Option<Seq>, Error> {
+        let next = match *self {
+            Seq::List(ref list) => {
+                let rest = list.rest();
+                if rest.is_empty() { None } else { Some(Seq::List(rest)) }
+            },
+            Seq::Vector(ref vector, i) =>
+                if i + 1 < vector.len() { Some(Seq::Vector(vector.clone(), i + 1)) } else { None },
+            Seq::Chars(ref chars, i) =>
+                if i + 1 < chars.len() { Some(Seq::Chars(chars.clone(), i + 1)) } else { None },
+            Seq::Items(ref items, i) =>
+                if i + 1 < items.len() { Some(Seq::Items(items.clone(), i + 1)) } else { None },
+            Seq::Cons(ref cons) => try!(seq(&cons.rest)),
+            Seq::Chunked(ref chunk, i) =>
+                if i + 1 < chunk.items.len() {
+                    Some(Seq::Chunked(chunk.clone(), i + 1))
+                }
+                else {
+                    try!(seq(&chunk.more))
+                },
+        };
+        Ok(next)
+    }
+
+    /// Like `next`, but an exhausted seq is the empty list rather than nil.
+    pub fn rest(&self) -> Result<Value, Error> {
+        match *self {
+            Seq::Cons(ref cons) => return Ok(cons.rest.clone()),
+            Seq::Chunked(ref chunk, i) if i + 1 == chunk.items.len() => return Ok(chunk.more.clone()),
+            _ => (),
+        }
+
+        match try!(self.next()) {
+            Some(seq) => Ok(Value::Seq(seq)),
+            None => Ok(Value::List(List::new())),
+        }
+    }
+
+    /// For seqs backed by arrays: the elements up to the end of the current
+    /// chunk, and what follows them.
+    pub fn chunk(&self) -> Option<(Vec<Value>, Value)> {
+        fn remainder(seq: Option<Seq>) -> Value {
+            match seq {
+                Some(seq) => Value::Seq(seq),
+                None => Value::List(List::new()),
+            }
+        }
+
+        match *self {
+            Seq::Vector(ref vector, i) => {
+                let (start, leaf) = vector.chunk_for(i).unwrap();
+                let end = start + leaf.len();
+                let rest = if end < vector.len() { Some(Seq::Vector(vector.clone(), end)) } else { None };
+                Some((leaf[i - start..].to_vec(), remainder(rest)))
+            },
+            Seq::Items(ref items, i) => {
+                let end = if i + CHUNK_SIZE < items.len() { i + CHUNK_SIZE } else { items.len() };
+                let rest = if end < items.len() { Some(Seq::Items(items.clone(), end)) } else { None };
+                Some((items[i..end].to_vec(), remainder(rest)))
+            },
+            Seq::Chunked(ref chunk, i) => Some((chunk.items[i..].to_vec(), chunk.more.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl LazySeq {
+    pub fn is_realized(&self) -> bool {
+        match *self.state.borrow() {
+            LazyState::Realized(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn realize(&self) -> Result<Option<Seq>, Error> {
+        let state = mem::replace(&mut *self.state.borrow_mut(), LazyState::Realizing);
+        let mut thunk = match state {
+            LazyState::Realized(seq) => {
+                *self.state.borrow_mut() = LazyState::Realized(seq.clone());
+                return Ok(seq);
+            },
+            LazyState::Failed(e) => {
+                *self.state.borrow_mut() = LazyState::Failed(e.clone());
+                return Err(e);
+            },
+            LazyState::Realizing => return error(String::from("Lazy seq realized while realizing itself")),
+            LazyState::Pending(thunk) => thunk,
+        };
+
+        // a thunk running builtins only, as range's does, evaluates nothing
+        // that would notice an interrupt
+        let realized = match check_interrupt().and_then(|_| thunk()) {
+            Ok(value) => seq(&value),
+            Err(e) => Err(e),
+        };
+
+        *self.state.borrow_mut() = match realized {
+            Ok(ref seq) => LazyState::Realized(seq.clone()),
+            // the evaluation was stopped, not the thunk wrong; it's run
+            // again on the next use
+            Err(ref e) if e.interrupt => LazyState::Pending(thunk),
+            Err(ref e) => LazyState::Failed(e.clone()),
+        };
+        realized
+    }
+}
+
+impl Drop for LazySeq {
+    // a long realized chain would otherwise be dropped recursively, one
+    // stack frame per cell
+    fn drop(&mut self) {
+        fn take_rest(state: &mut LazyState) -> Option<Value> {
+            match mem::replace(state, LazyState::Realized(None)) {
+                LazyState::Realized(Some(Seq::Cons(cons))) => match Rc::try_unwrap(cons) {
+                    Ok(cons) => Some(cons.rest),
+                    Err(_) => None,
+                },
+                LazyState::Realized(Some(Seq::Chunked(chunk, _))) => match Rc::try_unwrap(chunk) {
+                    Ok(chunk) => Some(chunk.more),
+                    Err(_) => None,
+                },
+                _ => None,
+            }
+        }
+
+        let mut next = take_rest(self.state.get_mut());
+        while let Some(Value::LazySeq(lazy)) = next {
+            next = match Rc::try_unwrap(lazy) {
+                Ok(mut lazy) => take_rest(lazy.state.get_mut()),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+pub fn lazy<F>(thunk: F) -> Value
+    where F: FnMut() -> Result<Value, Error> + 'static
+{
+    Value::LazySeq(Rc::new(LazySeq { state: RefCell::new(LazyState::Pending(Box::new(thunk))) }))
+}
+
+/// A lazy seq stepping through `input`, which the thunk gives up while the
+/// step walks it, so it doesn't hold on to what's walked past. The step
+/// leaves in `input` where it got to; when it fails, the seq is retried
+/// from there if realizing it is, as after an interrupt.
+pub fn lazy_walk<T, F>(input: T, mut step: F) -> Value
+    where T: 'static, F: FnMut(&mut T) -> Result<Value, Error> + 'static
+{
+    let mut input = Some(input);
+    lazy(move || {
+        let mut current = input.take().expect("lazy seq stepped after it was realized");
+        let result = step(&mut current);
+        if result.is_err() {
+            input = Some(current);
+        }
+        result
+    })
+}
+
+pub fn cons(first: Value, rest: Value) -> Value {
+    Value::Seq(Seq::Cons(Rc::new(Cons { first: first, rest: rest })))
+}
+
+pub fn chunked(items: Rc<Vec<Value>>, more: Value) -> Value {
+    if items.is_empty() {
+        more
+    }
+    else {
+        Value::Seq(Seq::Chunked(Rc::new(Chunk { items: items, more: more }), 0))
+    }
+}
+
+/// Values as a seq; no values is the empty list.
+pub fn from_vec(items: Vec<Value>) -> Value {
+    if items.is_empty() {
+        Value::List(List::new())
+    }
+    else {
+        Value::Seq(Seq::Items(Rc::new(items), 0))
+    }
+}
+
+/// The seq over a collection, None when it is empty.
+pub fn seq(coll: &Value) -> Result<Option<Seq>, Error> {
+    let seq = match *coll {
+        Value::Nil => None,
+        Value::Seq(ref seq) => Some(seq.clone()),
+        Value::LazySeq(ref lazy) => try!(lazy.realize()),
+        Value::List(ref list) =>
+            if list.is_empty() { None } else { Some(Seq::List(list.clone())) },
+        Value::Vector(ref vector) =>
+            if vector.is_empty() { None } else { Some(Seq::Vector(vector.clone(), 0)) },
+        Value::Str(ref s) =>
+            if s.is_empty() { None } else { Some(Seq::Chars(Rc::new(s.chars().collect()), 0)) },
+        Value::Map(ref map) => {
+            let entries: Vec<Value> = map.iter()
+                .map(|(k, v)| Value::Vector(Vector::from_vec(vec!(k.clone(), v.clone()))))
+                .collect();
+            if entries.is_empty() { None } else { Some(Seq::Items(Rc::new(entries), 0)) }
+        },
+        Value::Set(ref set) => {
+            let items: Vec<Value> = set.iter().cloned().collect();
+            if items.is_empty() { None } else { Some(Seq::Items(Rc::new(items), 0)) }
+        },
+        ref other => return error(format!("Don't know how to create seq from: {}", other.type_name())),
+    };
+    Ok(seq)
+}
+
+pub fn iter(coll: &Value) -> Result<Iter, Error> {
+    Ok(Iter { current: try!(seq(coll)), advance: false })
+}
+
+/// Iterates a seq the caller gives up, so the elements already visited can
+/// be freed while iterating.
+pub fn into_iter(coll: Value) -> Result<Iter, Error> {
+    iter(&coll)
+}
+
+pub fn to_vec(coll: &Value) -> Result<Vec<Value>, Error> {
+    let mut items = Vec::new();
+    for item in try!(iter(coll)) {
+        items.push(try!(item));
+    }
+    Ok(items)
+}
+
+/// Walks a seq without holding on to its head. The seq only advances when
+/// the next element is asked for, so nothing past it gets realized. Builtins
+/// loop over seqs with one, so it checks for an interrupt at every element.
+pub struct Iter {
+    current: Option<Seq>,
+    advance: bool,
+}
+
+impl Iterator for Iter {
+    type Item = Result<Value, Error>;
+
+    fn next(&mut self) -> Option<Result<Value, Error>> {
+        if let Err(e) = check_interrupt() {
+            return Some(Err(e));
+        }
+
+        if self.advance {
+            let current = match self.current.take() {
+                Some(current) => current,
+                None => return None,
+            };
+            match current.next() {
+                Ok(next) => self.current = next,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        self.advance = true;
+        self.current.as_ref().map(|s| Ok(s.first()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::{cons, into_iter, lazy};
+    use super::super::value::Value;
+
+    fn naturals(from: i64, realized: Rc<Cell<i64>>) -> Value {
+        lazy(move || {
+            realized.set(realized.get() + 1);
+            Ok(cons(Value::Int(from), naturals(from + 1, realized.clone())))
+        })
+    }
+
+    #[test]
+    fn realizes_on_demand() {
+        let realized = Rc::new(Cell::new(0));
+        let numbers = naturals(0, realized.clone());
+        assert_eq!(0, realized.get());
+
+        let firsts: Vec<Value> = into_iter(numbers).unwrap().take(3).map(|v| v.unwrap()).collect();
+        assert_eq!(vec!(Value::Int(0), Value::Int(1), Value::Int(2)), firsts);
+        assert_eq!(3, realized.get());
+    }
+
+    #[test]
+    fn long_chains_do_not_overflow() {
+        let realized = Rc::new(Cell::new(0));
+        let head = naturals(0, realized.clone());
+        let last = into_iter(head.clone()).unwrap().take(1000000).last().unwrap().unwrap();
+        assert_eq!(Value::Int(999999), last);
+        drop(head);
+    }
+}
@@ -0,0 +1,558 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use super::apply;
+use super::core::{arity, conj, int_arg, MANY};
+use super::error::{Error, error};
+use super::hashmap::Map;
+use super::seq;
+use super::value::{self, NativeFn, Value};
+use super::vector::Vector;
+
+pub fn builtins() -> Vec<(&'static str, NativeFn)> {
+    vec!(
+        ("map", fn_map),
+        ("filter", fn_filter),
+        ("remove", fn_remove),
+        ("reduce", fn_reduce),
+        ("range", fn_range),
+        ("iterate", fn_iterate),
+        ("repeat", fn_repeat),
+        ("cycle", fn_cycle),
+        ("take", fn_take),
+        ("drop", fn_drop),
+        ("partition", fn_partition),
+        ("group-by", fn_group_by),
+        ("frequencies", fn_frequencies),
+        ("sort", fn_sort),
+        ("sort-by", fn_sort_by),
+        ("into", fn_into),
+        ("concat", fn_concat),
+        ("interleave", fn_interleave),
+        ("mapcat", fn_mapcat),
+    )
+}
+
+fn fn_map(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("map", &args, 2, MANY));
+    let mut args = args.into_iter();
+    let f = args.next().unwrap();
+    let colls: Vec<Value> = args.collect();
+
+    if colls.len() == 1 {
+        Ok(lazy_map(f, colls.into_iter().next().unwrap()))
+    }
+    else {
+        Ok(lazy_map_n(f, colls))
+    }
+}
+
+fn lazy_map(f: Value, coll: Value) -> Value {
+    seq::lazy_walk(coll, move |coll| {
+        let s = match try!(seq::seq(coll)) {
+            Some(s) => s,
+            None => return Ok(Value::Nil),
+        };
+
+        if let Some((chunk, rest)) = s.chunk() {
+            let mut mapped = Vec::with_capacity(chunk.len());
+            for item in chunk {
+                mapped.push(try!(apply(&f, vec!(item))));
+            }
+            Ok(seq::chunked(Rc::new(mapped), lazy_map(f.clone(), rest)))
+        }
+        else {
+            let first = try!(apply(&f, vec!(s.first())));
+            Ok(seq::cons(first, lazy_map(f.clone(), try!(s.rest()))))
+        }
+    })
+}
+
+fn lazy_map_n(f: Value, colls: Vec<Value>) -> Value {
+    seq::lazy_walk(colls, move |colls| {
+        let mut firsts = Vec::new();
+        let mut rests = Vec::new();
+        for c in colls.iter() {
+            match try!(seq::seq(c)) {
+                Some(s) => {
+                    firsts.push(s.first());
+                    rests.push(try!(s.rest()));
+                },
+                None => return Ok(Value::Nil),
+            }
+        }
+
+        let first = try!(apply(&f, firsts));
+        Ok(seq::cons(first, lazy_map_n(f.clone(), rests)))
+    })
+}
+
+fn lazy_filter(pred: Value, coll: Value, keep: bool) -> Value {
+    // skipped elements are dropped as we go, not kept alive by the thunk
+    seq::lazy_walk(coll, move |current| {
+        loop {
+            let s = match try!(seq::seq(current)) {
+                Some(s) => s,
+                None => return Ok(Value::Nil),
+            };
+
+            if let Some((chunk, rest)) = s.chunk() {
+                let mut kept = Vec::new();
+                for item in chunk {
+                    if try!(apply(&pred, vec!(item.clone()))).is_truthy() == keep {
+                        kept.push(item);
+                    }
+                }
+                if !kept.is_empty() {
+                    return Ok(seq::chunked(Rc::new(kept), lazy_filter(pred.clone(), rest, keep)));
+                }
+                *current = rest;
+            }
+            else {
+                let item = s.first();
+                let rest = try!(s.rest());
+                if try!(apply(&pred, vec!(item.clone()))).is_truthy() == keep {
+                    return Ok(seq::cons(item, lazy_filter(pred.clone(), rest, keep)));
+                }
+                *current = rest;
+            }
+        }
+    })
+}
+
+fn fn_filter(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("filter", &args, 2, 2));
+    let mut args = args.into_iter();
+    Ok(lazy_filter(args.next().unwrap(), args.next().unwrap(), true))
+}
+
+fn fn_remove(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("remove", &args, 2, 2));
+    let mut args = args.into_iter();
+    Ok(lazy_filter(args.next().unwrap(), args.next().unwrap(), false))
+}
+
+fn fn_reduce(mut args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("reduce", &args, 2, 3));
+    let coll = args.pop().unwrap();
+    let f = &args[0];
+    let mut items = try!(seq::into_iter(coll));
+
+    let mut acc = if args.len() == 2 {
+        args[1].clone()
+    }
+    else {
+        match items.next() {
+            Some(first) => try!(first),
+            None => return apply(f, vec!()),
+        }
+    };
+
+    for item in items {
+        acc = try!(apply(f, vec!(acc, try!(item))));
+    }
+    Ok(acc)
+}
+
+#[derive(Clone, Copy)]
+enum Step {
+    Int(i64, Option<i64>, i64),
+    Float(f64, Option<f64>, f64),
+}
+
+fn lazy_range(step: Step) -> Value {
+    seq::lazy(move || {
+        let mut chunk = Vec::with_capacity(seq::CHUNK_SIZE);
+        let mut next = step;
+        while chunk.len() < seq::CHUNK_SIZE {
+            next = match next {
+                Step::Int(i, end, by) => {
+                    let done = match end {
+                        Some(end) => (by > 0 && i >= end) || (by < 0 && i <= end) || by == 0,
+                        None => false,
+                    };
+                    if done {
+                        break;
+                    }
+                    chunk.push(Value::Int(i));
+                    match i.checked_add(by) {
+                        Some(i) => Step::Int(i, end, by),
+                        // nothing past the end of i64 can be in the range
+                        None => {
+                            next = Step::Int(i, Some(i), by);
+                            break;
+                        },
+                    }
+                },
+                Step::Float(x, end, by) => {
+                    let done = match end {
+                        Some(end) => (by > 0.0 && x >= end) || (by < 0.0 && x <= end) || by == 0.0,
+                        None => false,
+                    };
+                    if done {
+                        break;
+                    }
+                    chunk.push(Value::Float(x));
+                    Step::Float(x + by, end, by)
+                }
+            };
+        }
+
+        if chunk.len() < seq::CHUNK_SIZE {
+            Ok(seq::chunked(Rc::new(chunk), Value::Nil))
+        }
+        else {
+            Ok(seq::chunked(Rc::new(chunk), lazy_range(next)))
+        }
+    })
+}
+
+fn number_arg(name: &str, value: &Value) -> Result<f64, Error> {
+    match *value {
+        Value::Int(i) => Ok(i as f64),
+        Value::Float(x) => Ok(x),
+        ref other => error(format!("{} expects a number, got {}", name, other.type_name())),
+    }
+}
+
+fn fn_range(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("range", &args, 0, 3));
+    let (start, end, step) = match args.len() {
+        0 => (Value::Int(0), None, Value::Int(1)),
+        1 => (Value::Int(0), Some(args[0].clone()), Value::Int(1)),
+        2 => (args[0].clone(), Some(args[1].clone()), Value::Int(1)),
+        _ => (args[0].clone(), Some(args[1].clone()), args[2].clone()),
+    };
+
+    match (start, end, step) {
+        (Value::Int(start), None, Value::Int(step)) =>
+            Ok(lazy_range(Step::Int(start, None, step))),
+        (Value::Int(start), Some(Value::Int(end)), Value::Int(step)) =>
+            Ok(lazy_range(Step::Int(start, Some(end), step))),
+        (start, end, step) => {
+            let end = match end {
+                Some(end) => Some(try!(number_arg("range", &end))),
+                None => None,
+            };
+            Ok(lazy_range(Step::Float(try!(number_arg("range", &start)), end,
+                                      try!(number_arg("range", &step)))))
+        }
+    }
+}
+
+fn fn_iterate(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("iterate", &args, 2, 2));
+    let mut args = args.into_iter();
+    let f = args.next().unwrap();
+    let x = args.next().unwrap();
+    Ok(seq::cons(x.clone(), lazy_iterate(f, x)))
+}
+
+fn lazy_iterate(f: Value, x: Value) -> Value {
+    seq::lazy(move || {
+        let next = try!(apply(&f, vec!(x.clone())));
+        Ok(seq::cons(next.clone(), lazy_iterate(f.clone(), next)))
+    })
+}
+
+fn lazy_repeat(x: Value, remaining: Option<usize>) -> Value {
+    seq::lazy(move || {
+        let n = match remaining {
+            Some(n) if n < seq::CHUNK_SIZE => n,
+            _ => seq::CHUNK_SIZE,
+        };
+        let chunk = Rc::new(vec!(x.clone(); n));
+        let more = match remaining {
+            Some(left) if left <= n => Value::Nil,
+            Some(left) => lazy_repeat(x.clone(), Some(left - n)),
+            None => lazy_repeat(x.clone(), None),
+        };
+        Ok(seq::chunked(chunk, more))
+    })
+}
+
+fn fn_repeat(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("repeat", &args, 1, 2));
+    if args.len() == 1 {
+        Ok(lazy_repeat(args[0].clone(), None))
+    }
+    else {
+        Ok(lazy_repeat(args[1].clone(), Some(try!(count_arg("repeat", &args[0])))))
+    }
+}
+
+/// Walks `current` and starts over from `coll` whenever it runs out.
+fn lazy_cycle(current: Value, coll: Value) -> Value {
+    seq::lazy(move || {
+        let s = match try!(seq::seq(&current)) {
+            Some(s) => s,
+            None => match try!(seq::seq(&coll)) {
+                Some(s) => s,
+                None => return Ok(Value::Nil),
+            },
+        };
+
+        if let Some((chunk, rest)) = s.chunk() {
+            Ok(seq::chunked(Rc::new(chunk), lazy_cycle(rest, coll.clone())))
+        }
+        else {
+            Ok(seq::cons(s.first(), lazy_cycle(try!(s.rest()), coll.clone())))
+        }
+    })
+}
+
+fn fn_cycle(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("cycle", &args, 1, 1));
+    let coll = args.into_iter().next().unwrap();
+    Ok(lazy_cycle(coll.clone(), coll))
+}
+
+fn count_arg(name: &str, value: &Value) -> Result<usize, Error> {
+    let n = try!(int_arg(name, value));
+    Ok(if n < 0 { 0 } else { n as usize })
+}
+
+fn lazy_take(n: usize, coll: Value) -> Value {
+    seq::lazy_walk(coll, move |coll| {
+        if n == 0 {
+            return Ok(Value::Nil);
+        }
+        match try!(seq::seq(coll)) {
+            Some(s) => Ok(seq::cons(s.first(), lazy_take(n - 1, try!(s.rest())))),
+            None => Ok(Value::Nil),
+        }
+    })
+}
+
+fn fn_take(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("take", &args, 2, 2));
+    let n = try!(count_arg("take", &args[0]));
+    Ok(lazy_take(n, args.into_iter().nth(1).unwrap()))
+}
+
+fn fn_drop(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("drop", &args, 2, 2));
+    let mut n = try!(count_arg("drop", &args[0]));
+    Ok(seq::lazy_walk(args.into_iter().nth(1).unwrap(), move |current| {
+        // counted down as we go, so a retry drops only what's left
+        while n > 0 {
+            *current = match try!(seq::seq(current)) {
+                Some(s) => try!(s.rest()),
+                None => break,
+            };
+            n -= 1;
+        }
+        Ok(current.clone())
+    }))
+}
+
+fn lazy_partition(n: usize, step: usize, pad: Option<Rc<Vec<Value>>>, coll: Value) -> Value {
+    seq::lazy(move || {
+        let mut part = Vec::with_capacity(n);
+        let mut current = try!(seq::seq(&coll));
+        while part.len() < n {
+            current = match current {
+                Some(s) => {
+                    part.push(s.first());
+                    try!(s.next())
+                },
+                None => break,
+            };
+        }
+
+        if part.len() < n {
+            return Ok(match pad {
+                Some(ref pad) if !part.is_empty() => {
+                    part.extend(pad.iter().take(n - part.len()).cloned());
+                    seq::cons(seq::from_vec(part), Value::Nil)
+                },
+                _ => Value::Nil,
+            });
+        }
+
+        let mut rest = try!(seq::seq(&coll));
+        for _ in 0..step {
+            rest = match rest {
+                Some(s) => try!(s.next()),
+                None => break,
+            };
+        }
+        let rest = match rest {
+            Some(s) => Value::Seq(s),
+            None => Value::Nil,
+        };
+        Ok(seq::cons(seq::from_vec(part), lazy_partition(n, step, pad.clone(), rest)))
+    })
+}
+
+fn fn_partition(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("partition", &args, 2, 4));
+    let n = try!(count_arg("partition", &args[0]));
+    let step = if args.len() > 2 { try!(count_arg("partition", &args[1])) } else { n };
+    let pad = if args.len() == 4 { Some(Rc::new(try!(seq::to_vec(&args[2])))) } else { None };
+
+    if n == 0 || step == 0 {
+        return error(String::from("partition requires a positive size and step"));
+    }
+    Ok(lazy_partition(n, step, pad, args.last().unwrap().clone()))
+}
+
+fn fn_group_by(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("group-by", &args, 2, 2));
+    let mut groups = Map::new();
+    for item in try!(seq::iter(&args[1])) {
+        let item = try!(item);
+        let key = try!(apply(&args[0], vec!(item.clone())));
+        try!(key.check_key());
+        let group = match groups.get(&key) {
+            Some(&Value::Vector(ref group)) => group.conj(item),
+            _ => Vector::from_vec(vec!(item)),
+        };
+        groups = groups.assoc(key, Value::Vector(group));
+    }
+    Ok(Value::Map(groups))
+}
+
+fn fn_frequencies(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("frequencies", &args, 1, 1));
+    let mut counts = Map::new();
+    for item in try!(seq::iter(&args[0])) {
+        let item = try!(item);
+        try!(item.check_key());
+        let count = match counts.get(&item) {
+            Some(&Value::Int(count)) => count + 1,
+            _ => 1,
+        };
+        counts = counts.assoc(item, Value::Int(count));
+    }
+    Ok(Value::Map(counts))
+}
+
+/// Ordering from a user comparator, which returns either a number or a
+/// boolean meaning "less than".
+fn compare_with(comparator: &Value, a: &Value, b: &Value) -> Result<Ordering, Error> {
+    match try!(apply(comparator, vec!(a.clone(), b.clone()))) {
+        Value::Int(i) => Ok(i.cmp(&0)),
+        Value::Float(x) => Ok(x.partial_cmp(&0.0).unwrap_or(Ordering::Equal)),
+        Value::Bool(true) => Ok(Ordering::Less),
+        Value::Bool(false) | Value::Nil => {
+            if try!(apply(comparator, vec!(b.clone(), a.clone()))).is_truthy() {
+                Ok(Ordering::Greater)
+            }
+            else {
+                Ok(Ordering::Equal)
+            }
+        },
+        other => error(format!("Comparator returned {}", other)),
+    }
+}
+
+fn sort_values(keys: Vec<(Value, Value)>, comparator: Option<&Value>) -> Result<Value, Error> {
+    let mut keys = keys;
+    let mut failure = None;
+    keys.sort_by(|a, b| {
+        let ordering = match comparator {
+            Some(comparator) => compare_with(comparator, &a.0, &b.0),
+            None => value::compare(&a.0, &b.0),
+        };
+        match ordering {
+            Ok(ordering) => ordering,
+            Err(e) => {
+                if failure.is_none() {
+                    failure = Some(e);
+                }
+                Ordering::Equal
+            }
+        }
+    });
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(seq::from_vec(keys.into_iter().map(|(_, v)| v).collect())),
+    }
+}
+
+fn fn_sort(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("sort", &args, 1, 2));
+    let items = try!(seq::to_vec(args.last().unwrap()));
+    let keyed = items.into_iter().map(|v| (v.clone(), v)).collect();
+    sort_values(keyed, if args.len() == 2 { Some(&args[0]) } else { None })
+}
+
+fn fn_sort_by(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("sort-by", &args, 2, 3));
+    let mut keyed = Vec::new();
+    for item in try!(seq::iter(args.last().unwrap())) {
+        let item = try!(item);
+        keyed.push((try!(apply(&args[0], vec!(item.clone()))), item));
+    }
+    sort_values(keyed, if args.len() == 3 { Some(&args[1]) } else { None })
+}
+
+fn fn_into(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("into", &args, 2, 2));
+    let mut to = args[0].clone();
+    for item in try!(seq::iter(&args[1])) {
+        to = try!(conj(to, try!(item)));
+    }
+    Ok(to)
+}
+
+/// Elements of `current`, then of each collection in the seq `colls`.
+fn lazy_concat(current: Value, colls: Value) -> Value {
+    seq::lazy(move || {
+        let mut current = current.clone();
+        let mut colls = colls.clone();
+        loop {
+            if let Some(s) = try!(seq::seq(&current)) {
+                if let Some((chunk, rest)) = s.chunk() {
+                    return Ok(seq::chunked(Rc::new(chunk), lazy_concat(rest, colls)));
+                }
+                return Ok(seq::cons(s.first(), lazy_concat(try!(s.rest()), colls)));
+            }
+
+            match try!(seq::seq(&colls)) {
+                Some(s) => {
+                    current = s.first();
+                    colls = try!(s.rest());
+                },
+                None => return Ok(Value::Nil),
+            }
+        }
+    })
+}
+
+fn fn_concat(args: Vec<Value>) -> Result<Value, Error> {
+    Ok(lazy_concat(Value::Nil, seq::from_vec(args)))
+}
+
+fn lazy_interleave(colls: Vec<Value>) -> Value {
+    seq::lazy(move || {
+        if colls.is_empty() {
+            return Ok(Value::Nil);
+        }
+
+        let mut firsts = Vec::with_capacity(colls.len());
+        let mut rests = Vec::with_capacity(colls.len());
+        for c in &colls {
+            match try!(seq::seq(c)) {
+                Some(s) => {
+                    firsts.push(s.first());
+                    rests.push(try!(s.rest()));
+                },
+                None => return Ok(Value::Nil),
+            }
+        }
+        Ok(seq::chunked(Rc::new(firsts), lazy_interleave(rests)))
+    })
+}
+
+fn fn_interleave(args: Vec<Value>) -> Result<Value, Error> {
+    Ok(lazy_interleave(args))
+}
+
+fn fn_mapcat(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("mapcat", &args, 2, MANY));
+    let colls = try!(fn_map(args));
+    Ok(lazy_concat(Value::Nil, colls))
+}
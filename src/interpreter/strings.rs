@@ -0,0 +1,382 @@
+use std::iter;
+
+use super::core::{arity, int_arg, MANY};
+use super::error::{Error, error};
+use super::io::print_str;
+use super::seq;
+use super::value::{NativeFn, Value};
+use super::vector::Vector;
+
+// Strings are indexed and counted by chars throughout, never by bytes.
+
+/// The namespace holding the string library.
+pub const STRING: &'static str = "fancy.string";
+
+pub fn builtins() -> Vec<(&'static str, NativeFn)> {
+    vec!(
+        ("str", fn_str),
+        ("subs", fn_subs),
+        ("format", fn_format),
+    )
+}
+
+/// The functions of `fancy.string`.
+pub fn string_builtins() -> Vec<(&'static str, NativeFn)> {
+    vec!(
+        ("split", fn_split),
+        ("join", fn_join),
+        ("trim", fn_trim),
+        ("triml", fn_triml),
+        ("trimr", fn_trimr),
+        ("upper-case", fn_upper_case),
+        ("lower-case", fn_lower_case),
+        ("replace", fn_replace),
+        ("starts-with?", fn_starts_with),
+        ("ends-with?", fn_ends_with),
+        ("includes?", fn_includes),
+        ("index-of", fn_index_of),
+        ("blank?", fn_blank),
+    )
+}
+
+fn string_arg<'a>(name: &str, value: &'a Value) -> Result<&'a str, Error> {
+    match *value {
+        Value::Str(ref s) => Ok(s),
+        ref other => error(format!("{} expects a string, got {}", name, other.type_name())),
+    }
+}
+
+/// A string or char argument, as a string.
+fn text_arg(name: &str, value: &Value) -> Result<String, Error> {
+    match *value {
+        Value::Str(ref s) => Ok(s.clone()),
+        Value::Char(c) => Ok(c.to_string()),
+        ref other => error(format!("{} expects a string or char, got {}", name, other.type_name())),
+    }
+}
+
+/// The byte offset of the char at index i, which may be one past the end.
+fn byte_offset(s: &str, i: usize) -> Option<usize> {
+    s.char_indices().map(|(offset, _)| offset).chain(iter::once(s.len())).nth(i)
+}
+
+fn char_index(s: &str, offset: usize) -> usize {
+    s[..offset].chars().count()
+}
+
+/// Text of a value as `str` sees it, where nil is the empty string.
+fn str_of(value: &Value) -> Result<String, Error> {
+    match *value {
+        Value::Nil => Ok(String::new()),
+        ref other => print_str(other),
+    }
+}
+
+fn fn_str(args: Vec<Value>) -> Result<Value, Error> {
+    let mut text = String::new();
+    for arg in &args {
+        text.push_str(&try!(str_of(arg)));
+    }
+    Ok(Value::Str(text))
+}
+
+/// `(subs s start end?)`
+fn fn_subs(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("subs", &args, 2, 3));
+    let s = try!(string_arg("subs", &args[0]));
+    let len = s.chars().count() as i64;
+    let start = try!(int_arg("subs", &args[1]));
+    let end = match args.get(2) {
+        Some(end) => try!(int_arg("subs", end)),
+        None => len,
+    };
+    if start < 0 || end > len || start > end {
+        return error(format!("String index out of range: {} to {} of {}", start, end, len));
+    }
+    Ok(Value::Str(s.chars().skip(start as usize).take((end - start) as usize).collect()))
+}
+
+/// `(split s separator)` with a string or char separator; an empty one
+/// splits into chars. Trailing empty strings are dropped.
+fn fn_split(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("split", &args, 2, 2));
+    let s = try!(string_arg("split", &args[0]));
+    let separator = try!(text_arg("split", &args[1]));
+
+    let mut parts: Vec<String> = if separator.is_empty() {
+        s.chars().map(|c| c.to_string()).collect()
+    }
+    else {
+        s.split(&*separator).map(String::from).collect()
+    };
+    while parts.len() > 1 && parts.last().map_or(false, |part| part.is_empty()) {
+        parts.pop();
+    }
+    Ok(Value::Vector(Vector::from_vec(parts.into_iter().map(Value::Str).collect())))
+}
+
+/// `(join coll)` or `(join separator coll)`
+fn fn_join(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("join", &args, 1, 2));
+    let (separator, coll) = match args.len() {
+        1 => (String::new(), &args[0]),
+        _ => (try!(text_arg("join", &args[0])), &args[1]),
+    };
+
+    let mut parts = vec!();
+    for item in try!(seq::iter(coll)) {
+        parts.push(try!(str_of(&try!(item))));
+    }
+    Ok(Value::Str(parts.join(&separator)))
+}
+
+fn map_string(name: &str, args: &Vec<Value>, f: fn(&str) -> String) -> Result<Value, Error> {
+    try!(arity(name, args, 1, 1));
+    Ok(Value::Str(f(try!(string_arg(name, &args[0])))))
+}
+
+fn fn_trim(args: Vec<Value>) -> Result<Value, Error> {
+    map_string("trim", &args, |s| String::from(s.trim()))
+}
+
+fn fn_triml(args: Vec<Value>) -> Result<Value, Error> {
+    map_string("triml", &args, |s| String::from(s.trim_left()))
+}
+
+fn fn_trimr(args: Vec<Value>) -> Result<Value, Error> {
+    map_string("trimr", &args, |s| String::from(s.trim_right()))
+}
+
+fn fn_upper_case(args: Vec<Value>) -> Result<Value, Error> {
+    map_string("upper-case", &args, str::to_uppercase)
+}
+
+fn fn_lower_case(args: Vec<Value>) -> Result<Value, Error> {
+    map_string("lower-case", &args, str::to_lowercase)
+}
+
+/// `(replace s match replacement)` replaces every occurrence of a string
+/// or char.
+fn fn_replace(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("replace", &args, 3, 3));
+    let s = try!(string_arg("replace", &args[0]));
+    let from = try!(text_arg("replace", &args[1]));
+    let to = try!(text_arg("replace", &args[2]));
+    if from.is_empty() {
+        return error(String::from("replace requires a non-empty match"));
+    }
+    Ok(Value::Str(s.replace(&*from, &to)))
+}
+
+fn test_string(name: &str, args: &Vec<Value>, test: fn(&str, &str) -> bool) -> Result<Value, Error> {
+    try!(arity(name, args, 2, 2));
+    let s = try!(string_arg(name, &args[0]));
+    let part = try!(text_arg(name, &args[1]));
+    Ok(Value::Bool(test(s, &part)))
+}
+
+fn fn_starts_with(args: Vec<Value>) -> Result<Value, Error> {
+    test_string("starts-with?", &args, |s, part| s.starts_with(part))
+}
+
+fn fn_ends_with(args: Vec<Value>) -> Result<Value, Error> {
+    test_string("ends-with?", &args, |s, part| s.ends_with(part))
+}
+
+fn fn_includes(args: Vec<Value>) -> Result<Value, Error> {
+    test_string("includes?", &args, |s, part| s.contains(part))
+}
+
+/// `(index-of s value from?)` is the char index of the first occurrence at
+/// or after from, or nil.
+fn fn_index_of(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("index-of", &args, 2, 3));
+    let s = try!(string_arg("index-of", &args[0]));
+    let part = try!(text_arg("index-of", &args[1]));
+    let from = match args.get(2) {
+        Some(from) => try!(int_arg("index-of", from)),
+        None => 0,
+    };
+
+    let start = match byte_offset(s, if from < 0 { 0 } else { from as usize }) {
+        Some(start) => start,
+        None => return Ok(Value::Nil),
+    };
+    match s[start..].find(&*part) {
+        Some(offset) => Ok(Value::Int(char_index(s, start + offset) as i64)),
+        None => Ok(Value::Nil),
+    }
+}
+
+fn fn_blank(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("blank?", &args, 1, 1));
+    match args[0] {
+        Value::Nil => Ok(Value::Bool(true)),
+        ref other => Ok(Value::Bool(try!(string_arg("blank?", other)).trim().is_empty())),
+    }
+}
+
+/// One `%[flags][width][.precision]conversion` directive.
+struct Directive {
+    left: bool,
+    zero: bool,
+    plus: bool,
+    space: bool,
+    width: usize,
+    precision: Option<usize>,
+    conversion: char,
+}
+
+fn parse_directive<I>(chars: &mut iter::Peekable<I>) -> Result<Directive, Error>
+    where I: Iterator<Item = char>
+{
+    let mut directive = Directive {
+        left: false, zero: false, plus: false, space: false,
+        width: 0, precision: None, conversion: ' ',
+    };
+
+    loop {
+        match chars.peek().cloned() {
+            Some('-') => directive.left = true,
+            Some('0') => directive.zero = true,
+            Some('+') => directive.plus = true,
+            Some(' ') => directive.space = true,
+            _ => break,
+        }
+        chars.next();
+    }
+
+    fn number<I: Iterator<Item = char>>(chars: &mut iter::Peekable<I>) -> usize {
+        let mut n = 0;
+        while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+            n = n * 10 + d as usize;
+            chars.next();
+        }
+        n
+    }
+
+    directive.width = number(chars);
+    if chars.peek() == Some(&'.') {
+        chars.next();
+        directive.precision = Some(number(chars));
+    }
+    match chars.next() {
+        Some(c) => directive.conversion = c,
+        None => return error(String::from("format string ends in the middle of a directive")),
+    }
+    Ok(directive)
+}
+
+/// Pads to the directive's width, counting chars; zeros go after the sign.
+fn pad(directive: &Directive, text: String, numeric: bool) -> String {
+    let len = text.chars().count();
+    if len >= directive.width {
+        return text;
+    }
+
+    let fill: String = iter::repeat(if directive.zero && numeric && !directive.left { '0' } else { ' ' })
+        .take(directive.width - len)
+        .collect();
+    if directive.left {
+        text + &fill
+    }
+    else if fill.starts_with('0') && (text.starts_with('-') || text.starts_with('+') || text.starts_with(' ')) {
+        let (sign, digits) = text.split_at(1);
+        format!("{}{}{}", sign, fill, digits)
+    }
+    else {
+        fill + &text
+    }
+}
+
+fn signed(directive: &Directive, text: String) -> String {
+    if text.starts_with('-') {
+        text
+    }
+    else if directive.plus {
+        format!("+{}", text)
+    }
+    else if directive.space {
+        format!(" {}", text)
+    }
+    else {
+        text
+    }
+}
+
+fn float_arg(value: &Value) -> Result<f64, Error> {
+    match *value {
+        Value::Int(i) => Ok(i as f64),
+        Value::Float(x) => Ok(x),
+        Value::BigInt(ref b) => Ok(b.to_f64()),
+        Value::Ratio(ref r) => Ok(r.numer.to_f64() / r.denom.to_f64()),
+        ref other => error(format!("format %f expects a number, got {}", other.type_name())),
+    }
+}
+
+/// Scientific notation the way printf writes it: `1.500000e+03`.
+fn exponential(x: f64, precision: usize) -> String {
+    let text = format!("{:.*e}", precision, x);
+    match text.find('e') {
+        Some(i) => {
+            let exponent: i32 = text[i + 1..].parse().unwrap_or(0);
+            format!("{}e{}{:02}", &text[..i], if exponent < 0 { '-' } else { '+' }, exponent.abs())
+        },
+        None => text,
+    }
+}
+
+fn format_one(directive: &Directive, arg: &Value) -> Result<String, Error> {
+    let (text, numeric) = match (directive.conversion, arg) {
+        ('s', value) => {
+            let text = try!(print_str(value));
+            match directive.precision {
+                Some(precision) => (text.chars().take(precision).collect(), false),
+                None => (text, false),
+            }
+        },
+        ('d', &Value::Int(i)) => (signed(directive, i.to_string()), true),
+        ('d', &Value::BigInt(ref b)) => (signed(directive, b.to_string()), true),
+        ('x', &Value::Int(i)) => (format!("{:x}", i), true),
+        ('X', &Value::Int(i)) => (format!("{:X}", i), true),
+        ('o', &Value::Int(i)) => (format!("{:o}", i), true),
+        ('f', value) => (signed(directive, format!("{:.*}", directive.precision.unwrap_or(6), try!(float_arg(value)))), true),
+        ('e', value) => (signed(directive, exponential(try!(float_arg(value)), directive.precision.unwrap_or(6))), true),
+        ('c', &Value::Char(c)) => (c.to_string(), false),
+        ('b', value) => (value.is_truthy().to_string(), false),
+        (c, value) if "dxXoc".contains(c) =>
+            return error(format!("format %{} does not accept {}", c, value.type_name())),
+        (c, _) => return error(format!("Unknown format conversion: %{}", c)),
+    };
+    Ok(pad(directive, text, numeric))
+}
+
+/// `(format fmt args...)` with printf-style directives: `%s %d %x %X %o %f
+/// %e %c %b`, flags `-0+ `, width and precision, and `%%` and `%n`.
+fn fn_format(args: Vec<Value>) -> Result<Value, Error> {
+    try!(arity("format", &args, 1, MANY));
+    let fmt = try!(string_arg("format", &args[0]));
+    let mut args = args[1..].iter();
+
+    let mut out = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.peek().cloned() {
+            Some('%') => { chars.next(); out.push('%'); continue; },
+            Some('n') => { chars.next(); out.push('\n'); continue; },
+            _ => (),
+        }
+
+        let directive = try!(parse_directive(&mut chars));
+        match args.next() {
+            Some(arg) => out.push_str(&try!(format_one(&directive, arg))),
+            None => return error(format!("format has too few arguments for {}", fmt)),
+        }
+    }
+    Ok(Value::Str(out))
+}
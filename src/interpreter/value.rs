@@ -0,0 +1,498 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use parser::Expression;
+
+use super::bigint::BigInt;
+use super::env::Env;
+use super::error::{Error, error};
+use super::hashmap::{self, Map, Set};
+use super::io::Reader;
+use super::list::List;
+use super::numbers::{self, Ratio};
+use super::refs::{Atom, Volatile};
+use super::seq::{self, LazySeq, Seq};
+use super::vector::Vector;
+
+/// How many elements of a sequential collection its hash covers. Hashing
+/// only a prefix keeps an infinite seq usable as a key, and realizes no
+/// more of a lazy one than that.
+const HASHED_ITEMS: usize = 16;
+
+pub type NativeFn = fn(Vec<Value>) -> Result<Value, Error>;
+
+#[derive(Clone)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub f: NativeFn,
+}
+
+/// A function registered by the program embedding the interpreter.
+pub struct Native {
+    pub name: String,
+    pub f: Box<Fn(Vec<Value>) -> Result<Value, Error>>,
+}
+
+pub struct Lambda {
+    pub name: Option<String>,
+    pub params: Vec<String>,
+    pub rest: Option<String>,
+    pub body: Vec<Expression>,
+    pub env: Env,
+}
+
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    BigInt(Rc<BigInt>),
+    Ratio(Rc<Ratio>),
+    Float(f64),
+    Str(String),
+    Char(char),
+    Symbol(String),
+    Keyword(String),
+    List(List),
+    Vector(Vector),
+    Map(Map),
+    Set(Set),
+    Seq(Seq),
+    LazySeq(Rc<LazySeq>),
+    Builtin(Builtin),
+    Native(Rc<Native>),
+    Lambda(Rc<Lambda>),
+    Macro(Rc<Lambda>),
+    Error(Rc<Error>),
+    Atom(Rc<Atom>),
+    Volatile(Rc<Volatile>),
+    Reader(Rc<Reader>),
+    /// What `recur` evaluates to; never seen outside `loop` and `fn`.
+    Recur(Vec<Value>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "boolean",
+            Value::Int(_) => "integer",
+            Value::BigInt(_) => "bigint",
+            Value::Ratio(_) => "ratio",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::Char(_) => "char",
+            Value::Symbol(_) => "symbol",
+            Value::Keyword(_) => "keyword",
+            Value::List(_) => "list",
+            Value::Vector(_) => "vector",
+            Value::Map(_) => "map",
+            Value::Set(_) => "set",
+            Value::Seq(_) => "seq",
+            Value::LazySeq(_) => "lazy-seq",
+            Value::Builtin(_) | Value::Native(_) | Value::Lambda(_) => "function",
+            Value::Macro(_) => "macro",
+            Value::Error(_) => "error",
+            Value::Atom(_) => "atom",
+            Value::Volatile(_) => "volatile",
+            Value::Reader(_) => "reader",
+            Value::Recur(_) => "recur",
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        match *self {
+            Value::Nil | Value::Bool(false) => false,
+            _ => true,
+        }
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        match *self {
+            Value::List(_) | Value::Vector(_) | Value::Seq(_) | Value::LazySeq(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Elements of a sequential collection, None for anything else.
+    pub fn sequential_items(&self) -> Option<Vec<Value>> {
+        if self.is_sequential() {
+            seq::to_vec(self).ok()
+        }
+        else {
+            None
+        }
+    }
+
+    /// Realizes every lazy seq in the value, failing as the first that fails
+    /// to does. `Display` and `==` can't fail, and take such a seq to end
+    /// where it failed.
+    pub fn realize(&self) -> Result<(), Error> {
+        match *self {
+            Value::List(_) | Value::Vector(_) | Value::Seq(_) | Value::LazySeq(_) => {
+                for item in try!(seq::iter(self)) {
+                    try!(try!(item).realize());
+                }
+            },
+            Value::Map(ref map) => for (k, v) in map.iter() {
+                try!(k.realize());
+                try!(v.realize());
+            },
+            Value::Set(ref set) => for item in set.iter() {
+                try!(item.realize());
+            },
+            Value::Atom(ref a) => try!(a.get().realize()),
+            Value::Volatile(ref v) => try!(v.get().realize()),
+            Value::Error(ref e) => try!(e.data.realize()),
+            Value::Recur(ref values) => for value in values {
+                try!(value.realize());
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Realizes what hashing the value looks at, so that a key whose seq
+    /// fails to realize fails where it's used rather than hashing short.
+    pub fn check_key(&self) -> Result<(), Error> {
+        match *self {
+            Value::List(_) | Value::Vector(_) | Value::Seq(_) | Value::LazySeq(_) => {
+                for item in try!(seq::iter(self)).take(HASHED_ITEMS) {
+                    try!(try!(item).check_key());
+                }
+            },
+            // the keys of a map and the items of a set were checked as they
+            // went in
+            Value::Map(ref map) => for (_, v) in map.iter() {
+                try!(v.check_key());
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Converts a value back into the expression that reads as it.
+    pub fn to_expression(&self) -> Expression {
+        fn tagged(tag: &'static str, mut items: Vec<Expression>) -> Expression {
+            items.insert(0, Expression::Symbol(String::from(tag)));
+            Expression::SExpression(items)
+        }
+
+        match *self {
+            Value::Nil => Expression::Symbol(String::from("nil")),
+            Value::Bool(b) => Expression::Symbol(b.to_string()),
+            Value::Int(_) | Value::BigInt(_) | Value::Ratio(_) | Value::Float(_) =>
+                Expression::Number(self.to_string()),
+            Value::Str(ref s) => Expression::String(s.clone()),
+            Value::Char(c) => Expression::String(c.to_string()),
+            Value::Symbol(ref s) => Expression::Symbol(s.clone()),
+            Value::Keyword(ref k) => Expression::Symbol(format!(":{}", k)),
+            Value::List(_) | Value::Seq(_) | Value::LazySeq(_) => Expression::SExpression(
+                self.sequential_items().unwrap_or(vec!()).iter().map(|v| v.to_expression()).collect()),
+            Value::Vector(ref vector) =>
+                tagged("vector", vector.iter().map(|v| v.to_expression()).collect()),
+            Value::Map(ref map) => {
+                let mut items = Vec::with_capacity(map.len() * 2);
+                for (k, v) in map.iter() {
+                    items.push(k.to_expression());
+                    items.push(v.to_expression());
+                }
+                tagged("hash-map", items)
+            },
+            Value::Set(ref set) =>
+                tagged("set", set.iter().map(|v| v.to_expression()).collect()),
+            Value::Builtin(_) | Value::Native(_) | Value::Lambda(_) | Value::Macro(_) |
+            Value::Error(_) | Value::Recur(_) |
+            Value::Atom(_) | Value::Volatile(_) | Value::Reader(_) =>
+                Expression::Symbol(self.to_string()),
+        }
+    }
+}
+
+fn write_seq<'a, I>(f: &mut fmt::Formatter, open: &str, close: &str, items: I) -> fmt::Result
+    where I: Iterator<Item = &'a Value>
+{
+    try!(write!(f, "{}", open));
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            try!(write!(f, " "));
+        }
+        try!(write!(f, "{}", item));
+    }
+    write!(f, "{}", close)
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    try!(write!(f, "\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(write!(f, "\\\"")),
+            '\\' => try!(write!(f, "\\\\")),
+            '\n' => try!(write!(f, "\\n")),
+            '\t' => try!(write!(f, "\\t")),
+            '\r' => try!(write!(f, "\\r")),
+            _ => try!(write!(f, "{}", c)),
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::BigInt(ref b) => write!(f, "{}", b),
+            Value::Ratio(ref r) => write!(f, "{}/{}", r.numer, r.denom),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Str(ref s) => write_string(f, s),
+            Value::Char(c) => match c {
+                ' ' => write!(f, "\\space"),
+                '\n' => write!(f, "\\newline"),
+                '\t' => write!(f, "\\tab"),
+                _ => write!(f, "\\{}", c),
+            },
+            Value::Symbol(ref s) => write!(f, "{}", s),
+            Value::Keyword(ref k) => write!(f, ":{}", k),
+            Value::List(ref list) => write_seq(f, "(", ")", list.iter()),
+            Value::Seq(_) | Value::LazySeq(_) => {
+                let items = self.sequential_items().unwrap_or(vec!());
+                write_seq(f, "(", ")", items.iter())
+            },
+            Value::Vector(ref vector) => {
+                let items: Vec<Value> = vector.iter().collect();
+                write_seq(f, "[", "]", items.iter())
+            },
+            Value::Map(ref map) => {
+                try!(write!(f, "{{"));
+                for (i, (k, v)) in map.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ", "));
+                    }
+                    try!(write!(f, "{} {}", k, v));
+                }
+                write!(f, "}}")
+            },
+            Value::Set(ref set) => write_seq(f, "#{", "}", set.iter()),
+            Value::Builtin(ref b) => write!(f, "#<fn {}>", b.name),
+            Value::Native(ref n) => write!(f, "#<fn {}>", n.name),
+            Value::Lambda(ref l) => match l.name {
+                Some(ref name) => write!(f, "#<fn {}>", name),
+                None => write!(f, "#<fn>"),
+            },
+            Value::Macro(ref m) => write!(f, "#<macro {}>", m.name.as_ref().unwrap()),
+            Value::Recur(ref values) => write_seq(f, "#<recur ", ">", values.iter()),
+            Value::Atom(ref a) => write!(f, "#<atom {}>", a.get()),
+            Value::Volatile(ref v) => write!(f, "#<volatile {}>", v.get()),
+            Value::Reader(ref r) => write!(f, "#<reader {}>", r.name),
+            Value::Error(ref e) => {
+                try!(write!(f, "#error {{:message "));
+                try!(write_string(f, &e.message));
+                if e.data != Value::Nil {
+                    try!(write!(f, ", :data {}", e.data));
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (&Value::Nil, &Value::Nil) => true,
+            (&Value::Bool(a), &Value::Bool(b)) => a == b,
+            (&Value::Int(a), &Value::Int(b)) => a == b,
+            (&Value::BigInt(ref a), &Value::BigInt(ref b)) => a == b,
+            (&Value::Int(a), &Value::BigInt(ref b)) | (&Value::BigInt(ref b), &Value::Int(a)) =>
+                b.to_i64() == Some(a),
+            (&Value::Ratio(ref a), &Value::Ratio(ref b)) => a == b,
+            (&Value::Float(a), &Value::Float(b)) => a == b,
+            (&Value::Str(ref a), &Value::Str(ref b)) => a == b,
+            (&Value::Char(a), &Value::Char(b)) => a == b,
+            (&Value::Symbol(ref a), &Value::Symbol(ref b)) => a == b,
+            (&Value::Keyword(ref a), &Value::Keyword(ref b)) => a == b,
+            (&Value::Map(ref a), &Value::Map(ref b)) =>
+                a.len() == b.len() && a.iter().all(|(k, v)| b.get(k) == Some(v)),
+            (&Value::Set(ref a), &Value::Set(ref b)) =>
+                a.len() == b.len() && a.iter().all(|v| b.contains(v)),
+            (&Value::Builtin(ref a), &Value::Builtin(ref b)) => a.name == b.name,
+            (&Value::Native(ref a), &Value::Native(ref b)) => &**a as *const Native == &**b as *const Native,
+            (&Value::Error(ref a), &Value::Error(ref b)) => a == b,
+            (&Value::Lambda(ref a), &Value::Lambda(ref b)) =>
+                &**a as *const Lambda == &**b as *const Lambda,
+            (&Value::Macro(ref a), &Value::Macro(ref b)) =>
+                &**a as *const Lambda == &**b as *const Lambda,
+            (&Value::Atom(ref a), &Value::Atom(ref b)) => &**a as *const Atom == &**b as *const Atom,
+            (&Value::Volatile(ref a), &Value::Volatile(ref b)) =>
+                &**a as *const Volatile == &**b as *const Volatile,
+            (&Value::Reader(ref a), &Value::Reader(ref b)) => &**a as *const Reader == &**b as *const Reader,
+            (a, b) => match (a.sequential_items(), b.sequential_items()) {
+                (Some(x), Some(y)) => x == y,
+                _ => false,
+            }
+        }
+    }
+}
+
+impl Eq for Value {}
+
+/// Whether two values are the same one: those kept behind a pointer are
+/// compared by it, the rest, which are copied whenever they're passed on,
+/// by value.
+pub fn identical(a: &Value, b: &Value) -> bool {
+    fn same<T>(a: &Rc<T>, b: &Rc<T>) -> bool {
+        &**a as *const T == &**b as *const T
+    }
+
+    match (a, b) {
+        (&Value::List(ref a), &Value::List(ref b)) => a.is_identical(b),
+        (&Value::Vector(ref a), &Value::Vector(ref b)) => a.is_identical(b),
+        (&Value::Map(ref a), &Value::Map(ref b)) => a.is_identical(b),
+        (&Value::Set(ref a), &Value::Set(ref b)) => a.is_identical(b),
+        (&Value::Seq(ref a), &Value::Seq(ref b)) => a.is_identical(b),
+        (&Value::LazySeq(ref a), &Value::LazySeq(ref b)) => same(a, b),
+        (&Value::BigInt(ref a), &Value::BigInt(ref b)) => same(a, b),
+        (&Value::Ratio(ref a), &Value::Ratio(ref b)) => same(a, b),
+        (&Value::Error(ref a), &Value::Error(ref b)) => same(a, b),
+        (&Value::Recur(_), _) | (_, &Value::Recur(_)) => false,
+        // an int equals the bigint of the same value, but isn't it
+        (a, b) => a.type_name() == b.type_name() && a == b,
+    }
+}
+
+/// Equality as `=` sees it: like `==`, but failing when realizing a lazy seq
+/// being compared does. Seqs are compared an element at a time, so no more
+/// of them is realized than tells them apart.
+pub fn equals(a: &Value, b: &Value) -> Result<bool, Error> {
+    if a.is_sequential() && b.is_sequential() {
+        let (mut x, mut y) = (try!(seq::iter(a)), try!(seq::iter(b)));
+        loop {
+            match (x.next(), y.next()) {
+                (None, None) => return Ok(true),
+                (Some(i), Some(j)) => if !try!(equals(&try!(i), &try!(j))) {
+                    return Ok(false);
+                },
+                (Some(i), None) | (None, Some(i)) => {
+                    try!(i);
+                    return Ok(false);
+                },
+            }
+        }
+    }
+
+    match (a, b) {
+        (&Value::Map(ref a), &Value::Map(ref b)) if a.len() == b.len() => {
+            for (k, v) in a.iter() {
+                match b.get(k) {
+                    Some(w) => if !try!(equals(v, w)) {
+                        return Ok(false);
+                    },
+                    None => return Ok(false),
+                }
+            }
+            Ok(true)
+        },
+        _ => Ok(a == b),
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Value::Nil => 0u8.hash(state),
+            Value::Bool(b) => (1u8, b).hash(state),
+            Value::Int(i) => (2u8, i).hash(state),
+            // -0.0 equals 0.0, so it has to hash alike
+            Value::Float(x) => (3u8, if x == 0.0 { 0u64 } else { x.to_bits() }).hash(state),
+            Value::Str(ref s) => (4u8, s).hash(state),
+            Value::Symbol(ref s) => (5u8, s).hash(state),
+            Value::Keyword(ref k) => (6u8, k).hash(state),
+            Value::Char(c) => (12u8, c).hash(state),
+            // a bigint equals the int of the same value, so it has to hash alike
+            Value::BigInt(ref b) => match b.to_i64() {
+                Some(i) => (2u8, i).hash(state),
+                None => (13u8, &**b).hash(state),
+            },
+            Value::Ratio(ref r) => (14u8, &**r).hash(state),
+            Value::List(_) | Value::Vector(_) | Value::Seq(_) | Value::LazySeq(_) => {
+                // a seq that fails to realize fails at the same element every
+                // time, so equal seqs still hash alike
+                7u8.hash(state);
+                if let Ok(items) = seq::iter(self) {
+                    for item in items.take(HASHED_ITEMS) {
+                        match item {
+                            Ok(item) => item.hash(state),
+                            Err(_) => break,
+                        }
+                    }
+                }
+            },
+            // unordered, so equal maps and sets hash alike whatever their layout
+            Value::Map(ref map) => {
+                let sum = map.iter().fold(0u32, |acc, (k, v)| {
+                    acc.wrapping_add(hashmap::hash_of(k) ^ hashmap::hash_of(v).rotate_left(16))
+                });
+                (8u8, sum).hash(state);
+            },
+            Value::Set(ref set) => {
+                let sum = set.iter().fold(0u32, |acc, v| acc.wrapping_add(hashmap::hash_of(v)));
+                (9u8, sum).hash(state);
+            },
+            Value::Builtin(ref b) => (10u8, b.name).hash(state),
+            Value::Native(ref n) => (21u8, &**n as *const Native as usize).hash(state),
+            Value::Lambda(ref l) => (11u8, &**l as *const Lambda as usize).hash(state),
+            Value::Macro(ref m) => (16u8, &**m as *const Lambda as usize).hash(state),
+            Value::Error(ref e) => (15u8, &e.message).hash(state),
+            Value::Recur(ref values) => (17u8, values).hash(state),
+            Value::Atom(ref a) => (18u8, &**a as *const Atom as usize).hash(state),
+            Value::Volatile(ref v) => (19u8, &**v as *const Volatile as usize).hash(state),
+            Value::Reader(ref r) => (20u8, &**r as *const Reader as usize).hash(state),
+        }
+    }
+}
+
+/// Natural ordering used by `sort`: numbers, strings, chars, keywords and
+/// symbols among themselves, vectors by length and then element-wise.
+pub fn compare(a: &Value, b: &Value) -> Result<Ordering, Error> {
+    let ordering = match (a, b) {
+        (&Value::Nil, &Value::Nil) => Some(Ordering::Equal),
+        (&Value::Nil, _) => Some(Ordering::Less),
+        (_, &Value::Nil) => Some(Ordering::Greater),
+        (a, b) if numbers::is_number(a) && numbers::is_number(b) => return numbers::compare(a, b),
+        (&Value::Bool(x), &Value::Bool(y)) => Some(x.cmp(&y)),
+        (&Value::Str(ref x), &Value::Str(ref y)) => Some(x.cmp(y)),
+        (&Value::Char(x), &Value::Char(y)) => Some(x.cmp(&y)),
+        (&Value::Keyword(ref x), &Value::Keyword(ref y)) => Some(x.cmp(y)),
+        (&Value::Symbol(ref x), &Value::Symbol(ref y)) => Some(x.cmp(y)),
+        (&Value::Vector(ref x), &Value::Vector(ref y)) => {
+            if x.len() != y.len() {
+                Some(x.len().cmp(&y.len()))
+            }
+            else {
+                for (i, j) in x.iter().zip(y.iter()) {
+                    match try!(compare(&i, &j)) {
+                        Ordering::Equal => (),
+                        other => return Ok(other),
+                    }
+                }
+                Some(Ordering::Equal)
+            }
+        },
+        _ => None,
+    };
+
+    match ordering {
+        Some(ordering) => Ok(ordering),
+        None => error(format!("Can't compare {} with {}", a, b)),
+    }
+}
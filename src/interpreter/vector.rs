@@ -0,0 +1,278 @@
+use std::rc::Rc;
+
+use super::value::Value;
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Clone)]
+enum Node {
+    Branch(Vec<Rc<Node>>),
+    Leaf(Rc<Vec<Value>>),
+}
+
+/// Persistent vector, a 32-way trie with the last (incomplete) leaf kept
+/// apart as tail, so conj is amortized O(1) and lookup O(log32 n).
+#[derive(Clone)]
+pub struct Vector {
+    count: usize,
+    shift: usize,
+    root: Rc<Node>,
+    tail: Rc<Vec<Value>>,
+}
+
+impl Vector {
+    pub fn new() -> Vector {
+        Vector {
+            count: 0,
+            shift: BITS,
+            root: Rc::new(Node::Branch(vec!())),
+            tail: Rc::new(Vec::new()),
+        }
+    }
+
+    pub fn from_vec(values: Vec<Value>) -> Vector {
+        let mut vector = Vector::new();
+        for v in values {
+            vector = vector.conj(v);
+        }
+        vector
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Whether both are the very same vector, not merely equal ones.
+    pub fn is_identical(&self, other: &Vector) -> bool {
+        self.count == other.count && &*self.root as *const Node == &*other.root as *const Node &&
+            &*self.tail as *const Vec<Value> == &*other.tail as *const Vec<Value>
+    }
+
+    fn tail_offset(&self) -> usize {
+        if self.count < WIDTH {
+            0
+        }
+        else {
+            ((self.count - 1) >> BITS) << BITS
+        }
+    }
+
+    /// The leaf holding index `i`, together with the index of its first element.
+    pub fn chunk_for(&self, i: usize) -> Option<(usize, Rc<Vec<Value>>)> {
+        if i >= self.count {
+            return None;
+        }
+
+        let offset = self.tail_offset();
+        if i >= offset {
+            return Some((offset, self.tail.clone()));
+        }
+
+        let mut node = &self.root;
+        let mut level = self.shift;
+        loop {
+            match **node {
+                Node::Branch(ref children) => {
+                    node = &children[(i >> level) & MASK];
+                    level -= BITS;
+                },
+                Node::Leaf(ref values) => return Some((i & !MASK, values.clone())),
+            }
+        }
+    }
+
+    pub fn get(&self, i: usize) -> Option<Value> {
+        self.chunk_for(i).map(|(start, chunk)| chunk[i - start].clone())
+    }
+
+    pub fn conj(&self, value: Value) -> Vector {
+        if self.count - self.tail_offset() < WIDTH {
+            let mut tail = (*self.tail).clone();
+            tail.push(value);
+            return Vector {
+                count: self.count + 1,
+                shift: self.shift,
+                root: self.root.clone(),
+                tail: Rc::new(tail),
+            };
+        }
+
+        let tail_node = Rc::new(Node::Leaf(self.tail.clone()));
+        let (root, shift) = if (self.count >> BITS) > (1 << self.shift) {
+            let branch = Node::Branch(vec!(self.root.clone(), new_path(self.shift, tail_node)));
+            (Rc::new(branch), self.shift + BITS)
+        }
+        else {
+            (push_tail(self.count, self.shift, &self.root, tail_node), self.shift)
+        };
+
+        Vector {
+            count: self.count + 1,
+            shift: shift,
+            root: root,
+            tail: Rc::new(vec!(value)),
+        }
+    }
+
+    /// Returns None when `i` is out of bounds; `i == len()` appends.
+    pub fn assoc(&self, i: usize, value: Value) -> Option<Vector> {
+        if i == self.count {
+            return Some(self.conj(value));
+        }
+        if i > self.count {
+            return None;
+        }
+
+        let offset = self.tail_offset();
+        if i >= offset {
+            let mut tail = (*self.tail).clone();
+            tail[i - offset] = value;
+            return Some(Vector {
+                count: self.count,
+                shift: self.shift,
+                root: self.root.clone(),
+                tail: Rc::new(tail),
+            });
+        }
+
+        Some(Vector {
+            count: self.count,
+            shift: self.shift,
+            root: assoc_in_node(self.shift, &self.root, i, value),
+            tail: self.tail.clone(),
+        })
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter { vector: self, index: 0, chunk: None }
+    }
+}
+
+fn new_path(level: usize, node: Rc<Node>) -> Rc<Node> {
+    if level == 0 {
+        node
+    }
+    else {
+        Rc::new(Node::Branch(vec!(new_path(level - BITS, node))))
+    }
+}
+
+fn push_tail(count: usize, level: usize, parent: &Rc<Node>, tail_node: Rc<Node>) -> Rc<Node> {
+    let mut children = match **parent {
+        Node::Branch(ref children) => children.clone(),
+        Node::Leaf(_) => unreachable!("push_tail on leaf"),
+    };
+
+    let sub_idx = ((count - 1) >> level) & MASK;
+    let insert = if level == BITS {
+        tail_node
+    }
+    else if sub_idx < children.len() {
+        push_tail(count, level - BITS, &children[sub_idx], tail_node)
+    }
+    else {
+        new_path(level - BITS, tail_node)
+    };
+
+    if sub_idx < children.len() {
+        children[sub_idx] = insert;
+    }
+    else {
+        children.push(insert);
+    }
+
+    Rc::new(Node::Branch(children))
+}
+
+fn assoc_in_node(level: usize, node: &Rc<Node>, i: usize, value: Value) -> Rc<Node> {
+    match **node {
+        Node::Leaf(ref values) => {
+            let mut values = (**values).clone();
+            values[i & MASK] = value;
+            Rc::new(Node::Leaf(Rc::new(values)))
+        },
+        Node::Branch(ref children) => {
+            let mut children = children.clone();
+            let sub_idx = (i >> level) & MASK;
+            children[sub_idx] = assoc_in_node(level - BITS, &children[sub_idx], i, value);
+            Rc::new(Node::Branch(children))
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    vector: &'a Vector,
+    index: usize,
+    chunk: Option<(usize, Rc<Vec<Value>>)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        if self.index >= self.vector.len() {
+            return None;
+        }
+
+        let refill = match self.chunk {
+            Some((start, ref chunk)) => self.index >= start + chunk.len(),
+            None => true,
+        };
+        if refill {
+            self.chunk = self.vector.chunk_for(self.index);
+        }
+
+        let value = match self.chunk {
+            Some((start, ref chunk)) => chunk[self.index - start].clone(),
+            None => return None,
+        };
+        self.index += 1;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Vector;
+    use super::super::value::Value;
+
+    fn ints(n: i64) -> Vector {
+        let mut v = Vector::new();
+        for i in 0..n {
+            v = v.conj(Value::Int(i));
+        }
+        v
+    }
+
+    #[test]
+    fn conj_across_levels() {
+        for &n in [0, 1, 31, 32, 33, 1024, 1056, 1057, 40000].iter() {
+            let v = ints(n);
+            assert_eq!(n as usize, v.len());
+            for i in 0..n {
+                assert_eq!(Some(Value::Int(i)), v.get(i as usize));
+            }
+            assert_eq!(None, v.get(n as usize));
+            assert_eq!(n as usize, v.iter().count());
+        }
+    }
+
+    #[test]
+    fn assoc_is_persistent() {
+        let v = ints(2000);
+        let w = v.assoc(10, Value::Nil).unwrap().assoc(1999, Value::Nil).unwrap();
+
+        assert_eq!(Some(Value::Int(10)), v.get(10));
+        assert_eq!(Some(Value::Int(1999)), v.get(1999));
+        assert_eq!(Some(Value::Nil), w.get(10));
+        assert_eq!(Some(Value::Nil), w.get(1999));
+        assert_eq!(2001, v.assoc(2000, Value::Nil).unwrap().len());
+        assert!(v.assoc(2001, Value::Nil).is_none());
+    }
+}
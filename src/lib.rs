@@ -0,0 +1,12 @@
+//! The fancy language: a reader, a tree-walking interpreter and an LLVM
+//! backend. Programs embedding fancy use `Interpreter`.
+
+extern crate llvm_sys as llvm;
+
+pub mod interpreter;
+pub mod nrepl;
+pub mod parser;
+pub mod repl;
+pub mod runtime;
+
+pub use interpreter::{Error, FromValue, Interpreter, IntoValue, Value};
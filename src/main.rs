@@ -1,23 +1,32 @@
-#![allow(dead_code, unused_variables, unused_imports)]
-
-mod interpreter;
-mod parser;
-
-mod runtime;
-
+extern crate fancy_boot;
 extern crate getopts;
-extern crate llvm_sys as llvm;
 
 use getopts::Options;
 use std::env;
-use std::io::stdin;
-use ::runtime::Environment;
+use std::fs::File;
+use std::io::{stdin, stdout, stderr, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use fancy_boot::{interpreter, nrepl, parser, repl, runtime};
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [FILE] [options]", program);
+    let brief = format!("Usage: {} [FILE] [options]\n       {} build FILE -o OUTPUT [options]\n       {} nrepl [--port PORT] [options]",
+                        program, program, program);
     print!("{}", opts.usage(&brief));
 }
 
+/// The contents of a source file; failing to read it ends the program.
+fn read_source(path: &str) -> String {
+    let mut source = String::new();
+    if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+        writeln!(&mut stderr(), "Couldn't read {}: {}", path, e).unwrap();
+        process::exit(1);
+    }
+    source
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
@@ -26,6 +35,13 @@ fn main() {
     opts.optflag("l", "llvm", "LLVM demo");
     opts.optopt("s", "str", "parse expression from string", "FANCY EXPR");
     opts.optflag("p", "pipe", "parse stdin");
+    opts.optflag("r", "repl", "start an interactive REPL");
+    opts.optflag("i", "interpret", "evaluate with the interpreter instead of compiling; arguments after FILE are *command-line-args*");
+    opts.optopt("o", "output", "the executable `build` writes, or the file for --emit instead of stdout", "OUTPUT");
+    opts.optopt("", "emit", "write out a stage of compiling the source instead of running it", "ir|bc|asm|obj|ast|tokens|forms");
+    opts.optopt("O", "opt-level", "how much to optimize compiled code, from 0 (the default) to 3", "LEVEL");
+    opts.optopt("", "port", "the port `nrepl` listens on; by default any free one", "PORT");
+    opts.optopt("", "source-path", "directories to load required namespaces from, separated by ':'", "DIRS");
     opts.optflag("h", "help", "print this help menu");
 
     let matches = match opts.parse(&args[1..]) {
@@ -43,33 +59,159 @@ fn main() {
         runtime::demo();
     }
 
-    let mut expressions = Vec::<parser::Expression>::new();
+    let opt_level = match matches.opt_str("O").map(|level| level.parse::<u32>()) {
+        None => 0,
+        Some(Ok(level)) if level <= 3 => level,
+        Some(_) => {
+            writeln!(&mut stderr(), "--opt-level must be 0, 1, 2 or 3").unwrap();
+            process::exit(1);
+        }
+    };
+
+    let source_path = matches.opt_str("source-path").unwrap_or(String::from("."));
+    let source_paths: Vec<PathBuf> = source_path.split(':').map(PathBuf::from).collect();
+
+    if matches.opt_present("r") {
+        let mut fancy = interpreter::Interpreter::new();
+        fancy.set_source_paths(source_paths);
+        fancy.set_command_line_args(matches.free.clone());
+        repl::run(fancy);
+        return;
+    }
+
+    if matches.free.first().map(|arg| &arg[..]) == Some("nrepl") {
+        let port = match matches.opt_str("port").map(|port| port.parse::<u16>()) {
+            None => 0,
+            Some(Ok(port)) => port,
+            Some(Err(_)) => {
+                writeln!(&mut stderr(), "--port must be a number from 0 to 65535").unwrap();
+                process::exit(1);
+            }
+        };
+        let listener = match TcpListener::bind(("127.0.0.1", port)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                writeln!(&mut stderr(), "Couldn't listen on port {}: {}", port, e).unwrap();
+                process::exit(1);
+            }
+        };
+        let port = listener.local_addr().unwrap().port();
+        println!("nREPL server started on port {} on host 127.0.0.1 - nrepl://127.0.0.1:{}", port, port);
+        // editors look for the port of the project's server here
+        File::create(".nrepl-port").and_then(|mut file| write!(file, "{}", port)).ok();
+
+        let mut fancy = interpreter::Interpreter::new();
+        fancy.set_source_paths(source_paths);
+        nrepl::serve(listener, fancy);
+        return;
+    }
+
+    if matches.free.first().map(|arg| &arg[..]) == Some("build") {
+        let (path, output) = match (matches.free.get(1), matches.opt_str("o")) {
+            (Some(path), Some(output)) => (path.clone(), output),
+            _ => {
+                print_usage(&program, opts);
+                process::exit(1);
+            }
+        };
+
+        if let Err(e) = runtime::native::build(parser::parse_located(read_source(&path)), Path::new(&output), opt_level) {
+            writeln!(&mut stderr(), "{}", e).unwrap();
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(name) = matches.opt_str("emit") {
+        let stage = match runtime::emit::Emit::from_name(&name) {
+            Some(stage) => stage,
+            None => {
+                writeln!(&mut stderr(), "Unknown --emit stage {}, expected ir, bc, asm, obj, ast, tokens or forms", name).unwrap();
+                process::exit(1);
+            }
+        };
+
+        let mut source = String::new();
+        if let Some(s) = matches.opt_str("s") {
+            source = s;
+        }
+        else if matches.opt_present("p") {
+            stdin().read_to_string(&mut source).unwrap();
+        }
+        else if let Some(path) = matches.free.first() {
+            source = read_source(path);
+        }
+        else {
+            print_usage(&program, opts);
+            process::exit(1);
+        }
+
+        let written = runtime::emit::emit(stage, source, opt_level).and_then(|output| {
+            let result = match matches.opt_str("o") {
+                Some(path) => File::create(path).and_then(|mut file| file.write_all(&output)),
+                None => stdout().write_all(&output),
+            };
+            result.map_err(|e| e.to_string())
+        });
+        if let Err(e) = written {
+            writeln!(&mut stderr(), "{}", e).unwrap();
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mut forms = Vec::<(parser::Position, parser::Expression)>::new();
+    let mut script_args = matches.free.clone();
 
     if let Some(s) =  matches.opt_str("s") {
-        expressions = parser::parse_string(s);
+        forms = parser::parse_located(s);
     }
 
-    if expressions.len() == 0 && matches.opt_present("p") {
-        let stdin = stdin();
-        expressions = parser::parse_buffer(stdin.lock());
+    if forms.len() == 0 && matches.opt_present("p") {
+        let mut source = String::new();
+        stdin().read_to_string(&mut source).unwrap();
+        forms = parser::parse_located(source);
     }
 
-    if expressions.len() == 0 && !matches.free.is_empty() {
-        let path = matches.free[0].clone();
-        expressions = parser::parse_file(path);
+    if forms.len() == 0 && !matches.free.is_empty() {
+        let path = script_args.remove(0);
+        forms = parser::parse_located(read_source(&path));
     }
 
-    if expressions.len() == 0 {
+    if forms.len() == 0 {
         print_usage(&program, opts);
         return;
     }
+    else if matches.opt_present("i") {
+        let env = interpreter::root_env();
+        interpreter::set_source_paths(&env, source_paths);
+        interpreter::set_command_line_args(&env, script_args);
+
+        let expressions: Vec<parser::Expression> = forms.into_iter().map(|(_, e)| e).collect();
+        let printed = interpreter::eval_forms(&expressions, &env).and_then(|(value, _)| match value {
+            interpreter::Value::Nil => Ok(None),
+            value => interpreter::pr_str(&value).map(Some),
+        });
+        match printed {
+            Ok(None) => (),
+            Ok(Some(text)) => println!("{}", text),
+            Err(e) => {
+                writeln!(&mut stderr(), "{}", e.stack_trace()).unwrap();
+                process::exit(1);
+            }
+        }
+    }
     else {
-        println!("{:?}", expressions);
-
-        let mut env = ::runtime::LLVMEnvironment::new();
-        let last = env.eval_all(expressions);
-
-        //env.llvm_dump();
+        let mut env = runtime::LLVMEnvironment::new();
+        env.set_opt_level(opt_level);
+        match env.run_all(forms) {
+            Ok(interpreter::Value::Nil) => (),
+            Ok(value) => println!("{}", value),
+            Err(e) => {
+                writeln!(&mut stderr(), "{}", e).unwrap();
+                process::exit(1);
+            }
+        }
     }
 
     return;
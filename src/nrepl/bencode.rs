@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+/// A bencoded value, the encoding nREPL messages are sent in. Byte strings
+/// are read as UTF-8 text, since that's all nREPL puts in them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bencode {
+    Int(i64),
+    Str(String),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<String, Bencode>),
+}
+
+impl Bencode {
+    pub fn str(s: &str) -> Bencode {
+        Bencode::Str(String::from(s))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Bencode::Str(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Bencode> {
+        match *self {
+            Bencode::Dict(ref dict) => dict.get(key),
+            _ => None,
+        }
+    }
+
+    /// The string stored under the key of a dict.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(Bencode::as_str)
+    }
+}
+
+fn invalid<T>(message: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+fn read_byte<R: BufRead>(input: &mut R) -> io::Result<Option<u8>> {
+    let byte = match try!(input.fill_buf()).first() {
+        Some(&byte) => byte,
+        None => return Ok(None),
+    };
+    input.consume(1);
+    Ok(Some(byte))
+}
+
+fn expect_byte<R: BufRead>(input: &mut R) -> io::Result<u8> {
+    match try!(read_byte(input)) {
+        Some(byte) => Ok(byte),
+        None => invalid(String::from("bencode value ends early")),
+    }
+}
+
+/// Digits up to the terminator, as a number.
+fn read_number<R: BufRead>(input: &mut R, first: u8, end: u8) -> io::Result<i64> {
+    let mut digits = vec!(first);
+    loop {
+        match try!(expect_byte(input)) {
+            b if b == end => break,
+            b => digits.push(b),
+        }
+    }
+    let text = String::from_utf8_lossy(&digits).into_owned();
+    match text.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => invalid(format!("invalid bencode number: {}", text)),
+    }
+}
+
+fn read_value<R: BufRead>(input: &mut R, first: u8) -> io::Result<Bencode> {
+    match first {
+        b'i' => {
+            let first = try!(expect_byte(input));
+            Ok(Bencode::Int(try!(read_number(input, first, b'e'))))
+        },
+        b'l' => {
+            let mut items = vec!();
+            loop {
+                match try!(expect_byte(input)) {
+                    b'e' => return Ok(Bencode::List(items)),
+                    b => items.push(try!(read_value(input, b))),
+                }
+            }
+        },
+        b'd' => {
+            let mut dict = BTreeMap::new();
+            loop {
+                let key = match try!(expect_byte(input)) {
+                    b'e' => return Ok(Bencode::Dict(dict)),
+                    b => try!(read_value(input, b)),
+                };
+                let key = match key {
+                    Bencode::Str(key) => key,
+                    other => return invalid(format!("bencode dict key must be a string, got {:?}", other)),
+                };
+                let b = try!(expect_byte(input));
+                dict.insert(key, try!(read_value(input, b)));
+            }
+        },
+        b'0'...b'9' => {
+            let len = try!(read_number(input, first, b':')) as usize;
+            let mut bytes = vec!(0; len);
+            try!(input.read_exact(&mut bytes));
+            Ok(Bencode::Str(String::from_utf8_lossy(&bytes).into_owned()))
+        },
+        other => invalid(format!("invalid bencode value starting with {:?}", other as char)),
+    }
+}
+
+/// Reads the next value, or None at the end of input.
+pub fn read<R: BufRead>(input: &mut R) -> io::Result<Option<Bencode>> {
+    match try!(read_byte(input)) {
+        Some(first) => read_value(input, first).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn write<W: Write>(output: &mut W, value: &Bencode) -> io::Result<()> {
+    match *value {
+        Bencode::Int(i) => write!(output, "i{}e", i),
+        Bencode::Str(ref s) => {
+            try!(write!(output, "{}:", s.len()));
+            output.write_all(s.as_bytes())
+        },
+        Bencode::List(ref items) => {
+            try!(write!(output, "l"));
+            for item in items {
+                try!(write(output, item));
+            }
+            write!(output, "e")
+        },
+        Bencode::Dict(ref dict) => {
+            try!(write!(output, "d"));
+            for (key, value) in dict {
+                try!(write(output, &Bencode::Str(key.clone())));
+                try!(write(output, value));
+            }
+            write!(output, "e")
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+
+    use super::{read, write, Bencode};
+
+    fn encode(value: &Bencode) -> String {
+        let mut out = vec!();
+        write(&mut out, value).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut dict = BTreeMap::new();
+        dict.insert(String::from("op"), Bencode::str("eval"));
+        dict.insert(String::from("code"), Bencode::str("(str \"λ\")"));
+        dict.insert(String::from("id"), Bencode::Int(-7));
+        dict.insert(String::from("status"), Bencode::List(vec!(Bencode::str("done"))));
+        let value = Bencode::Dict(dict);
+
+        let text = encode(&value);
+        assert_eq!("d4:code10:(str \"λ\")2:idi-7e2:op4:eval6:statusl4:doneee", text);
+        assert_eq!(Some(value), read(&mut Cursor::new(text.as_bytes())).unwrap());
+    }
+
+    #[test]
+    fn stream_of_values() {
+        let mut input = Cursor::new("i1e3:abcle".as_bytes());
+        assert_eq!(Some(Bencode::Int(1)), read(&mut input).unwrap());
+        assert_eq!(Some(Bencode::str("abc")), read(&mut input).unwrap());
+        assert_eq!(Some(Bencode::List(vec!())), read(&mut input).unwrap());
+        assert_eq!(None, read(&mut input).unwrap());
+        assert!(read(&mut Cursor::new("d3:key".as_bytes())).is_err());
+    }
+}
@@ -0,0 +1,542 @@
+mod bencode;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use interpreter::{self, Interpreter, Interrupt, Value};
+use parser;
+
+pub use self::bencode::Bencode;
+
+const OPS: [&'static str; 8] = ["clone", "close", "describe", "eval", "load-file", "complete", "lookup", "interrupt"];
+
+/// The writing half of a client connection, shared by the threads that
+/// answer on it.
+type Connection = Arc<Mutex<TcpStream>>;
+
+/// The session and id of the request being evaluated, if any.
+type Busy = Arc<Mutex<Option<(String, String)>>>;
+
+/// A message from a client, with the connection to answer on.
+struct Request {
+    message: Bencode,
+    connection: Connection,
+}
+
+/// What a session keeps between evaluations; the definitions themselves
+/// are shared by all sessions.
+struct Session {
+    namespace: String,
+    recent: Vec<Value>,
+    error: Value,
+}
+
+struct Server {
+    fancy: Interpreter,
+    sessions: HashMap<String, Session>,
+    busy: Busy,
+}
+
+/// Answers nREPL clients connecting to the listener, until it fails. Each
+/// connection is read on its own thread, but requests are evaluated one
+/// at a time on this one, which owns the interpreter; `interrupt` is
+/// handled as soon as it arrives.
+pub fn serve(listener: TcpListener, fancy: Interpreter) {
+    let (sender, receiver) = channel();
+    let busy: Busy = Arc::new(Mutex::new(None));
+
+    let accepting = busy.clone();
+    let stop = fancy.interrupt();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let sender = sender.clone();
+                let busy = accepting.clone();
+                let stop = stop.clone();
+                thread::spawn(move || read_requests(stream, sender, busy, stop));
+            }
+        }
+    });
+
+    let mut server = Server { fancy: fancy, sessions: HashMap::new(), busy: busy };
+    server.run(receiver);
+}
+
+fn read_requests(stream: TcpStream, sender: Sender<Request>, busy: Busy, stop: Interrupt) {
+    let connection = match stream.try_clone() {
+        Ok(writer) => Arc::new(Mutex::new(writer)),
+        Err(_) => return,
+    };
+    let mut input = BufReader::new(stream);
+
+    // a client sending something that isn't bencode is dropped
+    while let Ok(Some(message)) = bencode::read(&mut input) {
+        if message.get_str("op") == Some("interrupt") {
+            interrupt(&message, &connection, &busy, &stop);
+        }
+        else if sender.send(Request { message: message, connection: connection.clone() }).is_err() {
+            return;
+        }
+    }
+}
+
+fn status(names: &[&str]) -> Bencode {
+    Bencode::List(names.iter().map(|name| Bencode::str(name)).collect())
+}
+
+/// Sends a response to the request, which carries over its id and session.
+fn send(connection: &Connection, request: &Bencode, fields: Vec<(&str, Bencode)>) {
+    let mut dict = BTreeMap::new();
+    for key in &["id", "session"] {
+        if let Some(value) = request.get(key) {
+            dict.insert(String::from(*key), value.clone());
+        }
+    }
+    for (key, value) in fields {
+        dict.insert(String::from(key), value);
+    }
+
+    let mut bytes = vec!();
+    bencode::write(&mut bytes, &Bencode::Dict(dict)).unwrap();
+    if let Ok(mut stream) = connection.lock() {
+        // the client may have gone away; there is no one to tell
+        stream.write_all(&bytes).and_then(|_| stream.flush()).ok();
+    }
+}
+
+fn interrupt(request: &Bencode, connection: &Connection, busy: &Busy, stop: &Interrupt) {
+    let running = busy.lock().unwrap().clone();
+    match running {
+        Some((ref session, ref id))
+            if request.get_str("session") == Some(session) &&
+               request.get_str("interrupt-id").map_or(true, |target| target == id) => {
+            stop.interrupt();
+            send(connection, request, vec!(("status", status(&["done"]))));
+        },
+        _ => send(connection, request, vec!(("status", status(&["session-idle", "done"])))),
+    }
+}
+
+fn new_session_id() -> String {
+    thread_local!(static COUNTER: ::std::cell::Cell<u64> = ::std::cell::Cell::new(0));
+    let n = COUNTER.with(|counter| {
+        counter.set(counter.get() + 1);
+        counter.get()
+    });
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u64 ^ d.as_secs()).unwrap_or(0);
+    format!("{:08x}-{:04x}-4{:03x}-8{:03x}-{:012x}",
+            now & 0xffffffff, n & 0xffff, (now >> 32) & 0xfff, (now >> 44) & 0xfff, n)
+}
+
+impl Session {
+    fn new(namespace: String) -> Session {
+        Session { namespace: namespace, recent: vec!(), error: Value::Nil }
+    }
+}
+
+impl Server {
+    fn run(&mut self, requests: Receiver<Request>) {
+        for request in requests.iter() {
+            self.handle(&request.message, &request.connection);
+        }
+    }
+
+    fn handle(&mut self, request: &Bencode, connection: &Connection) {
+        let session_id = request.get_str("session").map(String::from);
+        if let Some(ref id) = session_id {
+            if !self.sessions.contains_key(id) && request.get_str("op") != Some("clone") {
+                send(connection, request, vec!(("status", status(&["error", "unknown-session", "done"]))));
+                return;
+            }
+        }
+
+        match request.get_str("op") {
+            Some("clone") => {
+                let namespace = self.session_namespace(&session_id).unwrap_or(String::from("user"));
+                let id = new_session_id();
+                self.sessions.insert(id.clone(), Session::new(namespace));
+                send(connection, request, vec!(
+                    ("new-session", Bencode::Str(id)),
+                    ("status", status(&["done"]))));
+            },
+            Some("close") => {
+                if let Some(ref id) = session_id {
+                    self.sessions.remove(id);
+                }
+                send(connection, request, vec!(("status", status(&["session-closed", "done"]))));
+            },
+            Some("describe") => {
+                let ops = OPS.iter().map(|op| (String::from(*op), Bencode::Dict(BTreeMap::new()))).collect();
+                let mut fancy = BTreeMap::new();
+                fancy.insert(String::from("version-string"), Bencode::str(env!("CARGO_PKG_VERSION")));
+                let mut versions = BTreeMap::new();
+                versions.insert(String::from("fancy"), Bencode::Dict(fancy));
+                send(connection, request, vec!(
+                    ("ops", Bencode::Dict(ops)),
+                    ("versions", Bencode::Dict(versions)),
+                    ("status", status(&["done"]))));
+            },
+            Some("eval") => match request.get_str("code") {
+                Some(code) => self.evaluate(request, connection, session_id, code),
+                None => send(connection, request, vec!(("status", status(&["error", "no-code", "done"])))),
+            },
+            Some("load-file") => match request.get_str("file") {
+                Some(file) => self.evaluate(request, connection, session_id, file),
+                None => send(connection, request, vec!(("status", status(&["error", "no-file", "done"])))),
+            },
+            Some("complete") => {
+                let namespace = self.session_namespace(&session_id);
+                self.enter(request, namespace.as_ref().map(|ns| &ns[..]));
+                let prefix = request.get_str("prefix").or(request.get_str("symbol")).unwrap_or("");
+                send(connection, request, vec!(
+                    ("completions", Bencode::List(self.completions(prefix))),
+                    ("status", status(&["done"]))));
+            },
+            Some("lookup") => {
+                let namespace = self.session_namespace(&session_id);
+                self.enter(request, namespace.as_ref().map(|ns| &ns[..]));
+                let symbol = request.get_str("sym").or(request.get_str("symbol")).unwrap_or("");
+                match self.lookup(symbol) {
+                    Some(info) => send(connection, request, vec!(("info", info), ("status", status(&["done"])))),
+                    None => send(connection, request, vec!(
+                        ("info", Bencode::Dict(BTreeMap::new())),
+                        ("status", status(&["no-info", "done"])))),
+                }
+            },
+            _ => send(connection, request, vec!(("status", status(&["error", "unknown-op", "done"])))),
+        }
+    }
+
+    /// Switches to the namespace the request names, or else the session's.
+    fn enter(&mut self, request: &Bencode, session_namespace: Option<&str>) {
+        let namespace = request.get_str("ns").or(session_namespace).unwrap_or("user");
+        self.fancy.set_namespace(namespace);
+    }
+
+    fn session_namespace(&self, session_id: &Option<String>) -> Option<String> {
+        session_id.as_ref().and_then(|id| self.sessions.get(id)).map(|session| session.namespace.clone())
+    }
+
+    /// Evaluates the forms of the code one by one, answering with the value
+    /// of each and streaming what they print, until one fails.
+    fn evaluate(&mut self, request: &Bencode, connection: &Connection, session_id: Option<String>, code: &str) {
+        let mut session = session_id.as_ref()
+            .and_then(|id| self.sessions.remove(id))
+            .unwrap_or(Session::new(String::from("user")));
+        self.enter(request, Some(&session.namespace));
+        for (name, value) in ["*1", "*2", "*3"].iter().zip(&session.recent) {
+            self.fancy.define(name, value.clone());
+        }
+        self.fancy.define("*e", session.error.clone());
+
+        let forms = match parser::try_parse_string(String::from(code)) {
+            Ok(forms) => forms,
+            Err(e) => {
+                send(connection, request, vec!(("err", Bencode::Str(format!("Read error: {}\n", e)))));
+                send(connection, request, vec!(("status", status(&["eval-error", "done"]))));
+                self.keep(session_id, session);
+                return;
+            },
+        };
+
+        let (out_connection, out_request) = (connection.clone(), request.clone());
+        interpreter::redirect_output(Some(Box::new(move |text: &str| {
+            send(&out_connection, &out_request, vec!(("out", Bencode::str(text))));
+        })));
+        // cleared before anyone can see it's busy, so that an interrupt for
+        // this request isn't lost
+        self.fancy.interrupt().clear();
+        *self.busy.lock().unwrap() = Some((session_id.clone().unwrap_or(String::new()),
+                                          String::from(request.get_str("id").unwrap_or(""))));
+
+        for form in forms {
+            let fancy = &mut self.fancy;
+            let evaluated = panic::catch_unwind(AssertUnwindSafe(|| {
+                fancy.eval_form(&form).and_then(|value| interpreter::pr_str(&value).map(|text| (value, text)))
+            }));
+            let result = match evaluated {
+                Ok(result) => result,
+                Err(_) => Err(interpreter::Error::new(String::from("Internal error"))),
+            };
+            match result {
+                Ok((value, text)) => {
+                    send(connection, request, vec!(
+                        ("value", Bencode::Str(text)),
+                        ("ns", Bencode::Str(self.fancy.namespace()))));
+                    session.recent.insert(0, value);
+                    session.recent.truncate(3);
+                },
+                Err(ref e) if e.interrupt => {
+                    send(connection, request, vec!(("status", status(&["interrupted"]))));
+                    break;
+                },
+                Err(e) => {
+                    send(connection, request, vec!(("err", Bencode::Str(format!("{}\n", e.stack_trace())))));
+                    let class = if e.data == Value::Nil { "Exception" } else { "ExceptionInfo" };
+                    send(connection, request, vec!(
+                        ("ex", Bencode::str(class)),
+                        ("root-ex", Bencode::str(class)),
+                        ("status", status(&["eval-error"]))));
+                    session.error = Value::Error(Rc::new(e));
+                    break;
+                },
+            }
+        }
+
+        *self.busy.lock().unwrap() = None;
+        interpreter::redirect_output(None);
+        session.namespace = self.fancy.namespace();
+        send(connection, request, vec!(
+            ("ns", Bencode::Str(session.namespace.clone())),
+            ("status", status(&["done"]))));
+        self.keep(session_id, session);
+    }
+
+    fn keep(&mut self, session_id: Option<String>, session: Session) {
+        if let Some(id) = session_id {
+            self.sessions.insert(id, session);
+        }
+    }
+
+    fn candidate(&self, name: String, symbol: &str, namespace: &str) -> Bencode {
+        let kind = match self.fancy.find_var(symbol).map(|var| var.value) {
+            Some(Value::Macro(_)) => "macro",
+            Some(Value::Lambda(_)) | Some(Value::Builtin(_)) | Some(Value::Native(_)) => "function",
+            _ => "var",
+        };
+        let mut dict = BTreeMap::new();
+        dict.insert(String::from("candidate"), Bencode::Str(name));
+        dict.insert(String::from("ns"), Bencode::str(namespace));
+        dict.insert(String::from("type"), Bencode::str(kind));
+        Bencode::Dict(dict)
+    }
+
+    /// Names starting with the prefix: those of the current namespace and
+    /// fancy.core, or of another one when the prefix is qualified.
+    fn completions(&self, prefix: &str) -> Vec<Bencode> {
+        let (qualifier, namespaces) = match prefix.find('/') {
+            Some(i) if i > 0 => (Some(&prefix[..i]), vec!(String::from(&prefix[..i]))),
+            _ => (None, vec!(self.fancy.namespace(), String::from("fancy.core"))),
+        };
+
+        let mut seen = vec!();
+        let mut candidates = vec!();
+        for namespace in namespaces {
+            for name in self.fancy.names_in(&namespace).unwrap_or(vec!()) {
+                let symbol = match qualifier {
+                    Some(qualifier) => format!("{}/{}", qualifier, name),
+                    None => name,
+                };
+                if symbol.starts_with(prefix) && !seen.contains(&symbol) {
+                    seen.push(symbol.clone());
+                    candidates.push(self.candidate(symbol.clone(), &symbol, &namespace));
+                }
+            }
+        }
+        candidates
+    }
+
+    fn lookup(&self, symbol: &str) -> Option<Bencode> {
+        self.fancy.find_var(symbol).map(|var| {
+            let mut info = BTreeMap::new();
+            info.insert(String::from("ns"), Bencode::Str(var.namespace.clone()));
+            info.insert(String::from("name"), Bencode::Str(var.name.clone()));
+            if let Some(arglists) = var.arglists() {
+                info.insert(String::from("arglists-str"), Bencode::Str(arglists));
+            }
+            if let Some(doc) = var.doc() {
+                info.insert(String::from("doc"), Bencode::str(doc));
+            }
+            if let Value::Macro(_) = var.value {
+                info.insert(String::from("macro"), Bencode::str("true"));
+            }
+            Bencode::Dict(info)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::{BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use interpreter::Interpreter;
+    use super::{bencode, serve, Bencode};
+
+    struct Client {
+        input: BufReader<TcpStream>,
+        output: TcpStream,
+        id: i64,
+    }
+
+    impl Client {
+        fn connect() -> Client {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            thread::spawn(move || serve(listener, Interpreter::new()));
+
+            let stream = TcpStream::connect(address).unwrap();
+            Client { input: BufReader::new(stream.try_clone().unwrap()), output: stream, id: 0 }
+        }
+
+        /// Sends a request without waiting for the answer; returns its id.
+        fn send(&mut self, fields: Vec<(&str, &str)>) -> String {
+            self.id += 1;
+            let mut dict = BTreeMap::new();
+            dict.insert(String::from("id"), Bencode::Str(self.id.to_string()));
+            for (key, value) in fields {
+                dict.insert(String::from(key), Bencode::str(value));
+            }
+            let mut bytes = vec!();
+            bencode::write(&mut bytes, &Bencode::Dict(dict)).unwrap();
+            self.output.write_all(&bytes).unwrap();
+            self.id.to_string()
+        }
+
+        fn read(&mut self) -> Bencode {
+            bencode::read(&mut self.input).unwrap().unwrap()
+        }
+
+        /// Sends a request and collects the responses up to the one that
+        /// says it's done.
+        fn request(&mut self, fields: Vec<(&str, &str)>) -> Vec<Bencode> {
+            let id = self.send(fields);
+            let mut responses = vec!();
+            loop {
+                let response = self.read();
+                assert_eq!(Some(&id[..]), response.get_str("id"));
+                let done = has_status(&response, "done");
+                responses.push(response);
+                if done {
+                    return responses;
+                }
+            }
+        }
+    }
+
+    fn has_status(response: &Bencode, name: &str) -> bool {
+        match response.get("status") {
+            Some(&Bencode::List(ref names)) => names.contains(&Bencode::str(name)),
+            _ => false,
+        }
+    }
+
+    fn strings(responses: &[Bencode], key: &str) -> Vec<String> {
+        responses.iter().filter_map(|r| r.get_str(key)).map(String::from).collect()
+    }
+
+    #[test]
+    fn eval_in_a_session() {
+        let mut client = Client::connect();
+        let session = client.request(vec!(("op", "clone")))[0].get_str("new-session").unwrap().to_string();
+
+        let responses = client.request(vec!(("op", "eval"), ("session", &session),
+                                            ("code", "(println \"hi\") (def x 41) (+ x 1)")));
+        assert_eq!(vec!("hi\n"), strings(&responses, "out"));
+        assert_eq!(vec!("nil", "x", "42"), strings(&responses, "value"));
+
+        let responses = client.request(vec!(("op", "eval"), ("session", &session), ("code", "(ns other) (inc *1)")));
+        assert_eq!(vec!("nil", "43"), strings(&responses, "value"));
+        let responses = client.request(vec!(("op", "eval"), ("session", &session), ("code", "user/x")));
+        assert_eq!(vec!("other", "other"), strings(&responses, "ns"));
+        assert_eq!(vec!("41"), strings(&responses, "value"));
+
+        let responses = client.request(vec!(("op", "eval"), ("session", &session), ("code", "(/ 1 0) 2")));
+        assert_eq!(vec!("Divide by zero\n    at /\n"), strings(&responses, "err"));
+        assert_eq!(vec!("Exception"), strings(&responses, "ex"));
+        assert!(strings(&responses, "value").is_empty());
+        let responses = client.request(vec!(("op", "eval"), ("session", &session), ("code", "(ex-message *e)")));
+        assert_eq!(vec!("\"Divide by zero\""), strings(&responses, "value"));
+    }
+
+    #[test]
+    fn load_file() {
+        let mut client = Client::connect();
+        let session = client.request(vec!(("op", "clone")))[0].get_str("new-session").unwrap().to_string();
+
+        let responses = client.request(vec!(("op", "load-file"), ("session", &session),
+                                            ("file", "(ns loaded) (defn twice [x] (* 2 x)) (twice 21)"),
+                                            ("file-path", "loaded.fc")));
+        assert_eq!(Some("42"), strings(&responses, "value").last().map(|v| &v[..]));
+        assert_eq!(Some("loaded"), strings(&responses, "ns").last().map(|ns| &ns[..]));
+        let responses = client.request(vec!(("op", "eval"), ("session", &session), ("code", "(loaded/twice 5)")));
+        assert_eq!(vec!("10"), strings(&responses, "value"));
+
+        let responses = client.request(vec!(("op", "load-file"), ("session", &session), ("file", "(defn broken [")));
+        assert_eq!(vec!("Read error: expected \"]\", got nothing\n"), strings(&responses, "err"));
+        let missing = client.request(vec!(("op", "load-file"), ("session", &session)));
+        assert!(has_status(&missing[0], "no-file"));
+    }
+
+    #[test]
+    fn interrupt_a_running_eval() {
+        let mut client = Client::connect();
+        let session = client.request(vec!(("op", "clone")))[0].get_str("new-session").unwrap().to_string();
+
+        let eval = client.send(vec!(("op", "eval"), ("session", &session),
+                                    ("code", "(println \"started\") (count (range)) :never")));
+        // it's busy once it has printed
+        while client.read().get_str("out") != Some("started\n") {}
+        let interrupt = client.send(vec!(("op", "interrupt"), ("session", &session), ("interrupt-id", &eval)));
+
+        let (mut eval_responses, mut interrupt_responses) = (vec!(), vec!());
+        while eval_responses.iter().chain(&interrupt_responses).filter(|r| has_status(r, "done")).count() < 2 {
+            let response = client.read();
+            if response.get_str("id") == Some(&eval[..]) {
+                eval_responses.push(response);
+            }
+            else {
+                assert_eq!(Some(&interrupt[..]), response.get_str("id"));
+                interrupt_responses.push(response);
+            }
+        }
+        assert!(eval_responses.iter().any(|r| has_status(r, "interrupted")));
+        assert!(!strings(&eval_responses, "value").contains(&String::from(":never")));
+        assert!(!interrupt_responses.iter().any(|r| has_status(r, "session-idle")));
+
+        let responses = client.request(vec!(("op", "eval"), ("session", &session), ("code", "(+ 1 2)")));
+        assert_eq!(vec!("3"), strings(&responses, "value"));
+    }
+
+    #[test]
+    fn describe_complete_and_lookup() {
+        let mut client = Client::connect();
+        let session = client.request(vec!(("op", "clone")))[0].get_str("new-session").unwrap().to_string();
+
+        let ops = client.request(vec!(("op", "describe")))[0].get("ops").cloned().unwrap();
+        assert!(ops.get("eval").is_some() && ops.get("interrupt").is_some());
+
+        client.request(vec!(("op", "eval"), ("session", &session),
+                            ("code", "(defn square \"Squares x.\" [x] (* x x)) (require (quote [fancy.string :as s]))")));
+        let completions = client.request(vec!(("op", "complete"), ("session", &session), ("prefix", "squ")));
+        let candidates = match completions[0].get("completions") {
+            Some(&Bencode::List(ref items)) => strings(items, "candidate"),
+            _ => vec!(),
+        };
+        assert_eq!(vec!("square"), candidates);
+        let completions = client.request(vec!(("op", "complete"), ("session", &session), ("prefix", "s/up")));
+        assert_eq!(Some("s/upper-case"), completions[0].get("completions")
+                   .and_then(|c| match *c { Bencode::List(ref items) => items[0].get_str("candidate"), _ => None }));
+
+        let info = client.request(vec!(("op", "lookup"), ("session", &session), ("sym", "square")))[0]
+            .get("info").cloned().unwrap();
+        assert_eq!(Some("Squares x."), info.get_str("doc"));
+        assert_eq!(Some("([x])"), info.get_str("arglists-str"));
+        assert_eq!(Some("user"), info.get_str("ns"));
+
+        let idle = client.request(vec!(("op", "interrupt"), ("session", &session)));
+        assert_eq!(Some(&Bencode::List(vec!(Bencode::str("session-idle"), Bencode::str("done")))),
+                   idle[0].get("status"));
+        let unknown = client.request(vec!(("op", "eval"), ("session", "nope"), ("code", "1")));
+        assert!(strings(&unknown, "value").is_empty());
+    }
+}
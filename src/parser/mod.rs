@@ -1,9 +1,10 @@
 mod tokenizer;
 mod reader;
 
-use std::io::{BufRead, Read};
+use std::fmt;
+use std::io::BufRead;
 
-use self::reader::Form;
+pub use self::reader::Form;
 use self::tokenizer as tok;
 
 const LIST: (&'static str, &'static str) = ("(",")");
@@ -20,6 +21,7 @@ const END_CHARS: [char; 3] = [')', '}', ']'];
 const QUOTE: char = '"';
 const DISPATCH : char = '#';
 const COMMENT: char = ';';
+const DEREF: char = '@';
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Expression {
@@ -30,6 +32,48 @@ pub enum Expression {
     Params(Vec<Expression>)
 }
 
+/// Where a form starts in the source, counted from one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+fn write_all(f: &mut fmt::Formatter, open: &str, items: &[Expression], close: &str) -> fmt::Result {
+    try!(write!(f, "{}", open));
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            try!(write!(f, " "));
+        }
+        try!(write!(f, "{}", item));
+    }
+    write!(f, "{}", close)
+}
+
+/// Prints the expression back as source, with the collections the reader
+/// turned into calls written as literals again.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expression::Symbol(ref s) | Expression::Number(ref s) => write!(f, "{}", s),
+            Expression::String(ref s) => write!(f, "\"{}\"", s),
+            Expression::Params(ref items) => write_all(f, "#[", items, "]"),
+            Expression::SExpression(ref items) => match items.first() {
+                Some(&Expression::Symbol(ref s)) if s == "vector" => write_all(f, "[", &items[1..], "]"),
+                Some(&Expression::Symbol(ref s)) if s == "hash-map" => write_all(f, "{", &items[1..], "}"),
+                Some(&Expression::Symbol(ref s)) if s == "set" => write_all(f, "#{", &items[1..], "}"),
+                _ => write_all(f, "(", items, ")"),
+            },
+        }
+    }
+}
+
 pub struct ExpressionStream<'rf> {
     forms: &'rf mut Iterator<Item = Form>,
 }
@@ -47,12 +91,40 @@ pub fn number(name: &'static str) -> Expression {
     Expression::Number(String::from(name))
 }
 
-fn dispatch(value: String, inner: Vec<Form>) -> Expression {
+fn dispatch(value: String, inner: Vec<Form>) -> Result<Expression, String> {
 
     match value.as_ref() {
-        "#{" => Expression::SExpression(prepend(symbol("set"), parse_vec(inner))),
-        "#[" => Expression::Params(parse_vec(inner)),
-        _ => panic!("Unknow dispatch value {}", value)
+        "#{" => Ok(Expression::SExpression(prepend(symbol("set"), try!(parse_vec(inner))))),
+        "#[" => Ok(Expression::Params(try!(parse_vec(inner)))),
+        _ => Err(format!("Unknow dispatch value {}", value))
+    }
+}
+
+fn expression(form: Form) -> Result<Expression, String> {
+    match form {
+        Form::List(inner) =>
+            Ok(Expression::SExpression(try!(parse_vec(inner)))),
+        Form::Vector(inner) =>
+            Ok(Expression::SExpression(
+                prepend(symbol("vector"), try!(parse_vec(inner))))),
+        Form::Map(inner) =>
+            Ok(Expression::SExpression(
+                prepend(symbol("hash-map"), try!(parse_vec(inner))))),
+        Form::Literal(value) => {
+            let chars = value.chars().collect::<Vec<char>>();
+            if chars[0].is_numeric() ||
+                (chars.len() > 1 && chars[0] == '-' && chars[1].is_numeric()) {
+                Ok(Expression::Number(value))
+            }
+            else {
+                Ok(Expression::Symbol(value))
+            }
+        },
+        Form::String(value) =>
+            Ok(Expression::String(value)),
+        Form::Dispatch(value, inner) =>
+            dispatch(value, inner),
+        _ => unreachable!("expression error")
     }
 }
 
@@ -60,43 +132,13 @@ impl<'a> Iterator for ExpressionStream<'a> {
     type Item = Expression;
 
     fn next(&mut self) -> Option<Expression> {
-        //None::<Expression>
-        if let Some(t) = self.forms.next() {
-            match t.clone() {
-                Form::List(inner) =>
-                    return Some(Expression::SExpression(parse_vec(inner))),
-                Form::Vector(inner) =>
-                    return Some(Expression::SExpression(
-                        prepend(symbol("vector"),(parse_vec(inner))))),
-                Form::Map(inner) =>
-                    return Some(Expression::SExpression(
-                        prepend(symbol("hash-map"),(parse_vec(inner))))),
-                Form::Literal(value) => {
-                    let chars = value.chars().collect::<Vec<char>>();
-                    if chars[0].is_numeric() ||
-                        (chars.len() > 1 && chars[0] == '-' && chars[1].is_numeric()) {
-                        return Some(Expression::Number(value));
-                    }
-                    else {
-                        return Some(Expression::Symbol(value));
-                    }
-                },
-                Form::String(value) =>
-                    return Some(Expression::String(value)),
-                Form::Dispatch(value, inner) =>
-                    return Some(dispatch(value, inner)),
-               _ => unreachable!("expression error")
-           }
-        }
-        else {
-            None::<Expression>
-        }
+        self.forms.next().map(|form| expression(form).unwrap_or_else(|e| panic!("read error: {}", e)))
     }
 }
 
-fn parse_vec(forms: Vec<Form>) -> Vec<Expression>
+fn parse_vec(forms: Vec<Form>) -> Result<Vec<Expression>, String>
 {
-    return parse(&mut forms.into_iter()).collect();
+    forms.into_iter().map(expression).collect()
 }
 
 fn parse<'rf>(forms: &'rf mut Iterator<Item = Form>) -> ExpressionStream<'rf>
@@ -104,26 +146,169 @@ fn parse<'rf>(forms: &'rf mut Iterator<Item = Form>) -> ExpressionStream<'rf>
     ExpressionStream{forms: forms}
 }
 
-pub fn parse_string(s: String) -> Vec<Expression> {
+/// Whether the source ends outside of any string or collection, so that
+/// reading it won't stop in the middle of a form. Input with unmatched
+/// closing brackets counts as complete, for the reader to reject.
+pub fn is_complete(source: &str) -> bool {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut last = None;
+    for token in tok::tokenize(String::from(source)) {
+        if token == STRING.0 {
+            in_string = !in_string;
+        }
+        else if !in_string {
+            match token.chars().last() {
+                Some(c) if START_CHARS.contains(&c) => depth += 1,
+                Some(c) if END_CHARS.contains(&c) => depth -= 1,
+                _ => (),
+            }
+        }
+        last = Some(token);
+    }
+    depth <= 0 && !in_string && last != Some(DEREF.to_string())
+}
+
+/// The tokens of the source, as the reader sees them.
+pub fn tokens(s: String) -> Vec<String> {
+    tok::tokenize(s).collect()
+}
+
+/// The forms the reader builds from the tokens of the source.
+pub fn read_string(s: String) -> Vec<Form> {
     let mut tokens = tok::tokenize(s);
-    let mut forms = reader::read(&mut tokens);
+    let mut reader = reader::read(&mut tokens);
+    let forms = reader.by_ref().collect();
+    check_read(&reader);
+    forms
+}
+
+/// Panics with the error the reader stopped at, if any; for the callers
+/// that take malformed source as a bug.
+fn check_read(forms: &reader::FormStream) {
+    if let Some(e) = forms.error() {
+        panic!("read error: {}", e);
+    }
+}
+
+/// The expressions of the source, or what is wrong with it.
+pub fn try_parse_string(s: String) -> Result<Vec<Expression>, String> {
+    let mut tokens = tok::tokenize(s);
+    let mut reader = reader::read(&mut tokens);
+    let forms: Vec<Form> = reader.by_ref().collect();
+    if let Some(e) = reader.error() {
+        return Err(e);
+    }
+    parse_vec(forms)
+}
 
-    let expressions = parse(&mut forms);
-    expressions.collect::<Vec<Expression>>()
+pub fn parse_string(s: String) -> Vec<Expression> {
+    try_parse_string(s).unwrap_or_else(|e| panic!("read error: {}", e))
+}
+
+/// The top-level forms of the source, each with the position it starts at.
+pub fn parse_located(s: String) -> Vec<(Position, Expression)> {
+    positions(&s).into_iter().zip(parse_string(s)).collect()
+}
+
+/// Where each top-level form of the source starts. A form is counted where
+/// its first token is read at the top level; `@form` is one form.
+fn positions(source: &str) -> Vec<Position> {
+    let mut tokens = tok::tokenize(String::from(source));
+    let mut starts = vec!();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut deref = false;
+    while let Some(token) = tokens.next() {
+        if in_string {
+            in_string = token != STRING.1;
+            continue;
+        }
+
+        let last = token.chars().last();
+        let closes = last.map_or(false, |c| END_CHARS.contains(&c));
+        if depth == 0 && !closes {
+            if !deref {
+                let (line, column) = tokens.start();
+                starts.push(Position { line: line + 1, column: column + 1 });
+            }
+            deref = token == DEREF.to_string();
+        }
+
+        if token == STRING.0 {
+            in_string = true;
+        }
+        else if last.map_or(false, |c| START_CHARS.contains(&c)) {
+            depth += 1;
+        }
+        else if closes {
+            depth -= 1;
+        }
+    }
+    starts
 }
 
 pub fn parse_file(path: String) -> Vec<Expression> {
     let mut tokens = tok::tokenize_file(path);
     let mut forms = reader::read(&mut tokens);
 
-    let expression = parse(&mut forms);
-    expression.collect::<Vec<Expression>>()
+    let expression = parse(&mut forms).collect::<Vec<Expression>>();
+    check_read(&forms);
+    expression
 }
 
 pub fn parse_buffer<R: BufRead>(reader: R) -> Vec<Expression> {
     let mut tokens = tok::tokenize_stream(reader);
     let mut forms = reader::read(&mut tokens);
 
-    let expression = parse(&mut forms);
-    expression.collect::<Vec<Expression>>()
+    let expression = parse(&mut forms).collect::<Vec<Expression>>();
+    check_read(&forms);
+    expression
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_complete, parse_located, parse_string, try_parse_string, Position};
+
+    #[test]
+    fn complete_input() {
+        assert!(is_complete("(+ 1 2)"));
+        assert!(is_complete("a b"));
+        assert!(is_complete(""));
+        assert!(is_complete("(a))"));
+        assert!(!is_complete("(defn f [x]\n  (+ x"));
+        assert!(!is_complete("{:a [1 2"));
+        assert!(!is_complete("#{1"));
+        assert!(!is_complete("(str \"(unclosed"));
+        assert!(is_complete("(str \"(\" \")\")"));
+        assert!(!is_complete("@"));
+    }
+
+    #[test]
+    fn top_level_positions() {
+        let source = "; comment\n(def a 1)\n  \"s\" @b [1 (2)]\n#{:x} c";
+        let positions: Vec<(usize, usize)> = parse_located(String::from(source)).iter()
+            .map(|&(Position { line, column }, _)| (line, column)).collect();
+        assert_eq!(vec!((2, 1), (3, 3), (3, 7), (3, 10), (4, 1), (4, 7)), positions);
+        assert_eq!(parse_string(String::from(source)),
+                   parse_located(String::from(source)).into_iter().map(|(_, e)| e).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn malformed_source_is_an_error() {
+        assert_eq!(Err(String::from("expected \")\", got nothing")), try_parse_string(String::from("(")));
+        assert_eq!(Err(String::from("Code ends with opening string")), try_parse_string(String::from("\"")));
+        assert!(try_parse_string(String::from("(str \"a")).is_err());
+        assert_eq!(Err(String::from("@ must be followed by a form")), try_parse_string(String::from("@")));
+        assert!(try_parse_string(String::from("[1 2)")).is_err());
+        assert!(try_parse_string(String::from("#<x>")).is_err());
+        assert_eq!(Ok(parse_string(String::from("(+ 1 [2])"))), try_parse_string(String::from("(+ 1 [2])")));
+    }
+
+    #[test]
+    fn print_as_source() {
+        let source = "(defn f [x & more] {:a #{1} :b \"s\"} (g x))";
+        let printed: Vec<String> = parse_string(String::from(source)).iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec!(source), printed);
+    }
 }
@@ -1,5 +1,5 @@
 use super::tokenizer::Token;
-use super::{LIST,VECTOR,MAP,STRING,DISPATCH,START_CHARS};
+use super::{LIST,VECTOR,MAP,STRING,DISPATCH,DEREF,START_CHARS};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Form {
@@ -25,7 +25,8 @@ pub struct FormStream<'rf> {
     tokens: &'rf mut Iterator<Item = Token>,
     current_token: Option<Token>,
     next_token: Option<Token>,
-    outer: Outer
+    outer: Outer,
+    error: Option<String>,
 }
 
 impl<'rf> FormStream<'rf> {
@@ -55,14 +56,26 @@ impl<'rf> FormStream<'rf> {
     }
 
     fn assert_token_string (&mut self, expected_token: String) {
-        if let Some(ref t) = self.current_token {
-            if *t != expected_token {
-                panic!("expected {:?}, got {:?}", expected_token, t);
-            }
-        }
-        else {
-            panic!("expected {:?}, got nothing", expected_token);
+        let message = match self.current_token {
+            Some(ref t) if *t == expected_token => return,
+            Some(ref t) => format!("expected {:?}, got {:?}", expected_token, t),
+            None => format!("expected {:?}, got nothing", expected_token),
+        };
+        self.fail(message);
+    }
+
+    /// Stops reading at the first error; the forms read so far end there.
+    fn fail(&mut self, message: String) {
+        if self.error.is_none() {
+            self.error = Some(message);
         }
+        self.current_token = None;
+        self.next_token = None;
+    }
+
+    /// The error reading stopped at, if it did.
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
     }
 }
 
@@ -71,6 +84,9 @@ impl<'a> Iterator for FormStream<'a> {
 
     fn next(&mut self) -> Option<Form> {
         let mut form = Form::None;
+        if self.error.is_some() {
+            return None;
+        }
 
         if let Some(t) = self.current_token.clone() {
             if t == LIST.0 {
@@ -98,7 +114,8 @@ impl<'a> Iterator for FormStream<'a> {
                     }
                 }
                 else {
-                    panic!("Code ends with opening string");
+                    self.fail(String::from("Code ends with opening string"));
+                    return None;
                 }
 
                 self.assert_token(STRING.0);
@@ -125,6 +142,17 @@ impl<'a> Iterator for FormStream<'a> {
 
                 form = Form::Dispatch(t, inner);
             }
+            else if t == DEREF.to_string() {
+                // @form reads as (deref form)
+                self.read_next();
+                match self.next() {
+                    Some(inner) => return Some(Form::List(vec!(Form::Literal(String::from("deref")), inner))),
+                    None => {
+                        self.fail(format!("{} must be followed by a form", DEREF));
+                        return None;
+                    },
+                }
+            }
             else if t != LIST.1 && t != VECTOR.1 && t != MAP.1 {
                 form = Form::Literal(t);
             }
@@ -138,7 +166,8 @@ impl<'a> Iterator for FormStream<'a> {
                 (self.outer != Outer::Map && t == MAP.1)
             {
 
-                panic!("read error: unmatched closing token for {:?}", self.outer);
+                let message = format!("unmatched closing token for {:?}", self.outer);
+                self.fail(message);
             }
             else { // nothing
             }
@@ -149,7 +178,7 @@ impl<'a> Iterator for FormStream<'a> {
          }
 
 
-        if form == Form::None {
+        if form == Form::None || self.error.is_some() {
             return None::<Form>;
         }
         else {
@@ -162,5 +191,5 @@ impl<'a> Iterator for FormStream<'a> {
 pub fn read<'rf>(tokens: &'rf mut Iterator<Item = Token>) -> FormStream<'rf> {
     let current = tokens.next();
     let next = tokens.next();
-    FormStream{tokens: tokens, current_token: current, next_token: next, outer: Outer::None}
+    FormStream{tokens: tokens, current_token: current, next_token: next, outer: Outer::None, error: None}
 }
@@ -1,8 +1,8 @@
 use std::error::Error as Err;
 use std::fs::File;
-use std::io::{BufReader, BufRead, Read};
+use std::io::{BufReader, BufRead};
 use std::path::Path;
-use super::{QUOTE,START_CHARS,END_CHARS,DISPATCH, COMMENT, LIST_CHARS, VECTOR_CHARS, MAP_CHARS};
+use super::{QUOTE,START_CHARS,END_CHARS,DISPATCH, COMMENT, DEREF, LIST_CHARS, VECTOR_CHARS, MAP_CHARS};
 
 pub type Token = String;
 
@@ -11,17 +11,22 @@ pub trait Reader {
     fn next_char(&self) -> Option<char>;
     fn pop(&mut self);
     fn flush_line(&mut self);
+    /// The line and column of the current char, counted from zero.
+    fn position(&self) -> (usize, usize);
 }
 
 pub struct StringReader {
     chars: Vec<char>,
     size: usize,
-    index: usize
+    index: usize,
+    line: usize,
+    column: usize
 }
 
 impl StringReader {
     fn new (str: & String) -> StringReader {
-        StringReader{chars: str.chars().collect(), size: str.len(), index: 0}
+        let chars = str.chars().collect::<Vec<char>>();
+        StringReader{size: chars.len(), chars: chars, index: 0, line: 0, column: 0}
     }
 }
 
@@ -45,11 +50,25 @@ impl Reader for StringReader {
     }
 
     fn pop(&mut self) {
+        if self.current_char() == Some('\n') {
+            self.line = self.line + 1;
+            self.column = 0;
+        }
+        else {
+            self.column = self.column + 1;
+        }
         self.index = self.index + 1;
     }
 
     fn flush_line(&mut self) {
-        self.index = self.size + 1;
+        while self.index < self.size && self.chars[self.index] != '\n' {
+            self.index = self.index + 1;
+            self.column = self.column + 1;
+        }
+    }
+
+    fn position(&self) -> (usize, usize) {
+        (self.line, self.column)
     }
 }
 
@@ -80,7 +99,8 @@ impl LineReader
     fn from_buffer<R: BufRead>(reader: R) -> Self {
         let lines = reader.lines()
             .filter_map(|result| result.ok())
-            .map(|s| (s.len(), s.chars().collect()))
+            .map(|s| s.chars().collect::<Vec<char>>())
+            .map(|chars| (chars.len(), chars))
             .collect::<Vec<(usize, Vec<char>)>>();
 
         LineReader{lines: lines,
@@ -144,11 +164,24 @@ impl Reader for LineReader {
         self.current = (self.current.0 + 1, 0);
         self.next = (self.next.0 + 1, 1);
     }
+
+    fn position(&self) -> (usize, usize) {
+        self.current
+    }
 }
 
 pub struct TokenStream<T: Reader + Sized> {
     reader: T,
-    stringing: bool
+    stringing: bool,
+    start: (usize, usize)
+}
+
+impl<T: Reader + Sized> TokenStream<T> {
+    /// The line and column the last token read starts at, counted from
+    /// zero.
+    pub fn start(&self) -> (usize, usize) {
+        self.start
+    }
 }
 
 fn is_whitespace(c: char) -> bool {
@@ -163,6 +196,10 @@ impl<T: Reader + Sized> Iterator for TokenStream<T> {
         let mut ready = false;
 
         while let Some(c) = self.reader.current_char() {
+            if token.is_empty() {
+                self.start = self.reader.position();
+            }
+
             if c == QUOTE {
                 token.push(c);
                 ready = true;
@@ -186,7 +223,8 @@ impl<T: Reader + Sized> Iterator for TokenStream<T> {
             else if is_whitespace(c)  {
 
             }
-            else if START_CHARS.contains(&c) || END_CHARS.contains(&c) {
+            else if START_CHARS.contains(&c) || END_CHARS.contains(&c) ||
+                (c == DEREF && token.is_empty()) {
                 token.push(c);
                 ready = true;
             }
@@ -228,17 +266,17 @@ impl<T: Reader + Sized> Iterator for TokenStream<T> {
 
 pub fn tokenize(str: String) -> TokenStream<StringReader> {
     let reader = StringReader::new(&str);
-    TokenStream {reader: reader, stringing: false}
+    TokenStream {reader: reader, stringing: false, start: (0, 0)}
 }
 
 pub fn tokenize_file(path: String) -> TokenStream<LineReader> {
     let reader = LineReader::from_file(&path);
-    TokenStream {reader: reader, stringing: false}
+    TokenStream {reader: reader, stringing: false, start: (0, 0)}
 }
 
 pub fn tokenize_stream<R: BufRead>(buf_reader: R) -> TokenStream<LineReader> {
     let reader = LineReader::from_buffer(buf_reader);
-    TokenStream {reader: reader, stringing: false}
+    TokenStream {reader: reader, stringing: false, start: (0, 0)}
 }
 
 #[cfg(test)]
@@ -281,6 +319,8 @@ mod tests {
                    token_vector("(1) [2] 12   \";;12\" {"));
         assert_eq!(vec!("\"", "str 1", "\"", "\"", "str 2", "\""),
                    token_vector("\"str 1\" \"str 2\" "));
+        assert_eq!(vec!("(", "ñ", "\"", "日本", "\"", ")"),
+                   token_vector("(ñ \"日本\")"));
     }
 
     #[test]
@@ -299,11 +339,19 @@ mod tests {
         assert_eq!(vec!("#", "[", "a"), token_vector("#[a"));
     }
 
+    #[test]
+    fn deref() {
+        assert_eq!(vec!("@", "a"), token_vector("@a"));
+        assert_eq!(vec!("(", "@", "(", "f", ")", ")"), token_vector("(@(f))"));
+        assert_eq!(vec!("a@b"), token_vector("a@b"));
+    }
+
     #[test]
     fn comment() {
         assert_eq!(vec!("[","[","]","]"), token_vector("[[]] ;; comment here"));
         assert_eq!(vec!("\"","with ;; in string too","\""),
                    token_vector("\"with ;; in string too\""));
+        assert_eq!(vec!("a", "b"), token_vector("a ; comment\nb"));
     }
 
     #[test]
@@ -316,5 +364,8 @@ mod tests {
 
         assert_eq!(vec!("(", "defn","test","[","a","]","(","+","1","a",")",")"),
                    tokens_from_file("resources/tokenizer/withcomments.fc"));
+
+        assert_eq!(vec!("(","str","\"","日本語","\"","\"","ñ","\"",")"),
+                   tokens_from_file("resources/tokenizer/unicode.fc"));
     }
 }
@@ -0,0 +1,341 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+
+/// Entries kept in the history file.
+const HISTORY_SIZE: usize = 1000;
+
+pub enum ReadLine {
+    Line(String),
+    /// Ctrl-C: the line being edited is thrown away.
+    Interrupted,
+    /// Ctrl-D on an empty line, or the end of input.
+    Eof,
+}
+
+/// Reads lines from the terminal with basic editing: the arrow keys move
+/// through the line and the history, and the emacs keys most shells have
+/// work too. When standard input isn't a terminal it just reads lines.
+pub struct Editor {
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    KillToEnd,
+    KillLine,
+    Interrupt,
+    /// Ctrl-D
+    Eof,
+    /// The end of input.
+    Closed,
+    Other,
+}
+
+impl Editor {
+    /// An editor whose history is loaded from, and saved to, the file.
+    pub fn new(history_path: Option<PathBuf>) -> Editor {
+        let mut history = vec!();
+        if let Some(file) = history_path.as_ref().and_then(|path| File::open(path).ok()) {
+            history = BufReader::new(file).lines().filter_map(|line| line.ok()).collect();
+        }
+        Editor { history: history, history_path: history_path }
+    }
+
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<ReadLine> {
+        try!(write!(io::stdout(), "{}", prompt));
+        try!(io::stdout().flush());
+
+        match terminal::RawMode::enable() {
+            Some(raw) => {
+                let result = self.edit(prompt);
+                drop(raw);
+                try!(write!(io::stdout(), "\r\n"));
+                result
+            },
+            None => {
+                let mut line = String::new();
+                match try!(io::stdin().read_line(&mut line)) {
+                    0 => Ok(ReadLine::Eof),
+                    _ => Ok(ReadLine::Line(String::from(line.trim_right_matches(|c| c == '\n' || c == '\r')))),
+                }
+            },
+        }
+    }
+
+    /// Adds an entry to the history, and appends it to the history file.
+    pub fn add_history(&mut self, entry: &str) {
+        let entry = entry.replace('\n', " ");
+        if entry.is_empty() || self.history.last() == Some(&entry) {
+            return;
+        }
+        self.history.push(entry.clone());
+
+        let path = match self.history_path {
+            Some(ref path) => path,
+            None => return,
+        };
+        if self.history.len() > HISTORY_SIZE {
+            let start = self.history.len() - HISTORY_SIZE;
+            self.history.drain(..start);
+            let text: String = self.history.iter().map(|line| format!("{}\n", line)).collect();
+            File::create(path).and_then(|mut file| file.write_all(text.as_bytes())).ok();
+        }
+        else {
+            OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}", entry)).ok();
+        }
+    }
+
+    fn edit(&mut self, prompt: &str) -> io::Result<ReadLine> {
+        let mut line: Vec<char> = vec!();
+        let mut cursor = 0;
+        // the entry shown when browsing history; the line being typed is
+        // kept as the one past the end
+        let mut browsing = self.history.len();
+        let mut typed: Vec<char> = vec!();
+
+        loop {
+            match try!(read_key()) {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                },
+                Key::Enter => return Ok(ReadLine::Line(line.into_iter().collect())),
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                },
+                Key::Delete if cursor < line.len() => {
+                    line.remove(cursor);
+                },
+                Key::Left if cursor > 0 => cursor -= 1,
+                Key::Right if cursor < line.len() => cursor += 1,
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::KillToEnd => line.truncate(cursor),
+                Key::KillLine => {
+                    line.clear();
+                    cursor = 0;
+                },
+                Key::Up if browsing > 0 => {
+                    if browsing == self.history.len() {
+                        typed = line.clone();
+                    }
+                    browsing -= 1;
+                    line = self.history[browsing].chars().collect();
+                    cursor = line.len();
+                },
+                Key::Down if browsing < self.history.len() => {
+                    browsing += 1;
+                    line = if browsing == self.history.len() {
+                        typed.clone()
+                    }
+                    else {
+                        self.history[browsing].chars().collect()
+                    };
+                    cursor = line.len();
+                },
+                Key::Interrupt => return Ok(ReadLine::Interrupted),
+                Key::Eof if line.is_empty() => return Ok(ReadLine::Eof),
+                Key::Eof if cursor < line.len() => {
+                    line.remove(cursor);
+                },
+                Key::Closed => return Ok(ReadLine::Eof),
+                _ => (),
+            }
+            try!(redraw(prompt, &line, cursor));
+        }
+    }
+}
+
+fn redraw(prompt: &str, line: &[char], cursor: usize) -> io::Result<()> {
+    let text: String = line.iter().cloned().collect();
+    let mut out = io::stdout();
+    try!(write!(out, "\r{}{}\x1b[K\r", prompt, text));
+    let column = prompt.chars().count() + cursor;
+    if column > 0 {
+        try!(write!(out, "\x1b[{}C", column));
+    }
+    out.flush()
+}
+
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    match try!(io::stdin().read(&mut byte)) {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn read_key() -> io::Result<Key> {
+    let byte = match try!(read_byte()) {
+        Some(byte) => byte,
+        None => return Ok(Key::Closed),
+    };
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        127 | 8 => Key::Backspace,
+        1 => Key::Home,
+        2 => Key::Left,
+        3 => Key::Interrupt,
+        4 => Key::Eof,
+        5 => Key::End,
+        6 => Key::Right,
+        11 => Key::KillToEnd,
+        14 => Key::Down,
+        16 => Key::Up,
+        21 => Key::KillLine,
+        27 => try!(read_escape()),
+        b if b < 32 => Key::Other,
+        b => try!(read_char(b)),
+    };
+    Ok(key)
+}
+
+/// `ESC [ x` and `ESC O x` sequences sent by the arrow, home, end and delete
+/// keys.
+fn read_escape() -> io::Result<Key> {
+    let kind = try!(read_byte());
+    if kind != Some(b'[') && kind != Some(b'O') {
+        return Ok(Key::Other);
+    }
+    let key = match try!(read_byte()) {
+        Some(b'A') => Key::Up,
+        Some(b'B') => Key::Down,
+        Some(b'C') => Key::Right,
+        Some(b'D') => Key::Left,
+        Some(b'H') => Key::Home,
+        Some(b'F') => Key::End,
+        Some(digit @ b'0'...b'9') => {
+            let mut code = vec!(digit);
+            loop {
+                match try!(read_byte()) {
+                    Some(b'~') | None => break,
+                    Some(b) => code.push(b),
+                }
+            }
+            match &code[..] {
+                b"1" | b"7" => Key::Home,
+                b"4" | b"8" => Key::End,
+                b"3" => Key::Delete,
+                _ => Key::Other,
+            }
+        },
+        _ => Key::Other,
+    };
+    Ok(key)
+}
+
+/// Reads the rest of a UTF-8 encoded char starting with the byte.
+fn read_char(first: u8) -> io::Result<Key> {
+    let len = if first >= 0xf0 { 4 } else if first >= 0xe0 { 3 } else if first >= 0xc0 { 2 } else { 1 };
+    let mut bytes = vec!(first);
+    while bytes.len() < len {
+        match try!(read_byte()) {
+            Some(b) => bytes.push(b),
+            None => break,
+        }
+    }
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(s.chars().next().map(Key::Char).unwrap_or(Key::Other)),
+        Err(_) => Ok(Key::Other),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod terminal {
+    use std::os::raw::{c_int, c_uint, c_uchar};
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Termios {
+        c_iflag: c_uint,
+        c_oflag: c_uint,
+        c_cflag: c_uint,
+        c_lflag: c_uint,
+        c_line: c_uchar,
+        c_cc: [c_uchar; 32],
+        c_ispeed: c_uint,
+        c_ospeed: c_uint,
+    }
+
+    extern "C" {
+        fn isatty(fd: c_int) -> c_int;
+        fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+        fn tcsetattr(fd: c_int, action: c_int, termios: *const Termios) -> c_int;
+    }
+
+    const STDIN: c_int = 0;
+    const TCSAFLUSH: c_int = 2;
+    const ISIG: c_uint = 0o1;
+    const ICANON: c_uint = 0o2;
+    const ECHO: c_uint = 0o10;
+    const IEXTEN: c_uint = 0o100000;
+    const ICRNL: c_uint = 0o400;
+    const IXON: c_uint = 0o2000;
+    const VTIME: usize = 5;
+    const VMIN: usize = 6;
+
+    /// The terminal switched to reading keys one at a time, without echo;
+    /// dropping it restores the terminal.
+    pub struct RawMode {
+        original: Termios,
+    }
+
+    impl RawMode {
+        pub fn enable() -> Option<RawMode> {
+            unsafe {
+                if isatty(STDIN) == 0 {
+                    return None;
+                }
+                let mut original = ::std::mem::zeroed::<Termios>();
+                if tcgetattr(STDIN, &mut original) != 0 {
+                    return None;
+                }
+
+                let mut raw = original;
+                raw.c_lflag &= !(ECHO | ICANON | ISIG | IEXTEN);
+                raw.c_iflag &= !(ICRNL | IXON);
+                raw.c_cc[VMIN] = 1;
+                raw.c_cc[VTIME] = 0;
+                if tcsetattr(STDIN, TCSAFLUSH, &raw) != 0 {
+                    return None;
+                }
+                Some(RawMode { original: original })
+            }
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe {
+                tcsetattr(STDIN, TCSAFLUSH, &self.original);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod terminal {
+    /// Line editing needs the Linux termios layout; elsewhere lines are
+    /// read as typed.
+    pub struct RawMode;
+
+    impl RawMode {
+        pub fn enable() -> Option<RawMode> {
+            None
+        }
+    }
+}
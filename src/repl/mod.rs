@@ -0,0 +1,116 @@
+mod editor;
+
+use std::env;
+use std::io::{stderr, Write};
+use std::iter;
+use std::mem;
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use interpreter::{self, Interpreter, Interrupt, Value};
+use parser;
+
+use self::editor::{Editor, ReadLine};
+
+const SIGINT: c_int = 2;
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+}
+
+/// The interrupt of the interpreter being run, for the signal handler,
+/// which can't be handed it. Set once and never freed.
+static INTERRUPT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern "C" fn on_interrupt(_: c_int) {
+    let interrupt = INTERRUPT.load(Ordering::SeqCst) as *const Interrupt;
+    if !interrupt.is_null() {
+        unsafe { (*interrupt).interrupt() }
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".fancy_history"))
+}
+
+/// Reads forms from the terminal and prints their values, until the end of
+/// input. A form may span several lines: input is read until the brackets
+/// and strings in it are closed. Ctrl-C cancels the line being typed, or
+/// the evaluation in progress.
+pub fn run(mut fancy: Interpreter) {
+    for name in &["*1", "*2", "*3", "*e"] {
+        fancy.define(name, Value::Nil);
+    }
+    let interrupt = fancy.interrupt();
+    INTERRUPT.store(Box::into_raw(Box::new(interrupt.clone())) as usize, Ordering::SeqCst);
+    unsafe {
+        signal(SIGINT, on_interrupt);
+    }
+    // what still panics is a bug in the interpreter; report it without the
+    // panic's location
+    panic::set_hook(Box::new(|info| {
+        let message = info.payload().downcast_ref::<String>().cloned()
+            .or(info.payload().downcast_ref::<&str>().map(|s| String::from(*s)))
+            .unwrap_or(String::from("unknown error"));
+        writeln!(&mut stderr(), "{}", message).unwrap();
+    }));
+
+    let mut editor = Editor::new(history_path());
+    let mut input = String::new();
+    let mut recent = vec!();
+    loop {
+        let prompt = if input.is_empty() {
+            format!("{}=> ", fancy.namespace())
+        }
+        else {
+            let width = fancy.namespace().chars().count();
+            format!("{}#_=> ", iter::repeat(' ').take(width.saturating_sub(2)).collect::<String>())
+        };
+
+        match editor.read_line(&prompt) {
+            Ok(ReadLine::Line(line)) => {
+                input.push_str(&line);
+                input.push('\n');
+            },
+            Ok(ReadLine::Interrupted) => {
+                input.clear();
+                continue;
+            },
+            Ok(ReadLine::Eof) | Err(_) => break,
+        }
+
+        if !parser::is_complete(&input) {
+            continue;
+        }
+        let source = mem::replace(&mut input, String::new());
+        if source.trim().is_empty() {
+            continue;
+        }
+        editor.add_history(source.trim());
+
+        interrupt.clear();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            fancy.eval_str(&source).and_then(|value| interpreter::pr_str(&value).map(|text| (value, text)))
+        }));
+        match result {
+            Ok(Ok((value, text))) => {
+                println!("{}", text);
+                recent.insert(0, value);
+                recent.truncate(3);
+                for (name, value) in ["*1", "*2", "*3"].iter().zip(&recent) {
+                    fancy.define(name, value.clone());
+                }
+            },
+            Ok(Err(e)) => {
+                writeln!(&mut stderr(), "{}", e.stack_trace()).unwrap();
+                fancy.define("*e", Value::Error(Rc::new(e)));
+            },
+            // the hook has reported it
+            Err(_) => (),
+        }
+    }
+    println!("");
+}
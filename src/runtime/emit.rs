@@ -0,0 +1,118 @@
+//! `--emit`: what each stage of the pipeline makes of the source, from the
+//! tokens to the object code.
+
+use ::parser;
+
+use super::LLVMEnvironment;
+
+/// A stage of the pipeline whose output `--emit` writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Emit {
+    Tokens,
+    Forms,
+    Ast,
+    Ir,
+    Bitcode,
+    Assembly,
+    Object,
+}
+
+impl Emit {
+    /// The stage of an `--emit` name: ir, bc, asm, obj, ast, tokens or forms.
+    pub fn from_name(name: &str) -> Option<Emit> {
+        match name {
+            "tokens" => Some(Emit::Tokens),
+            "forms" => Some(Emit::Forms),
+            "ast" => Some(Emit::Ast),
+            "ir" => Some(Emit::Ir),
+            "bc" => Some(Emit::Bitcode),
+            "asm" => Some(Emit::Assembly),
+            "obj" => Some(Emit::Object),
+            _ => None,
+        }
+    }
+}
+
+impl LLVMEnvironment {
+    /// The module as textual IR.
+    pub fn ir(&self) -> String {
+        self.module.print()
+    }
+
+    pub fn bitcode(&self) -> Vec<u8> {
+        self.module.bitcode()
+    }
+}
+
+fn lines<T, F: Fn(&T) -> String>(items: Vec<T>, show: F) -> Vec<u8> {
+    let mut text = String::new();
+    for item in &items {
+        text.push_str(&show(item));
+        text.push('\n');
+    }
+    text.into_bytes()
+}
+
+/// The output of the stage for the source. The stages after the AST
+/// compile the module `build` would, with a `main` running the top-level
+/// forms, optimized at the level.
+pub fn emit(stage: Emit, source: String, opt_level: u32) -> Result<Vec<u8>, String> {
+    match stage {
+        Emit::Tokens => return Ok(lines(parser::tokens(source), |token| token.clone())),
+        Emit::Forms => return Ok(lines(parser::read_string(source), |form| format!("{:?}", form))),
+        Emit::Ast => return Ok(lines(parser::parse_string(source), |expression| format!("{:?}", expression))),
+        _ => (),
+    }
+
+    let mut env = LLVMEnvironment::new();
+    env.set_opt_level(opt_level);
+    try!(env.build_main(parser::parse_located(source)).map_err(|e| e.to_string()));
+    match stage {
+        Emit::Ir => Ok(env.ir().into_bytes()),
+        Emit::Bitcode => Ok(env.bitcode()),
+        Emit::Assembly => env.emit_machine_code(true),
+        _ => env.emit_machine_code(false),
+    }
+}
+
+#[test]
+fn emits_each_stage() {
+    let source = "(fn twice [x] (* x 2)) (twice 21)";
+    let emit_text = |stage| String::from_utf8(emit(stage, String::from(source), 0).unwrap()).unwrap();
+
+    assert_eq!("(\nfn\ntwice\n[\nx\n]\n(\n*\nx\n2\n)\n)\n(\ntwice\n21\n)\n", emit_text(Emit::Tokens));
+    assert_eq!("List([Literal(\"twice\"), Literal(\"21\")])",
+               emit_text(Emit::Forms).lines().last().unwrap());
+    assert_eq!("SExpression([Symbol(\"twice\"), Number(\"21\")])",
+               emit_text(Emit::Ast).lines().last().unwrap());
+
+    let ir = emit_text(Emit::Ir);
+    assert!(ir.contains("define i64 @twice(i64 %x)"), ir);
+    assert!(ir.contains("define i32 @main()"), ir);
+    assert!(emit_text(Emit::Assembly).contains("twice:"));
+
+    assert!(emit(Emit::Bitcode, String::from(source), 0).unwrap().starts_with(b"BC\xC0\xDE"));
+    assert!(!emit(Emit::Object, String::from(source), 0).unwrap().is_empty());
+    assert_eq!(None, Emit::from_name("exe"));
+}
+
+#[test]
+fn optimizes_by_level() {
+    let source = "(fn square [x :i64] (* x x))
+                  (fn f [x] (let [a (+ x 1) b (+ x 1)] (+ (square a) b)))";
+    let ir = |opt_level| String::from_utf8(emit(Emit::Ir, String::from(source), opt_level).unwrap()).unwrap();
+    let body = |ir: &str| String::from(ir.split("define i64 @f").nth(1).unwrap().split("}").next().unwrap());
+
+    let unoptimized = body(&ir(0));
+    assert_eq!(2, unoptimized.matches("add i64 %x, 1").count(), "{}", unoptimized);
+    assert!(unoptimized.contains("call i64 @square"), unoptimized);
+
+    // GVN computes `(+ x 1)` once
+    let optimized = body(&ir(1));
+    assert_eq!(1, optimized.matches("add i64 %x, 1").count(), "{}", optimized);
+    assert!(optimized.contains("call i64 @square"), optimized);
+
+    // and the call is inlined
+    let inlined = body(&ir(2));
+    assert!(!inlined.contains("call"), inlined);
+}
@@ -0,0 +1,90 @@
+/* The runtime library linked into programs built by `fancy-boot build`.
+ * Values are printed the way the interpreter prints them. */
+
+#include <math.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* the tags of tagged values, as in runtime/types.rs */
+enum { NIL_TAG, BOOL_TAG, INT_TAG, FLOAT_TAG, STR_TAG };
+
+void fancy_print_i64(int64_t n) {
+    printf("%lld\n", (long long) n);
+}
+
+/* The shortest digits that read back as the same double, with a ".0" for
+ * whole numbers; very large and small ones get an exponent. */
+void fancy_print_f64(double x) {
+    char buf[400];
+    int exponent = x != 0 && (fabs(x) >= 1e16 || fabs(x) < 1e-5);
+
+    if (isnan(x)) {
+        puts("NaN");
+        return;
+    }
+    if (isinf(x)) {
+        puts(x < 0 ? "-inf" : "inf");
+        return;
+    }
+
+    for (int precision = exponent ? 1 : 0; precision <= (exponent ? 17 : 24); precision++) {
+        snprintf(buf, sizeof buf, exponent ? "%.*g" : "%.*f", precision, x);
+        if (strtod(buf, NULL) == x) {
+            break;
+        }
+    }
+    if (strpbrk(buf, ".e") == NULL) {
+        strcat(buf, ".0");
+    }
+    puts(buf);
+}
+
+void fancy_print_bool(int64_t b) {
+    puts(b ? "true" : "false");
+}
+
+/* A null string is nil. */
+void fancy_print_str(const char *s) {
+    if (s == NULL) {
+        puts("nil");
+        return;
+    }
+    putchar('"');
+    for (; *s; s++) {
+        switch (*s) {
+        case '"': fputs("\\\"", stdout); break;
+        case '\\': fputs("\\\\", stdout); break;
+        case '\n': fputs("\\n", stdout); break;
+        case '\t': fputs("\\t", stdout); break;
+        case '\r': fputs("\\r", stdout); break;
+        default: putchar(*s);
+        }
+    }
+    puts("\"");
+}
+
+void fancy_print_any(int64_t tag, int64_t bits) {
+    double x;
+
+    switch (tag) {
+    case NIL_TAG: puts("nil"); break;
+    case BOOL_TAG: fancy_print_bool(bits); break;
+    case INT_TAG: fancy_print_i64(bits); break;
+    case FLOAT_TAG:
+        memcpy(&x, &bits, sizeof x);
+        fancy_print_f64(x);
+        break;
+    case STR_TAG: fancy_print_str((const char *) (intptr_t) bits); break;
+    default: break;
+    }
+}
+
+/* A runtime error, which ends the program as an error nothing catches
+ * does. */
+void fancy_error(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "Runtime error: %s\n", message);
+    exit(1);
+}
@@ -0,0 +1,74 @@
+//! The runtime functions of `fancy_rt.c`, for code run by the JIT. They
+//! aren't exported from the executable, so they're registered with LLVM as
+//! absolute symbols.
+
+use std::ffi::{CStr, CString};
+use std::io::{stderr, stdout, Write};
+use std::mem;
+use std::process;
+
+use ::interpreter::Value;
+
+use super::{libc, types};
+
+// declared in llvm-c/Support.h, but not bound by llvm-sys 0.2
+extern "C" {
+    fn LLVMAddSymbol(symbol_name: *const libc::c_char, symbol_value: *mut libc::c_void);
+}
+
+extern "C" fn fancy_print_i64(n: i64) {
+    println!("{}", Value::Int(n));
+}
+
+extern "C" fn fancy_print_f64(x: f64) {
+    println!("{}", Value::Float(x));
+}
+
+extern "C" fn fancy_print_bool(b: i64) {
+    println!("{}", Value::Bool(b != 0));
+}
+
+extern "C" fn fancy_print_str(s: *const libc::c_char) {
+    if s.is_null() {
+        println!("{}", Value::Nil);
+        return;
+    }
+    let s = unsafe { CStr::from_ptr(s) };
+    println!("{}", Value::Str(s.to_string_lossy().into_owned()));
+}
+
+extern "C" fn fancy_print_any(tag: i64, bits: i64) {
+    match tag as u64 {
+        types::NIL_TAG => println!("{}", Value::Nil),
+        types::BOOL_TAG => fancy_print_bool(bits),
+        types::INT_TAG => fancy_print_i64(bits),
+        types::FLOAT_TAG => fancy_print_f64(unsafe { mem::transmute(bits) }),
+        types::STR_TAG => fancy_print_str(bits as usize as *const libc::c_char),
+        _ => (),
+    }
+}
+
+extern "C" fn fancy_error(message: *const libc::c_char) {
+    let message = unsafe { CStr::from_ptr(message) };
+    stdout().flush().ok();
+    writeln!(&mut stderr(), "Runtime error: {}", message.to_string_lossy()).ok();
+    process::exit(1);
+}
+
+/// Makes the functions visible to every module the JIT links.
+pub fn register_symbols() {
+    let symbols: [(&'static str, *mut libc::c_void); 6] = [
+        ("fancy_print_i64", fancy_print_i64 as *mut libc::c_void),
+        ("fancy_print_f64", fancy_print_f64 as *mut libc::c_void),
+        ("fancy_print_bool", fancy_print_bool as *mut libc::c_void),
+        ("fancy_print_str", fancy_print_str as *mut libc::c_void),
+        ("fancy_print_any", fancy_print_any as *mut libc::c_void),
+        ("fancy_error", fancy_error as *mut libc::c_void),
+    ];
+
+    for &(name, address) in symbols.iter() {
+        unsafe {
+            LLVMAddSymbol(CString::new(name).unwrap().as_ptr(), address);
+        }
+    }
+}
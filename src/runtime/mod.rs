@@ -1,363 +1,1357 @@
 extern crate llvm_sys as llvm;
 extern crate libc;
 
+pub mod emit;
+mod host;
+pub mod native;
+mod types;
+mod wrap;
+
 use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
 use std::sync::{Once, ONCE_INIT};
-use std::ffi::{CString, CStr};
+use std::ffi::CStr;
 use std::str;
 
-use llvm::prelude::*;
-use llvm::core::*;
-use llvm::analysis as analysis;
+use llvm::{LLVMIntPredicate, LLVMOpcode, LLVMRealPredicate};
 use llvm::execution_engine as exec;
 use llvm::target as target;
-use llvm::execution_engine::LLVMExecutionEngineRef;
+use llvm::transforms::{ipo, scalar};
+
+use ::interpreter;
+use ::parser::{Expression, Position};
 
-use ::parser::Expression;
+use self::types::{Signature, Type, TypeError};
+use self::wrap::{BasicBlock, Builder, Context, ExecutionEngine, LLVMType, Module, PassManager};
 
 pub trait Value {
     fn dump(&mut self) -> Self;
 }
 
 pub trait Environment<V: Value> {
-    fn eval_all(&mut self, expressions: Vec<Expression>) -> V;
-    fn eval(&mut self, expression: Expression) -> V;
+    fn eval_all(&mut self, expressions: Vec<Expression>) -> Result<V, CodegenError>;
+    fn eval(&mut self, expression: Expression) -> Result<V, CodegenError>;
+}
+
+/// Code that doesn't compile: a type error, a malformed form, or the
+/// verifier's or the IR parser's message; the source of the top-level form
+/// it was compiled from, and where that form starts in the input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodegenError {
+    pub message: String,
+    pub source: String,
+    pub position: Option<Position>,
+}
+
+impl CodegenError {
+    /// The error with the source of the top-level form it came up in, for
+    /// errors found while compiling one of its subforms.
+    fn in_form(self, source: &str) -> CodegenError {
+        if self.source.is_empty() {
+            CodegenError { source: String::from(source), ..self }
+        }
+        else {
+            self
+        }
+    }
+
+    fn at(self, position: Position) -> CodegenError {
+        CodegenError { position: self.position.or(Some(position)), ..self }
+    }
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some(position) => write!(f, "Codegen error: {}\n    at {}: {}", self.message.trim_right(), position, self.source),
+            None => write!(f, "Codegen error: {}\n    in {}", self.message.trim_right(), self.source),
+        }
+    }
+}
+
+fn error<T>(message: String) -> Result<T, CodegenError> {
+    Err(CodegenError { message: message, source: String::new(), position: None })
 }
 
 static INIT_LLVM: Once = ONCE_INIT;
 
+/// The fancy.core macros that are expanded before compiling, as they
+/// rewrite into forms the backend compiles. The others aren't: some, like
+/// `when` and `and`, the backend compiles itself, and the rest expand into
+/// `def`s and calls of the interpreter's functions.
+const EXPANDED_MACROS: [&'static str; 7] = ["when-not", "if-not", "if-let", "when-let", "->", "->>", "cond->"];
+
+/// The functions every module starts with.
+const BUILTINS: &'static str =
+"define i64 @\"add\"(i64, i64) {
+   entry:
+     %tmp = add i64 %0, %1
+     ret i64 %tmp
+}
+
+define i64 @\"sub\"(i64, i64) {
+   entry:
+     %tmp = sub i64 %0, %1
+     ret i64 %tmp
+ }
+
+; Function Attrs: nounwind uwtable
+define double @\"add_double\"(double %a, double %b) {
+  %1 = alloca double, align 8
+  %2 = alloca double, align 8
+  store double %a, double* %1, align 8
+  store double %b, double* %2, align 8
+  %3 = load double* %1, align 8
+  %4 = load double* %2, align 8
+  %5 = fadd double %3, %4
+  ret double %5
+}
+
+; Function Attrs: nounwind readonly
+declare double @atof(i8*)
+";
+
+/// The fields are dropped in order: the passes before the module they run
+/// on, and the context after everything made in it.
 pub struct LLVMEnvironment {
-    builder: LLVMBuilderRef,
-    module: LLVMModuleRef,
-    engine: LLVMExecutionEngineRef,
-    named_values: HashMap<String, LLVMValueRef>
+    /// Run on each function as it's compiled.
+    passes: PassManager,
+    /// Run on the whole module before its code runs or is emitted.
+    module_passes: PassManager,
+    /// The module code is compiled into until the JIT takes it.
+    module: Module,
+    /// Created when the first module is done, and given the later ones,
+    /// which may call into the earlier.
+    engine: Option<ExecutionEngine>,
+    opt_level: u32,
+    macros: interpreter::Env,
+    thunks: usize,
+    context: Rc<Context>,
+}
+
+/// Compiles forms into the module being built. The values and blocks it
+/// keeps track of borrow the module, so they're kept here, for a
+/// definition or thunk at a time, rather than by the environment owning
+/// the module.
+struct Codegen<'m> {
+    module: &'m Module,
+    /// The modules the JIT has taken, whose functions the module may call.
+    committed: &'m [Module],
+    macros: &'m interpreter::Env,
+    builder: Builder<'m>,
+    /// The stack slots of the locals in scope.
+    named_values: HashMap<String, wrap::Value<'m>>,
+    /// The start and the binding slots of each loop `recur` may jump back
+    /// to, innermost last.
+    loops: Vec<(BasicBlock<'m>, Vec<wrap::Value<'m>>)>,
 }
 
-impl Value for LLVMValueRef {
-    fn dump(&mut self) -> Self {
-        unsafe {LLVMDumpValue(*self);}
+impl<'m> Environment<wrap::Value<'m>> for Codegen<'m> {
+    fn eval_all(&mut self, expressions: Vec<Expression>) -> Result<wrap::Value<'m>, CodegenError> {
+        self.eval_do(expressions).and_then(given)
+    }
 
-        *self
+    fn eval(&mut self, expression: Expression) -> Result<wrap::Value<'m>, CodegenError> {
+        self.eval_tail(expression).and_then(given)
     }
 }
 
-impl Environment<LLVMValueRef> for LLVMEnvironment {
-    fn eval_all(&mut self, expressions: Vec<Expression>) -> LLVMValueRef {
-        let mut result = 0 as LLVMValueRef;
-        for e in expressions {
-            result = self.eval(e);
+impl LLVMEnvironment {
+
+    /// Compiles the top-level forms in order. Definitions are added to the
+    /// module; every other form is compiled into a thunk and run straight
+    /// away. Returns the value of the last form run, or the error of the
+    /// first one that doesn't compile; the forms before it stay defined.
+    pub fn run_all(&mut self, forms: Vec<(Position, Expression)>) -> Result<interpreter::Value, CodegenError> {
+        let mut result = interpreter::Value::Nil;
+        for (position, e) in forms {
+            let ran = self.expand_form(e).and_then(|e| match e {
+                Some(ref e) if is_definition(e) => self.define(e.clone()).map(|_| None),
+                Some(e) => self.run(e).map(Some),
+                None => Ok(None),
+            });
+            match ran {
+                Ok(Some(value)) => result = value,
+                Ok(None) => (),
+                Err(e) => return Err(e.at(position)),
+            }
         }
 
-        result
+        // what's left of the input gets a module of its own
+        try!(self.commit());
+        Ok(result)
     }
 
-    fn eval(&mut self, expression: Expression) -> LLVMValueRef {
-        let result = match expression {
-            Expression::Symbol(symbol) => self.eval_symbol(symbol),
-            Expression::Number(number) => self.eval_number(number),
-            Expression::String(s) => self.eval_string(s),
-            Expression::SExpression(sexp) => self.eval_sexp(sexp),
-            Expression::Params(params) => unreachable!("Params")
+    /// Compiles an `fn` or `extern` into the module.
+    fn define(&mut self, definition: Expression) -> Result<(), CodegenError> {
+        let source = definition.to_string();
+        let function = try!(self.codegen().eval(definition).map_err(|e| e.in_form(&source)));
+        self.finish(function, source)
+    }
+
+    /// Verifies a function just compiled and runs the function passes on
+    /// it. A function that doesn't verify is removed from the module, so
+    /// what's compiled after it still can be.
+    fn finish(&self, function: wrap::Value, source: String) -> Result<(), CodegenError> {
+        if let Err(message) = self.module.verify() {
+            unsafe { function.delete_function() };
+            return Err(CodegenError { message: message, source: source, position: None });
+        }
+
+        self.passes.run_on_function(function);
+        Ok(())
+    }
+
+    /// Wraps the expression in a function without parameters, compiles it
+    /// with the JIT and calls it. A tagged result is stored in globals for
+    /// its tag and bits and read from there.
+    fn run(&mut self, expression: Expression) -> Result<interpreter::Value, CodegenError> {
+        let source = expression.to_string();
+        self.thunks += 1;
+        let thunk_name = format!("__thunk{}", self.thunks);
+        let (result_type, result_globals) = try!(self.build_thunk(&thunk_name, expression)
+                                                 .map_err(|e| e.in_form(&source)));
+        self.optimize_module();
+
+        let address = try!(self.function_address(&thunk_name).map_err(|e| e.in_form(&source)));
+        unsafe {
+            match result_globals {
+                Some(globals) => {
+                    let thunk: extern "C" fn() = mem::transmute(address as usize);
+                    thunk();
+                    let engine = self.engine.as_ref().unwrap();
+                    let tag = *(engine.global_address(&globals[0]) as usize as *const u64);
+                    let bits = *(engine.global_address(&globals[1]) as usize as *const u64);
+                    Ok(from_tagged(tag, bits))
+                },
+                None => Ok(call_native(address, result_type)),
+            }
+        }
+    }
+
+    /// Compiles the thunk `run` calls, returning the type of its result
+    /// and, for a tagged one, the names of the globals it's stored in.
+    fn build_thunk(&self, name: &str, expression: Expression) -> Result<(Type, Option<Vec<String>>), CodegenError> {
+        let source = expression.to_string();
+        let mut codegen = self.codegen();
+        let result_type = try!(checked(types::infer_expression(&expression, &|name| codegen.signature(name))));
+
+        let int_type = self.module.llvm_type(Type::Int);
+        let returns = if result_type == Type::Tagged { self.module.void_type() } else { self.module.llvm_type(result_type) };
+        let thunk = self.module.add_function(name, &[], returns);
+        codegen.builder.position_at_end(self.module.append_block(thunk, "entry"));
+
+        let value = match codegen.eval(expression).and_then(|value| codegen.coerce(value, result_type)) {
+            Ok(value) => value,
+            Err(e) => {
+                unsafe { thunk.delete_function() };
+                return Err(e);
+            },
         };
+        let result_globals = if result_type == Type::Tagged {
+            let mut globals = vec!();
+            for i in 0..2 {
+                let global_name = format!("__result{}.{}", self.thunks, i);
+                let global = self.module.add_global(int_type, &global_name);
+                let field = codegen.builder.extract_element(value, i, "field");
+                codegen.builder.store(field, global);
+                globals.push(global_name);
+            }
+            codegen.builder.ret_void();
+            Some(globals)
+        }
+        else {
+            codegen.builder.ret(value);
+            None
+        };
+
+        try!(self.finish(thunk, source));
+        Ok((result_type, result_globals))
+    }
 
-        result
+    /// Hands the module being built to the JIT and starts a new one for the
+    /// code after it. The JIT compiles a module when code in it is first
+    /// looked up, resolving calls to earlier ones by name.
+    fn commit(&mut self) -> Result<(), CodegenError> {
+        let module = mem::replace(&mut self.module, Module::new("fancy", &self.context));
+        let engine = match self.engine.take() {
+            Some(mut engine) => {
+                engine.add_module(module);
+                engine
+            },
+            None => match ExecutionEngine::new(module, self.opt_level) {
+                Ok(engine) => engine,
+                Err(e) => return error(format!("Couldn't create the JIT: {}", e)),
+            },
+        };
+        self.engine = Some(engine);
+        self.reset_passes();
+        Ok(())
+    }
+
+    /// The address of a function the JIT has compiled, committing the
+    /// module it's in if it hasn't yet.
+    fn function_address(&mut self, name: &str) -> Result<u64, CodegenError> {
+        if self.module.named_function(name).is_some() {
+            try!(self.commit());
+        }
+
+        let address = match self.engine {
+            Some(ref engine) => engine.function_address(name),
+            None => 0,
+        };
+        if address == 0 {
+            return error(format!("Undefined function {}", name));
+        }
+        Ok(address)
+    }
+
+    /// Expands a top-level form, with its errors in the form. As in the
+    /// interpreter, every `recur` in the expanded form has to be in tail
+    /// position; the bodies of `loop`s and `fn`s are checked as they're
+    /// compiled.
+    fn expand_form(&mut self, expression: Expression) -> Result<Option<Expression>, CodegenError> {
+        let source = expression.to_string();
+        expand(&self.macros, expression).and_then(|expanded| {
+            if let Some(ref e) = expanded {
+                try!(recur_checked(interpreter::check_recur(e, false)));
+            }
+            Ok(expanded)
+        }).map_err(|e| e.in_form(&source))
+    }
+
+    /// Code generation into the module being built.
+    fn codegen(&self) -> Codegen {
+        Codegen {
+            module: &self.module,
+            committed: self.engine.as_ref().map(|engine| engine.modules()).unwrap_or(&[]),
+            macros: &self.macros,
+            builder: Builder::new(&self.module),
+            named_values: HashMap::new(),
+            loops: vec!(),
+        }
     }
 }
 
-impl LLVMEnvironment {
+/// Macros are written in fancy and run by the interpreter: `defmacro` forms
+/// are evaluated there, and calls of those macros and of the
+/// `EXPANDED_MACROS` in every other form are expanded before the form is
+/// compiled. Expanding desugars destructuring too, into calls the backend
+/// has nothing to compile to, so a form that destructures is an error.
+fn expand(macros: &interpreter::Env, expression: Expression) -> Result<Option<Expression>, CodegenError> {
+    let defines_macro = match expression {
+        Expression::SExpression(ref sexp) => sexp.first() == Some(&::parser::symbol("defmacro")),
+        _ => false,
+    };
 
-    fn eval_symbol(&mut self, symbol: String) -> LLVMValueRef {
-        if self.named_values.contains_key(&symbol) {
-            *self.named_values.get(&symbol).unwrap()
+    if defines_macro {
+        match interpreter::eval_form(&expression, macros) {
+            Ok(_) => Ok(None),
+            Err(e) => error(e.stack_trace()),
         }
-        else {
-            panic!("Undefined symbol {}", symbol);
+    }
+    else {
+        match interpreter::expand_all(&expression, macros) {
+            Ok(ref expanded) if destructures(expanded) =>
+                error(String::from("Destructuring isn't supported in compiled code, which has no collections")),
+            Ok(expanded) => Ok(Some(expanded)),
+            Err(e) => error(e.stack_trace()),
         }
     }
+}
 
-    fn eval_number(&mut self, number: String) -> LLVMValueRef {
-        let result = self.eval(Expression::SExpression(vec!(
-            ::parser::symbol("atof"), Expression::String(number))));
+/// Whether an expanded form calls the functions desugared destructuring
+/// does.
+fn destructures(expression: &Expression) -> bool {
+    match *expression {
+        Expression::SExpression(ref items) => match items.first() {
+            Some(&Expression::Symbol(ref s)) if s == "quote" => false,
+            Some(&Expression::Symbol(ref s)) if interpreter::DESTRUCTURING_HELPERS.contains(&&s[..]) => true,
+            _ => items.iter().any(destructures),
+        },
+        Expression::Params(ref items) => items.iter().any(destructures),
+        _ => false,
+    }
+}
 
-        asserted_return(result)
+/// The value of a form that has to give one. A `recur` jumping away
+/// instead isn't in tail position.
+fn given(value: Option<wrap::Value>) -> Result<wrap::Value, CodegenError> {
+    match value {
+        Some(value) => Ok(value),
+        None => error(String::from("Can only recur from tail position")),
     }
+}
 
-    fn eval_string(&mut self, s: String) -> LLVMValueRef {
-        unsafe {
-            let len = s.len();
-            let result = LLVMBuildGlobalStringPtr(self.builder, cstring(s), cstring_a("tmpstring"));
+impl<'m> Codegen<'m> {
+    /// The signature of a function in the module, for type inference.
+    fn signature(&self, name: &str) -> Option<Signature> {
+        self.find_fn(name).map(|function| {
+            Signature {
+                params: function.params().iter().map(|param| param.ty()).collect(),
+                result: function.return_type(),
+            }
+        })
+    }
+
+    /// A function callable from the module being built, declared in it if
+    /// it's defined in one the JIT has taken.
+    fn get_fn(&mut self, name: &str) -> Option<wrap::Value<'m>> {
+        if let Some(function) = self.module.named_function(name) {
+            return Some(function);
+        }
+
+        self.find_fn(name).map(|function| self.module.declare_like(function))
+    }
+
+    /// The definition of a function in the module being built or, failing
+    /// that, the latest committed one; or else a declaration of it.
+    fn find_fn(&self, name: &str) -> Option<wrap::Value<'m>> {
+        let mut declaration = None;
+        for module in Some(self.module).into_iter().chain(self.committed.iter().rev()) {
+            match module.named_function(name) {
+                Some(function) if !function.is_declaration() => return Some(function),
+                Some(function) if declaration.is_none() => declaration = Some(function),
+                _ => (),
+            }
+        }
+        declaration
+    }
+
+    fn llvm_type(&self, ty: Type) -> LLVMType<'m> {
+        self.module.llvm_type(ty)
+    }
+
+    /// Compiles a form in tail position, which may jump away rather than
+    /// give a value, as `recur` does: None then.
+    fn eval_tail(&mut self, expression: Expression) -> Result<Option<wrap::Value<'m>>, CodegenError> {
+        match expression {
+            Expression::Symbol(symbol) => self.eval_symbol(symbol).map(Some),
+            Expression::Number(number) => self.eval_number(number).map(Some),
+            Expression::String(s) => Ok(Some(self.eval_string(s))),
+            Expression::SExpression(sexp) => self.eval_sexp(sexp),
+            Expression::Params(params) => error(format!("Unexpected params {}", Expression::Params(params))),
+        }
+    }
+
+    fn eval_symbol(&mut self, symbol: String) -> Result<wrap::Value<'m>, CodegenError> {
+        match symbol.as_ref() {
+            "true" | "false" => return Ok(wrap::Value::const_int(self.llvm_type(Type::Bool), (symbol == "true") as u64)),
+            "nil" => return Ok(wrap::Value::const_null(self.llvm_type(Type::Tagged))),
+            _ => (),
+        }
 
-            asserted_return(result)
+        match self.named_values.get(&symbol) {
+            Some(&slot) => Ok(self.builder.load(slot, &symbol)),
+            None => error(format!("Undefined symbol {}", symbol)),
         }
     }
 
-    fn eval_sexp(&mut self, sexp: Vec<Expression>) -> LLVMValueRef {
+    fn eval_number(&mut self, number: String) -> Result<wrap::Value<'m>, CodegenError> {
+        let result = match types::literal_type(&number) {
+            Type::Int => match number.parse::<i64>() {
+                Ok(n) => wrap::Value::const_int(self.llvm_type(Type::Int), n as u64),
+                Err(_) => return error(format!("Invalid number {}", number)),
+            },
+            _ => match number.parse::<f64>() {
+                Ok(x) => wrap::Value::const_real(self.llvm_type(Type::Float), x),
+                Err(_) => return error(format!("Invalid number {}", number)),
+            },
+        };
+
+        Ok(result)
+    }
+
+    fn eval_string(&mut self, s: String) -> wrap::Value<'m> {
+        self.builder.global_string_ptr(&s, "tmpstring")
+    }
+
+    fn eval_sexp(&mut self, sexp: Vec<Expression>) -> Result<Option<wrap::Value<'m>>, CodegenError> {
         if sexp.len() > 0 {
             if let Expression::Symbol(name) = sexp[0].clone() {
 
                 match name.as_ref() {
-                    "extern" => self.defextern(sexp[1..].to_vec()).dump(),
-                    "fn" => self.defn(sexp[1..].to_vec()),
-                    _ => self.eval_fn(name, sexp[1..].to_vec())
+                    "extern" => self.defextern(sexp[1..].to_vec()).map(Some),
+                    "fn" => self.defn(sexp[1..].to_vec()).map(Some),
+                    "do" => self.eval_do(sexp[1..].to_vec()),
+                    "if" => self.eval_if(sexp[1..].to_vec()),
+                    "when" => self.eval_when(sexp[1..].to_vec()),
+                    "let" => self.eval_let(sexp[1..].to_vec()),
+                    "loop" => self.eval_loop(sexp[1..].to_vec()).map(Some),
+                    "recur" => self.eval_recur(sexp[1..].to_vec()).map(|_| None),
+                    "cond" => self.eval_cond(sexp[1..].to_vec()),
+                    "and" => self.eval_logical(sexp[1..].to_vec(), true).map(Some),
+                    "or" => self.eval_logical(sexp[1..].to_vec(), false).map(Some),
+                    "not" => self.eval_not(sexp[1..].to_vec()).map(Some),
+                    "<" | ">" | "<=" | ">=" | "=" | "not=" => self.eval_compare(&name, sexp[1..].to_vec()).map(Some),
+                    "+" | "-" | "*" | "/" => self.eval_arithmetic(&name, sexp[1..].to_vec()).map(Some),
+                    "prn" => self.eval_prn(sexp[1..].to_vec()).map(Some),
+                    _ => self.eval_fn(name, sexp[1..].to_vec()).map(Some)
                 }
             }
             else {
-                panic!("Expected symbol, got {:?}", sexp[0]);
+                error(format!("Expected symbol, got {}", sexp[0]))
             }
         }
         else {
             // should be unit, 0 for now
-            self.eval(Expression::Number(String::from("0")))
+            self.eval(Expression::Number(String::from("0"))).map(Some)
         }
     }
 
-    fn eval_fn(&mut self, name: String, args: Vec<Expression>) -> LLVMValueRef {
-        let function = self.get_fn(&name);
-
-        if function == 0 as LLVMValueRef {
-            panic!("Undefined function {}", name);
+    /// Compiles the forms in order, the last in tail position, and gives
+    /// its value, or nil if there are none.
+    fn eval_do(&mut self, body: Vec<Expression>) -> Result<Option<wrap::Value<'m>>, CodegenError> {
+        let mut forms = vec!();
+        for e in body {
+            if let Some(e) = try!(expand(self.macros, e)) {
+                forms.push(e);
+            }
         }
 
-        let arg_count = unsafe{LLVMCountParams(function)};
-
-        if arg_count != args.len() as u32 {
-            panic!("{} requires {} args, got {}", name, arg_count, args.len());
+        let last = match forms.pop() {
+            Some(last) => last,
+            None => return self.eval(::parser::symbol("nil")).map(Some),
+        };
+        for e in forms {
+            try!(self.eval(e));
         }
+        self.eval_tail(last)
+    }
 
-        let mut fn_args = Vec::<LLVMValueRef>::new();
-        for a in args {
-            fn_args.push(self.eval(a));
+    fn eval_if(&mut self, args: Vec<Expression>) -> Result<Option<wrap::Value<'m>>, CodegenError> {
+        if args.len() < 2 || args.len() > 3 {
+            return error(format!("if requires 2 or 3 args, got {}", args.len()));
         }
 
-        let result =
-        unsafe {
-            LLVMBuildCall(self.builder, function, fn_args.as_mut_ptr(), arg_count, cstring_a("tmpcall")).dump()
-        };
-
-        result
+        let condition = try!(self.eval(args[0].clone()));
+        self.build_branch(condition, args[1].clone(), args.get(2).cloned())
     }
 
-    fn defextern(&mut self, args: Vec<Expression>) -> LLVMValueRef {
-        if args.len() == 2 {
-            if let Expression::Symbol(name) = args[0].clone() {
-                let mut param_types = Vec::<LLVMTypeRef>::with_capacity(args.len() - 1);
+    fn eval_when(&mut self, args: Vec<Expression>) -> Result<Option<wrap::Value<'m>>, CodegenError> {
+        if args.is_empty() {
+            return error(String::from("when requires a test"));
+        }
 
-                if let Expression::Params(params) = args[1].clone() {
+        let condition = try!(self.eval(args[0].clone()));
+        let mut body = vec!(::parser::symbol("do"));
+        body.extend(args[1..].iter().cloned());
+        self.build_branch(condition, Expression::SExpression(body), None)
+    }
 
-                    unsafe {
+    /// `(cond test expr ...)` compiles like nested ifs. A keyword test, as
+    /// in `:else expr`, always holds.
+    fn eval_cond(&mut self, clauses: Vec<Expression>) -> Result<Option<wrap::Value<'m>>, CodegenError> {
+        if clauses.len() % 2 != 0 {
+            return error(String::from("cond requires an even number of forms"));
+        }
+        if clauses.is_empty() {
+            return self.eval(::parser::symbol("nil")).map(Some);
+        }
 
-                        for a in args[1..].to_vec() {
-                            param_types.push(LLVMDoubleType());
-                        }
+        match clauses[0] {
+            Expression::Symbol(ref s) if s.starts_with(':') => return self.eval_tail(clauses[1].clone()),
+            _ => (),
+        }
 
+        let condition = try!(self.eval(clauses[0].clone()));
+        let rest = if clauses.len() > 2 {
+            let mut rest = vec!(::parser::symbol("cond"));
+            rest.extend(clauses[2..].iter().cloned());
+            Some(Expression::SExpression(rest))
+        }
+        else {
+            None
+        };
+        self.build_branch(condition, clauses[1].clone(), rest)
+    }
 
-                        let function_type = LLVMFunctionType(LLVMDoubleType(),
-                                                             param_types.as_mut_ptr(),
-                                                             param_types.len() as u32, 0);
+    /// Compiles the branches into blocks of their own, chosen by the
+    /// condition, and joins their values with a phi in the block after
+    /// them. A missing else branch gives nil.
+    fn build_branch(&mut self, condition: wrap::Value<'m>, then: Expression, otherwise: Option<Expression>)
+                    -> Result<Option<wrap::Value<'m>>, CodegenError>
+    {
+        let condition = self.to_bool(condition);
+        let then_bb = self.builder.append_block("then");
+        let else_bb = self.builder.append_block("else");
+        let merge_bb = self.builder.append_block("ifcont");
+        self.builder.cond_br(condition, then_bb, else_bb);
+
+        // a branch may add blocks of its own; the phi takes its value
+        // from the block it ends in
+        self.builder.position_at_end(then_bb);
+        let then_value = try!(self.eval_tail(then));
+        let then_end = self.leave_branch(then_value, merge_bb);
+
+        else_bb.move_after(then_end);
+        self.builder.position_at_end(else_bb);
+        let else_value = match otherwise {
+            Some(otherwise) => try!(self.eval_tail(otherwise)),
+            None => Some(try!(self.eval(::parser::symbol("nil")))),
+        };
+        let else_end = self.leave_branch(else_value, merge_bb);
+
+        // a branch that recurs has jumped away already and gives the phi
+        // nothing
+        let branches: Vec<(wrap::Value<'m>, BasicBlock<'m>)> = vec!((then_value, then_end), (else_value, else_end))
+            .into_iter().filter_map(|(value, block)| value.map(|value| (value, block))).collect();
+        if branches.is_empty() {
+            unsafe { merge_bb.delete() };
+            return Ok(None);
+        }
 
-                        let function = LLVMAddFunction(self.module, cstring(name), function_type);
+        merge_bb.move_after(else_end);
+        self.join(branches, merge_bb, "iftmp").map(Some)
+    }
 
+    /// A phi in the block where the branches meet, taking the value each
+    /// branch gives when leaving its block. The values are cast to the type
+    /// they join in before they leave.
+    fn join(&mut self, branches: Vec<(wrap::Value<'m>, BasicBlock<'m>)>, merge_bb: BasicBlock<'m>, name: &str)
+            -> Result<wrap::Value<'m>, CodegenError>
+    {
+        let mut result_type = branches[0].0.ty();
+        for branch in &branches[1..] {
+            result_type = try!(checked(types::join(result_type, branch.0.ty())));
+        }
+        let mut incoming = vec!();
+        for (value, block) in branches {
+            if let Some(terminator) = block.terminator() {
+                self.builder.position_before(terminator);
+            }
+            incoming.push((try!(self.coerce(value, result_type)), block));
+        }
 
-                        let llvm_params = LLVMEnvironment::get_params(function);
+        self.builder.position_at_end(merge_bb);
+        Ok(self.builder.phi(self.llvm_type(result_type), &incoming, name))
+    }
 
-                        for i in 0..params.len() {
-                            if let Expression::Symbol(name) = params[i].clone() {
-                                LLVMSetValueName(llvm_params[i],  cstring(name));
-                            }
-                        }
+    /// `and` and `or` stop at the first operand that decides the result,
+    /// and give its value: `and` the first falsy one, `or` the first
+    /// truthy one, and either the last if none is. With no operands, `and`
+    /// gives true and `or` nil.
+    fn eval_logical(&mut self, args: Vec<Expression>, is_and: bool) -> Result<wrap::Value<'m>, CodegenError> {
+        if args.is_empty() {
+            return self.eval(::parser::symbol(if is_and { "true" } else { "nil" }));
+        }
 
-                        function
-                    }
+        let merge_bb = self.builder.append_block("logicend");
+
+        let mut incoming = vec!();
+        let last = args.len() - 1;
+        for (i, arg) in args.into_iter().enumerate() {
+            let value = try!(self.eval(arg));
+            if i < last {
+                let condition = self.to_bool(value);
+                let next_bb = self.builder.append_block("logicnext");
+                if is_and {
+                    self.builder.cond_br(condition, next_bb, merge_bb);
                 }
                 else {
-                    panic!("Expected params, got {:?}", args[1]);
+                    self.builder.cond_br(condition, merge_bb, next_bb);
                 }
+                incoming.push((value, self.builder.insert_block()));
+                next_bb.move_after(self.builder.insert_block());
+                self.builder.position_at_end(next_bb);
             }
             else {
-                panic!("Expected symbol, got {:?}", args[0]);
+                self.builder.br(merge_bb);
+                incoming.push((value, self.builder.insert_block()));
             }
         }
-        else {
-            panic!("extern requires 2 params");
+
+        merge_bb.move_after(self.builder.insert_block());
+        self.join(incoming, merge_bb, "logictmp")
+    }
+
+    fn eval_not(&mut self, args: Vec<Expression>) -> Result<wrap::Value<'m>, CodegenError> {
+        if args.len() != 1 {
+            return error(format!("not requires 1 arg, got {}", args.len()));
         }
+
+        let value = try!(self.eval(args[0].clone()));
+        let value = self.to_bool(value);
+        Ok(self.builder.not(value, "nottmp"))
     }
 
-    fn defn(&mut self, args: Vec<Expression>) -> LLVMValueRef {
-        if args.len() >= 2 {
-            let mut fndef = self.defextern(args[0..2].to_vec());
+    /// `(prn x)` prints the value with the runtime library, and gives nil.
+    fn eval_prn(&mut self, args: Vec<Expression>) -> Result<wrap::Value<'m>, CodegenError> {
+        if args.len() != 1 {
+            return error(format!("prn requires 1 arg, got {}", args.len()));
+        }
 
-            unsafe {
-                let bb = LLVMAppendBasicBlockInContext(LLVMGetGlobalContext(), fndef, cstring_a("entry"));
-	        LLVMPositionBuilderAtEnd(self.builder, bb);
+        let value = try!(self.eval(args[0].clone()));
+        self.build_print(value);
+        Ok(wrap::Value::const_null(self.llvm_type(Type::Tagged)))
+    }
 
-                self.named_values.clear();
-                for param in LLVMEnvironment::get_params(fndef) {
-                    self.named_values.insert(buf_to_string(LLVMGetValueName(param)), param);
-                }
+    /// Calls the runtime function printing values of the type: the one
+    /// `fancy_rt.c` defines, or `host` for the JIT.
+    fn build_print(&mut self, value: wrap::Value<'m>) {
+        let (name, args) = match value.ty() {
+            Type::Int => ("fancy_print_i64", vec!(value)),
+            Type::Float => ("fancy_print_f64", vec!(value)),
+            Type::Bool => ("fancy_print_bool", vec!(self.builder.cast(LLVMOpcode::LLVMZExt, value, self.llvm_type(Type::Int), "bits"))),
+            Type::Str => ("fancy_print_str", vec!(value)),
+            Type::Tagged => {
+                let tag = self.builder.extract_element(value, 0, "tag");
+                let bits = self.builder.extract_element(value, 1, "bits");
+                ("fancy_print_any", vec!(tag, bits))
+            },
+        };
 
-                let inner = self.eval_all(args[2..].to_vec());
+        self.build_runtime_call(name, &args);
+    }
 
-	        LLVMBuildRet(self.builder, inner);
+    /// Calls a function of the runtime library, declaring it in the module
+    /// the first time.
+    fn build_runtime_call(&mut self, name: &str, args: &[wrap::Value<'m>]) {
+        let function = match self.module.named_function(name) {
+            Some(function) => function,
+            None => {
+                let param_types: Vec<_> = args.iter().map(|arg| arg.llvm_type()).collect();
+                self.module.add_function(name, &param_types, self.module.void_type())
+            },
+        };
+        self.builder.call(function, args, "");
+    }
 
-                analysis::LLVMVerifyFunction(fndef,
-                                                 analysis::LLVMVerifierFailureAction::LLVMPrintMessageAction);
+    /// Raises a runtime error with the message if the condition holds.
+    /// Compiled code can't unwind, so `fancy_error` ends the program, as an
+    /// error nothing catches does.
+    fn build_check(&mut self, failed: wrap::Value<'m>, message: &str) {
+        let fail_bb = self.builder.append_block("fail");
+        let ok_bb = self.builder.append_block("ok");
+        self.builder.cond_br(failed, fail_bb, ok_bb);
+
+        self.builder.position_at_end(fail_bb);
+        let message = self.builder.global_string_ptr(message, "message");
+        self.build_runtime_call("fancy_error", &[message]);
+        self.builder.unreachable();
+        self.builder.position_at_end(ok_bb);
+    }
 
-                fndef.dump()
-            }
+    /// Ends a branch that gave a value by jumping to where the branches
+    /// meet, and returns the block it ends in.
+    fn leave_branch(&mut self, value: Option<wrap::Value<'m>>, merge_bb: BasicBlock<'m>) -> BasicBlock<'m> {
+        if value.is_some() {
+            self.builder.br(merge_bb);
         }
-        else {
-            panic!("fn requires at least two params");
+        self.builder.insert_block()
+    }
+
+    /// `(let [name value ...] body...)` binds the names in order, each
+    /// seeing the ones before it, and shadows outer locals until the body
+    /// ends.
+    fn eval_let(&mut self, args: Vec<Expression>) -> Result<Option<wrap::Value<'m>>, CodegenError> {
+        let (_, shadowed) = try!(self.bind("let", &args));
+        let value = self.eval_do(args[1..].to_vec());
+        self.unbind(shadowed);
+        value
+    }
+
+    /// `(loop [name value ...] body...)` is a `let` whose body `recur`, in
+    /// tail position, jumps back to the start of with the names rebound.
+    fn eval_loop(&mut self, args: Vec<Expression>) -> Result<wrap::Value<'m>, CodegenError> {
+        if let Some(bindings) = args.first() {
+            try!(recur_checked(interpreter::check_recur(bindings, false)));
+        }
+        try!(recur_checked(interpreter::check_recur_body(&args[1..], true)));
+
+        let (slots, shadowed) = try!(self.bind("loop", &args));
+        let loop_bb = self.builder.append_block("loop");
+        self.builder.br(loop_bb);
+        self.builder.position_at_end(loop_bb);
+
+        self.loops.push((loop_bb, slots));
+        let value = self.eval_do(args[1..].to_vec());
+        self.loops.pop();
+        self.unbind(shadowed);
+
+        match try!(value) {
+            Some(value) => Ok(value),
+            None => error(String::from("loop recurs on every path and never gives a value")),
         }
     }
 
-    fn get_params(function: LLVMValueRef) -> Vec<LLVMValueRef> {
-        let params_count = unsafe {LLVMCountParams(function)};
+    /// `(recur values...)` stores the values in the bindings of the
+    /// innermost loop and jumps back to its start. As nothing after it
+    /// runs, it gives no value.
+    fn eval_recur(&mut self, args: Vec<Expression>) -> Result<(), CodegenError> {
+        let (loop_bb, slots) = match self.loops.last() {
+            Some(innermost) => innermost.clone(),
+            None => return error(String::from("recur outside of loop")),
+        };
+        if args.len() != slots.len() {
+            return error(format!("Mismatched argument count to recur, expected: {} args, got: {}", slots.len(), args.len()));
+        }
 
-        let mut params = Vec::<LLVMValueRef>::with_capacity(params_count as usize);
-        unsafe {
-            for i in 0..params_count {
-                params.push(LLVMGetParam(function, i));
+        let mut values = vec!();
+        for arg in args {
+            values.push(try!(self.eval(arg)));
+        }
+
+        for (value, slot) in values.into_iter().zip(slots) {
+            let value = try!(self.coerce(value, slot.slot_type()));
+            self.builder.store(value, slot);
+        }
+        self.builder.br(loop_bb);
+
+        Ok(())
+    }
+
+    /// Gives each name of a binding vector a stack slot holding its value,
+    /// returning the slots and the locals they shadow. On an error the
+    /// names bound so far are unbound again.
+    fn bind(&mut self, form: &str, args: &[Expression])
+            -> Result<(Vec<wrap::Value<'m>>, Vec<(String, Option<wrap::Value<'m>>)>), CodegenError>
+    {
+        let bindings = match args.first().and_then(params) {
+            Some(bindings) => bindings,
+            None => return error(format!("{} requires a binding vector", form)),
+        };
+        if bindings.len() % 2 != 0 {
+            return error(format!("{} requires an even number of forms in its bindings", form));
+        }
+
+        let mut slots = vec!();
+        let mut shadowed = vec!();
+        for pair in bindings.chunks(2) {
+            let value = match symbol_name(&pair[0]).and_then(|name| self.eval(pair[1].clone()).map(|value| (name, value))) {
+                Ok(value) => value,
+                Err(e) => {
+                    self.unbind(shadowed);
+                    return Err(e);
+                },
+            };
+            let (name, value) = value;
+            let slot = self.slot(&name, value);
+            shadowed.push((name.clone(), self.named_values.insert(name, slot)));
+            slots.push(slot);
+        }
+        Ok((slots, shadowed))
+    }
+
+    fn unbind(&mut self, shadowed: Vec<(String, Option<wrap::Value<'m>>)>) {
+        for (name, slot) in shadowed.into_iter().rev() {
+            match slot {
+                Some(slot) => { self.named_values.insert(name, slot); },
+                None => { self.named_values.remove(&name); },
             }
         }
+    }
+
+    /// A stack slot in the entry block of the current function, where
+    /// mem2reg can promote it to a register, holding the value.
+    fn slot(&mut self, name: &str, value: wrap::Value<'m>) -> wrap::Value<'m> {
+        let entry = self.builder.function().entry_block().expect("a function being built has an entry block");
 
-        params
+        let builder = Builder::new(self.module);
+        match entry.first_instruction() {
+            Some(first) => builder.position_before(first),
+            None => builder.position_at_end(entry),
+        }
+        let slot = builder.alloca(value.llvm_type(), name);
+
+        self.builder.store(value, slot);
+        slot
     }
-}
 
-fn asserted_return(val: LLVMValueRef) -> LLVMValueRef {
-    assert!(val != 0 as LLVMValueRef);
-    val
-}
+    /// Comparisons of two numbers, giving an i1. An integer compared with a
+    /// double is converted to one.
+    fn eval_compare(&mut self, op: &str, args: Vec<Expression>) -> Result<wrap::Value<'m>, CodegenError> {
+        if args.len() != 2 {
+            return error(format!("{} requires 2 args, got {}", op, args.len()));
+        }
 
+        let lhs = try!(self.eval(args[0].clone()));
+        let rhs = try!(self.eval(args[1].clone()));
+        let operand_type = try!(numeric_type(op, &[lhs, rhs]));
+        let lhs = try!(self.coerce(lhs, operand_type));
+        let rhs = try!(self.coerce(rhs, operand_type));
+
+        Ok(match operand_type {
+            Type::Float => {
+                let predicate = match op {
+                    "<" => LLVMRealPredicate::LLVMRealOLT,
+                    ">" => LLVMRealPredicate::LLVMRealOGT,
+                    "<=" => LLVMRealPredicate::LLVMRealOLE,
+                    ">=" => LLVMRealPredicate::LLVMRealOGE,
+                    "=" => LLVMRealPredicate::LLVMRealOEQ,
+                    _ => LLVMRealPredicate::LLVMRealUNE,
+                };
+                self.builder.fcmp(predicate, lhs, rhs, "cmptmp")
+            },
+            _ => {
+                let predicate = match op {
+                    "<" => LLVMIntPredicate::LLVMIntSLT,
+                    ">" => LLVMIntPredicate::LLVMIntSGT,
+                    "<=" => LLVMIntPredicate::LLVMIntSLE,
+                    ">=" => LLVMIntPredicate::LLVMIntSGE,
+                    "=" => LLVMIntPredicate::LLVMIntEQ,
+                    _ => LLVMIntPredicate::LLVMIntNE,
+                };
+                self.builder.icmp(predicate, lhs, rhs, "cmptmp")
+            },
+        })
+    }
 
-impl LLVMEnvironment {
-    pub fn new() -> Self {
+    /// `+`, `-`, `*` and `/` on integers, or on doubles when an operand is
+    /// one. `(- x)` negates, and `(/ x)` is `(/ 1 x)`.
+    fn eval_arithmetic(&mut self, op: &str, args: Vec<Expression>) -> Result<wrap::Value<'m>, CodegenError> {
+        if args.is_empty() {
+            return match op {
+                "+" => self.eval(Expression::Number(String::from("0"))),
+                "*" => self.eval(Expression::Number(String::from("1"))),
+                _ => error(format!("{} requires at least 1 arg", op)),
+            };
+        }
 
-        INIT_LLVM.call_once(|| {
-            unsafe {
-                exec::LLVMLinkInInterpreter();
-                target::LLVM_InitializeNativeTarget();
-            }
-        });
+        let mut values = vec!();
+        for arg in args {
+            values.push(try!(self.eval(arg)));
+        }
 
-        let builder = unsafe {LLVMCreateBuilderInContext(LLVMGetGlobalContext())};
-        let module = unsafe {LLVMModuleCreateWithName(b"fancy\0".as_ptr() as *const _)};
+        let operand_type = try!(numeric_type(op, &values));
+        let mut operands = vec!();
+        for value in values {
+            operands.push(try!(self.coerce(value, operand_type)));
+        }
 
-        let engine = unsafe {
-            let mut error = 0 as *mut libc::c_char;
-            let mut exec_engine = 0 as LLVMExecutionEngineRef;
-            exec::LLVMCreateExecutionEngineForModule(&mut exec_engine,
-                                                     module,
-                                                     &mut error);
+        if operands.len() == 1 {
+            match op {
+                "-" if operand_type == Type::Float => return Ok(self.builder.fneg(operands[0], "negtmp")),
+                "-" => return Ok(self.builder.neg(operands[0], "negtmp")),
+                "/" => {
+                    let one = try!(self.eval(Expression::Number(String::from("1"))));
+                    operands.insert(0, try!(self.coerce(one, operand_type)));
+                },
+                _ => return Ok(operands[0]),
+            }
+        }
 
-            assert!(exec_engine != 0 as exec::LLVMExecutionEngineRef);
-            exec_engine
+        let float = operands[0].ty() == Type::Float;
+        // made for each operation, as opcodes aren't Copy
+        let opcode = || match (op, float) {
+            ("+", true) => LLVMOpcode::LLVMFAdd,
+            ("-", true) => LLVMOpcode::LLVMFSub,
+            ("*", true) => LLVMOpcode::LLVMFMul,
+            ("/", true) => LLVMOpcode::LLVMFDiv,
+            ("+", false) => LLVMOpcode::LLVMAdd,
+            ("-", false) => LLVMOpcode::LLVMSub,
+            ("*", false) => LLVMOpcode::LLVMMul,
+            _ => LLVMOpcode::LLVMSDiv,
         };
+        let mut result = operands[0];
+        for operand in &operands[1..] {
+            result = match (op, float) {
+                ("/", false) => self.build_int_div(result, *operand),
+                _ => self.builder.binary(opcode(), result, *operand, "arithtmp"),
+            };
+        }
+
+        Ok(result)
+    }
+
+    /// Integer division, which gives what the interpreter's does or a
+    /// runtime error: dividing by zero, a quotient too big for an i64, and
+    /// a remainder, which would make a ratio, are errors.
+    fn build_int_div(&mut self, lhs: wrap::Value<'m>, rhs: wrap::Value<'m>) -> wrap::Value<'m> {
+        let int_type = self.llvm_type(Type::Int);
+        let zero = wrap::Value::const_null(int_type);
+        let is_zero = self.builder.icmp(LLVMIntPredicate::LLVMIntEQ, rhs, zero, "zerotmp");
+        self.build_check(is_zero, "Divide by zero");
+
+        let is_min = self.builder.icmp(LLVMIntPredicate::LLVMIntEQ, lhs,
+                                       wrap::Value::const_int(int_type, i64::min_value() as u64), "mintmp");
+        let is_minus_one = self.builder.icmp(LLVMIntPredicate::LLVMIntEQ, rhs,
+                                             wrap::Value::const_int(int_type, -1i64 as u64), "minusonetmp");
+        let overflows = self.builder.binary(LLVMOpcode::LLVMAnd, is_min, is_minus_one, "overflowtmp");
+        self.build_check(overflows, "Integer overflow");
+
+        let remainder = self.builder.binary(LLVMOpcode::LLVMSRem, lhs, rhs, "remtmp");
+        let inexact = self.builder.icmp(LLVMIntPredicate::LLVMIntNE, remainder, zero, "inexacttmp");
+        self.build_check(inexact, "Integer division with a remainder: compiled code has no ratios");
+
+        self.builder.binary(LLVMOpcode::LLVMSDiv, lhs, rhs, "divtmp")
+    }
+
+    /// A value as a condition. Only nil and false are falsy: a tagged value
+    /// holds unless its tag says it's either, a string unless it's null,
+    /// which is nil, and a number always does.
+    fn to_bool(&mut self, value: wrap::Value<'m>) -> wrap::Value<'m> {
+        match value.ty() {
+            Type::Bool => value,
+            Type::Int | Type::Float => wrap::Value::const_int(self.llvm_type(Type::Bool), 1),
+            Type::Str => {
+                let null = wrap::Value::const_null(value.llvm_type());
+                self.builder.icmp(LLVMIntPredicate::LLVMIntNE, value, null, "booltmp")
+            },
+            Type::Tagged => {
+                let int_type = self.llvm_type(Type::Int);
+                let tag = self.builder.extract_element(value, 0, "tag");
+                let bits = self.builder.extract_element(value, 1, "bits");
+                let is_nil = self.builder.icmp(LLVMIntPredicate::LLVMIntEQ, tag,
+                                               wrap::Value::const_int(int_type, types::NIL_TAG), "niltmp");
+                let is_bool = self.builder.icmp(LLVMIntPredicate::LLVMIntEQ, tag,
+                                                wrap::Value::const_int(int_type, types::BOOL_TAG), "booltag");
+                let is_zero = self.builder.icmp(LLVMIntPredicate::LLVMIntEQ, bits,
+                                                wrap::Value::const_null(int_type), "zerotmp");
+                let is_false = self.builder.binary(LLVMOpcode::LLVMAnd, is_bool, is_zero, "falsetmp");
+                let is_falsy = self.builder.binary(LLVMOpcode::LLVMOr, is_nil, is_false, "falsytmp");
+                self.builder.not(is_falsy, "booltmp")
+            },
+        }
+    }
+
+    /// Converts a value to the type, with the casts implicit conversions
+    /// stand for: integers widen to doubles, and anything can be tagged.
+    fn coerce(&mut self, value: wrap::Value<'m>, to: Type) -> Result<wrap::Value<'m>, CodegenError> {
+        let from = value.ty();
+        let int_type = self.llvm_type(Type::Int);
+        match (from, to) {
+            _ if from == to => Ok(value),
+            (Type::Int, Type::Float) => Ok(self.builder.cast(LLVMOpcode::LLVMSIToFP, value, self.llvm_type(Type::Float), "casttmp")),
+            (_, Type::Tagged) => {
+                let bits = match from {
+                    Type::Float => self.builder.cast(LLVMOpcode::LLVMBitCast, value, int_type, "bits"),
+                    Type::Bool => self.builder.cast(LLVMOpcode::LLVMZExt, value, int_type, "bits"),
+                    Type::Str => self.builder.cast(LLVMOpcode::LLVMPtrToInt, value, int_type, "bits"),
+                    _ => value,
+                };
+                let tag = wrap::Value::const_int(int_type, from.tag());
+                let tagged = self.builder.insert_element(wrap::Value::undef(self.llvm_type(to)), tag, 0, "tagged");
+                Ok(self.builder.insert_element(tagged, bits, 1, "tagged"))
+            },
+            _ => checked(Err(TypeError { message: format!("expected {}, got {}", to, from) })),
+        }
+    }
 
-        let mut env = LLVMEnvironment{builder: builder,
-                                      module: module, engine: engine,
-                                      named_values: HashMap::new()
+    fn eval_fn(&mut self, name: String, args: Vec<Expression>) -> Result<wrap::Value<'m>, CodegenError> {
+        let function = match self.get_fn(&name) {
+            Some(function) => function,
+            None => return error(format!("Undefined function {}", name)),
         };
 
-        env.init_from_ir(String::from(
-"define i64 @\"add\"(i64, i64) {
-   entry:
-     %tmp = add i64 %0, %1
-     ret i64 %tmp
-}
+        let params = function.params();
 
-define i64 @\"sub\"(i64, i64) {
-   entry:
-     %tmp = sub i64 %0, %1
-     ret i64 %tmp
- }
+        if params.len() != args.len() {
+            return error(format!("{} requires {} args, got {}", name, params.len(), args.len()));
+        }
 
-; Function Attrs: nounwind uwtable
-define double @\"add_double\"(double %a, double %b) {
-  %1 = alloca double, align 8
-  %2 = alloca double, align 8
-  store double %a, double* %1, align 8
-  store double %b, double* %2, align 8
-  %3 = load double* %1, align 8
-  %4 = load double* %2, align 8
-  %5 = fadd double %3, %4
-  ret double %5
-}
+        let mut fn_args = Vec::<wrap::Value<'m>>::new();
+        for (a, param) in args.into_iter().zip(params) {
+            let value = try!(self.eval(a));
+            fn_args.push(try!(self.coerce(value, param.ty())));
+        }
 
-; Function Attrs: nounwind readonly
-declare double @atof(i8*)
-"));
+        Ok(self.builder.call(function, &fn_args, "tmpcall"))
+    }
 
-        env
+    /// `(extern name [params] result?)` declares a function defined outside
+    /// of the module. Parameters and the result are doubles unless
+    /// annotated, as in `[s :str]` and `:i64`.
+    fn defextern(&mut self, args: Vec<Expression>) -> Result<wrap::Value<'m>, CodegenError> {
+        if args.len() != 2 && args.len() != 3 {
+            return error(String::from("extern requires 2 or 3 params"));
+        }
+
+        let name = try!(symbol_name(&args[0]));
+        let params = try!(typed_params(&args[1]));
+        let result = match args.get(2) {
+            Some(result) => match annotation(result) {
+                Some(result) => result,
+                None => return error(format!("Expected a type, got {}", result)),
+            },
+            None => Type::Float,
+        };
+
+        let signature = Signature {
+            params: params.iter().map(|param| param.1.unwrap_or(Type::Float)).collect(),
+            result: result,
+        };
+        let names: Vec<String> = params.into_iter().map(|param| param.0).collect();
+        Ok(self.declare(&name, &names, &signature))
     }
 
-    #[allow(dead_code)]
-    pub fn llvm_dump(&mut self) {
-        unsafe {
-            LLVMDumpModule(self.module);
+    /// `(fn name [params] result? body...)`. The types of the parameters
+    /// and the result that aren't annotated are inferred from the body.
+    /// A body that doesn't compile leaves no function behind.
+    fn defn(&mut self, args: Vec<Expression>) -> Result<wrap::Value<'m>, CodegenError> {
+        if args.len() >= 2 {
+            let name = try!(symbol_name(&args[0]));
+            let params = try!(typed_params(&args[1]));
+            let (result, body) = match args.get(2).and_then(annotation) {
+                Some(result) => (Some(result), args[3..].to_vec()),
+                None => (None, args[2..].to_vec()),
+            };
+            try!(recur_checked(interpreter::check_recur_body(&body, true)));
+
+            let signature = try!(checked(types::infer_fn(&name, &params, result, &body, &|name| self.signature(name))));
+            let names: Vec<String> = params.into_iter().map(|param| param.0).collect();
+            let fndef = self.declare(&name, &names, &signature);
+
+            let bb = self.module.append_block(fndef, "entry");
+            self.builder.position_at_end(bb);
+
+            self.named_values.clear();
+            self.loops.clear();
+            for (param, name) in fndef.params().into_iter().zip(names) {
+                let slot = self.slot(&name, param);
+                self.named_values.insert(name, slot);
+            }
+
+            let inner = match self.eval_do(body).and_then(given).and_then(|inner| self.coerce(inner, signature.result)) {
+                Ok(inner) => inner,
+                Err(e) => {
+                    unsafe { fndef.delete_function() };
+                    return Err(e);
+                },
+            };
+
+            self.builder.ret(inner);
+
+            Ok(fndef)
+        }
+        else {
+            error(String::from("fn requires at least two params"))
         }
     }
 
-    fn verify(&mut self) {
-        unsafe {
-            let mut error = 0 as *mut libc::c_char;
-            analysis::LLVMVerifyModule(self.module,
-                             analysis::LLVMVerifierFailureAction::LLVMAbortProcessAction,
-                             &mut error);
+    fn declare(&mut self, name: &str, params: &[String], signature: &Signature) -> wrap::Value<'m> {
+        let param_types: Vec<_> = signature.params.iter().map(|ty| self.llvm_type(*ty)).collect();
+        let function = self.module.add_function(name, &param_types, self.llvm_type(signature.result));
+
+        for (param, name) in function.params().into_iter().zip(params) {
+            param.set_name(name);
         }
 
+        function
     }
+}
 
-    fn init_from_ir(&mut self, ir_code: String) {
-        unsafe {
-            let mut msg = 0 as *mut libc::c_char;
-            let len = ir_code.len();
-            let cstr = cstring(ir_code);
+/// The parameters of `extern` and `fn`, written `[a b]` or `#[a b]`.
+fn params(expression: &Expression) -> Option<Vec<Expression>> {
+    match *expression {
+        Expression::Params(ref params) => Some(params.clone()),
+        Expression::SExpression(ref items) if items.first() == Some(&::parser::symbol("vector")) =>
+            Some(items[1..].to_vec()),
+        _ => None,
+    }
+}
+
+fn symbol_name(expression: &Expression) -> Result<String, CodegenError> {
+    match *expression {
+        Expression::Symbol(ref name) => Ok(name.clone()),
+        _ => error(format!("Expected symbol, got {}", expression)),
+    }
+}
 
-            let membuf = LLVMCreateMemoryBufferWithMemoryRangeCopy(
-                cstr,
-                len as u64,
-                b"fancy\0".as_ptr() as *const _);
+/// The type a keyword like `:i64` annotates a parameter or result with.
+fn annotation(expression: &Expression) -> Option<Type> {
+    match *expression {
+        Expression::Symbol(ref s) => Type::from_keyword(s),
+        _ => None,
+    }
+}
 
-            llvm::ir_reader::LLVMParseIRInContext(LLVMGetGlobalContext(),
-                                                  membuf,
-                                                  &mut self.module,
-                                                  &mut msg);
+/// Parameter names, each with the type it is annotated with, if any.
+fn typed_params(expression: &Expression) -> Result<Vec<(String, Option<Type>)>, CodegenError> {
+    let params = match params(expression) {
+        Some(params) => params,
+        None => return error(format!("Expected params, got {}", expression)),
+    };
 
+    let mut typed: Vec<(String, Option<Type>)> = vec!();
+    for param in params {
+        match (annotation(&param), param) {
+            (Some(ty), _) => match typed.last_mut() {
+                Some(last) if last.1.is_none() => last.1 = Some(ty),
+                _ => return error(format!("Type annotation {} must follow a parameter", ty)),
+            },
+            (None, Expression::Symbol(name)) => typed.push((name, None)),
+            (None, other) => return error(format!("Expected symbol, got {}", other)),
         }
     }
+    Ok(typed)
+}
 
-    fn get_fn(&mut self, name: &String) -> LLVMValueRef {
-        unsafe {
-            LLVMGetNamedFunction(self.module, cstring(name.clone()))
+/// The type the operands of an arithmetic or comparison operator are
+/// converted to.
+fn numeric_type(op: &str, values: &[wrap::Value]) -> Result<Type, CodegenError> {
+    let mut result = Type::Int;
+    for (i, value) in values.iter().enumerate() {
+        let ty = value.ty();
+        if !ty.is_number() {
+            return checked(Err(TypeError { message: format!("{} requires numbers, got {}", op, ty) }));
         }
+        result = if i == 0 { ty } else { try!(checked(types::join(result, ty))) };
     }
+    Ok(result)
 }
 
-impl Drop for LLVMEnvironment {
-    fn drop(&mut self) {
+/// A type error as the error of the form it's found in.
+fn checked<T>(result: Result<T, TypeError>) -> Result<T, CodegenError> {
+    result.map_err(|e| CodegenError { message: e.to_string(), source: String::new(), position: None })
+}
+
+/// An error of the interpreter's check that a `recur` is in tail position,
+/// which compiled code would jump away from the middle of a form at.
+fn recur_checked(result: Result<(), interpreter::Error>) -> Result<(), CodegenError> {
+    result.or_else(|e| error(e.message))
+}
+
+fn is_definition(expression: &Expression) -> bool {
+    match *expression {
+        Expression::SExpression(ref sexp) =>
+            sexp.first() == Some(&::parser::symbol("fn")) || sexp.first() == Some(&::parser::symbol("extern")),
+        _ => false,
+    }
+}
+
+/// Calls compiled code without parameters returning a value of the type,
+/// and reads the value back as a fancy one.
+unsafe fn call_native(address: u64, ty: Type) -> interpreter::Value {
+    let address = address as usize;
+    match ty {
+        Type::Float => interpreter::Value::Float(mem::transmute::<usize, extern "C" fn() -> f64>(address)()),
+        // only the lowest bit of an i1 is defined
+        Type::Bool => interpreter::Value::Bool(mem::transmute::<usize, extern "C" fn() -> u8>(address)() & 1 != 0),
+        Type::Int => interpreter::Value::Int(mem::transmute::<usize, extern "C" fn() -> i64>(address)()),
+        Type::Str => buf_to_value(mem::transmute::<usize, extern "C" fn() -> *const libc::c_char>(address)()),
+        Type::Tagged => interpreter::Value::Nil,
+    }
+}
+
+/// Reads the tag and bits of a tagged value back as a fancy value.
+unsafe fn from_tagged(tag: u64, bits: u64) -> interpreter::Value {
+    match tag {
+        types::BOOL_TAG => interpreter::Value::Bool(bits != 0),
+        types::INT_TAG => interpreter::Value::Int(bits as i64),
+        types::FLOAT_TAG => interpreter::Value::Float(f64::from_bits(bits)),
+        types::STR_TAG => buf_to_value(bits as usize as *const libc::c_char),
+        _ => interpreter::Value::Nil,
+    }
+}
+
+
+/// Readies LLVM for the JIT and code for the host, once per process.
+fn initialize() {
+    INIT_LLVM.call_once(|| {
         unsafe {
-            LLVMDisposeBuilder(self.builder);
-            LLVMDisposeModule(self.module);
+            exec::LLVMLinkInMCJIT();
+            target::LLVM_InitializeNativeTarget();
+            target::LLVM_InitializeNativeAsmPrinter();
         }
-    }
+        host::register_symbols();
+    });
 }
 
-fn cstring_a(str: &'static str) -> *const libc::c_char {
-    cstring(String::from(str))
+impl LLVMEnvironment {
+    pub fn new() -> Self {
+        initialize();
+
+        let context = Context::new();
+        let module = Module::new("fancy", &context);
+        let (passes, module_passes) = create_passes(&module, 0);
+
+        // fancy.core macros are hidden behind symbols, which user macros
+        // defined later replace
+        let macros = interpreter::root_env();
+        for name in interpreter::names_in(&macros, "fancy.core").unwrap_or(vec!()) {
+            if let Some(interpreter::Value::Macro(_)) = macros.lookup(&name) {
+                if !EXPANDED_MACROS.contains(&&name[..]) {
+                    macros.define(&name, interpreter::Value::Symbol(name.clone()));
+                }
+            }
+        }
+
+        let mut env = LLVMEnvironment{passes: passes,
+                                      module_passes: module_passes,
+                                      module: module,
+                                      engine: None,
+                                      opt_level: 0,
+                                      macros: macros,
+                                      thunks: 0,
+                                      context: context
+        };
+
+        env.init_from_ir(String::from(BUILTINS)).unwrap_or_else(|e| panic!("{}", e));
+        env
+    }
+
+    /// Sets how much compiled code is optimized, from 0 to 3: 1 adds
+    /// instcombine, reassociate, GVN and simplifycfg to the passes run on
+    /// each function, 2 and up inlining and global DCE on the module.
+    pub fn set_opt_level(&mut self, level: u32) {
+        self.opt_level = level;
+        self.reset_passes();
+    }
+
+    /// Passes for the module being built, after it's changed.
+    fn reset_passes(&mut self) {
+        let (passes, module_passes) = create_passes(&self.module, self.opt_level);
+        self.passes = passes;
+        self.module_passes = module_passes;
+    }
+
+    fn optimize_module(&self) {
+        self.module_passes.run_on_module(&self.module);
+    }
+
+    /// Replaces the module being built with one parsed from textual IR.
+    fn init_from_ir(&mut self, ir_code: String) -> Result<(), CodegenError> {
+        let module = match Module::parse_ir("fancy", &ir_code, &self.context) {
+            Ok(module) => module,
+            Err(message) => return Err(CodegenError { message: message, source: ir_code, position: None }),
+        };
+
+        // the passes go before the module they run on
+        let (passes, module_passes) = create_passes(&module, self.opt_level);
+        self.passes = passes;
+        self.module_passes = module_passes;
+        self.module = module;
+        Ok(())
+    }
 }
 
-fn cstring(str: String) -> *const libc::c_char {
-    CString::new(str).unwrap().as_ptr()
+/// The passes run on each function of the module as it's compiled, and on
+/// the whole module, at the optimization level.
+fn create_passes(module: &Module, opt_level: u32) -> (PassManager, PassManager) {
+    // locals live in stack slots until mem2reg turns them into registers,
+    // so it runs at every level
+    let passes = PassManager::for_functions(module);
+    passes.add(scalar::LLVMAddPromoteMemoryToRegisterPass);
+    if opt_level >= 1 {
+        passes.add(scalar::LLVMAddInstructionCombiningPass);
+        passes.add(scalar::LLVMAddReassociatePass);
+        passes.add(scalar::LLVMAddGVNPass);
+        passes.add(scalar::LLVMAddCFGSimplificationPass);
+    }
+    passes.initialize();
+
+    let module_passes = PassManager::for_modules();
+    if opt_level >= 2 {
+        module_passes.add(ipo::LLVMAddFunctionInliningPass);
+        module_passes.add(ipo::LLVMAddGlobalDCEPass);
+    }
+    (passes, module_passes)
 }
 
-fn buf_to_string(buf: *const libc::c_char) -> String {
+/// Null, as a string result may be, is nil.
+fn buf_to_value(buf: *const libc::c_char) -> interpreter::Value {
+    if buf.is_null() {
+        return interpreter::Value::Nil;
+    }
     let str = unsafe {
         let slice = CStr::from_ptr(buf);
         str::from_utf8(slice.to_bytes()).unwrap()
     };
 
-    String::from(str)
+    interpreter::Value::Str(String::from(str))
 }
 
 #[test]
@@ -365,25 +1359,219 @@ fn demo_test() {
     demo();
 }
 
-pub fn demo() {
-    let mut llvm_env = LLVMEnvironment::new();
-    llvm_env.verify();
+#[test]
+fn runs_top_level_expressions() {
+    let mut env = LLVMEnvironment::new();
+    let forms = ::parser::parse_located(String::from("(add_double 1 2)"));
+    assert_eq!(interpreter::Value::Float(3.0), env.run_all(forms).unwrap());
+
+    let forms = ::parser::parse_located(String::from("(add_double 1 2) (add_double 0.25 2)"));
+    assert_eq!(interpreter::Value::Float(2.25), env.run_all(forms).unwrap());
+}
 
-    let add = llvm_env.get_fn(&String::from("add"));
-    let x: u64 = 2;
-    let y: u64 = 3;
+#[test]
+fn compiles_conditionals() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source))).unwrap();
+
+    run(&mut env, "(fn max [a b] (if (> a b) a b))
+                   (fn sign [x] (cond (< x 0) -1 (> x 0) 1 :else 0))
+                   (fn between [x lo hi] (if (and (<= lo x) (<= x hi)) 1 0))");
+    assert_eq!(interpreter::Value::Float(7.0), run(&mut env, "(max 3 7)"));
+    assert_eq!(interpreter::Value::Int(-1), run(&mut env, "(sign -2)"));
+    assert_eq!(interpreter::Value::Int(0), run(&mut env, "(sign 0)"));
+    assert_eq!(interpreter::Value::Int(1), run(&mut env, "(between 2 1 3)"));
+    assert_eq!(interpreter::Value::Int(0), run(&mut env, "(between 4 1 3)"));
+
+    assert_eq!(interpreter::Value::Bool(true), run(&mut env, "(or (> 1 2) (not (= 1 2)))"));
+    assert_eq!(interpreter::Value::Bool(false), run(&mut env, "(and (< 1 2) (> 1 2) (< 1 2))"));
+    assert_eq!(interpreter::Value::Int(5), run(&mut env, "(when (< 1 2) 4 5)"));
+    assert_eq!(interpreter::Value::Nil, run(&mut env, "(when (> 1 2) 5)"));
+    assert_eq!(interpreter::Value::Nil, run(&mut env, "(if (> 1 2) \"yes\")"));
+    assert_eq!(interpreter::Value::Nil, run(&mut env, "(cond (> 1 2) 1 (> 1 3) \"three\")"));
+}
 
-    let sum_result = unsafe {
-        let int64 = LLVMInt64Type();
+#[test]
+fn conditions_and_logic_agree_with_the_interpreter() {
+    let mut interpreter = interpreter::Interpreter::new();
+    let sources = ["(if 0 1 2)", "(if 0.0 1 2)", "(if \"\" 1 2)", "(if nil 1 2)", "(if false 1 2)", "(not 0)",
+                   "(and 1 2)", "(and 1 nil 2)", "(and true false)", "(and)", "(or nil 5)", "(or false nil)", "(or)",
+                   "(or nil \"yes\")", "(when true)", "(do)", "(cond 0 1 :else 2)", "(let [x 0] (when x 3))"];
+    for source in &sources {
+        let mut env = LLVMEnvironment::new();
+        let compiled = env.run_all(::parser::parse_located(String::from(*source))).unwrap();
+        assert_eq!(interpreter.eval_str(source).unwrap(), compiled, "{}", source);
+    }
+}
+
+#[test]
+fn infers_native_types() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source))).unwrap();
+
+    run(&mut env, "(fn fib [n] (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+                   (fn average [a b] (/ (+ a b) 2.0))
+                   (fn halve [x :i64] :f64 (/ x 2))");
+    assert_eq!(interpreter::Value::Int(6765), run(&mut env, "(fib 20)"));
+    assert_eq!(interpreter::Value::Float(1.5), run(&mut env, "(average 1 2)"));
+    assert_eq!(interpreter::Value::Float(4.0), run(&mut env, "(halve 8)"));
+    assert_eq!(interpreter::Value::Int(2), run(&mut env, "(/ 12 2 3)"));
+    assert_eq!(interpreter::Value::Int(-1), run(&mut env, "(/ -1)"));
+    assert_eq!(interpreter::Value::Float(0.5), run(&mut env, "(/ 2.0)"));
+    assert_eq!(interpreter::Value::Float(3.5), run(&mut env, "(/ 7 2.0)"));
+    assert_eq!(interpreter::Value::Int(42), run(&mut env, "(add 40 (- 7 5))"));
+
+    assert_eq!(interpreter::Value::Str(String::from("yes")), run(&mut env, "(if (< 1 2) \"yes\" \"no\")"));
+    assert_eq!(interpreter::Value::Float(2.5), run(&mut env, "(if (< 1 2) 2.5 nil)"));
+    assert_eq!(interpreter::Value::Nil, run(&mut env, "(if (> 1 2) 2.5 nil)"));
+    assert_eq!(interpreter::Value::Str(String::from("big")), run(&mut env, "(if (> 1 2) nil \"big\")"));
+}
+
+#[test]
+fn compiles_local_bindings_and_loops() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source))).unwrap();
+
+    assert_eq!(interpreter::Value::Int(8), run(&mut env, "(let [a 2 b (* a 3)] (+ a b))"));
+    assert_eq!(interpreter::Value::Int(11), run(&mut env, "(let [x 1] (+ (let [x 10] x) x))"));
+
+    run(&mut env, "(fn sum_to [n] (loop [i 0 acc 0] (if (> i n) acc (recur (+ i 1) (+ acc i)))))
+                   (fn mean_to [n :i64] (loop [i 1 acc 0.0] (if (> i n) (/ acc n) (recur (+ i 1) (+ acc i)))))");
+    assert_eq!(interpreter::Value::Int(5050), run(&mut env, "(sum_to 100)"));
+    assert_eq!(interpreter::Value::Float(2.5), run(&mut env, "(mean_to 4)"));
+
+    // the slots are promoted to registers, and the loop is a back-edge
+    let sum_to = env.codegen().find_fn("sum_to").unwrap().print();
+    assert!(!sum_to.contains("alloca"), sum_to);
+    assert!(!sum_to.contains("call"), sum_to);
+    assert!(sum_to.contains("phi"), sum_to);
+}
 
-        let lx = exec::LLVMCreateGenericValueOfInt(int64, x, 1);
-        let ly = exec::LLVMCreateGenericValueOfInt(int64, y, 1);
+#[test]
+fn jit_compiles_a_module_per_input() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source))).unwrap();
+
+    run(&mut env, "(fn fib [n] (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))");
+    run(&mut env, "(fn fib_sum [a b] (+ (fib a) (fib b)))");
+    assert_eq!(interpreter::Value::Int(832040 + 6765), run(&mut env, "(fib_sum 30 20)"));
+    assert_eq!(interpreter::Value::Nil, run(&mut env, "(prn (fib 10))"));
+    assert!(env.engine.as_ref().unwrap().modules().len() >= 4);
+}
 
-        let mut args = vec!(lx, ly);
+#[test]
+fn rejects_mismatched_types() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source)));
+
+    let error = run(&mut env, "(add 1.5 2)").unwrap_err();
+    assert_eq!("Type error: expected i64, got f64", error.message);
+    assert_eq!("(add 1.5 2)", error.source);
+
+    let error = run(&mut env, "(fn bad [x :i64] (+ x \"one\"))").unwrap_err();
+    assert_eq!("Type error: expected a number, got str", error.message);
+    assert!(env.codegen().find_fn("bad").is_none());
+    assert_eq!(interpreter::Value::Int(3), run(&mut env, "(add 1 2)").unwrap());
+}
 
-        let res = exec::LLVMRunFunction(llvm_env.engine, add, 2, args.as_mut_ptr());
+#[test]
+fn reports_and_removes_invalid_functions() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source)));
+
+    // a `recur` that isn't in tail position would leave code after the jump
+    let error = run(&mut env, "(fn twice [x] (* x 2))\n  (fn bad [] (loop [i 0] (do (recur 1) 2)))").unwrap_err();
+    assert_eq!("Can only recur from tail position", error.message);
+    assert_eq!("(fn bad [] (loop [i 0] (do (recur 1) 2)))", error.source);
+    assert_eq!(Some(Position { line: 2, column: 3 }), error.position);
+    assert!(env.codegen().find_fn("bad").is_none());
+
+    assert_eq!(interpreter::Value::Int(42), run(&mut env, "(twice 21)").unwrap());
+
+    let error = env.init_from_ir(String::from("define i64 @broken(")).unwrap_err();
+    assert!(error.message.contains("error"), error.message);
+    assert_eq!(interpreter::Value::Int(8), run(&mut env, "(twice 4)").unwrap());
+}
+
+#[test]
+fn expands_user_and_supported_macros() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source)));
+
+    assert_eq!(interpreter::Value::Int(42),
+               run(&mut env, "(defmacro twice [x] (list (quote *) 2 x)) (twice 21)").unwrap());
+    assert_eq!(interpreter::Value::Int(9), run(&mut env, "(-> 5 (add 1) (twice) (sub 3))").unwrap());
+    assert_eq!(interpreter::Value::Int(3), run(&mut env, "(when-not (> 1 2) 3)").unwrap());
+
+    // prelude macros expanding into code the backend can't compile stay
+    // unexpanded, and are reported as unknown
+    let error = run(&mut env, "(defn f [x] x)").unwrap_err();
+    assert_eq!("Type error: undefined function defn", error.message);
+    let error = run(&mut env, "(dotimes [i 3] (prn i))").unwrap_err();
+    assert_eq!("Type error: undefined function dotimes", error.message);
+}
 
-        exec::LLVMGenericValueToInt(res, 0)
+#[test]
+fn reports_malformed_forms() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source)));
+
+    let error = run(&mut env, "(add 1 2) (fn f [x] (+ x y))").unwrap_err();
+    assert_eq!("Type error: undefined symbol y", error.message);
+    assert_eq!("(fn f [x] (+ x y))", error.source);
+    assert_eq!(Some(Position { line: 1, column: 11 }), error.position);
+    assert!(env.codegen().find_fn("f").is_none());
+
+    assert_eq!("Type error: undefined function nope", run(&mut env, "(nope 1)").unwrap_err().message);
+    assert_eq!("extern requires 2 or 3 params", run(&mut env, "(extern e)").unwrap_err().message);
+    assert!(run(&mut env, "(defmacro m [x] (undefined-fn x))\n(m 1)").unwrap_err().message.contains("undefined-fn"));
+
+    assert_eq!(interpreter::Value::Int(3), run(&mut env, "(add 1 2)").unwrap());
+}
+
+#[test]
+fn rejects_destructuring() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source)));
+
+    for source in &["(let [[a b] [1 2]] a)", "(fn f [{:keys [a]}] a)", "(loop [[x & xs] [1 2]] x)",
+                    "(if-let [[a] nil] a 0)"] {
+        let error = run(&mut env, source).unwrap_err();
+        assert_eq!("Destructuring isn't supported in compiled code, which has no collections", error.message);
+        assert_eq!(*source, error.source);
+    }
+    assert!(env.codegen().find_fn("f").is_none());
+
+    assert_eq!(interpreter::Value::Int(1), run(&mut env, "(let [a 1] a)").unwrap());
+}
+
+#[test]
+fn rejects_recur_outside_of_tail_position() {
+    let mut env = LLVMEnvironment::new();
+    let run = |env: &mut LLVMEnvironment, source: &str| env.run_all(::parser::parse_located(String::from(source)));
+
+    for source in &["(loop [i 0] (+ 1 (recur 1)))", "(loop [i 0] (let [x (recur 1)] x))", "(do (recur 1) 3)",
+                    "(fn f [n] (loop [i 0] (if (recur 1) i 2)))"] {
+        let error = run(&mut env, source).unwrap_err();
+        assert_eq!("Can only recur from tail position", error.message);
+        assert_eq!(*source, error.source);
+    }
+    assert!(env.codegen().find_fn("f").is_none());
+
+    assert_eq!(interpreter::Value::Int(3), run(&mut env, "(loop [i 0] (if (< i 3) (recur (+ i 1)) i))").unwrap());
+}
+
+pub fn demo() {
+    let mut llvm_env = LLVMEnvironment::new();
+    llvm_env.module.verify().unwrap();
+
+    let add = llvm_env.function_address("add").unwrap();
+    let x: i64 = 2;
+    let y: i64 = 3;
+
+    let sum_result = unsafe {
+        let add: extern "C" fn(i64, i64) -> i64 = mem::transmute(add as usize);
+        add(x, y)
     };
 
     println!("LLVM SUM RESULT {}", sum_result);
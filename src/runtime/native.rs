@@ -0,0 +1,214 @@
+//! Ahead-of-time compilation: the module as an object file for the host,
+//! with a `main` running the top-level forms, linked into an executable
+//! with the runtime library.
+
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use llvm::core::*;
+use llvm::prelude::*;
+use llvm::target as target;
+use llvm::target_machine::*;
+use llvm::LLVMIntPredicate;
+
+use ::parser::{Expression, Position};
+
+use super::{checked, is_definition, libc, Codegen, CodegenError, Environment, LLVMEnvironment};
+use super::types::{self, Type};
+use super::wrap::{self, take_buffer, take_message};
+
+/// The source of the runtime library, compiled along with the object.
+const RUNTIME: &'static str = include_str!("fancy_rt.c");
+
+impl LLVMEnvironment {
+    /// Compiles the definitions, and a `main` that runs the other top-level
+    /// forms in order and prints the value of the last one, as running the
+    /// file does.
+    pub fn build_main(&mut self, located: Vec<(Position, Expression)>) -> Result<(), CodegenError> {
+        let mut forms = vec!();
+        for (position, e) in located {
+            match self.expand_form(e) {
+                Ok(Some(ref e)) if is_definition(e) => try!(self.define(e.clone()).map_err(|err| err.at(position))),
+                Ok(Some(e)) => forms.push((position, e)),
+                Ok(None) => (),
+                Err(err) => return Err(err.at(position)),
+            }
+        }
+
+        let source: Vec<String> = forms.iter().map(|&(_, ref form)| form.to_string()).collect();
+        let source = source.join(" ");
+        let mut codegen = self.codegen();
+        let main = self.module.add_function("main", &[], self.module.int32_type());
+        codegen.builder.position_at_end(self.module.append_block(main, "entry"));
+
+        let mut last = None;
+        for (position, form) in forms {
+            let form_source = form.to_string();
+            let compiled = checked(types::infer_expression(&form, &|name| codegen.signature(name))).and_then(|form_type| {
+                codegen.named_values.clear();
+                codegen.loops.clear();
+                codegen.eval(form).and_then(|value| codegen.coerce(value, form_type))
+            });
+            match compiled {
+                Ok(value) => last = Some(value),
+                Err(e) => {
+                    unsafe { main.delete_function() };
+                    return Err(e.in_form(&form_source).at(position));
+                },
+            }
+        }
+        if let Some(value) = last {
+            codegen.build_print_result(value);
+        }
+        codegen.builder.ret(wrap::Value::const_int(self.module.int32_type(), 0));
+        try!(self.finish(main, source));
+        self.optimize_module();
+        Ok(())
+    }
+}
+
+impl<'m> Codegen<'m> {
+    /// Prints the value of the last form unless it's nil, which `prn`
+    /// prints, but running a file doesn't.
+    fn build_print_result(&mut self, value: wrap::Value<'m>) {
+        if value.ty() != Type::Tagged {
+            return self.build_print(value);
+        }
+
+        let int_type = self.llvm_type(Type::Int);
+        let tag = self.builder.extract_element(value, 0, "tag");
+        let is_nil = self.builder.icmp(LLVMIntPredicate::LLVMIntEQ, tag,
+                                       wrap::Value::const_int(int_type, types::NIL_TAG), "niltmp");
+        let print_bb = self.builder.append_block("print");
+        let done_bb = self.builder.append_block("done");
+        self.builder.cond_br(is_nil, done_bb, print_bb);
+        self.builder.position_at_end(print_bb);
+        self.build_print(value);
+        self.builder.br(done_bb);
+        self.builder.position_at_end(done_bb);
+    }
+}
+
+impl LLVMEnvironment {
+    /// A target machine for the host, which the module is set up for.
+    unsafe fn host_machine(&mut self) -> Result<LLVMTargetMachineRef, String> {
+        target::LLVM_InitializeNativeAsmPrinter();
+
+        let triple = LLVMGetDefaultTargetTriple();
+        let mut llvm_target = 0 as LLVMTargetRef;
+        let mut error = 0 as *mut libc::c_char;
+        if LLVMGetTargetFromTriple(triple, &mut llvm_target, &mut error) != 0 {
+            LLVMDisposeMessage(triple);
+            return Err(take_message(error));
+        }
+
+        // executables are position independent by default on most hosts
+        let none = CString::new("").unwrap();
+        let machine = LLVMCreateTargetMachine(llvm_target, triple, none.as_ptr(), none.as_ptr(),
+                                              LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+                                              LLVMRelocMode::LLVMRelocPIC,
+                                              LLVMCodeModel::LLVMCodeModelDefault);
+        LLVMSetTarget(self.module.raw(), triple);
+        LLVMDisposeMessage(triple);
+        Ok(machine)
+    }
+
+    /// Writes the module as an object file for the host.
+    pub fn emit_object(&mut self, path: &Path) -> Result<(), String> {
+        unsafe {
+            let machine = try!(self.host_machine());
+            let mut error = 0 as *mut libc::c_char;
+            let path = CString::new(path.to_string_lossy().into_owned()).unwrap();
+            let failed = LLVMTargetMachineEmitToFile(machine, self.module.raw(), path.as_ptr() as *mut _,
+                                                     LLVMCodeGenFileType::LLVMObjectFile, &mut error);
+            LLVMDisposeTargetMachine(machine);
+            if failed != 0 {
+                return Err(take_message(error));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The module as assembly or object code for the host.
+    pub fn emit_machine_code(&mut self, assembly: bool) -> Result<Vec<u8>, String> {
+        let file_type = if assembly { LLVMCodeGenFileType::LLVMAssemblyFile } else { LLVMCodeGenFileType::LLVMObjectFile };
+        unsafe {
+            let machine = try!(self.host_machine());
+            let mut error = 0 as *mut libc::c_char;
+            let mut buffer = 0 as LLVMMemoryBufferRef;
+            let failed = LLVMTargetMachineEmitToMemoryBuffer(machine, self.module.raw(), file_type, &mut error, &mut buffer);
+            LLVMDisposeTargetMachine(machine);
+            if failed != 0 {
+                return Err(take_message(error));
+            }
+            Ok(take_buffer(buffer))
+        }
+    }
+}
+
+/// Links the object and the runtime library into an executable with the
+/// system's C compiler, `$CC` or `cc`.
+pub fn link(object: &Path, output: &Path) -> Result<(), String> {
+    let runtime = env::temp_dir().join(format!("fancy_rt-{}.c", unsafe { libc::getpid() }));
+    try!(File::create(&runtime).and_then(|mut file| file.write_all(RUNTIME.as_bytes()))
+         .map_err(|e| format!("Couldn't write the runtime library: {}", e)));
+
+    let cc = env::var("CC").unwrap_or(String::from("cc"));
+    let status = Command::new(&cc).arg(object).arg(&runtime).arg("-o").arg(output).arg("-lm").status();
+    fs::remove_file(&runtime).ok();
+
+    match status {
+        Ok(ref status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} failed linking {}: {}", cc, output.display(), status)),
+        Err(e) => Err(format!("Couldn't run {}: {}", cc, e)),
+    }
+}
+
+/// Compiles the forms into an executable, leaving the object file next to
+/// it.
+pub fn build(forms: Vec<(Position, Expression)>, output: &Path, opt_level: u32) -> Result<(), String> {
+    let mut env = LLVMEnvironment::new();
+    env.set_opt_level(opt_level);
+    try!(env.build_main(forms).map_err(|e| e.to_string()));
+
+    let object = output.with_extension("o");
+    try!(env.emit_object(&object));
+    link(&object, output)
+}
+
+#[test]
+fn builds_an_executable() {
+    let dir = env::temp_dir().join(format!("fancy-build-{}", unsafe { libc::getpid() }));
+    fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("sum");
+
+    let source = "(fn sum_to [n] (loop [i 0 acc 0] (if (> i n) acc (recur (+ i 1) (+ acc i)))))
+                  (sum_to 100)
+                  (/ (sum_to 10) 4.0)";
+    build(::parser::parse_located(String::from(source)), &output, 2).unwrap();
+
+    let run = Command::new(&output).output().unwrap();
+    assert!(run.status.success());
+    assert_eq!("13.75\n", String::from_utf8_lossy(&run.stdout));
+    assert!(dir.join("sum.o").exists());
+
+    // nil prints when printed, but not as the value of the last form
+    let output = dir.join("nils");
+    build(::parser::parse_located(String::from("(prn (when (> 1 2) 1)) (when (> 1 2) 2)")), &output, 0).unwrap();
+    let run = Command::new(&output).output().unwrap();
+    assert_eq!("nil\n", String::from_utf8_lossy(&run.stdout));
+
+    let output = dir.join("divide");
+    build(::parser::parse_located(String::from("(fn h [a] (/ a 0)) (prn 1) (h 5)")), &output, 2).unwrap();
+    let run = Command::new(&output).output().unwrap();
+    assert_eq!(Some(1), run.status.code());
+    assert_eq!("1\n", String::from_utf8_lossy(&run.stdout));
+    assert_eq!("Runtime error: Divide by zero\n", String::from_utf8_lossy(&run.stderr));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
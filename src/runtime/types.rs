@@ -0,0 +1,534 @@
+use std::fmt;
+
+use ::parser::Expression;
+
+/// The native types compiled code works with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+    Str,
+    /// A value of any type, with a tag saying which: how values whose type
+    /// is only known at run time, like nil, are passed around.
+    Tagged,
+}
+
+pub const NIL_TAG: u64 = 0;
+pub const BOOL_TAG: u64 = 1;
+pub const INT_TAG: u64 = 2;
+pub const FLOAT_TAG: u64 = 3;
+pub const STR_TAG: u64 = 4;
+
+impl Type {
+    /// The type an annotation like `:i64` names.
+    pub fn from_keyword(keyword: &str) -> Option<Type> {
+        match keyword {
+            ":i64" => Some(Type::Int),
+            ":f64" => Some(Type::Float),
+            ":bool" => Some(Type::Bool),
+            ":str" => Some(Type::Str),
+            ":any" => Some(Type::Tagged),
+            _ => None,
+        }
+    }
+
+    pub fn tag(self) -> u64 {
+        match self {
+            Type::Bool => BOOL_TAG,
+            Type::Int => INT_TAG,
+            Type::Float => FLOAT_TAG,
+            Type::Str => STR_TAG,
+            Type::Tagged => NIL_TAG,
+        }
+    }
+
+    pub fn is_number(self) -> bool {
+        self == Type::Int || self == Type::Float
+    }
+
+    /// Whether a value can be used where the type is expected, by a cast:
+    /// integers widen to doubles, and anything can be tagged.
+    pub fn converts_to(self, to: Type) -> bool {
+        self == to || (self == Type::Int && to == Type::Float) || to == Type::Tagged
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Type::Int => "i64",
+            Type::Float => "f64",
+            Type::Bool => "bool",
+            Type::Str => "str",
+            Type::Tagged => "any",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeError {
+    pub message: String,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Type error: {}", self.message)
+    }
+}
+
+/// The names and values of a `let` or `loop` binding vector.
+fn bindings<'e>(form: &str, expression: &'e Expression) -> Result<Vec<(String, &'e Expression)>, TypeError> {
+    let items = match *expression {
+        Expression::Params(ref items) => &items[..],
+        Expression::SExpression(ref items) if items.first() == Some(&::parser::symbol("vector")) => &items[1..],
+        _ => return error(format!("{} requires a binding vector", form)),
+    };
+    if items.len() % 2 != 0 {
+        return error(format!("{} requires an even number of forms in its bindings", form));
+    }
+
+    let mut result = vec!();
+    for pair in items.chunks(2) {
+        match pair[0] {
+            Expression::Symbol(ref name) => result.push((name.clone(), &pair[1])),
+            ref other => return error(format!("cannot bind {}", other)),
+        }
+    }
+    Ok(result)
+}
+
+fn error<T>(message: String) -> Result<T, TypeError> {
+    Err(TypeError { message: message })
+}
+
+/// The type two values are converted to where they meet, as the branches
+/// of an `if` or the operands of `+`.
+pub fn join(a: Type, b: Type) -> Result<Type, TypeError> {
+    if b.converts_to(a) {
+        Ok(a)
+    }
+    else if a.converts_to(b) {
+        Ok(b)
+    }
+    else {
+        error(format!("{} and {} don't mix", a, b))
+    }
+}
+
+/// Integer literals are i64, those with a fraction or exponent f64.
+pub fn literal_type(number: &str) -> Type {
+    if number.contains(|c| c == '.' || c == 'e' || c == 'E') {
+        Type::Float
+    }
+    else {
+        Type::Int
+    }
+}
+
+/// The parameter and result types of a function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub result: Type,
+}
+
+pub const COMPARISONS: [&'static str; 6] = ["<", ">", "<=", ">=", "=", "not="];
+pub const ARITHMETIC: [&'static str; 4] = ["+", "-", "*", "/"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Term {
+    Known(Type),
+    Var(usize),
+}
+
+enum Binding {
+    /// Not known yet; numeric when it's been used as a number.
+    Free(bool),
+    Bound(Term),
+}
+
+/// Finds the types of expressions by unifying how values are used. What is
+/// still open at the end, like the parameters of `(fn id [x] x)`, is a
+/// double, as every value used to be.
+struct Inference<'a> {
+    vars: Vec<Binding>,
+    locals: Vec<(String, Term)>,
+    /// The bindings of the loops `recur` may jump back to, innermost last.
+    loops: Vec<Vec<Term>>,
+    known: &'a Fn(&str) -> Option<Signature>,
+    /// The function being defined, which its body may call.
+    current: Option<(String, Vec<Term>, Term)>,
+}
+
+impl<'a> Inference<'a> {
+    fn new(known: &'a Fn(&str) -> Option<Signature>) -> Inference<'a> {
+        Inference { vars: vec!(), locals: vec!(), loops: vec!(), known: known, current: None }
+    }
+
+    fn var(&mut self) -> Term {
+        self.vars.push(Binding::Free(false));
+        Term::Var(self.vars.len() - 1)
+    }
+
+    fn resolve(&self, term: Term) -> Term {
+        match term {
+            Term::Var(i) => match self.vars[i] {
+                Binding::Bound(bound) => self.resolve(bound),
+                Binding::Free(_) => term,
+            },
+            known => known,
+        }
+    }
+
+    fn is_numeric(&self, i: usize) -> bool {
+        match self.vars[i] {
+            Binding::Free(numeric) => numeric,
+            Binding::Bound(_) => false,
+        }
+    }
+
+    fn bind(&mut self, i: usize, ty: Type) -> Result<Term, TypeError> {
+        if self.is_numeric(i) && !ty.is_number() {
+            return error(format!("expected a number, got {}", ty));
+        }
+        self.vars[i] = Binding::Bound(Term::Known(ty));
+        Ok(Term::Known(ty))
+    }
+
+    /// Two values that meet, as the branches of an `if`, have one type.
+    fn unify(&mut self, a: Term, b: Term) -> Result<Term, TypeError> {
+        match (self.resolve(a), self.resolve(b)) {
+            (Term::Known(a), Term::Known(b)) => join(a, b).map(Term::Known),
+            (Term::Var(i), Term::Known(ty)) | (Term::Known(ty), Term::Var(i)) => self.bind(i, ty),
+            (Term::Var(i), Term::Var(j)) => {
+                if i != j {
+                    let numeric = self.is_numeric(i) || self.is_numeric(j);
+                    self.vars[j] = Binding::Bound(Term::Var(i));
+                    self.vars[i] = Binding::Free(numeric);
+                }
+                Ok(Term::Var(i))
+            },
+        }
+    }
+
+    /// A value passed where the type is expected.
+    fn expect(&mut self, term: Term, ty: Type) -> Result<(), TypeError> {
+        match self.resolve(term) {
+            Term::Known(actual) if actual.converts_to(ty) => Ok(()),
+            Term::Known(actual) => error(format!("expected {}, got {}", ty, actual)),
+            Term::Var(i) => self.bind(i, ty).map(|_| ()),
+        }
+    }
+
+    fn numeric(&mut self, term: Term) -> Result<Term, TypeError> {
+        match self.resolve(term) {
+            Term::Known(ty) if ty.is_number() => Ok(term),
+            Term::Known(ty) => error(format!("expected a number, got {}", ty)),
+            Term::Var(i) => {
+                self.vars[i] = Binding::Free(true);
+                Ok(term)
+            },
+        }
+    }
+
+    fn finish(&self, term: Term) -> Type {
+        match self.resolve(term) {
+            Term::Known(ty) => ty,
+            Term::Var(_) => Type::Float,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Term> {
+        self.locals.iter().rev().find(|local| local.0 == name).map(|local| local.1)
+    }
+
+    /// The type of a body, whose last form gives its value, or nil.
+    fn infer_all(&mut self, expressions: &[Expression]) -> Result<Term, TypeError> {
+        let mut result = Term::Known(Type::Tagged);
+        for e in expressions {
+            result = try!(self.infer(e));
+        }
+        Ok(result)
+    }
+
+    fn infer(&mut self, expression: &Expression) -> Result<Term, TypeError> {
+        match *expression {
+            Expression::Number(ref number) => Ok(Term::Known(literal_type(number))),
+            Expression::String(_) => Ok(Term::Known(Type::Str)),
+            Expression::Symbol(ref symbol) => match symbol.as_ref() {
+                "true" | "false" => Ok(Term::Known(Type::Bool)),
+                "nil" => Ok(Term::Known(Type::Tagged)),
+                _ => match self.lookup(symbol) {
+                    Some(term) => Ok(term),
+                    None => error(format!("undefined symbol {}", symbol)),
+                },
+            },
+            Expression::SExpression(ref sexp) => match sexp.first() {
+                Some(&Expression::Symbol(ref name)) => self.infer_form(name, &sexp[1..]),
+                Some(other) => error(format!("cannot call {}", other)),
+                None => Ok(Term::Known(Type::Int)),
+            },
+            Expression::Params(_) => error(String::from("parameters outside of a function")),
+        }
+    }
+
+    fn infer_form(&mut self, name: &str, args: &[Expression]) -> Result<Term, TypeError> {
+        match name {
+            "do" => self.infer_all(args),
+            "if" if args.len() == 2 || args.len() == 3 => {
+                try!(self.infer(&args[0]));
+                let then = try!(self.infer(&args[1]));
+                match args.get(2) {
+                    Some(otherwise) => {
+                        let otherwise = try!(self.infer(otherwise));
+                        self.unify(then, otherwise)
+                    },
+                    // a missing else gives nil
+                    None => Ok(Term::Known(Type::Tagged)),
+                }
+            },
+            "when" if !args.is_empty() => {
+                try!(self.infer(&args[0]));
+                try!(self.infer_all(&args[1..]));
+                Ok(Term::Known(Type::Tagged))
+            },
+            "cond" => {
+                let mut terms = vec!();
+                let mut exhaustive = false;
+                for clause in args.chunks(2) {
+                    match clause[0] {
+                        Expression::Symbol(ref s) if s.starts_with(':') => exhaustive = true,
+                        ref test => { try!(self.infer(test)); },
+                    }
+                    if let Some(expression) = clause.get(1) {
+                        terms.push(try!(self.infer(expression)));
+                    }
+                }
+                // without a keyword test, no clause holding gives nil,
+                // which any value joins with
+                if !exhaustive || terms.is_empty() {
+                    return Ok(Term::Known(Type::Tagged));
+                }
+                let mut result = terms[0];
+                for &term in &terms[1..] {
+                    result = try!(self.unify(result, term));
+                }
+                Ok(result)
+            },
+            "let" | "loop" if !args.is_empty() => {
+                let depth = self.locals.len();
+                let mut terms = vec!();
+                for (local, value) in try!(bindings(name, &args[0])) {
+                    let term = try!(self.infer(value));
+                    self.locals.push((local, term));
+                    terms.push(term);
+                }
+
+                if name == "loop" {
+                    self.loops.push(terms);
+                }
+                let result = self.infer_all(&args[1..]);
+                if name == "loop" {
+                    self.loops.pop();
+                }
+                self.locals.truncate(depth);
+                result
+            },
+            // a value for every binding of the loop, which keeps its type;
+            // recur itself has none, so it fits any branch it's in
+            "recur" => {
+                let terms = match self.loops.last() {
+                    Some(terms) => terms.clone(),
+                    None => return error(String::from("recur outside of loop")),
+                };
+                if args.len() != terms.len() {
+                    return error(format!("recur requires {} args, got {}", terms.len(), args.len()));
+                }
+                for (arg, term) in args.iter().zip(terms) {
+                    let arg = try!(self.infer(arg));
+                    match self.resolve(term) {
+                        Term::Known(ty) => try!(self.expect(arg, ty)),
+                        _ => { try!(self.unify(arg, term)); },
+                    }
+                }
+                Ok(self.var())
+            },
+            "prn" => {
+                try!(self.infer_all(args));
+                Ok(Term::Known(Type::Tagged))
+            },
+            // `and` and `or` give the value of the operand that decides
+            "and" | "or" => {
+                let mut result = Term::Known(if name == "and" { Type::Bool } else { Type::Tagged });
+                for (i, arg) in args.iter().enumerate() {
+                    let term = try!(self.infer(arg));
+                    result = if i == 0 { term } else { try!(self.unify(result, term)) };
+                }
+                Ok(result)
+            },
+            "not" => {
+                try!(self.infer_all(args));
+                Ok(Term::Known(Type::Bool))
+            },
+            _ if COMPARISONS.contains(&name) || ARITHMETIC.contains(&name) => {
+                let mut result = None;
+                for arg in args {
+                    let term = try!(self.infer(arg));
+                    let term = try!(self.numeric(term));
+                    result = Some(match result {
+                        Some(previous) => try!(self.unify(previous, term)),
+                        None => term,
+                    });
+                }
+                if COMPARISONS.contains(&name) {
+                    Ok(Term::Known(Type::Bool))
+                }
+                else {
+                    Ok(result.unwrap_or(Term::Known(Type::Int)))
+                }
+            },
+            _ => self.infer_call(name, args),
+        }
+    }
+
+    fn infer_call(&mut self, name: &str, args: &[Expression]) -> Result<Term, TypeError> {
+        if let Some((ref current, ref params, result)) = self.current.clone() {
+            if current == name {
+                if args.len() != params.len() {
+                    return error(format!("{} requires {} args, got {}", name, params.len(), args.len()));
+                }
+                for (arg, param) in args.iter().zip(params) {
+                    let term = try!(self.infer(arg));
+                    try!(self.unify(term, *param));
+                }
+                return Ok(result);
+            }
+        }
+
+        let signature = match (self.known)(name) {
+            Some(signature) => signature,
+            None => return error(format!("undefined function {}", name)),
+        };
+        if args.len() != signature.params.len() {
+            return error(format!("{} requires {} args, got {}", name, signature.params.len(), args.len()));
+        }
+        for (arg, param) in args.iter().zip(&signature.params) {
+            let term = try!(self.infer(arg));
+            try!(self.expect(term, *param));
+        }
+        Ok(Term::Known(signature.result))
+    }
+}
+
+/// The type of an expression compiled outside of any function.
+pub fn infer_expression(expression: &Expression, known: &Fn(&str) -> Option<Signature>) -> Result<Type, TypeError> {
+    let mut inference = Inference::new(known);
+    let term = try!(inference.infer(expression));
+    Ok(inference.finish(term))
+}
+
+/// The signature of a function from its body; the parameters and result
+/// may be annotated.
+pub fn infer_fn(name: &str, params: &[(String, Option<Type>)], result: Option<Type>, body: &[Expression],
+                known: &Fn(&str) -> Option<Signature>) -> Result<Signature, TypeError> {
+    let mut inference = Inference::new(known);
+
+    let mut param_terms = vec!();
+    for &(ref param, ty) in params {
+        let term = match ty {
+            Some(ty) => Term::Known(ty),
+            None => inference.var(),
+        };
+        inference.locals.push((param.clone(), term));
+        param_terms.push(term);
+    }
+    let result_term = match result {
+        Some(ty) => Term::Known(ty),
+        None => inference.var(),
+    };
+    inference.current = Some((String::from(name), param_terms.clone(), result_term));
+
+    let body_term = try!(inference.infer_all(body));
+    match result {
+        Some(ty) => try!(inference.expect(body_term, ty)),
+        None => { try!(inference.unify(result_term, body_term)); },
+    }
+
+    Ok(Signature {
+        params: param_terms.iter().map(|term| inference.finish(*term)).collect(),
+        result: inference.finish(result_term),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{infer_expression, infer_fn, Signature, Type};
+    use ::parser::{parse_string, Expression};
+
+    fn known(name: &str) -> Option<Signature> {
+        match name {
+            "add_double" => Some(Signature { params: vec!(Type::Float, Type::Float), result: Type::Float }),
+            "add" => Some(Signature { params: vec!(Type::Int, Type::Int), result: Type::Int }),
+            _ => None,
+        }
+    }
+
+    fn parse(source: &str) -> Expression {
+        parse_string(String::from(source)).remove(0)
+    }
+
+    fn signature(params: &[&str], body: &str) -> Result<Signature, String> {
+        let params: Vec<(String, Option<Type>)> = params.iter().map(|p| (String::from(*p), None)).collect();
+        infer_fn("f", &params, None, &[parse(body)], &known).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn literals_and_calls() {
+        assert_eq!(Ok(Type::Int), infer_expression(&parse("(+ 1 2)"), &known));
+        assert_eq!(Ok(Type::Float), infer_expression(&parse("(+ 1 2.5)"), &known));
+        assert_eq!(Ok(Type::Float), infer_expression(&parse("(add_double 1 2)"), &known));
+        assert_eq!(Ok(Type::Bool), infer_expression(&parse("(and (< 1 2) true)"), &known));
+        assert_eq!(Ok(Type::Int), infer_expression(&parse("(and 1 2)"), &known));
+        assert_eq!(Ok(Type::Tagged), infer_expression(&parse("(or nil 5)"), &known));
+        assert_eq!(Ok(Type::Tagged), infer_expression(&parse("(do)"), &known));
+        assert_eq!(Ok(Type::Float), infer_expression(&parse("(if true 1 2.0)"), &known));
+        assert_eq!(Ok(Type::Tagged), infer_expression(&parse("(if true 1 nil)"), &known));
+        assert_eq!(Ok(Type::Tagged), infer_expression(&parse("(if true \"one\")"), &known));
+        assert_eq!(Ok(Type::Tagged), infer_expression(&parse("(when true 1)"), &known));
+        assert_eq!(Ok(Type::Tagged), infer_expression(&parse("(cond false 1 true \"s\")"), &known));
+        assert_eq!(Ok(Type::Int), infer_expression(&parse("(cond false 1 :else 2)"), &known));
+        assert_eq!("Type error: expected i64, got f64",
+                   infer_expression(&parse("(add 1.5 2)"), &known).unwrap_err().to_string());
+        assert_eq!("Type error: i64 and str don't mix",
+                   infer_expression(&parse("(if true 1 \"one\")"), &known).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn function_signatures() {
+        let ints = Signature { params: vec!(Type::Int), result: Type::Int };
+        assert_eq!(Ok(ints), signature(&["n"], "(if (< n 2) n (+ (f (- n 1)) (f (- n 2))))"));
+
+        let floats = Signature { params: vec!(Type::Float, Type::Float), result: Type::Float };
+        assert_eq!(Ok(floats.clone()), signature(&["a", "b"], "(if (> a b) a b)"));
+        assert_eq!(Ok(floats), signature(&["a", "b"], "(add_double a b)"));
+
+        assert_eq!(Err(String::from("Type error: expected a number, got str")),
+                   signature(&["s", "t"], "(if (< s t) s \"s\")"));
+    }
+
+    #[test]
+    fn local_bindings() {
+        assert_eq!(Ok(Type::Float), infer_expression(&parse("(let [x 1.5 y x] y)"), &known));
+        assert_eq!(Ok(Type::Int), infer_expression(&parse("(let [x 1.5] (let [x 2] x))"), &known));
+
+        let sum = "(loop [i 0 acc 0] (if (> i n) acc (recur (+ i 1) (+ acc i))))";
+        assert_eq!(Ok(Signature { params: vec!(Type::Int), result: Type::Int }), signature(&["n"], sum));
+
+        assert_eq!("Type error: expected i64, got f64",
+                   infer_expression(&parse("(loop [i 0] (if (< i 3) (recur 0.5) i))"), &known).unwrap_err().to_string());
+        assert_eq!("Type error: recur outside of loop",
+                   infer_expression(&parse("(recur 1)"), &known).unwrap_err().to_string());
+    }
+}
@@ -0,0 +1,609 @@
+//! Owned wrappers around the llvm-sys handles the backend uses, so code
+//! generation doesn't need `unsafe` for every call. An owner disposes of
+//! its handle when dropped; modules and the JIT each keep the context they
+//! were made in alive, and the modules the JIT has taken are disposed of
+//! with it.
+//!
+//! Values, blocks and types are handles into a module, and borrow it: one
+//! can't outlive the module it's in, so looking through it is safe. Only
+//! deleting a function or a block, which leaves the handles into it
+//! dangling, is `unsafe`.
+
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+use llvm::{LLVMIntPredicate, LLVMOpcode, LLVMRealPredicate, LLVMTypeKind};
+use llvm::prelude::*;
+use llvm::core::*;
+use llvm::analysis as analysis;
+use llvm::bit_writer;
+use llvm::execution_engine as exec;
+use llvm::execution_engine::{LLVMExecutionEngineRef, LLVMMCJITCompilerOptions};
+use llvm::ir_reader;
+
+use super::libc;
+use super::types::Type;
+
+/// A name as LLVM takes it. The string has to outlive the call it's passed
+/// to, so it's made in the argument list: `c_str(name).as_ptr()`.
+fn c_str(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+/// A message LLVM allocated, which is disposed of.
+pub unsafe fn take_message(message: *mut libc::c_char) -> String {
+    if message.is_null() {
+        return String::new();
+    }
+    let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+    LLVMDisposeMessage(message);
+    text
+}
+
+/// The contents of a memory buffer, which is disposed of.
+pub unsafe fn take_buffer(buffer: LLVMMemoryBufferRef) -> Vec<u8> {
+    let start = LLVMGetBufferStart(buffer) as *const u8;
+    let bytes = ::std::slice::from_raw_parts(start, LLVMGetBufferSize(buffer) as usize).to_vec();
+    LLVMDisposeMemoryBuffer(buffer);
+    bytes
+}
+
+pub struct Context {
+    raw: LLVMContextRef,
+}
+
+impl Context {
+    pub fn new() -> Rc<Context> {
+        Rc::new(Context { raw: unsafe { LLVMContextCreate() } })
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe { LLVMContextDispose(self.raw) }
+    }
+}
+
+pub struct Module {
+    /// Null once the JIT has disposed of the module.
+    raw: LLVMModuleRef,
+    /// The types and constants of the module's values are made in it.
+    context: Rc<Context>,
+}
+
+impl Module {
+    pub fn new(name: &str, context: &Rc<Context>) -> Module {
+        let raw = unsafe { LLVMModuleCreateWithNameInContext(c_str(name).as_ptr(), context.raw) };
+        Module { raw: raw, context: context.clone() }
+    }
+
+    /// A module read from textual IR, or the parser's message.
+    pub fn parse_ir(name: &str, ir: &str, context: &Rc<Context>) -> Result<Module, String> {
+        unsafe {
+            // the parser takes the buffer
+            let buffer = LLVMCreateMemoryBufferWithMemoryRangeCopy(ir.as_ptr() as *const _, ir.len() as u64,
+                                                                   c_str(name).as_ptr());
+            let mut raw = ptr::null_mut();
+            let mut message = ptr::null_mut();
+            if ir_reader::LLVMParseIRInContext(context.raw, buffer, &mut raw, &mut message) != 0 {
+                return Err(take_message(message));
+            }
+            Ok(Module { raw: raw, context: context.clone() })
+        }
+    }
+
+    /// The handle, which is disposed of with the module.
+    pub unsafe fn raw(&self) -> LLVMModuleRef {
+        self.raw
+    }
+
+    /// The LLVM type of values of the type: `<2 x i64>` for tagged ones,
+    /// the tag and the bits of the value.
+    pub fn llvm_type(&self, ty: Type) -> LLVMType {
+        let context = self.context.raw;
+        LLVMType::new(unsafe {
+            match ty {
+                Type::Int => LLVMInt64TypeInContext(context),
+                Type::Float => LLVMDoubleTypeInContext(context),
+                Type::Bool => LLVMInt1TypeInContext(context),
+                Type::Str => LLVMPointerType(LLVMInt8TypeInContext(context), 0),
+                Type::Tagged => LLVMVectorType(LLVMInt64TypeInContext(context), 2),
+            }
+        })
+    }
+
+    pub fn int32_type(&self) -> LLVMType {
+        LLVMType::new(unsafe { LLVMInt32TypeInContext(self.context.raw) })
+    }
+
+    pub fn void_type(&self) -> LLVMType {
+        LLVMType::new(unsafe { LLVMVoidTypeInContext(self.context.raw) })
+    }
+
+    pub fn add_function<'m>(&'m self, name: &str, params: &[LLVMType<'m>], result: LLVMType<'m>) -> Value<'m> {
+        unsafe {
+            let mut params: Vec<LLVMTypeRef> = params.iter().map(|param| param.raw).collect();
+            let function_type = LLVMFunctionType(result.raw, params.as_mut_ptr(), params.len() as u32, 0);
+            Value::new(LLVMAddFunction(self.raw, c_str(name).as_ptr(), function_type))
+        }
+    }
+
+    /// A declaration of a function defined in another module of the
+    /// context.
+    pub fn declare_like(&self, function: Value) -> Value {
+        unsafe {
+            let name = LLVMGetValueName(function.function());
+            Value::new(LLVMAddFunction(self.raw, name, LLVMGetElementType(LLVMTypeOf(function.raw))))
+        }
+    }
+
+    pub fn named_function(&self, name: &str) -> Option<Value> {
+        let function = unsafe { LLVMGetNamedFunction(self.raw, c_str(name).as_ptr()) };
+        if function.is_null() { None } else { Some(Value::new(function)) }
+    }
+
+    /// A global of the type, initialized to zero.
+    pub fn add_global<'m>(&'m self, ty: LLVMType<'m>, name: &str) -> Value<'m> {
+        unsafe {
+            let global = LLVMAddGlobal(self.raw, ty.raw, c_str(name).as_ptr());
+            LLVMSetInitializer(global, LLVMConstNull(ty.raw));
+            Value::new(global)
+        }
+    }
+
+    /// A block added at the end of the function.
+    pub fn append_block<'m>(&'m self, function: Value<'m>, name: &str) -> BasicBlock<'m> {
+        BasicBlock::new(unsafe {
+            LLVMAppendBasicBlockInContext(self.context.raw, function.function(), c_str(name).as_ptr())
+        })
+    }
+
+    /// The verifier's message if the module is invalid.
+    pub fn verify(&self) -> Result<(), String> {
+        unsafe {
+            let mut message = ptr::null_mut();
+            let failed = analysis::LLVMVerifyModule(self.raw,
+                                                    analysis::LLVMVerifierFailureAction::LLVMReturnStatusAction,
+                                                    &mut message);
+            let message = take_message(message);
+            if failed != 0 { Err(message) } else { Ok(()) }
+        }
+    }
+
+    /// The module as textual IR.
+    pub fn print(&self) -> String {
+        unsafe { take_message(LLVMPrintModuleToString(self.raw)) }
+    }
+
+    pub fn bitcode(&self) -> Vec<u8> {
+        unsafe { take_buffer(bit_writer::LLVMWriteBitcodeToMemoryBuffer(self.raw)) }
+    }
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        if !self.raw.is_null() {
+            unsafe { LLVMDisposeModule(self.raw) }
+        }
+    }
+}
+
+/// Builds instructions into the functions of a module.
+pub struct Builder<'m> {
+    raw: LLVMBuilderRef,
+    module: &'m Module,
+}
+
+impl<'m> Builder<'m> {
+    pub fn new(module: &'m Module) -> Builder<'m> {
+        Builder { raw: unsafe { LLVMCreateBuilderInContext(module.context.raw) }, module: module }
+    }
+
+    pub fn position_at_end(&self, block: BasicBlock<'m>) {
+        unsafe { LLVMPositionBuilderAtEnd(self.raw, block.raw) }
+    }
+
+    pub fn position_before(&self, instruction: Value<'m>) {
+        unsafe { LLVMPositionBuilderBefore(self.raw, instruction.raw) }
+    }
+
+    /// The block being built. Panics if the builder hasn't been
+    /// positioned in one.
+    pub fn insert_block(&self) -> BasicBlock<'m> {
+        let block = unsafe { LLVMGetInsertBlock(self.raw) };
+        assert!(!block.is_null(), "the builder isn't in a block");
+        BasicBlock::new(block)
+    }
+
+    /// The function being built.
+    pub fn function(&self) -> Value<'m> {
+        self.insert_block().parent()
+    }
+
+    /// A block added at the end of the function being built.
+    pub fn append_block(&self, name: &str) -> BasicBlock<'m> {
+        self.module.append_block(self.function(), name)
+    }
+
+    pub fn alloca(&self, ty: LLVMType<'m>, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildAlloca(self.raw, ty.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn load(&self, pointer: Value<'m>, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildLoad(self.raw, pointer.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn store(&self, value: Value<'m>, pointer: Value<'m>) {
+        unsafe { LLVMBuildStore(self.raw, value.raw, pointer.raw); }
+    }
+
+    pub fn br(&self, block: BasicBlock<'m>) {
+        unsafe { LLVMBuildBr(self.raw, block.raw); }
+    }
+
+    pub fn cond_br(&self, condition: Value<'m>, then: BasicBlock<'m>, otherwise: BasicBlock<'m>) {
+        unsafe { LLVMBuildCondBr(self.raw, condition.raw, then.raw, otherwise.raw); }
+    }
+
+    pub fn ret(&self, value: Value<'m>) {
+        unsafe { LLVMBuildRet(self.raw, value.raw); }
+    }
+
+    pub fn ret_void(&self) {
+        unsafe { LLVMBuildRetVoid(self.raw); }
+    }
+
+    /// Ends a block that's never left, as after a call that doesn't
+    /// return.
+    pub fn unreachable(&self) {
+        unsafe { LLVMBuildUnreachable(self.raw); }
+    }
+
+    /// A phi taking each value when coming from its block.
+    pub fn phi(&self, ty: LLVMType<'m>, incoming: &[(Value<'m>, BasicBlock<'m>)], name: &str) -> Value<'m> {
+        unsafe {
+            let phi = LLVMBuildPhi(self.raw, ty.raw, c_str(name).as_ptr());
+            let mut values: Vec<LLVMValueRef> = incoming.iter().map(|branch| branch.0.raw).collect();
+            let mut blocks: Vec<LLVMBasicBlockRef> = incoming.iter().map(|branch| branch.1.raw).collect();
+            LLVMAddIncoming(phi, values.as_mut_ptr(), blocks.as_mut_ptr(), values.len() as u32);
+            Value::new(phi)
+        }
+    }
+
+    pub fn call(&self, function: Value<'m>, args: &[Value<'m>], name: &str) -> Value<'m> {
+        unsafe {
+            let mut args: Vec<LLVMValueRef> = args.iter().map(|arg| arg.raw).collect();
+            Value::new(LLVMBuildCall(self.raw, function.function(), args.as_mut_ptr(), args.len() as u32,
+                                     c_str(name).as_ptr()))
+        }
+    }
+
+    pub fn binary(&self, op: LLVMOpcode, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildBinOp(self.raw, op, lhs.raw, rhs.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn cast(&self, op: LLVMOpcode, value: Value<'m>, ty: LLVMType<'m>, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildCast(self.raw, op, value.raw, ty.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn neg(&self, value: Value<'m>, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildNeg(self.raw, value.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn fneg(&self, value: Value<'m>, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildFNeg(self.raw, value.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn not(&self, value: Value<'m>, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildNot(self.raw, value.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn icmp(&self, predicate: LLVMIntPredicate, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildICmp(self.raw, predicate, lhs.raw, rhs.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn fcmp(&self, predicate: LLVMRealPredicate, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildFCmp(self.raw, predicate, lhs.raw, rhs.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn extract_element(&self, vector: Value<'m>, index: u64, name: &str) -> Value<'m> {
+        let index = Value::const_int(self.module.int32_type(), index);
+        Value::new(unsafe { LLVMBuildExtractElement(self.raw, vector.raw, index.raw, c_str(name).as_ptr()) })
+    }
+
+    pub fn insert_element(&self, vector: Value<'m>, element: Value<'m>, index: u64, name: &str) -> Value<'m> {
+        let index = Value::const_int(self.module.int32_type(), index);
+        Value::new(unsafe {
+            LLVMBuildInsertElement(self.raw, vector.raw, element.raw, index.raw, c_str(name).as_ptr())
+        })
+    }
+
+    /// A pointer to a global holding the string.
+    pub fn global_string_ptr(&self, s: &str, name: &str) -> Value<'m> {
+        Value::new(unsafe { LLVMBuildGlobalStringPtr(self.raw, c_str(s).as_ptr(), c_str(name).as_ptr()) })
+    }
+}
+
+impl<'m> Drop for Builder<'m> {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeBuilder(self.raw) }
+    }
+}
+
+/// The type of values in a module.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LLVMType<'m> {
+    raw: LLVMTypeRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> LLVMType<'m> {
+    fn new(raw: LLVMTypeRef) -> LLVMType<'m> {
+        LLVMType { raw: raw, module: PhantomData }
+    }
+
+    /// The type of values of the LLVM type.
+    pub fn ty(self) -> Type {
+        unsafe {
+            match LLVMGetTypeKind(self.raw) {
+                LLVMTypeKind::LLVMDoubleTypeKind => Type::Float,
+                LLVMTypeKind::LLVMIntegerTypeKind if LLVMGetIntTypeWidth(self.raw) == 1 => Type::Bool,
+                LLVMTypeKind::LLVMIntegerTypeKind => Type::Int,
+                LLVMTypeKind::LLVMPointerTypeKind => Type::Str,
+                _ => Type::Tagged,
+            }
+        }
+    }
+}
+
+/// A value, or a function, in a module.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Value<'m> {
+    raw: LLVMValueRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> Value<'m> {
+    fn new(raw: LLVMValueRef) -> Value<'m> {
+        Value { raw: raw, module: PhantomData }
+    }
+
+    /// The handle, which has to be a function's for the methods that take
+    /// one.
+    fn function(self) -> LLVMValueRef {
+        assert!(unsafe { !LLVMIsAFunction(self.raw).is_null() }, "not a function");
+        self.raw
+    }
+
+    pub fn const_int(ty: LLVMType<'m>, n: u64) -> Value<'m> {
+        Value::new(unsafe { LLVMConstInt(ty.raw, n, 0) })
+    }
+
+    pub fn const_real(ty: LLVMType<'m>, x: f64) -> Value<'m> {
+        Value::new(unsafe { LLVMConstReal(ty.raw, x) })
+    }
+
+    pub fn const_null(ty: LLVMType<'m>) -> Value<'m> {
+        Value::new(unsafe { LLVMConstNull(ty.raw) })
+    }
+
+    pub fn undef(ty: LLVMType<'m>) -> Value<'m> {
+        Value::new(unsafe { LLVMGetUndef(ty.raw) })
+    }
+
+    pub fn llvm_type(self) -> LLVMType<'m> {
+        LLVMType::new(unsafe { LLVMTypeOf(self.raw) })
+    }
+
+    pub fn ty(self) -> Type {
+        self.llvm_type().ty()
+    }
+
+    /// The type of what a stack slot holds.
+    pub fn slot_type(self) -> Type {
+        unsafe {
+            assert!(!LLVMIsAAllocaInst(self.raw).is_null(), "not a stack slot");
+            LLVMType::new(LLVMGetElementType(LLVMTypeOf(self.raw))).ty()
+        }
+    }
+
+    pub fn set_name(self, name: &str) {
+        unsafe { LLVMSetValueName(self.raw, c_str(name).as_ptr()) }
+    }
+
+    pub fn params(self) -> Vec<Value<'m>> {
+        let function = self.function();
+        unsafe { (0..LLVMCountParams(function)).map(|i| Value::new(LLVMGetParam(function, i))).collect() }
+    }
+
+    pub fn return_type(self) -> Type {
+        LLVMType::new(unsafe { LLVMGetReturnType(LLVMGetElementType(LLVMTypeOf(self.function()))) }).ty()
+    }
+
+    pub fn is_declaration(self) -> bool {
+        unsafe { LLVMIsDeclaration(self.function()) != 0 }
+    }
+
+    /// The first block of the function, unless it's only declared.
+    pub fn entry_block(self) -> Option<BasicBlock<'m>> {
+        let entry = unsafe { LLVMGetEntryBasicBlock(self.function()) };
+        if entry.is_null() { None } else { Some(BasicBlock::new(entry)) }
+    }
+
+    /// Removes the function from its module, along with the calls to it.
+    /// Unsafe as the handles into it are left dangling.
+    pub unsafe fn delete_function(self) {
+        let function = self.function();
+        LLVMReplaceAllUsesWith(function, LLVMGetUndef(LLVMTypeOf(function)));
+        LLVMDeleteFunction(function);
+    }
+
+    /// The value, or the function, as textual IR.
+    #[cfg(test)]
+    pub fn print(self) -> String {
+        unsafe { take_message(LLVMPrintValueToString(self.raw)) }
+    }
+}
+
+impl<'m> super::Value for Value<'m> {
+    fn dump(&mut self) -> Self {
+        unsafe { LLVMDumpValue(self.raw); }
+
+        *self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BasicBlock<'m> {
+    raw: LLVMBasicBlockRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> BasicBlock<'m> {
+    fn new(raw: LLVMBasicBlockRef) -> BasicBlock<'m> {
+        BasicBlock { raw: raw, module: PhantomData }
+    }
+
+    /// The function the block is in.
+    pub fn parent(self) -> Value<'m> {
+        Value::new(unsafe { LLVMGetBasicBlockParent(self.raw) })
+    }
+
+    /// The instruction ending the block, unless it's still being built.
+    pub fn terminator(self) -> Option<Value<'m>> {
+        let terminator = unsafe { LLVMGetBasicBlockTerminator(self.raw) };
+        if terminator.is_null() { None } else { Some(Value::new(terminator)) }
+    }
+
+    pub fn first_instruction(self) -> Option<Value<'m>> {
+        let first = unsafe { LLVMGetFirstInstruction(self.raw) };
+        if first.is_null() { None } else { Some(Value::new(first)) }
+    }
+
+    pub fn move_after(self, block: BasicBlock<'m>) {
+        unsafe { LLVMMoveBasicBlockAfter(self.raw, block.raw) }
+    }
+
+    /// Unsafe as the handles into the block are left dangling.
+    pub unsafe fn delete(self) {
+        LLVMDeleteBasicBlock(self.raw)
+    }
+}
+
+/// Passes run on each function of a module as it's compiled, or on whole
+/// modules.
+pub struct PassManager {
+    raw: LLVMPassManagerRef,
+}
+
+impl PassManager {
+    pub fn for_functions(module: &Module) -> PassManager {
+        PassManager { raw: unsafe { LLVMCreateFunctionPassManagerForModule(module.raw) } }
+    }
+
+    pub fn for_modules() -> PassManager {
+        PassManager { raw: unsafe { LLVMCreatePassManager() } }
+    }
+
+    /// Adds a pass with one of the `LLVMAdd...Pass` functions.
+    pub fn add(&self, add_pass: unsafe extern "C" fn(LLVMPassManagerRef)) {
+        unsafe { add_pass(self.raw) }
+    }
+
+    /// Readies function passes for running, once they're all added.
+    pub fn initialize(&self) {
+        unsafe { LLVMInitializeFunctionPassManager(self.raw); }
+    }
+
+    pub fn run_on_function(&self, function: Value) {
+        unsafe { LLVMRunFunctionPassManager(self.raw, function.function()); }
+    }
+
+    pub fn run_on_module(&self, module: &Module) {
+        unsafe { LLVMRunPassManager(self.raw, module.raw); }
+    }
+}
+
+impl Drop for PassManager {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposePassManager(self.raw) }
+    }
+}
+
+/// MCJIT, which owns the modules it's given and compiles each when code in
+/// it is first looked up.
+pub struct ExecutionEngine {
+    raw: LLVMExecutionEngineRef,
+    modules: Vec<Module>,
+}
+
+impl ExecutionEngine {
+    pub fn new(mut module: Module, opt_level: u32) -> Result<ExecutionEngine, String> {
+        unsafe {
+            let mut options: LLVMMCJITCompilerOptions = mem::zeroed();
+            let size = mem::size_of::<LLVMMCJITCompilerOptions>() as libc::size_t;
+            exec::LLVMInitializeMCJITCompilerOptions(&mut options, size);
+            options.OptLevel = opt_level;
+
+            let mut raw = ptr::null_mut();
+            let mut error = ptr::null_mut();
+            let failed = exec::LLVMCreateMCJITCompilerForModule(&mut raw, module.raw, &mut options, size, &mut error);
+            if failed != 0 {
+                // the module was disposed of with the engine that couldn't
+                // be made
+                module.raw = ptr::null_mut();
+                return Err(take_message(error));
+            }
+            Ok(ExecutionEngine { raw: raw, modules: vec!(module) })
+        }
+    }
+
+    pub fn add_module(&mut self, module: Module) {
+        unsafe { exec::LLVMAddModule(self.raw, module.raw) }
+        self.modules.push(module);
+    }
+
+    /// The modules the engine has taken, oldest first.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    /// The address of the compiled function, or zero if none has the name.
+    pub fn function_address(&self, name: &str) -> u64 {
+        unsafe { exec::LLVMGetFunctionAddress(self.raw, c_str(name).as_ptr()) }
+    }
+
+    pub fn global_address(&self, name: &str) -> u64 {
+        unsafe { exec::LLVMGetGlobalValueAddress(self.raw, c_str(name).as_ptr()) }
+    }
+}
+
+impl Drop for ExecutionEngine {
+    fn drop(&mut self) {
+        unsafe { exec::LLVMDisposeExecutionEngine(self.raw) }
+        // which disposed of the modules
+        for module in &mut self.modules {
+            module.raw = ptr::null_mut();
+        }
+    }
+}
+
+#[test]
+fn owners_keep_the_context_alive() {
+    super::initialize();
+    let context = Context::new();
+    let module = Module::parse_ir("test", "define i64 @one() {\n  ret i64 1\n}\n", &context).unwrap();
+    assert!(Module::parse_ir("test", "define i64 @one(", &context).is_err());
+    assert_eq!(Type::Int, module.named_function("one").unwrap().return_type());
+
+    let engine = ExecutionEngine::new(module, 0).unwrap();
+    assert_eq!(2, Rc::strong_count(&context));
+    let one: extern "C" fn() -> i64 = unsafe { mem::transmute(engine.function_address("one") as usize) };
+    assert_eq!(1, one());
+
+    drop(engine);
+    assert_eq!(1, Rc::strong_count(&context));
+}
@@ -0,0 +1,65 @@
+//! Runs the `fancy-boot` executable the way it's used from a shell.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// The executable, which cargo builds next to the directory of the test's.
+fn fancy_boot(args: &[&str]) -> Output {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    let path: PathBuf = path.join(format!("fancy-boot{}", env::consts::EXE_SUFFIX));
+    Command::new(&path).args(args).output().unwrap()
+}
+
+fn stdout(args: &[&str]) -> String {
+    let output = fancy_boot(args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn prints_the_value_of_compiled_source() {
+    // doubles print as the interpreter prints them, whole ones with a ".0"
+    assert_eq!("3.0\n", stdout(&["-s", "(add_double 1 2)"]));
+    assert_eq!("3\n", stdout(&["-s", "(+ 1 2)"]));
+    assert_eq!("\"fancy\"\n", stdout(&["-s", "\"fancy\""]));
+    assert_eq!("", stdout(&["-s", "(when (> 1 2) 3)"]));
+    assert_eq!("3.0\n", stdout(&["-i", "-s", "(+ 1.0 2)"]));
+}
+
+#[test]
+fn reports_compile_errors() {
+    let output = fancy_boot(&["-s", "(+ 1 \"two\")"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Type error: expected a number, got str"));
+}
+
+#[test]
+fn reports_bad_arguments() {
+    for args in &[&["--emit", "ast", "missing.fc"][..], &["nrepl", "--port", "http"][..], &["-O", "4", "-s", "1"][..]] {
+        let output = fancy_boot(args);
+        assert_eq!(Some(1), output.status.code(), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!stderr.contains("panicked"), "{:?}: {}", args, stderr);
+    }
+    assert!(String::from_utf8_lossy(&fancy_boot(&["--emit", "ast", "missing.fc"]).stderr).starts_with("Couldn't read missing.fc"));
+    assert_eq!("--port must be a number from 0 to 65535\n",
+               String::from_utf8_lossy(&fancy_boot(&["nrepl", "--port", "http"]).stderr));
+}
+
+#[test]
+fn reports_runtime_errors() {
+    // the interpreter gives ratios, compiled code an error
+    for &(source, message) in &[("(/ 1 0)", "Divide by zero"), ("(fn h [a] (/ a 0)) (h 5)", "Divide by zero"),
+                                ("(/ 7 2)", "Integer division with a remainder"), ("(/ 2)", "Integer division with a remainder"),
+                                ("(prn 1) (/ (- 0 9223372036854775807 1) -1)", "Integer overflow")] {
+        let output = fancy_boot(&["-s", source]);
+        assert_eq!(Some(1), output.status.code(), "{}", source);
+        assert!(String::from_utf8_lossy(&output.stderr).starts_with(&format!("Runtime error: {}", message)), "{}", source);
+    }
+    assert_eq!("1\n", String::from_utf8_lossy(&fancy_boot(&["-s", "(prn 1) (/ 1 0)"]).stdout));
+}